@@ -1,5 +1,12 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_prost_build::compile_protos("proto/rog.proto")?;
-    tonic_prost_build::compile_protos("proto/rog_reverse.proto")?;
+    // stream payloads are handed through the relay as `Bytes`, without copying
+    tonic_prost_build::configure()
+        .bytes(".moe.rikaaa0928.rog.StreamReq.payload")
+        .bytes(".moe.rikaaa0928.rog.StreamRes.payload")
+        .compile_protos(&["proto/rog.proto"], &["proto"])?;
+    tonic_prost_build::configure()
+        .bytes(".moe.rikaaa0928.rev_rog.RevStreamReq.payload")
+        .bytes(".moe.rikaaa0928.rev_rog.RevStreamRes.payload")
+        .compile_protos(&["proto/rog_reverse.proto"], &["proto"])?;
     Ok(())
 }
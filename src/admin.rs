@@ -0,0 +1,360 @@
+//! The admin HTTP API, a JSON view of the running proxy:
+//!
+//! - `GET /connections`, `DELETE /connections/{id}`
+//! - `GET /reverse_clients`
+//! - `GET /connectors`
+//! - `GET /data`, `POST /data/refresh`
+//! - `GET /stats`
+//! - `GET /config`, with secrets redacted
+//!
+//! Every request needs `Authorization: Bearer <token>` when a token is set.
+
+use crate::block::BlockManager;
+use crate::connector::health;
+use crate::connector::rev_grpc::get_global_rev_grpc_state;
+use crate::def::config;
+use crate::object::connections;
+use crate::object::timeouts;
+use crate::router::DefaultRouter;
+use crate::util::auth_token::ct_eq;
+use log::{debug, error, info};
+use serde_json::{Value, json};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::spawn;
+use tokio::time::timeout;
+
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Keys whose values never leave the process.
+const SECRET_KEYS: [&str; 6] = ["pw", "password", "token", "secret", "psk", "auth"];
+
+pub struct AdminState {
+    pub config: config::Config,
+    pub router: Arc<DefaultRouter>,
+    pub block_manager: Option<Arc<BlockManager>>,
+}
+
+pub async fn start_admin_server(cfg: config::Admin, state: AdminState) -> io::Result<()> {
+    let state = Arc::new(state);
+    let token = cfg.token.filter(|t| !t.is_empty());
+    #[cfg(unix)]
+    if let Some(path) = cfg.endpoint.strip_prefix("unix:") {
+        remove_stale_socket(path)?;
+        let listener = tokio::net::UnixListener::bind(path)?;
+        info!("admin api listening on {}", cfg.endpoint);
+        spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        spawn(serve(stream, state.clone(), token.clone()));
+                    }
+                    Err(e) => error!("admin api accept error: {}", e),
+                }
+            }
+        });
+        return Ok(());
+    }
+    let addr: SocketAddr = cfg.endpoint.parse().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid admin endpoint {}: {}", cfg.endpoint, e),
+        )
+    })?;
+    if token.is_none() && !addr.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "admin api needs a token to listen beyond localhost",
+        ));
+    }
+    let listener = TcpListener::bind(addr).await?;
+    info!("admin api listening on {}", addr);
+    spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    spawn(serve(stream, state.clone(), token.clone()));
+                }
+                Err(e) => error!("admin api accept error: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Removes the socket a previous run left at `path`, refusing to touch
+/// anything else that is there.
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("admin endpoint {} exists and is not a socket", path),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+}
+
+/// Reads the request line and headers, a body is never needed.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Request> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_REQUEST_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            head.truncate(end + 4);
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let mut first = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(path)) = (first.next(), first.next()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad request line",
+        ));
+    };
+    let authorization = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("authorization")
+            .then(|| value.trim().to_string())
+    });
+    Ok(Request {
+        method: method.to_string(),
+        path: path.split('?').next().unwrap_or_default().to_string(),
+        authorization,
+    })
+}
+
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    state: Arc<AdminState>,
+    token: Option<String>,
+) {
+    let req = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(req)) => req,
+        Ok(Err(e)) => {
+            debug!("admin api bad request: {}", e);
+            return;
+        }
+        Err(_) => return,
+    };
+    let (status, body) = if authorized(token.as_deref(), req.authorization.as_deref()) {
+        handle(&state, &req.method, &req.path).await
+    } else {
+        (401, json!({ "error": "unauthorized" }))
+    };
+    debug!("admin api {} {} -> {}", req.method, req.path, status);
+    let body = body.to_string();
+    let reason = match status {
+        200 => "OK",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Bad Request",
+    };
+    let resp = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(resp.as_bytes()).await {
+        debug!("admin api write error: {}", e);
+    }
+    let _ = stream.shutdown().await;
+}
+
+fn authorized(token: Option<&str>, authorization: Option<&str>) -> bool {
+    match token {
+        None => true,
+        Some(token) => authorization
+            .and_then(|a| a.strip_prefix("Bearer "))
+            .is_some_and(|given| ct_eq(given.trim(), token)),
+    }
+}
+
+async fn handle(state: &AdminState, method: &str, path: &str) -> (u16, Value) {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["connections"]) => (200, json!(connections::list())),
+        ("DELETE", ["connections", id]) => match id.parse::<u64>() {
+            Ok(id) if connections::kill(id) => (200, json!({ "killed": id })),
+            Ok(_) => (404, json!({ "error": "no such connection" })),
+            Err(_) => (400, json!({ "error": "invalid connection id" })),
+        },
+        ("GET", ["reverse_clients"]) => (200, json!(get_global_rev_grpc_state().clients())),
+        ("GET", ["connectors"]) => (200, connectors(&state.config)),
+        ("GET", ["data"]) => (200, json!(state.router.data_info())),
+        ("POST", ["data", "refresh"]) => (200, json!(state.router.refresh_data().await)),
+        ("GET", ["stats"]) => (200, stats(state.block_manager.as_deref())),
+        ("GET", ["config"]) => {
+            let mut config = json!(state.config);
+            redact(&mut config);
+            (200, config)
+        }
+        (
+            _,
+            ["connections"]
+            | ["connections", _]
+            | ["reverse_clients"]
+            | ["connectors"]
+            | ["data"]
+            | ["data", "refresh"]
+            | ["stats"]
+            | ["config"],
+        ) => (405, json!({ "error": "method not allowed" })),
+        _ => (404, json!({ "error": "not found" })),
+    }
+}
+
+fn connectors(config: &config::Config) -> Value {
+    let rev = get_global_rev_grpc_state();
+    let list: Vec<Value> = config
+        .connector
+        .iter()
+        .map(|c| {
+            let mut entry = json!({
+                "name": c.name,
+                "proto": c.proto,
+                "endpoint": c.endpoint,
+            });
+            if c.proto == "rev_grpc" {
+                entry["online"] = json!(rev.online(&c.name));
+            }
+            if let (Value::Object(entry), Value::Object(health)) =
+                (&mut entry, json!(health::get(&c.name)))
+            {
+                entry.extend(health);
+            }
+            entry
+        })
+        .collect();
+    json!(list)
+}
+
+fn stats(block_manager: Option<&BlockManager>) -> Value {
+    let expired: serde_json::Map<String, Value> = timeouts::expired()
+        .iter()
+        .map(|(kind, n)| (kind.name().to_string(), json!(n)))
+        .collect();
+    let buffer = block_manager.map(|bm| {
+        let buffered = bm.buffered();
+        json!({
+            "block_limit": bm.block_limit(),
+            "taken_blocks": bm.taken_blocks(),
+            "buffered_bytes": buffered.iter().map(|c| c.bytes).sum::<u64>(),
+            "connections": buffered,
+        })
+    });
+    json!({
+        "connections": connections::list().len(),
+        "timeouts_expired": expired,
+        "buffer": buffer,
+    })
+}
+
+/// Replaces every secret in a config dump.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if SECRET_KEYS
+                    .iter()
+                    .any(|s| key == *s || key.ends_with(&format!("_{}", s)))
+                {
+                    if value.is_string() {
+                        *value = json!("<redacted>");
+                    }
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let mut config = json!({
+            "admin": { "endpoint": "127.0.0.1:9090", "token": "t0ken" },
+            "listener": [{ "name": "in", "pw": "secret1", "user": null,
+                "options": { "tls_key": "/etc/key.pem", "mux_psk": "abc", "legacy_auth": true } }],
+        });
+        redact(&mut config);
+        assert_eq!(config["admin"]["token"], "<redacted>");
+        assert_eq!(config["admin"]["endpoint"], "127.0.0.1:9090");
+        assert_eq!(config["listener"][0]["pw"], "<redacted>");
+        assert_eq!(config["listener"][0]["user"], Value::Null);
+        assert_eq!(config["listener"][0]["options"]["tls_key"], "/etc/key.pem");
+        assert_eq!(config["listener"][0]["options"]["mux_psk"], "<redacted>");
+        assert_eq!(config["listener"][0]["options"]["legacy_auth"], true);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_remove_stale_socket() {
+        let dir = std::env::temp_dir();
+        let name = format!("rog_admin_test_{}", std::process::id());
+        let file = dir.join(format!("{}.txt", name));
+        std::fs::write(&file, b"keep").unwrap();
+        assert!(remove_stale_socket(file.to_str().unwrap()).is_err());
+        assert!(file.exists());
+        std::fs::remove_file(&file).unwrap();
+
+        let socket = dir.join(format!("{}.sock", name));
+        let _ = std::fs::remove_file(&socket);
+        drop(tokio::net::UnixListener::bind(&socket).unwrap());
+        remove_stale_socket(socket.to_str().unwrap()).unwrap();
+        assert!(!socket.exists());
+        remove_stale_socket(socket.to_str().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client
+            .write_all(b"DELETE /connections/7?x=1 HTTP/1.1\r\nHost: a\r\nauthorization: Bearer abc\r\n\r\n")
+            .await
+            .unwrap();
+        let req = read_request(&mut server).await.unwrap();
+        assert_eq!(req.method, "DELETE");
+        assert_eq!(req.path, "/connections/7");
+        assert!(authorized(Some("abc"), req.authorization.as_deref()));
+        assert!(!authorized(Some("abd"), req.authorization.as_deref()));
+        assert!(!authorized(Some("abc"), None));
+        assert!(authorized(None, None));
+    }
+}
@@ -1,37 +1,110 @@
+use crate::consts::TCP_IO_BUFFER_SIZE;
+use bytes::Bytes;
 use log::{debug, info};
-use std::collections::VecDeque;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use tokio::select;
-use tokio::sync::Notify;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
 
+/// The global TCP buffer, counted in blocks of `TCP_IO_BUFFER_SIZE` bytes.
+///
+/// Each direction of a connection may queue `min_blocks` blocks of data without
+/// waiting, taking blocks only when some are free. Beyond that a chunk waits
+/// for its blocks, first from its listener's sub-budget and then from the
+/// global one. Both are FIFO semaphores, so waiters are served in order and a
+/// busy connection can't take blocks freed for one that waited longer.
 pub struct BlockManager {
     block_limit: u64,
-    taken_blocks: AtomicU64,
+    min_blocks: u64,
+    permits: Arc<Semaphore>,
     take_count: AtomicU64,
-    notify: Notify,
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Weak<ConnBuffer>>>,
 }
 
 impl BlockManager {
     pub fn new(block_limit: u64) -> BlockManager {
         BlockManager {
             block_limit,
-            taken_blocks: Default::default(),
+            min_blocks: 1,
+            permits: Arc::new(Semaphore::new(block_limit as usize)),
             take_count: Default::default(),
-            notify: Notify::new(),
+            next_id: Default::default(),
+            connections: Default::default(),
         }
     }
 
+    /// Blocks each connection direction may queue regardless of the limits.
+    pub fn with_min_blocks(mut self, min_blocks: u64) -> Self {
+        self.min_blocks = min_blocks.max(1);
+        self
+    }
+
+    #[cfg(test)]
     pub fn can_take(&self) -> bool {
-        self.taken_blocks.load(Ordering::Relaxed) < self.block_limit
+        self.permits.available_permits() > 0
+    }
+
+    /// The share of `listener`, which may not use more than `block_limit`
+    /// blocks when set.
+    pub fn pool(self: &Arc<Self>, listener: &str, block_limit: Option<u64>) -> Arc<BlockPool> {
+        Arc::new(BlockPool {
+            manager: self.clone(),
+            listener: listener.to_string(),
+            permits: block_limit.map(|n| Arc::new(Semaphore::new(n as usize))),
+            block_limit: block_limit
+                .unwrap_or(self.block_limit)
+                .min(self.block_limit),
+        })
+    }
+
+    pub fn block_limit(&self) -> u64 {
+        self.block_limit
     }
-    pub fn take(&self) {
-        let taken = self.taken_blocks.fetch_add(1, Ordering::Relaxed);
+
+    /// Blocks held by queued chunks right now.
+    pub fn taken_blocks(&self) -> u64 {
+        self.block_limit
+            .saturating_sub(self.permits.available_permits() as u64)
+    }
+
+    /// Buffered bytes of every open connection, most first. Spliced tcp -> tcp
+    /// relays hold no blocks and aren't listed.
+    pub fn buffered(&self) -> Vec<BufferedConnection> {
+        // collected first, the map must not be locked when the last reference drops
+        let open: Vec<Arc<ConnBuffer>> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut list: Vec<BufferedConnection> = open
+            .iter()
+            .map(|c| BufferedConnection {
+                listener: c.pool.listener.clone(),
+                label: c.label.clone(),
+                bytes: c.bytes(),
+            })
+            .collect();
+        list.sort_by_key(|c| Reverse(c.bytes));
+        list
+    }
+
+    fn count_take(&self) {
         let count = self.take_count.fetch_add(1, Ordering::Relaxed);
+        let taken = self.taken_blocks();
         if count % 1000 == 0 {
+            let buffered = self.buffered();
             info!(
-                "BlockManager: take count {}, taken_blocks/block_limit: {}/{}",
-                count, taken, self.block_limit
+                "BlockManager: take count {}, taken_blocks/block_limit: {}/{}, {} connections, most {:?}",
+                count,
+                taken,
+                self.block_limit,
+                buffered.len(),
+                buffered.first()
             );
         } else if count % 100 == 0 {
             debug!(
@@ -40,100 +113,183 @@ impl BlockManager {
             );
         }
     }
-    pub fn release(&self) {
-        self.taken_blocks.fetch_sub(1, Ordering::Relaxed);
-        self.notify.notify_one();
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BufferedConnection {
+    pub listener: String,
+    pub label: String,
+    pub bytes: u64,
+}
+
+/// One listener's view of the `BlockManager`.
+pub struct BlockPool {
+    manager: Arc<BlockManager>,
+    listener: String,
+    permits: Option<Arc<Semaphore>>,
+    block_limit: u64,
+}
+
+/// Blocks held by one queued chunk, given back when it is consumed.
+#[derive(Default)]
+struct Permits {
+    _listener: Option<OwnedSemaphorePermit>,
+    _global: Option<OwnedSemaphorePermit>,
+}
+
+impl BlockPool {
+    /// Registers a connection for the buffer stats.
+    pub fn connection(self: &Arc<Self>, label: String) -> Arc<ConnBuffer> {
+        let id = self.manager.next_id.fetch_add(1, Ordering::Relaxed);
+        let conn = Arc::new(ConnBuffer {
+            id,
+            pool: self.clone(),
+            label,
+            bytes: AtomicU64::new(0),
+        });
+        self.manager
+            .connections
+            .lock()
+            .unwrap()
+            .insert(id, Arc::downgrade(&conn));
+        conn
+    }
+
+    /// Blocks for a chunk of `len` bytes, capped so that it always fits.
+    fn blocks(&self, len: usize) -> u32 {
+        (len.div_ceil(TCP_IO_BUFFER_SIZE) as u64).min(self.block_limit) as u32
+    }
+
+    /// Whatever blocks are free right now.
+    fn try_take(&self, blocks: u32) -> Permits {
+        if blocks == 0 {
+            return Permits::default();
+        }
+        Permits {
+            _listener: self
+                .permits
+                .as_ref()
+                .and_then(|p| p.clone().try_acquire_many_owned(blocks).ok()),
+            _global: self
+                .manager
+                .permits
+                .clone()
+                .try_acquire_many_owned(blocks)
+                .ok(),
+        }
+    }
+
+    async fn take(&self, blocks: u32) -> Permits {
+        if blocks == 0 {
+            return Permits::default();
+        }
+        // the semaphores are never closed
+        let listener = match &self.permits {
+            Some(p) => p.clone().acquire_many_owned(blocks).await.ok(),
+            None => None,
+        };
+        let global = self
+            .manager
+            .permits
+            .clone()
+            .acquire_many_owned(blocks)
+            .await
+            .ok();
+        Permits {
+            _listener: listener,
+            _global: global,
+        }
     }
-    pub async fn wait(&self) {
-        self.notify.notified().await;
+}
+
+/// Buffer use of one connection, shared by its two directions.
+pub struct ConnBuffer {
+    id: u64,
+    pool: Arc<BlockPool>,
+    label: String,
+    bytes: AtomicU64,
+}
+
+impl ConnBuffer {
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
     }
 }
 
-use bytes::Bytes;
+impl Drop for ConnBuffer {
+    fn drop(&mut self) {
+        self.pool
+            .manager
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+    }
+}
 
+/// The queue of one direction of a connection.
 pub struct DataBlock {
-    block_manager: Arc<BlockManager>,
-    data: Mutex<VecDeque<Bytes>>,
+    conn: Arc<ConnBuffer>,
+    data: Mutex<VecDeque<(Bytes, Permits)>>,
+    /// Blocks worth of data in `data`.
+    queued: AtomicU64,
     provide_notify: Notify,
-    consume_notify: Notify,
 }
 
 impl DataBlock {
-    pub fn new(block_manager: Arc<BlockManager>) -> Self {
+    pub fn new(conn: Arc<ConnBuffer>) -> Self {
         DataBlock {
-            block_manager,
+            conn,
             data: Mutex::new(VecDeque::new()),
+            queued: AtomicU64::new(0),
             provide_notify: Notify::new(),
-            consume_notify: Notify::new(),
         }
     }
 
     pub async fn provide(&self, data: Bytes) {
-        if self.inner_provide(data.clone()) {
-            return;
-        }
-        loop {
-            select! {
-                _=self.consume_notify.notified() => {
-
-                },
-                _=self.block_manager.wait() => {
-
-                },
-            }
-            if self.inner_provide(data.clone()) {
-                return;
-            }
-        }
-    }
-
-    fn inner_provide(&self, data: Bytes) -> bool {
-        // 允许一定量的超出限制, 为空必定写入，防止阻塞流量
-        if self.data.lock().unwrap().is_empty() {
-            self.block_manager.take();
-            self.data.lock().unwrap().push_back(data);
-            self.provide_notify.notify_one();
-            return true;
-        }
-        // 允许一定量的超出限制
-        if self.block_manager.can_take() {
-            self.block_manager.take();
-            self.data.lock().unwrap().push_back(data);
-            self.provide_notify.notify_one();
-            return true;
+        let pool = &self.conn.pool;
+        let blocks = pool.blocks(data.len());
+        // below the minimum, a chunk never waits, even when it has to go over the limits
+        let queued = self.queued.fetch_add(blocks as u64, Ordering::Relaxed);
+        let permits = if queued < pool.manager.min_blocks {
+            pool.try_take(blocks)
+        } else {
+            pool.take(blocks).await
+        };
+        if blocks > 0 {
+            pool.manager.count_take();
         }
-        false
+        self.conn
+            .bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.data.lock().unwrap().push_back((data, permits));
+        self.provide_notify.notify_one();
     }
 
     pub async fn consume(&self) -> Bytes {
-        if self.data.lock().unwrap().is_empty() {
-            loop {
-                self.provide_notify.notified().await;
-                if !self.data.lock().unwrap().is_empty() {
-                    let data = self.data.lock().unwrap().pop_front().unwrap();
-                    self.block_manager.release();
-                    self.consume_notify.notify_one();
-                    return data;
-                }
+        loop {
+            let next = self.data.lock().unwrap().pop_front();
+            if let Some((data, _permits)) = next {
+                self.queued
+                    .fetch_sub(self.conn.pool.blocks(data.len()) as u64, Ordering::Relaxed);
+                self.conn
+                    .bytes
+                    .fetch_sub(data.len() as u64, Ordering::Relaxed);
+                return data;
             }
-        } else {
-            let data = self.data.lock().unwrap().pop_front().unwrap();
-            self.block_manager.release();
-            self.consume_notify.notify_one();
-            return data;
+            self.provide_notify.notified().await;
         }
     }
 }
 
 impl Drop for DataBlock {
     fn drop(&mut self) {
-        let remaining_blocks = match self.data.get_mut() {
-            Ok(data) => data.len(),
-            Err(poisoned) => poisoned.into_inner().len(),
+        let remaining: usize = match self.data.get_mut() {
+            Ok(data) => data.iter().map(|(d, _)| d.len()).sum(),
+            Err(poisoned) => poisoned.into_inner().iter().map(|(d, _)| d.len()).sum(),
         };
-
-        for _ in 0..remaining_blocks {
-            self.block_manager.release();
-        }
+        self.conn
+            .bytes
+            .fetch_sub(remaining as u64, Ordering::Relaxed);
     }
 }
@@ -6,6 +6,7 @@ use crate::def::{RunConnector, config};
 
 pub(crate) mod block;
 pub(crate) mod grpc;
+pub(crate) mod health;
 pub(crate) mod pb_tcp;
 pub(crate) mod rev_grpc;
 pub(crate) mod tcp;
@@ -13,7 +14,7 @@ pub(crate) mod tcp;
 pub async fn create(cfg: &config::Connector) -> std::io::Result<Box<dyn RunConnector>> {
     match cfg.proto.as_str() {
         "tcp" => {
-            let res = TcpRunConnector::new();
+            let res = TcpRunConnector::new(cfg);
             Ok(Box::new(res))
         }
         "grpc" => {
@@ -1,28 +1,134 @@
-use crate::def::config::get_option_bool;
+use crate::def::config::{get_option_bool, get_option_u64};
 use crate::def::{RunConnector, RunStream, RunUdpReader, RunUdpWriter, config};
 use crate::proto::v1::pb::rog_service_client::RogServiceClient;
 use crate::proto::v1::pb::{StreamReq, UdpReq};
 use crate::stream::grpc_client::GrpcClientRunStream;
 use crate::stream::grpc_udp_client::{GrpcUdpClientRunReader, GrpcUdpClientRunWriter};
-use crate::util::grpc_transport::connect_channel_without_proxy;
-use log::{error, info};
+use crate::util::auth_token::client_auth;
+use crate::util::grpc_transport::{connect_channel_without_proxy, with_client_tls};
+use log::{error, info, warn};
 use std::io;
 use std::io::ErrorKind;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, mpsc};
 use tokio::time::sleep;
-use tonic::Request;
 use tonic::codegen::tokio_stream;
-use tonic::transport::Endpoint;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Status};
 // pub mod pb {
 //     tonic::include_proto!("moe.rikaaa0928.rog");
 // }
 
+type Client = RogServiceClient<Channel>;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// One pooled HTTP/2 connection. A broken channel is dropped and redialed on
+/// its next use; failed dials back off before the next attempt.
+struct ChannelSlot {
+    client: StdMutex<Option<Client>>,
+    failures: AtomicU32,
+    retry_at: StdMutex<Option<Instant>>,
+    dialing: Mutex<()>,
+}
+
+impl ChannelSlot {
+    fn new() -> Self {
+        Self {
+            client: StdMutex::new(None),
+            failures: AtomicU32::new(0),
+            retry_at: StdMutex::new(None),
+            dialing: Mutex::new(()),
+        }
+    }
+
+    fn current(&self) -> Option<Client> {
+        self.client.lock().unwrap().clone()
+    }
+
+    fn backing_off(&self) -> bool {
+        matches!(*self.retry_at.lock().unwrap(), Some(t) if Instant::now() < t)
+    }
+
+    fn mark_broken(&self) {
+        self.client.lock().unwrap().take();
+    }
+
+    fn mark_failed(&self) {
+        self.mark_broken();
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed);
+        *self.retry_at.lock().unwrap() = Some(Instant::now() + backoff(failures));
+    }
+
+    async fn get(&self, endpoint: &Endpoint) -> io::Result<Client> {
+        if let Some(client) = self.current() {
+            return Ok(client);
+        }
+        let _dialing = self.dialing.lock().await;
+        if let Some(client) = self.current() {
+            return Ok(client);
+        }
+        if self.backing_off() {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "grpc channel backing off",
+            ));
+        }
+        match connect_channel_without_proxy(endpoint.clone()).await {
+            Ok(channel) => {
+                if self.failures.swap(0, Ordering::Relaxed) > 0 {
+                    info!("grpc channel to {} reconnected", endpoint.uri());
+                }
+                *self.retry_at.lock().unwrap() = None;
+                let client = RogServiceClient::new(channel);
+                *self.client.lock().unwrap() = Some(client.clone());
+                Ok(client)
+            }
+            Err(e) => {
+                warn!("grpc channel to {} connect error: {}", endpoint.uri(), e);
+                self.mark_failed();
+                Err(io::Error::other(e))
+            }
+        }
+    }
+}
+
+pub(crate) fn backoff(failures: u32) -> Duration {
+    Duration::from_millis(100)
+        .saturating_mul(1 << failures.min(10))
+        .min(MAX_BACKOFF)
+}
+
+/// Errors that mean the HTTP/2 connection itself is gone: `Unavailable`, or a
+/// local transport failure in the status' source chain. Handler errors the
+/// server maps to `Unknown` leave the channel pooled.
+fn is_connection_error(status: &Status) -> bool {
+    if status.code() == Code::Unavailable {
+        return true;
+    }
+    let mut source = std::error::Error::source(status);
+    while let Some(err) = source {
+        if err.is::<tonic::transport::Error>() || err.is::<io::Error>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// gRPC connector over a pool of `pool_size` channels (default 1). Streams
+/// are spread round robin, and broken channels reconnect with backoff.
 pub struct GrpcRunConnector {
-    client: Arc<Mutex<RogServiceClient<tonic::transport::Channel>>>,
+    endpoint: Endpoint,
+    slots: Vec<ChannelSlot>,
+    next: AtomicUsize,
+    /// Send the plaintext password instead of auth tokens.
+    legacy_auth: bool,
     cfg: config::Connector,
 }
+
 impl GrpcRunConnector {
     pub async fn new(cfg: &config::Connector) -> io::Result<Self> {
         let endpoint = cfg.endpoint.as_ref().ok_or_else(|| {
@@ -30,43 +136,68 @@ impl GrpcRunConnector {
             error!("{}", err_msg);
             io::Error::new(ErrorKind::InvalidInput, err_msg)
         })?;
-        let mut err = None;
-        let keep_alive = get_option_bool(&cfg.options, "keep_alive");
-        for i in 1..=3 {
-            let client = match Endpoint::new(endpoint.clone()) {
-                Ok(endpoint) => {
-                    let endpoint = if keep_alive {
-                        endpoint
-                            .http2_keep_alive_interval(Duration::from_secs(30))
-                            .keep_alive_timeout(Duration::from_secs(10))
-                            .keep_alive_while_idle(true)
-                    } else {
-                        endpoint
-                    };
-                    connect_channel_without_proxy(endpoint)
-                        .await
-                        .map(RogServiceClient::new)
-                }
-                Err(e) => Err(e),
-            };
-            match client {
-                Ok(client) => {
-                    if err.is_some() {
-                        info!("grpc Connector {} is established after retry", endpoint);
+        let endpoint = Endpoint::new(endpoint.clone()).map_err(io::Error::other)?;
+        let endpoint = with_client_tls(endpoint, &cfg.options)?;
+        let endpoint = if get_option_bool(&cfg.options, "keep_alive") {
+            endpoint
+                .http2_keep_alive_interval(Duration::from_secs(30))
+                .keep_alive_timeout(Duration::from_secs(10))
+                .keep_alive_while_idle(true)
+        } else {
+            endpoint
+        };
+        let pool_size = get_option_u64(&cfg.options, "pool_size")
+            .unwrap_or(1)
+            .max(1) as usize;
+        let connector = Self {
+            endpoint,
+            slots: (0..pool_size).map(|_| ChannelSlot::new()).collect(),
+            next: AtomicUsize::new(0),
+            legacy_auth: get_option_bool(&cfg.options, "legacy_auth"),
+            cfg: cfg.clone(),
+        };
+        // fail when the server can't be reached at all, so the broken
+        // connector isn't cached
+        let mut attempt = 0;
+        loop {
+            match connector.client().await {
+                Ok(_) => {
+                    if attempt > 0 {
+                        info!(
+                            "grpc Connector {} is established after retry",
+                            connector.endpoint.uri()
+                        );
                     }
-                    return Ok(Self {
-                        client: Arc::new(Mutex::new(client)),
-                        cfg: cfg.clone(),
-                    });
-                }
-                Err(e) => {
-                    error!("grpc connection error: {}", e);
-                    err = Some(e);
-                    sleep(Duration::from_millis(i * 100)).await;
+                    return Ok(connector);
                 }
+                Err(e) if attempt == 2 => return Err(e),
+                Err(_) => sleep(backoff(attempt)).await,
             }
+            attempt += 1;
         }
-        Err(io::Error::other(err.unwrap()))
+    }
+
+    /// Next usable channel, round robin, skipping channels that are down.
+    async fn client(&self) -> io::Result<(usize, Client)> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut err = None;
+        for i in 0..self.slots.len() {
+            let index = (start + i) % self.slots.len();
+            match self.slots[index].get(&self.endpoint).await {
+                Ok(client) => return Ok((index, client)),
+                Err(e) => err = Some(e),
+            }
+        }
+        Err(err.unwrap())
+    }
+
+    /// Drops the channel behind a failed call, true when it was broken.
+    fn check(&self, index: usize, status: &Status) -> bool {
+        let broken = is_connection_error(status);
+        if broken {
+            self.slots[index].mark_broken();
+        }
+        broken
     }
 }
 
@@ -74,21 +205,31 @@ impl GrpcRunConnector {
 impl RunConnector for GrpcRunConnector {
     async fn connect(&self, addr: String) -> io::Result<Box<dyn RunStream>> {
         let (host, port) = parse_address(addr.as_str())?;
-        let (tx, rx) = mpsc::channel::<StreamReq>(8);
-        let rx = tokio_stream::wrappers::ReceiverStream::new(rx);
-        let rx = Request::new(rx);
-
-        let resp = match self.client.lock().await.stream(rx).await {
-            Ok(r) => r.into_inner(),
-            Err(e) => {
-                error!("gRPC connector failed to open stream: {}", e);
-                return Err(io::Error::other(e));
+        // a broken channel is redialed once before the error reaches the caller
+        let mut retried = false;
+        let (tx, resp) = loop {
+            let (tx, rx) = mpsc::channel::<StreamReq>(8);
+            let rx = Request::new(tokio_stream::wrappers::ReceiverStream::new(rx));
+            let (index, mut client) = self.client().await?;
+            match client.stream(rx).await {
+                Ok(r) => break (tx, r.into_inner()),
+                Err(e) => {
+                    error!("gRPC connector failed to open stream: {}", e);
+                    if !self.check(index, &e) || retried {
+                        return Err(io::Error::other(e));
+                    }
+                    retried = true;
+                }
             }
         };
 
         let t = tx.clone();
         let auth = StreamReq {
-            auth: self.cfg.pw.clone().unwrap(),
+            auth: client_auth(
+                self.cfg.pw.as_ref().unwrap(),
+                &format!("stream {}:{}", host, port),
+                self.legacy_auth,
+            ),
             dst_port: Some(port as u32),
             dst_addr: Some(host.clone()),
             ..Default::default()
@@ -112,30 +253,36 @@ impl RunConnector for GrpcRunConnector {
         &self,
         src_addr: String,
     ) -> io::Result<Option<(Box<dyn RunUdpReader>, Box<dyn RunUdpWriter>)>> {
-        let (tx, rx) = mpsc::channel::<UdpReq>(8);
-        let rx = tokio_stream::wrappers::ReceiverStream::new(rx);
-        let rx = Request::new(rx);
-        let res = self.client.lock().await.udp(rx).await;
-        if res.is_err() {
-            return Err(io::Error::other("grpc stream error"));
-        }
-        let resp = res.unwrap().into_inner();
+        let mut retried = false;
+        let (tx, resp) = loop {
+            let (tx, rx) = mpsc::channel::<UdpReq>(8);
+            let rx = Request::new(tokio_stream::wrappers::ReceiverStream::new(rx));
+            let (index, mut client) = self.client().await?;
+            match client.udp(rx).await {
+                Ok(r) => break (tx, r.into_inner()),
+                Err(e) => {
+                    error!("gRPC connector failed to open udp stream: {}", e);
+                    if !self.check(index, &e) || retried {
+                        return Err(io::Error::other(e));
+                    }
+                    retried = true;
+                }
+            }
+        };
+        // one token for the whole udp stream, checked on its first packet
+        let auth = client_auth(self.cfg.pw.as_ref().unwrap(), "udp", self.legacy_auth);
         Ok(Some((
             Box::new(GrpcUdpClientRunReader::new(
                 resp,
                 src_addr.clone(),
-                self.cfg.pw.as_ref().unwrap().clone(),
-            )),
-            Box::new(GrpcUdpClientRunWriter::new(
-                tx,
-                src_addr,
-                self.cfg.pw.as_ref().unwrap().clone(),
+                auth.clone(),
             )),
+            Box::new(GrpcUdpClientRunWriter::new(tx, src_addr, auth)),
         )))
     }
 }
 
-pub(super) fn parse_address(addr: &str) -> io::Result<(String, u16)> {
+pub(crate) fn parse_address(addr: &str) -> io::Result<(String, u16)> {
     // 使用 rsplit_once 从右边分割,这样可以处理 IPv6 地址中的冒号
     let (host, port) = addr
         .rsplit_once(':')
@@ -153,3 +300,160 @@ pub(super) fn parse_address(addr: &str) -> io::Result<(String, u16)> {
 
     Ok((host.to_string(), port))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::v1::pb::rog_service_server::{RogService, RogServiceServer};
+    use crate::proto::v1::pb::{StreamRes, UdpRes};
+    use socket2::SockRef;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::spawn;
+    use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
+    use tonic::transport::Server;
+    use tonic::{Response, Streaming};
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_millis(100));
+        assert_eq!(backoff(3), Duration::from_millis(800));
+        assert_eq!(backoff(40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_is_connection_error() {
+        assert!(is_connection_error(&Status::unavailable("gone")));
+        assert!(!is_connection_error(&Status::unknown("handler failed")));
+        assert!(!is_connection_error(&Status::internal("bad frame")));
+        let reset = io::Error::new(ErrorKind::ConnectionReset, "reset");
+        let status = Status::from_error(Box::new(reset));
+        assert_eq!(status.code(), Code::Unknown);
+        assert!(is_connection_error(&status));
+    }
+
+    /// Accepts every call and keeps it open until the client ends it.
+    struct Sink;
+
+    fn drain<Req: Send + 'static, Res: Send + 'static>(
+        mut request: Streaming<Req>,
+    ) -> ReceiverStream<Result<Res, Status>> {
+        let (tx, rx) = mpsc::channel(1);
+        spawn(async move {
+            while let Ok(Some(_)) = request.message().await {}
+            drop(tx);
+        });
+        ReceiverStream::new(rx)
+    }
+
+    #[tonic::async_trait]
+    impl RogService for Sink {
+        type streamStream = ReceiverStream<Result<StreamRes, Status>>;
+
+        async fn stream(
+            &self,
+            request: Request<Streaming<StreamReq>>,
+        ) -> Result<Response<Self::streamStream>, Status> {
+            Ok(Response::new(drain(request.into_inner())))
+        }
+
+        type udpStream = ReceiverStream<Result<UdpRes, Status>>;
+
+        async fn udp(
+            &self,
+            request: Request<Streaming<UdpReq>>,
+        ) -> Result<Response<Self::udpStream>, Status> {
+            Ok(Response::new(drain(request.into_inner())))
+        }
+    }
+
+    /// Forwards to `upstream`. Once `cut` moves on, older connections are
+    /// reset on their next request, like a peer that died silently.
+    async fn proxy(upstream: SocketAddr, cut: Arc<AtomicUsize>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let generation = cut.load(Ordering::SeqCst);
+                let cut = cut.clone();
+                spawn(async move {
+                    SockRef::from(&client)
+                        .set_linger(Some(Duration::ZERO))
+                        .unwrap();
+                    let server = TcpStream::connect(upstream).await.unwrap();
+                    let (mut client_r, mut client_w) = client.into_split();
+                    let (mut server_r, mut server_w) = server.into_split();
+                    let down = spawn(async move {
+                        let _ = tokio::io::copy(&mut server_r, &mut client_w).await;
+                    });
+                    let mut buf = vec![0; 16 * 1024];
+                    while let Ok(n) = client_r.read(&mut buf).await
+                        && n > 0
+                        && cut.load(Ordering::SeqCst) == generation
+                        && server_w.write_all(&buf[..n]).await.is_ok()
+                    {}
+                    down.abort();
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_broken_channel() {
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        spawn(
+            Server::builder()
+                .add_service(RogServiceServer::new(Sink))
+                .serve(upstream),
+        );
+        sleep(Duration::from_millis(100)).await;
+        let cut = Arc::new(AtomicUsize::new(0));
+        let addr = proxy(upstream, cut.clone()).await;
+        let connector = GrpcRunConnector::new(&config::Connector {
+            endpoint: Some(format!("http://{}", addr)),
+            name: "grpc".to_string(),
+            user: None,
+            pw: Some("pw".to_string()),
+            proto: "grpc".to_string(),
+            options: None,
+        })
+        .await
+        .unwrap();
+        assert!(
+            connector
+                .connect("example.com:80".to_string())
+                .await
+                .is_ok()
+        );
+
+        cut.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = connector.connect("example.com:80".to_string()).await {
+            panic!("connect after the channel broke: {}", e);
+        }
+        assert!(
+            connector
+                .udp_tunnel("127.0.0.1:1".to_string())
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slot_backs_off_after_failure() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let endpoint = Endpoint::new(format!("http://{}", addr)).unwrap();
+        let slot = ChannelSlot::new();
+        assert!(slot.get(&endpoint).await.is_err());
+        assert!(slot.backing_off());
+        let err = slot.get(&endpoint).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+    }
+}
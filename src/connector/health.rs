@@ -0,0 +1,77 @@
+//! Outcome of the latest connects of every connector, for the admin API.
+
+use dashmap::DashMap;
+use serde::Serialize;
+use std::io;
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static HEALTH: LazyLock<DashMap<String, Health>> = LazyLock::new(DashMap::new);
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Health {
+    pub connects: u64,
+    pub failures: u64,
+    /// Failures since the last successful connect.
+    pub consecutive_failures: u64,
+    /// Unix seconds.
+    pub last_success: Option<u64>,
+    pub last_failure: Option<u64>,
+    pub last_error: Option<String>,
+    /// Of the last successful connect.
+    pub latency_ms: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Counts one connect (or UDP tunnel) of `connector` that took `elapsed`.
+pub fn record<T>(connector: &str, res: &io::Result<T>, elapsed: Duration) {
+    let mut health = HEALTH.entry(connector.to_string()).or_default();
+    health.connects += 1;
+    match res {
+        Ok(_) => {
+            health.consecutive_failures = 0;
+            health.last_success = Some(now());
+            health.latency_ms = Some(elapsed.as_millis() as u64);
+        }
+        Err(e) => {
+            health.failures += 1;
+            health.consecutive_failures += 1;
+            health.last_failure = Some(now());
+            health.last_error = Some(e.to_string());
+        }
+    }
+}
+
+/// The counters of `connector`, all zero before its first connect.
+pub fn get(connector: &str) -> Health {
+    HEALTH
+        .get(connector)
+        .map(|h| h.value().clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let name = "test_record";
+        record(name, &Ok(()), Duration::from_millis(12));
+        record::<()>(name, &Err(io::Error::other("refused")), Duration::ZERO);
+        record::<()>(name, &Err(io::Error::other("reset")), Duration::ZERO);
+        let health = get(name);
+        assert_eq!(health.connects, 3);
+        assert_eq!(health.failures, 2);
+        assert_eq!(health.consecutive_failures, 2);
+        assert_eq!(health.latency_ms, Some(12));
+        assert_eq!(health.last_error.as_deref(), Some("reset"));
+        assert_eq!(get("test_record_unused"), Health::default());
+    }
+}
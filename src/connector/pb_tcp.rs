@@ -1,17 +1,34 @@
 use crate::connector::grpc::parse_address;
+use crate::def::config::{get_option_bool, get_option_u64};
 use crate::def::{RunConnector, RunStream, RunUdpReader, RunUdpWriter, config};
 use crate::proto::v1::pb::StreamReq;
 use crate::stream::pb_tcp_client::PbTcpClientRunStream;
 use crate::stream::pb_tcp_udp_client::{PbTcpUdpClientReader, PbTcpUdpClientWriter};
 use crate::util::crypto::encrypt_field;
+use crate::util::mux::MuxSession;
+use crate::util::secure::{SecureKey, client_handshake};
 use crate::util::tcp_frame::*;
+use crate::util::tls::TlsClient;
 use std::io;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 
 pub struct PbTcpRunConnector {
     cfg: config::Connector,
+    tls: Option<TlsClient>,
+    mux: Option<MuxPool>,
+    secure: Option<SecureKey>,
+    encrypt: EncryptMode,
+    padding: FramePadding,
+}
+
+/// Long-lived mux sessions shared by all streams of a connector. A closed
+/// session is redialed on its next use.
+struct MuxPool {
+    sessions: Vec<Mutex<Option<MuxSession>>>,
+    next: AtomicUsize,
 }
 
 impl PbTcpRunConnector {
@@ -22,7 +39,65 @@ impl PbTcpRunConnector {
                 "pb_tcp connector config is missing 'endpoint'",
             )
         })?;
-        Ok(Self { cfg: cfg.clone() })
+        let mux = get_option_bool(&cfg.options, "mux").then(|| {
+            let connections = get_option_u64(&cfg.options, "mux_connections")
+                .unwrap_or(2)
+                .max(1);
+            MuxPool {
+                sessions: (0..connections).map(|_| Mutex::new(None)).collect(),
+                next: AtomicUsize::new(0),
+            }
+        });
+        Ok(Self {
+            cfg: cfg.clone(),
+            tls: TlsClient::from_options(&cfg.options)?,
+            mux,
+            encrypt: EncryptMode::from_options(&cfg.options)?,
+            padding: FramePadding::from_options(&cfg.options),
+            secure: get_option_bool(&cfg.options, "secure")
+                .then(|| SecureKey::new(cfg.pw.as_deref().unwrap_or_default())),
+        })
+    }
+
+    /// A connection for one stream or udp tunnel: a fresh tcp connection, or
+    /// a logical stream of a mux session.
+    async fn open(&self) -> io::Result<(FrameReader, FrameWriter)> {
+        let Some(mux) = &self.mux else {
+            return self.dial().await;
+        };
+        let index = mux.next.fetch_add(1, Ordering::Relaxed) % mux.sessions.len();
+        let mut session = mux.sessions[index].lock().await;
+        if let Some(s) = session.as_ref()
+            && !s.is_closed()
+        {
+            return s.open().await;
+        }
+        let (reader, mut writer) = self.dial().await?;
+        write_conn_type(&mut writer, CONN_TYPE_MUX).await?;
+        let s = session.insert(MuxSession::client(reader, writer));
+        s.open().await
+    }
+
+    async fn dial(&self) -> io::Result<(FrameReader, FrameWriter)> {
+        let endpoint = self.cfg.endpoint.as_ref().unwrap();
+        let stream = TcpStream::connect(endpoint).await?;
+        let (reader, mut writer): (FrameReader, FrameWriter) = match &self.tls {
+            Some(tls) => {
+                let (r, w) = tokio::io::split(tls.connect(endpoint, stream).await?);
+                (Box::new(r), Box::new(w))
+            }
+            None => {
+                let (r, w) = stream.into_split();
+                (Box::new(r), Box::new(w))
+            }
+        };
+        match &self.secure {
+            Some(key) => {
+                write_conn_type(&mut writer, CONN_TYPE_SECURE).await?;
+                client_handshake(reader, writer, key).await
+            }
+            None => Ok((reader, writer)),
+        }
     }
 }
 
@@ -31,21 +106,34 @@ impl RunConnector for PbTcpRunConnector {
     async fn connect(&self, addr: String) -> io::Result<Box<dyn RunStream>> {
         let (host, port) = parse_address(addr.as_str())?;
         let pw = self.cfg.pw.as_ref().unwrap();
+        // a secure session is already authenticated and encrypted
+        let secure = self.secure.is_some();
+        let encrypt = !secure && self.encrypt.for_port(port);
 
-        let stream = TcpStream::connect(self.cfg.endpoint.as_ref().unwrap()).await?;
-        let (reader, mut writer) = stream.into_split();
+        let (reader, mut writer) = self.open().await?;
 
         write_conn_type(&mut writer, CONN_TYPE_STREAM).await?;
 
-        let auth_req = StreamReq {
-            auth: encrypt_field(pw, pw)?,
-            dst_addr: Some(encrypt_field(&host, pw)?),
-            dst_port: Some(port as u32),
-            ..Default::default()
+        let auth_req = if secure {
+            StreamReq {
+                dst_addr: Some(host.clone()),
+                dst_port: Some(port as u32),
+                encrypt: Some(false),
+                ..Default::default()
+            }
+        } else {
+            StreamReq {
+                auth: encrypt_field(pw, pw)?,
+                dst_addr: Some(encrypt_field(&host, pw)?),
+                dst_port: Some(port as u32),
+                encrypt: Some(encrypt),
+                ..Default::default()
+            }
         };
-        write_frame(&mut writer, &auth_req).await?;
+        write_frame(&mut writer, &auth_req, self.padding).await?;
 
-        let mut stream = PbTcpClientRunStream::new(reader, writer, pw.clone(), port != 443);
+        let mut stream =
+            PbTcpClientRunStream::new(reader, writer, pw.clone(), encrypt, self.padding);
         stream.set_info(&mut |x| {
             x.protocol_name = "pb_tcp".to_string();
             x.dst_port = Some(port);
@@ -58,10 +146,13 @@ impl RunConnector for PbTcpRunConnector {
         &self,
         _src_addr: String,
     ) -> io::Result<Option<(Box<dyn RunUdpReader>, Box<dyn RunUdpWriter>)>> {
-        let pw = self.cfg.pw.as_ref().unwrap().clone();
+        // packets of a secure session need no auth or encryption of their own
+        let pw = match self.secure {
+            Some(_) => None,
+            None => Some(self.cfg.pw.as_ref().unwrap().clone()),
+        };
 
-        let stream = TcpStream::connect(self.cfg.endpoint.as_ref().unwrap()).await?;
-        let (reader, mut writer) = stream.into_split();
+        let (reader, mut writer) = self.open().await?;
 
         write_conn_type(&mut writer, CONN_TYPE_UDP).await?;
 
@@ -73,8 +164,9 @@ impl RunConnector for PbTcpRunConnector {
                 as Box<dyn RunUdpReader>,
             Box::new(PbTcpUdpClientWriter::new(
                 Arc::clone(&writer),
-                pw.clone(),
+                pw.clone().unwrap_or_default(),
                 pw,
+                self.padding,
             )) as Box<dyn RunUdpWriter>,
         )))
     }
@@ -1,6 +1,7 @@
 use crate::connector::grpc::parse_address;
-use crate::def::config::get_option_bool;
+use crate::def::config::{get_option_bool, get_option_str, get_option_u64};
 use crate::def::{RunConnector, RunStream, RunUdpReader, RunUdpWriter, config};
+use crate::object::timeouts::{Kind, Timeouts, bounded};
 use crate::proto::v1::pb::rog_reverse_service_server::{
     RogReverseService, RogReverseServiceServer,
 };
@@ -9,24 +10,38 @@ use crate::proto::v1::pb::{
 };
 use crate::stream::rev_grpc_server::RevGrpcServerRunStream;
 use crate::stream::rev_grpc_udp_server::{RevGrpcUdpServerReader, RevGrpcUdpServerWriter};
+use crate::util::auth_token::TokenVerifier;
+use crate::util::grpc_transport::server_tls_config;
 use dashmap::DashMap;
-use dashmap::mapref::entry::Entry;
 use futures::Stream;
 use log::{error, info, warn};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::io;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::OnceLock;
-use std::time::Duration;
-use tokio::spawn;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{Mutex, mpsc, oneshot};
-use tokio::time::timeout;
+use tokio::time::{Instant, interval_at};
+use tokio::{select, spawn};
 use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
 use tonic::transport::Server;
 use tonic::{Request, Response, Status, Streaming};
 
+mod publish;
+
+use publish::PublishAcl;
+
+/// How long a connector waits for a reverse client to open the stream or udp
+/// tunnel it asked for when `connect_timeout` is disabled.
+const OPEN_FALLBACK: Duration = Duration::from_secs(10);
+
 // The state shared between the gRPC Server handlers and the RunConnector
 pub struct PendingConn {
     pub tx: oneshot::Sender<RevGrpcServerRunStream>,
@@ -40,10 +55,58 @@ pub struct PendingUdpConn {
     pub pw: Option<String>,
 }
 
+/// The control stream of an online reverse client.
+pub struct Manager {
+    pub id: u64,
+    pub tx: mpsc::Sender<Result<ManagerRes, Status>>,
+    pub since: SystemTime,
+    pub remote: Option<SocketAddr>,
+    /// Requests sent to the client that it hasn't answered yet.
+    pending: AtomicUsize,
+}
+
+/// Counts a request as pending on its client until dropped.
+struct PendingOn(Arc<Manager>);
+
+impl PendingOn {
+    fn new(manager: Arc<Manager>) -> Self {
+        manager.pending.fetch_add(1, Ordering::Relaxed);
+        Self(manager)
+    }
+}
+
+impl Drop for PendingOn {
+    fn drop(&mut self) {
+        self.0.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What the admin API shows of an online reverse client.
+#[derive(Serialize, Debug)]
+pub struct ReverseClient {
+    pub tag: String,
+    pub id: u64,
+    /// Unix seconds.
+    pub since: u64,
+    pub remote: Option<String>,
+    pub pending: usize,
+}
+
+/// How a `rev_grpc` connector picks one of the clients of its tag.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Balance {
+    RoundRobin,
+    LeastPending,
+}
+
 pub struct RevGrpcState {
-    pub managers: DashMap<String, mpsc::Sender<Result<ManagerRes, Status>>>,
+    /// The clients of each tag, in the order they connected.
+    pub managers: DashMap<String, Vec<Arc<Manager>>>,
     pub pending_streams: DashMap<String, PendingConn>,
     pub pending_udp: DashMap<String, PendingUdpConn>,
+    next_manager_id: AtomicU64,
+    /// The reverse server's `connect_timeout`, `None` when disabled.
+    connect_timeout: OnceLock<Option<Duration>>,
 }
 
 impl RevGrpcState {
@@ -52,8 +115,116 @@ impl RevGrpcState {
             managers: DashMap::new(),
             pending_streams: DashMap::new(),
             pending_udp: DashMap::new(),
+            next_manager_id: AtomicU64::new(1),
+            connect_timeout: OnceLock::new(),
         }
     }
+
+    /// Bounds the wait for a reverse client to open a requested stream, so
+    /// its pending entry goes away even when `connect_timeout` is disabled.
+    fn open_timeout(&self) -> Duration {
+        self.connect_timeout
+            .get()
+            .copied()
+            .flatten()
+            .unwrap_or(OPEN_FALLBACK)
+    }
+
+    fn add_manager(
+        &self,
+        tag: &str,
+        tx: mpsc::Sender<Result<ManagerRes, Status>>,
+        remote: Option<SocketAddr>,
+    ) -> (Arc<Manager>, usize) {
+        let manager = Arc::new(Manager {
+            id: self.next_manager_id.fetch_add(1, Ordering::Relaxed),
+            tx,
+            since: SystemTime::now(),
+            remote,
+            pending: AtomicUsize::new(0),
+        });
+        let mut managers = self.managers.entry(tag.to_string()).or_default();
+        managers.push(manager.clone());
+        (manager, managers.len())
+    }
+
+    fn remove_manager(&self, tag: &str, id: u64) {
+        if let Some(mut managers) = self.managers.get_mut(tag) {
+            managers.retain(|m| m.id != id);
+        }
+        self.managers
+            .remove_if(tag, |_, managers| managers.is_empty());
+    }
+
+    /// The clients of `tag` in the order to try them.
+    fn candidates(&self, tag: &str, balance: Balance, start: usize) -> Vec<Arc<Manager>> {
+        let mut managers = match self.managers.get(tag) {
+            Some(managers) => managers.clone(),
+            None => return Vec::new(),
+        };
+        if !managers.is_empty() {
+            let len = managers.len();
+            managers.rotate_left(start % len);
+        }
+        if balance == Balance::LeastPending {
+            // stable, so ties stay round robin
+            managers.sort_by_key(|m| m.pending.load(Ordering::Relaxed));
+        }
+        managers
+    }
+
+    pub fn online(&self, tag: &str) -> bool {
+        self.managers.contains_key(tag)
+    }
+
+    pub fn clients(&self) -> Vec<ReverseClient> {
+        let mut clients: Vec<ReverseClient> = self
+            .managers
+            .iter()
+            .flat_map(|entry| {
+                let tag = entry.key().clone();
+                entry
+                    .value()
+                    .iter()
+                    .map(|m| ReverseClient {
+                        tag: tag.clone(),
+                        id: m.id,
+                        since: m
+                            .since
+                            .duration_since(UNIX_EPOCH)
+                            .map(|d| d.as_secs())
+                            .unwrap_or(0),
+                        remote: m.remote.map(|a| a.to_string()),
+                        pending: m.pending.load(Ordering::Relaxed),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        clients.sort_by(|a, b| a.tag.cmp(&b.tag).then(a.id.cmp(&b.id)));
+        clients
+    }
+}
+
+const HEARTBEAT_INTERVAL: u64 = 15;
+const HEARTBEAT_MISSED: u64 = 3;
+
+/// Pings on the manager streams, `heartbeat_interval` seconds apart (0
+/// disables them) with `heartbeat_missed` of them allowed to go unanswered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub missed: u32,
+}
+
+impl Heartbeat {
+    pub fn from_options(options: &Option<HashMap<String, toml::Value>>) -> Option<Self> {
+        let interval = get_option_u64(options, "heartbeat_interval").unwrap_or(HEARTBEAT_INTERVAL);
+        let missed = get_option_u64(options, "heartbeat_missed").unwrap_or(HEARTBEAT_MISSED);
+        (interval > 0).then(|| Heartbeat {
+            interval: Duration::from_secs(interval),
+            missed: missed.max(1) as u32,
+        })
+    }
 }
 
 static REV_GRPC_STATE: OnceLock<Arc<RevGrpcState>> = OnceLock::new();
@@ -68,13 +239,26 @@ pub async fn start_reverse_server(
     endpoint: String,
     pw_map: HashMap<String, Option<String>>,
     options: &Option<HashMap<String, toml::Value>>,
-) {
+    timeouts: Timeouts,
+) -> io::Result<()> {
     let state = get_global_rev_grpc_state();
-    let rog = RevGrpcServer { pw_map, state };
+    let _ = state.connect_timeout.set(timeouts.connect);
+    let verifier = TokenVerifier::new(get_option_bool(options, "legacy_auth"));
+    let rog = RevGrpcServer {
+        pw_map,
+        state,
+        verifier,
+        heartbeat: Heartbeat::from_options(options),
+        publish_acl: PublishAcl::from_options(options)?,
+        timeouts,
+    };
     let keep_alive = get_option_bool(options, "keep_alive");
+    let mut builder = Server::builder();
+    if let Some(tls) = server_tls_config(options)? {
+        builder = builder.tls_config(tls).map_err(io::Error::other)?;
+    }
 
     spawn(async move {
-        let mut builder = Server::builder();
         if keep_alive {
             builder = builder
                 .http2_keepalive_interval(Some(Duration::from_secs(30)))
@@ -89,22 +273,83 @@ pub async fn start_reverse_server(
             Err(e) => error!("Reverse grpc server err: {}", e),
         }
     });
+    Ok(())
+}
+
+/// Drops a pending request once its caller stops waiting for it, answered
+/// or not (e.g. on `connect_timeout`).
+struct PendingGuard<'a, V> {
+    map: &'a DashMap<String, V>,
+    conn_id: String,
 }
 
+impl<V> Drop for PendingGuard<'_, V> {
+    fn drop(&mut self) {
+        self.map.remove(&self.conn_id);
+    }
+}
+
+/// Sends requests to the reverse clients registered under its name,
+/// spread by `options.balance` and failing over past dead clients.
 pub struct RevGrpcRunConnector {
     cfg: config::Connector,
     state: Arc<RevGrpcState>,
+    balance: Balance,
+    next: AtomicUsize,
 }
 
 impl RevGrpcRunConnector {
     pub async fn new(cfg: &config::Connector) -> io::Result<Self> {
         let state = get_global_rev_grpc_state();
 
+        let balance = match get_option_str(&cfg.options, "balance").as_deref() {
+            None | Some("round_robin") => Balance::RoundRobin,
+            Some("least_pending") => Balance::LeastPending,
+            Some(other) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown rev_grpc balance '{}'", other),
+                ));
+            }
+        };
         Ok(Self {
             cfg: cfg.clone(),
             state,
+            balance,
+            next: AtomicUsize::new(0),
         })
     }
+
+    /// Hands `req` to one of the tag's clients. A client whose control
+    /// stream is gone is dropped and the next one tried.
+    async fn dispatch(&self, req: ManagerRes) -> io::Result<PendingOn> {
+        let tag = &self.cfg.name;
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let candidates = self.state.candidates(tag, self.balance, start);
+        if candidates.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                format!("reverse client '{}' offline", tag),
+            ));
+        }
+        for manager in candidates {
+            let pending = PendingOn::new(manager.clone());
+            match manager.tx.send(Ok(req.clone())).await {
+                Ok(()) => return Ok(pending),
+                Err(e) => {
+                    warn!(
+                        "reverse client {} #{} is gone, failing over: {}",
+                        tag, manager.id, e
+                    );
+                    self.state.remove_manager(tag, manager.id);
+                }
+            }
+        }
+        Err(io::Error::new(
+            ErrorKind::ConnectionAborted,
+            format!("no reverse client '{}' took the request", tag),
+        ))
+    }
 }
 
 #[async_trait::async_trait]
@@ -112,20 +357,6 @@ impl RunConnector for RevGrpcRunConnector {
     async fn connect(&self, addr: String) -> io::Result<Box<dyn RunStream>> {
         let (host, port) = parse_address(addr.as_str())?;
 
-        let manager_tx = {
-            let tag = &self.cfg.name;
-            self.state
-                .managers
-                .get(tag)
-                .map(|m| m.value().clone())
-                .ok_or_else(|| {
-                    io::Error::new(
-                        ErrorKind::NotConnected,
-                        format!("reverse client '{}' offline", tag),
-                    )
-                })?
-        };
-
         let conn_id = uuid::Uuid::new_v4().to_string();
         let (tx, rx) = oneshot::channel();
 
@@ -148,53 +379,28 @@ impl RunConnector for RevGrpcRunConnector {
             }),
             udp: Some(0),
             conn_id: Some(conn_id.clone()),
+            ..Default::default()
         };
 
-        if let Err(e) = manager_tx.send(Ok(req)).await {
-            self.state.pending_streams.remove(&conn_id);
-            return Err(io::Error::new(
-                ErrorKind::ConnectionAborted,
-                format!("failed to send to manager: {}", e),
-            ));
-        }
+        let _pending = PendingGuard {
+            map: &self.state.pending_streams,
+            conn_id: conn_id.clone(),
+        };
 
-        match timeout(Duration::from_secs(10), rx).await {
-            Ok(Ok(stream)) => Ok(Box::new(stream)),
-            Ok(Err(_)) => {
-                self.state.pending_streams.remove(&conn_id);
-                Err(io::Error::new(
-                    ErrorKind::ConnectionAborted,
-                    "stream channel closed",
-                ))
-            }
-            Err(_) => {
-                self.state.pending_streams.remove(&conn_id);
-                Err(io::Error::new(
-                    ErrorKind::TimedOut,
-                    "timeout waiting for reverse stream",
-                ))
-            }
-        }
+        let _client = self.dispatch(req).await?;
+
+        let stream = bounded(Some(self.state.open_timeout()), Kind::Connect, async {
+            rx.await
+                .map_err(|_| io::Error::new(ErrorKind::ConnectionAborted, "stream channel closed"))
+        })
+        .await?;
+        Ok(Box::new(stream))
     }
 
     async fn udp_tunnel(
         &self,
         src_addr: String,
     ) -> io::Result<Option<(Box<dyn RunUdpReader>, Box<dyn RunUdpWriter>)>> {
-        let manager_tx = {
-            let tag = &self.cfg.name;
-            self.state
-                .managers
-                .get(tag)
-                .map(|m| m.value().clone())
-                .ok_or_else(|| {
-                    io::Error::new(
-                        ErrorKind::NotConnected,
-                        format!("reverse client '{}' offline", tag),
-                    )
-                })?
-        };
-
         let conn_id = uuid::Uuid::new_v4().to_string();
         let (tx, rx) = oneshot::channel();
 
@@ -210,39 +416,79 @@ impl RunConnector for RevGrpcRunConnector {
             addr_info: None,
             udp: Some(1),
             conn_id: Some(conn_id.clone()),
+            ..Default::default()
         };
 
-        if let Err(e) = manager_tx.send(Ok(req)).await {
-            self.state.pending_udp.remove(&conn_id);
-            return Err(io::Error::new(
-                ErrorKind::ConnectionAborted,
-                format!("failed to send udp manager req: {}", e),
-            ));
-        }
+        let _pending = PendingGuard {
+            map: &self.state.pending_udp,
+            conn_id: conn_id.clone(),
+        };
 
-        match timeout(Duration::from_secs(10), rx).await {
-            Ok(Ok(pair)) => Ok(Some(pair)),
-            Ok(Err(_)) => {
-                self.state.pending_udp.remove(&conn_id);
-                Err(io::Error::new(
+        let _client = self.dispatch(req).await?;
+
+        let pair = bounded(Some(self.state.open_timeout()), Kind::Connect, async {
+            rx.await.map_err(|_| {
+                io::Error::new(
                     ErrorKind::ConnectionAborted,
                     "udp channel closed before listener connected",
-                ))
-            }
-            Err(_) => {
-                self.state.pending_udp.remove(&conn_id);
-                Err(io::Error::new(
-                    ErrorKind::TimedOut,
-                    "timeout waiting for reverse udp stream",
-                ))
-            }
-        }
+                )
+            })
+        })
+        .await?;
+        Ok(Some(pair))
     }
 }
 
 struct RevGrpcServer {
     pw_map: HashMap<String, Option<String>>,
     state: Arc<RevGrpcState>,
+    verifier: TokenVerifier,
+    heartbeat: Option<Heartbeat>,
+    publish_acl: PublishAcl,
+    /// Of the relays of published ports.
+    timeouts: Timeouts,
+}
+
+/// Reads a client's control stream until it ends. Clients that answer pings
+/// are also dropped once `missed` pings in a row went unanswered.
+async fn watch_manager(
+    mut in_stream: Streaming<ManagerReq>,
+    tx: mpsc::Sender<Result<ManagerRes, Status>>,
+    heartbeat: Option<Heartbeat>,
+) -> &'static str {
+    let Some(heartbeat) = heartbeat else {
+        while let Ok(Some(_)) = in_stream.message().await {}
+        return "disconnected";
+    };
+    let mut ticker = interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+    let (mut sent, mut answered) = (0u64, 0u64);
+    loop {
+        select! {
+            msg = in_stream.message() => match msg {
+                Ok(Some(msg)) => {
+                    if let Some(pong) = msg.pong {
+                        answered = answered.max(pong);
+                    }
+                }
+                _ => return "disconnected",
+            },
+            _ = ticker.tick() => {
+                if sent - answered >= heartbeat.missed as u64 {
+                    return "evicted after missed heartbeats";
+                }
+                sent += 1;
+                let ping = ManagerRes {
+                    ping: Some(sent),
+                    heartbeat_interval: Some(heartbeat.interval.as_secs() as u32),
+                    ..Default::default()
+                };
+                // a full channel counts as a missed beat
+                if let Err(TrySendError::Closed(_)) = tx.try_send(Ok(ping)) {
+                    return "disconnected";
+                }
+            }
+        }
+    }
 }
 
 type ResponseStream = Pin<Box<dyn Stream<Item = Result<RevStreamRes, Status>> + Send>>;
@@ -257,6 +503,7 @@ impl RogReverseService for RevGrpcServer {
         &self,
         request: Request<Streaming<ManagerReq>>,
     ) -> Result<Response<Self::managerStream>, Status> {
+        let remote = request.remote_addr();
         let mut in_stream = request.into_inner();
 
         let first_msg = match in_stream.message().await {
@@ -272,34 +519,48 @@ impl RogReverseService for RevGrpcServer {
         };
 
         if let Some(expected_pw) = self.pw_map.get(&first_msg.tag).and_then(|p| p.as_ref()) {
-            if first_msg.auth != *expected_pw {
+            let context = format!("manager {}", first_msg.tag);
+            if self
+                .verifier
+                .verify(expected_pw, &first_msg.auth, &context)
+                .is_err()
+            {
                 return Err(Status::unauthenticated("invalid auth"));
             }
         }
 
         let tag = first_msg.tag.clone();
 
-        let rx = match self.state.managers.entry(tag.clone()) {
-            Entry::Occupied(_) => {
-                return Err(Status::already_exists(format!(
-                    "client with tag '{}' is already connected",
-                    tag
-                )));
-            }
-            Entry::Vacant(entry) => {
-                let (tx, rx) = mpsc::channel(32);
-                entry.insert(tx);
-                rx
-            }
-        };
-        info!("server with tag {} connected", tag);
+        let heartbeat = self
+            .heartbeat
+            .filter(|_| first_msg.heartbeat.unwrap_or(false));
+        let (tx, rx) = mpsc::channel(32);
+        let (manager, count) = self.state.add_manager(&tag, tx.clone(), remote);
+        let id = manager.id;
+        info!(
+            "server with tag {} connected as #{} ({} online)",
+            tag, id, count
+        );
+
+        let closed = CancellationToken::new();
+        if !first_msg.publish.is_empty() {
+            let publisher = publish::Publisher {
+                state: self.state.clone(),
+                manager,
+                tag: tag.clone(),
+                pw: self.pw_map.get(&tag).cloned().flatten(),
+                timeouts: self.timeouts,
+                closed: closed.clone(),
+            };
+            publisher.start(&self.publish_acl, first_msg.publish);
+        }
 
         let state_clone = self.state.clone();
-        let tag_clone = tag.clone();
         spawn(async move {
-            while let Ok(Some(_)) = in_stream.message().await {}
-            info!("server with tag {} disconnected", tag_clone);
-            state_clone.managers.remove(&tag_clone);
+            let reason = watch_manager(in_stream, tx, heartbeat).await;
+            info!("server with tag {} #{} {}", tag, id, reason);
+            closed.cancel();
+            state_clone.remove_manager(&tag, id);
         });
 
         let out_stream = ReceiverStream::new(rx);
@@ -327,7 +588,12 @@ impl RogReverseService for RevGrpcServer {
 
         if let Some((_, pending)) = self.state.pending_streams.remove(&conn_id) {
             if let Some(expected_pw) = pending.pw.as_ref() {
-                if first_msg.auth != *expected_pw {
+                let context = format!("rev_stream {}", conn_id);
+                if self
+                    .verifier
+                    .verify(expected_pw, &first_msg.auth, &context)
+                    .is_err()
+                {
                     return Err(Status::unauthenticated("invalid auth"));
                 }
             }
@@ -375,7 +641,12 @@ impl RogReverseService for RevGrpcServer {
         if let Some((_, pending)) = self.state.pending_udp.remove(&conn_id) {
             // 验证密码（如果配置了的话）
             if let Some(expected_pw) = pending.pw.as_ref() {
-                if first_msg.auth != *expected_pw {
+                let context = format!("rev_udp {}", conn_id);
+                if self
+                    .verifier
+                    .verify(expected_pw, &first_msg.auth, &context)
+                    .is_err()
+                {
                     return Err(Status::unauthenticated("invalid udp auth"));
                 }
             }
@@ -401,3 +672,173 @@ impl RogReverseService for RevGrpcServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connector(state: Arc<RevGrpcState>, balance: Balance) -> RevGrpcRunConnector {
+        RevGrpcRunConnector {
+            cfg: config::Connector {
+                endpoint: None,
+                name: "site".to_string(),
+                user: None,
+                pw: None,
+                proto: "rev_grpc".to_string(),
+                options: None,
+            },
+            state,
+            balance,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn request(n: u32) -> ManagerRes {
+        ManagerRes {
+            addr_info: None,
+            udp: Some(n),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let state = Arc::new(RevGrpcState::new());
+        let (tx1, mut rx1) = mpsc::channel(8);
+        let (tx2, rx2) = mpsc::channel(8);
+        let (tx3, mut rx3) = mpsc::channel(8);
+        state.add_manager("site", tx1, None);
+        state.add_manager("site", tx2, None);
+        state.add_manager("site", tx3, None);
+        drop(rx2);
+
+        let rr = connector(state.clone(), Balance::RoundRobin);
+        for n in 0..4 {
+            rr.dispatch(request(n)).await.unwrap();
+        }
+        // the second client is dropped on its turn, its request goes to the third
+        assert_eq!(state.clients().len(), 2);
+        let mut got1 = Vec::new();
+        while let Ok(Ok(req)) = rx1.try_recv() {
+            got1.push(req.udp.unwrap());
+        }
+        let mut got3 = Vec::new();
+        while let Ok(Ok(req)) = rx3.try_recv() {
+            got3.push(req.udp.unwrap());
+        }
+        assert_eq!(got1, vec![0, 2]);
+        assert_eq!(got3, vec![1, 3]);
+
+        let lp = connector(state.clone(), Balance::LeastPending);
+        let first = lp.dispatch(request(0)).await.unwrap();
+        let second = lp.dispatch(request(1)).await.unwrap();
+        assert_ne!(first.0.id, second.0.id);
+        drop(first);
+        let third = lp.dispatch(request(2)).await.unwrap();
+        assert_ne!(third.0.id, second.0.id);
+
+        drop((rx1, rx3));
+        let err = lp.dispatch(request(3)).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+        assert!(!state.online("site"));
+        let err = lp.dispatch(request(4)).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+    }
+
+    #[tokio::test]
+    async fn test_open_timeout() {
+        let state = Arc::new(RevGrpcState::new());
+        assert_eq!(state.open_timeout(), OPEN_FALLBACK);
+        let _ = state.connect_timeout.set(Some(Duration::from_millis(100)));
+
+        // the client takes the request but never opens the stream
+        let (tx, _rx) = mpsc::channel(8);
+        state.add_manager("site", tx, None);
+        let rr = connector(state.clone(), Balance::RoundRobin);
+        let err = rr
+            .connect("example.com:80".to_string())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(state.pending_streams.is_empty());
+
+        let disabled = RevGrpcState::new();
+        let _ = disabled.connect_timeout.set(None);
+        assert_eq!(disabled.open_timeout(), OPEN_FALLBACK);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_eviction() {
+        use crate::proto::v1::pb::rog_reverse_service_client::RogReverseServiceClient;
+
+        let state = Arc::new(RevGrpcState::new());
+        let server = RevGrpcServer {
+            pw_map: HashMap::new(),
+            state: state.clone(),
+            verifier: TokenVerifier::new(false),
+            heartbeat: Some(Heartbeat {
+                interval: Duration::from_millis(200),
+                missed: 2,
+            }),
+            publish_acl: PublishAcl::from_options(&None).unwrap(),
+            timeouts: Timeouts::default(),
+        };
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        spawn(
+            Server::builder()
+                .add_service(RogReverseServiceServer::new(server))
+                .serve(addr),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // one client answers the pings, the other one only reads them
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut client = RogReverseServiceClient::connect(format!("http://{}", addr))
+                .await
+                .unwrap();
+            let (mtx, mrx) = mpsc::channel(8);
+            mtx.send(ManagerReq {
+                tag: "site".to_string(),
+                heartbeat: Some(true),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            let stream = client
+                .manager(ReceiverStream::new(mrx))
+                .await
+                .unwrap()
+                .into_inner();
+            streams.push((mtx, stream));
+        }
+        let (silent_tx, mut silent) = streams.pop().unwrap();
+        let (pong_tx, mut answering) = streams.pop().unwrap();
+        spawn(async move {
+            while let Ok(Some(res)) = answering.message().await {
+                let _ = pong_tx
+                    .send(ManagerReq {
+                        pong: res.ping,
+                        ..Default::default()
+                    })
+                    .await;
+            }
+        });
+        assert_eq!(state.clients().len(), 2);
+
+        let mut pings = 0;
+        while let Ok(Some(res)) = silent.message().await {
+            assert!(res.ping.is_some());
+            pings += 1;
+        }
+        drop(silent_tx);
+        assert_eq!(pings, 2);
+        let clients = state.clients();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].id, 1);
+    }
+}
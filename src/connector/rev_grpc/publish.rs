@@ -0,0 +1,437 @@
+//! Server ports published by reverse clients (`publish` of a `rev_grpc`
+//! listener), allowed per tag by `publish_allow` of `[reverse_server]`.
+//! Each connection to a published port is relayed through a stream the
+//! client opens for it, one UDP session per source address.
+
+use super::{Manager, PendingConn, PendingGuard, PendingOn, PendingUdpConn, RevGrpcState};
+use crate::connector::grpc::backoff;
+use crate::def::config::{get_option_str, get_option_str_list};
+use crate::def::{RunUdpReader, RunUdpWriter, UDPMeta, UDPPacket};
+use crate::object::connections::ConnInfo;
+use crate::object::limits::Limits;
+use crate::object::tcp::handle_tcp_connection;
+use crate::object::timeouts::{Kind, Timeouts, bounded};
+use crate::proto::v1::pb::{AddrInfo, ManagerRes, Publish};
+use crate::stream::rev_grpc_server::RevGrpcServerRunStream;
+use crate::stream::tcp::TcpRunStream;
+use crate::util::RunAddr;
+use dashmap::DashMap;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
+use tokio::{select, spawn};
+use tokio_util::sync::CancellationToken;
+
+const UDP_BUFFER_SIZE: usize = 65536;
+const UDP_SESSION_QUEUE: usize = 64;
+
+/// The ports each tag may publish, `"tag:port"` or `"tag:low-high"`, and
+/// the address they are bound on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishAcl {
+    allow: HashMap<String, Vec<RangeInclusive<u16>>>,
+    bind: IpAddr,
+}
+
+impl PublishAcl {
+    pub fn from_options(options: &Option<HashMap<String, toml::Value>>) -> io::Result<Self> {
+        let mut allow: HashMap<String, Vec<RangeInclusive<u16>>> = HashMap::new();
+        for rule in get_option_str_list(options, "publish_allow") {
+            let invalid = || {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid publish_allow '{}'", rule),
+                )
+            };
+            let (tag, ports) = rule.rsplit_once(':').ok_or_else(invalid)?;
+            let port = |s: &str| s.trim().parse::<u16>().map_err(|_| invalid());
+            let range = match ports.split_once('-') {
+                Some((low, high)) => port(low)?..=port(high)?,
+                None => port(ports)?..=port(ports)?,
+            };
+            allow.entry(tag.to_string()).or_default().push(range);
+        }
+        let bind = match get_option_str(options, "publish_bind") {
+            Some(ip) => ip.parse().map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid publish_bind '{}'", ip),
+                )
+            })?,
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        Ok(Self { allow, bind })
+    }
+
+    fn allows(&self, tag: &str, port: u16) -> bool {
+        port != 0
+            && self
+                .allow
+                .get(tag)
+                .is_some_and(|ranges| ranges.iter().any(|r| r.contains(&port)))
+    }
+}
+
+/// The ports of one reverse client, closed once `closed` is cancelled.
+#[derive(Clone)]
+pub(super) struct Publisher {
+    pub state: Arc<RevGrpcState>,
+    pub manager: Arc<Manager>,
+    pub tag: String,
+    pub pw: Option<String>,
+    pub timeouts: Timeouts,
+    pub closed: CancellationToken,
+}
+
+impl Publisher {
+    /// Opens the ports of `publish` that `acl` allows.
+    pub fn start(&self, acl: &PublishAcl, publish: Vec<Publish>) {
+        for p in publish {
+            let port = u16::try_from(p.port).unwrap_or(0);
+            if !acl.allows(&self.tag, port) {
+                warn!(
+                    "reverse client {} #{} may not publish port {}",
+                    self.tag, self.manager.id, p.port
+                );
+                continue;
+            }
+            let addr = SocketAddr::new(acl.bind, port);
+            if p.udp {
+                spawn(self.clone().serve_udp(addr));
+            } else {
+                spawn(self.clone().serve_tcp(addr));
+            }
+        }
+    }
+
+    /// Binds `addr`, retrying while it is taken, e.g. by a client of the tag
+    /// that reconnected before its old stream was dropped.
+    async fn bind<T, F>(&self, addr: SocketAddr, bind: impl Fn(SocketAddr) -> F) -> Option<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        let mut failures = 0;
+        loop {
+            match bind(addr).await {
+                Ok(bound) => {
+                    info!(
+                        "reverse client {} #{} published {}",
+                        self.tag, self.manager.id, addr
+                    );
+                    return Some(bound);
+                }
+                Err(e) => {
+                    failures += 1;
+                    if failures == 1 {
+                        warn!(
+                            "reverse client {} #{} can't publish {} yet: {}",
+                            self.tag, self.manager.id, addr, e
+                        );
+                    }
+                }
+            }
+            select! {
+                _ = sleep(backoff(failures)) => {}
+                _ = self.closed.cancelled() => return None,
+            }
+        }
+    }
+
+    fn request(&self, port: u16, udp: bool, conn_id: &str, peer: SocketAddr) -> ManagerRes {
+        ManagerRes {
+            addr_info: Some(AddrInfo {
+                dst_addr: "".to_string(),
+                dst_port: port as u32,
+                src_addr: peer.ip().to_string(),
+                src_port: peer.port() as u32,
+            }),
+            udp: Some(udp as u32),
+            conn_id: Some(conn_id.to_string()),
+            publish: Some(Publish {
+                port: port as u32,
+                udp,
+            }),
+            ..Default::default()
+        }
+    }
+
+    async fn send(&self, req: ManagerRes) -> io::Result<PendingOn> {
+        let pending = PendingOn::new(self.manager.clone());
+        self.manager.tx.send(Ok(req)).await.map_err(|_| {
+            io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("reverse client {} #{} is gone", self.tag, self.manager.id),
+            )
+        })?;
+        Ok(pending)
+    }
+
+    fn info(&self, network: &'static str, port: u16, peer: SocketAddr) -> ConnInfo {
+        ConnInfo {
+            network,
+            listener: format!("publish:{}", port),
+            user: None,
+            source: peer.to_string(),
+            destination: format!("{}:{}", self.tag, port),
+            connector: self.tag.clone(),
+            site: None,
+        }
+    }
+
+    async fn serve_tcp(self, addr: SocketAddr) {
+        let Some(listener) = self.bind(addr, TcpListener::bind).await else {
+            return;
+        };
+        loop {
+            select! {
+                res = listener.accept() => match res {
+                    Ok((inbound, peer)) => {
+                        spawn(self.clone().relay_tcp(inbound, peer, addr.port()));
+                    }
+                    Err(e) => warn!("published port {} accept error: {}", addr, e),
+                },
+                _ = self.closed.cancelled() => break,
+            }
+        }
+        info!(
+            "reverse client {} #{} unpublished {}",
+            self.tag, self.manager.id, addr
+        );
+    }
+
+    /// Asks the client for a stream to carry the connection from `peer`.
+    async fn open_stream(&self, port: u16, peer: SocketAddr) -> io::Result<RevGrpcServerRunStream> {
+        let conn_id = uuid::Uuid::new_v4().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.state.pending_streams.insert(
+            conn_id.clone(),
+            PendingConn {
+                tx,
+                host: peer.ip().to_string(),
+                port: peer.port(),
+                pw: self.pw.clone(),
+            },
+        );
+        let _pending = PendingGuard {
+            map: &self.state.pending_streams,
+            conn_id: conn_id.clone(),
+        };
+        let _client = self.send(self.request(port, false, &conn_id, peer)).await?;
+        rx.await
+            .map_err(|_| io::Error::new(ErrorKind::ConnectionAborted, "stream channel closed"))
+    }
+
+    async fn relay_tcp(self, inbound: TcpStream, peer: SocketAddr, port: u16) {
+        let stream = match bounded(
+            self.timeouts.connect,
+            Kind::Connect,
+            self.open_stream(port, peer),
+        )
+        .await
+        {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("published port {} for {}: {}", port, peer, e);
+                return;
+            }
+        };
+        let limits = Limits::default()
+            .with_idle(self.timeouts.idle, Kind::Idle)
+            .with_linger(self.timeouts.linger)
+            .with_tracking(self.info("tcp", port, peer));
+        let addr = RunAddr {
+            addr: peer.ip().to_string(),
+            port: peer.port(),
+            udp: false,
+        };
+        if let Err(e) = handle_tcp_connection(
+            addr,
+            None,
+            Box::new(stream),
+            Box::new(TcpRunStream::new(inbound)),
+            None,
+            None,
+            limits,
+        )
+        .await
+        {
+            debug!("published port {} for {} closed: {}", port, peer, e);
+        }
+    }
+
+    async fn serve_udp(self, addr: SocketAddr) {
+        let Some(socket) = self.bind(addr, UdpSocket::bind).await else {
+            return;
+        };
+        let socket = Arc::new(socket);
+        let sessions: Arc<DashMap<SocketAddr, mpsc::Sender<Vec<u8>>>> = Default::default();
+        let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+        loop {
+            let (n, peer) = select! {
+                res = socket.recv_from(&mut buf) => match res {
+                    Ok(received) => received,
+                    Err(e) => {
+                        debug!("published port {} recv error: {}", addr, e);
+                        continue;
+                    }
+                },
+                _ = self.closed.cancelled() => break,
+            };
+            let session = sessions.get(&peer).map(|s| s.value().clone());
+            let session = match session {
+                Some(session) => session,
+                None => {
+                    let (tx, rx) = mpsc::channel(UDP_SESSION_QUEUE);
+                    sessions.insert(peer, tx.clone());
+                    spawn(self.clone().relay_udp(
+                        socket.clone(),
+                        sessions.clone(),
+                        peer,
+                        addr.port(),
+                        rx,
+                    ));
+                    tx
+                }
+            };
+            // dropped while the session is backed up, as UDP may
+            let _ = session.try_send(buf[..n].to_vec());
+        }
+        info!(
+            "reverse client {} #{} unpublished udp {}",
+            self.tag, self.manager.id, addr
+        );
+    }
+
+    /// Asks the client for a UDP stream to carry the session of `peer`.
+    async fn open_udp(
+        &self,
+        port: u16,
+        peer: SocketAddr,
+    ) -> io::Result<(Box<dyn RunUdpReader>, Box<dyn RunUdpWriter>)> {
+        let conn_id = uuid::Uuid::new_v4().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.state.pending_udp.insert(
+            conn_id.clone(),
+            PendingUdpConn {
+                tx,
+                pw: self.pw.clone(),
+            },
+        );
+        let _pending = PendingGuard {
+            map: &self.state.pending_udp,
+            conn_id: conn_id.clone(),
+        };
+        let _client = self.send(self.request(port, true, &conn_id, peer)).await?;
+        rx.await
+            .map_err(|_| io::Error::new(ErrorKind::ConnectionAborted, "udp channel closed"))
+    }
+
+    async fn relay_udp(
+        self,
+        socket: Arc<UdpSocket>,
+        sessions: Arc<DashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+        peer: SocketAddr,
+        port: u16,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+    ) {
+        if let Err(e) = self.run_udp(&socket, peer, port, &mut rx).await {
+            debug!("published udp port {} for {} closed: {}", port, peer, e);
+        }
+        sessions.remove(&peer);
+    }
+
+    async fn run_udp(
+        &self,
+        socket: &UdpSocket,
+        peer: SocketAddr,
+        port: u16,
+        rx: &mut mpsc::Receiver<Vec<u8>>,
+    ) -> io::Result<()> {
+        let (mut reader, writer) = bounded(
+            self.timeouts.connect,
+            Kind::Connect,
+            self.open_udp(port, peer),
+        )
+        .await?;
+        let limits = Limits::default()
+            .with_idle(self.timeouts.session, Kind::Session)
+            .with_tracking(self.info("udp", port, peer));
+        let token = CancellationToken::new();
+        // ends the watchers on every exit, `?` included
+        let _cancel_on_exit = token.clone().drop_guard();
+        limits.watch(&token);
+        loop {
+            select! {
+                data = rx.recv() => {
+                    let Some(data) = data else { break Ok(()) };
+                    limits.up(data.len() as u64).await?;
+                    let meta = UDPMeta {
+                        dst_addr: "".to_string(),
+                        dst_port: port,
+                        src_addr: peer.ip().to_string(),
+                        src_port: peer.port(),
+                    };
+                    if let Err(e) = writer.write(UDPPacket { meta, data }).await {
+                        break Err(e);
+                    }
+                }
+                packet = reader.read() => {
+                    let packet = match packet {
+                        Ok(packet) => packet,
+                        Err(e) => break Err(e),
+                    };
+                    limits.down(packet.data.len() as u64).await?;
+                    socket.send_to(&packet.data, peer).await?;
+                }
+                _ = token.cancelled() => break Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acl() {
+        let options = Some(HashMap::from([
+            (
+                "publish_allow".to_string(),
+                toml::Value::Array(vec![
+                    toml::Value::String("site:18000-18099".to_string()),
+                    toml::Value::String("office:8080".to_string()),
+                ]),
+            ),
+            (
+                "publish_bind".to_string(),
+                toml::Value::String("127.0.0.1".to_string()),
+            ),
+        ]));
+        let acl = PublishAcl::from_options(&options).unwrap();
+        assert_eq!(acl.bind, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(acl.allows("site", 18000));
+        assert!(acl.allows("site", 18099));
+        assert!(!acl.allows("site", 18100));
+        assert!(acl.allows("office", 8080));
+        assert!(!acl.allows("office", 8081));
+        assert!(!acl.allows("other", 8080));
+
+        let none = PublishAcl::from_options(&None).unwrap();
+        assert!(!none.allows("site", 18000));
+
+        let bad = Some(HashMap::from([(
+            "publish_allow".to_string(),
+            toml::Value::String("site:80-x".to_string()),
+        )]));
+        assert!(PublishAcl::from_options(&bad).is_err());
+    }
+}
@@ -1,33 +1,57 @@
+use crate::def::config::{self, get_option_bool};
 use crate::def::{RunConnector, RunStream, RunUdpReader, RunUdpWriter};
 use crate::stream::tcp::TcpRunStream;
 use crate::stream::udp::UdpRunStream;
+use crate::util::proxy_protocol;
 use log::error;
 use std::io::Result;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpStream, UdpSocket};
 
-pub struct TcpRunConnector {}
+pub struct TcpRunConnector {
+    /// Start each connection with a PROXY protocol v2 header naming the client.
+    proxy_protocol: bool,
+}
 
 impl TcpRunConnector {
-    pub fn new() -> Self {
-        TcpRunConnector {}
+    pub fn new(cfg: &config::Connector) -> Self {
+        TcpRunConnector {
+            proxy_protocol: get_option_bool(&cfg.options, "proxy_protocol"),
+        }
     }
-}
 
-#[async_trait::async_trait]
-impl RunConnector for TcpRunConnector {
-    async fn connect(&self, addr: String) -> Result<Box<dyn RunStream>> {
-        let tcp_stream = match TcpStream::connect(addr.clone()).await {
+    async fn dial(&self, addr: String, source: Option<SocketAddr>) -> Result<Box<dyn RunStream>> {
+        let mut tcp_stream = match TcpStream::connect(addr.clone()).await {
             Ok(s) => s,
             Err(e) => {
                 error!("Tcp connector failed to connect to {}: {}", addr, e);
                 return Err(e);
             }
         };
+        if self.proxy_protocol {
+            let header = match source {
+                Some(source) => proxy_protocol::encode_v2(source, tcp_stream.peer_addr()?),
+                None => proxy_protocol::encode_v2_local(),
+            };
+            tcp_stream.write_all(&header).await?;
+        }
         let mut stream = TcpRunStream::new(tcp_stream);
         stream.set_info(&mut |x| x.protocol_name = "tcp".to_string());
         Ok(Box::new(stream))
     }
+}
+
+#[async_trait::async_trait]
+impl RunConnector for TcpRunConnector {
+    async fn connect(&self, addr: String) -> Result<Box<dyn RunStream>> {
+        self.dial(addr, None).await
+    }
+
+    async fn connect_from(&self, addr: String, source: SocketAddr) -> Result<Box<dyn RunStream>> {
+        self.dial(addr, Some(source)).await
+    }
 
     async fn udp_tunnel(
         &self,
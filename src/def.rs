@@ -1,6 +1,8 @@
 pub mod config;
 
+use crate::consts::TCP_IO_BUFFER_SIZE;
 use crate::util::RunAddr;
+use bytes::{Bytes, BytesMut};
 use std::any::Any;
 use std::io::{Error, ErrorKind, Result};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
@@ -13,11 +15,22 @@ pub struct StreamInfo {
     pub dst_addr: Option<String>,
     pub dst_port: Option<u16>,
     pub udp: Option<bool>,
+    /// `[[user]]` the client authenticated as.
+    pub user: Option<String>,
 }
 
 #[async_trait::async_trait]
 pub trait RunReadHalf: Send {
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+    /// Reads the next chunk, empty on EOF. The relay moves chunks between
+    /// streams as they are, so tunneled streams hand over whole payloads
+    /// without copying them.
+    async fn read_chunk(&mut self) -> Result<Bytes> {
+        let mut buf = BytesMut::zeroed(TCP_IO_BUFFER_SIZE);
+        let n = self.read(&mut buf).await?;
+        buf.truncate(n);
+        Ok(buf.freeze())
+    }
     // async fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<usize> {
     //     let mut n = 0;
     //     while !buf.is_empty() {
@@ -38,6 +51,13 @@ pub trait RunReadHalf: Send {
 #[async_trait::async_trait]
 pub trait RunWriteHalf: Send {
     async fn write(&mut self, buf: &[u8]) -> Result<()>;
+    async fn write_chunk(&mut self, data: Bytes) -> Result<()> {
+        self.write(&data).await
+    }
+    /// Half-closes the stream: the peer reads EOF while its own direction stays open.
+    async fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -74,6 +94,12 @@ pub enum RunAccStream {
 pub trait RunConnector: Send + Sync {
     async fn connect(&self, addr: String) -> Result<Box<dyn RunStream>>;
 
+    /// `connect` on behalf of the client at `source`, for connectors that
+    /// pass its address on.
+    async fn connect_from(&self, addr: String, _source: SocketAddr) -> Result<Box<dyn RunStream>> {
+        self.connect(addr).await
+    }
+
     async fn udp_tunnel(
         &self,
         src_addr: String,
@@ -91,6 +117,27 @@ pub trait RunAcceptor: Send + Sync {
     async fn post_handshake(&self, _: &mut dyn RunStream, _: bool, _: u16) -> Result<()> {
         Ok(())
     }
+
+    /// Whether the client sends nothing before `post_handshake` confirms
+    /// the connection, so sniffing has to confirm it first.
+    fn confirms_first(&self, _: &dyn RunStream) -> bool {
+        false
+    }
+
+    /// Turns away a connection over the listener's limits with the protocol's
+    /// own error reply. Listeners without one just close the stream.
+    async fn reject(&self, _: &mut dyn RunStream, _: Rejection) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Why a connection was turned away before its handshake.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    /// The listener is at `max_connections`.
+    Overloaded,
+    /// The client is over its per source limits.
+    RateLimited,
 }
 
 #[async_trait::async_trait]
@@ -111,11 +158,20 @@ pub trait RouterSet: Send + Sync {
 #[async_trait::async_trait]
 pub trait RunUdpReader: Send {
     async fn read(&mut self) -> Result<UDPPacket>;
+
+    /// `[[user]]` the session authenticated as, known after the first read.
+    fn user(&self) -> Option<String> {
+        None
+    }
 }
 
 #[async_trait::async_trait]
 pub trait RunUdpWriter: Send {
     async fn write(&self, packet: UDPPacket) -> Result<()>;
+    /// [`RunAcceptor::reject`] for a UDP session.
+    async fn reject(&mut self, _: Rejection) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -1,24 +1,54 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
+    pub admin: Option<Admin>,
     pub reverse_server: Option<ReverseServer>,
+    pub dns_server: Option<DnsServer>,
     pub listener: Vec<Listener>,
     pub router: Vec<Router>,
     pub data: Option<Vec<RouteData>>,
     pub connector: Vec<Connector>,
     pub server_id: Option<String>,
     pub buffer_size: Option<String>,
+    /// Buffer each connection direction may use even when the pool is full.
+    pub buffer_connection_min: Option<String>,
+    pub user: Option<Vec<User>>,
+    pub quota: Option<Vec<Quota>>,
+    pub rate_limit: Option<Vec<RateLimit>>,
+    /// Where quota usage is kept across restarts.
+    pub quota_state: Option<String>,
+    /// Seconds, see `object::timeouts`.
+    pub handshake_timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub session_timeout: Option<u64>,
+    pub linger_timeout: Option<u64>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// The admin HTTP API, see `admin`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Admin {
+    /// "127.0.0.1:9090", or "unix:/path/to/socket".
+    pub endpoint: String,
+    /// Bearer token every request has to carry.
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ReverseServer {
     pub endpoint: String,
     pub options: Option<HashMap<String, toml::Value>>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DnsServer {
+    pub endpoint: String,
+    pub options: Option<HashMap<String, toml::Value>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Connector {
     pub endpoint: Option<String>,
     pub name: String,
@@ -28,14 +58,14 @@ pub struct Connector {
     pub options: Option<HashMap<String, toml::Value>>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Router {
     pub name: String,
     pub default: String,
     pub route_rules: Option<Vec<RouteRule>>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Listener {
     pub endpoint: String,
     pub name: String,
@@ -46,7 +76,50 @@ pub struct Listener {
     pub options: Option<HashMap<String, toml::Value>>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// A `[[user]]` accepted by `grpc` and `pb_tcp` listeners next to their `pw`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub pw: String,
+    pub enabled: Option<bool>,
+    /// Routes this user's traffic instead of the listener's router.
+    pub router: Option<String>,
+    /// Connectors this user may use; unset allows all.
+    pub connectors: Option<Vec<String>>,
+}
+
+/// A `[[quota]]` on the traffic of a user, listener or connector.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Quota {
+    pub name: String,
+    pub user: Option<String>,
+    pub listener: Option<String>,
+    pub connector: Option<String>,
+    /// "day" or "month" (UTC).
+    pub period: String,
+    /// Size such as "50G".
+    pub limit: String,
+    /// "up", "down" or "both" (default).
+    pub direction: Option<String>,
+}
+
+/// A `[[rate_limit]]` token bucket on the traffic of a user, listener or connector.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RateLimit {
+    pub name: String,
+    pub user: Option<String>,
+    pub listener: Option<String>,
+    pub connector: Option<String>,
+    /// A bucket per client IP instead of one for all matching connections.
+    pub per_source: Option<bool>,
+    /// Bytes per second, e.g. "10M".
+    pub up: Option<String>,
+    pub down: Option<String>,
+    /// Bucket size, one second of traffic by default.
+    pub burst: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RouteRule {
     pub name: String,
     pub select: String,
@@ -63,7 +136,44 @@ pub fn get_option_bool(options: &Option<HashMap<String, toml::Value>>, key: &str
         .unwrap_or(false)
 }
 
-#[derive(Deserialize, Debug, Clone)]
+pub fn get_option_str(options: &Option<HashMap<String, toml::Value>>, key: &str) -> Option<String> {
+    options
+        .as_ref()
+        .and_then(|m| m.get(key))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+}
+
+/// Accepts either a toml array of strings or a comma separated string.
+pub fn get_option_str_list(
+    options: &Option<HashMap<String, toml::Value>>,
+    key: &str,
+) -> Vec<String> {
+    match options.as_ref().and_then(|m| m.get(key)) {
+        Some(toml::Value::Array(items)) => items
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(|s| s.to_string())
+            .collect(),
+        Some(toml::Value::String(s)) => s
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+pub fn get_option_u64(options: &Option<HashMap<String, toml::Value>>, key: &str) -> Option<u64> {
+    options
+        .as_ref()
+        .and_then(|m| m.get(key))
+        .and_then(|v| v.as_integer())
+        .and_then(|v| u64::try_from(v).ok())
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RouteData {
     pub name: String,
     pub url: Option<String>,
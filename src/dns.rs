@@ -0,0 +1,2 @@
+pub(crate) mod fake_ip;
+pub(crate) mod server;
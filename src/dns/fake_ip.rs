@@ -0,0 +1,292 @@
+use crate::def::UDPMeta;
+use crate::def::config::{get_option_bool, get_option_str};
+use ipnet::Ipv4Net;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_FAKE_IP_RANGE: &str = "198.18.0.0/15";
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Bidirectional fake-IP ↔ domain table.
+/// Addresses are handed out sequentially from the pool; once the pool is
+/// exhausted the oldest mapping is recycled.
+pub struct FakeIpTable {
+    net: Ipv4Net,
+    persist_path: Option<String>,
+    dirty: AtomicBool,
+    inner: Mutex<FakeIpInner>,
+}
+
+struct FakeIpInner {
+    next: u32,
+    domain_to_ip: HashMap<String, Ipv4Addr>,
+    ip_to_domain: HashMap<Ipv4Addr, String>,
+}
+
+impl FakeIpTable {
+    pub fn new(range: &str, persist_path: Option<String>) -> io::Result<Self> {
+        let net: Ipv4Net = range.parse().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid fake ip range '{}': {}", range, e),
+            )
+        })?;
+        if net.prefix_len() > 29 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fake ip range '{}' is too small", range),
+            ));
+        }
+        let table = FakeIpTable {
+            net: net.trunc(),
+            persist_path,
+            dirty: AtomicBool::new(false),
+            inner: Mutex::new(FakeIpInner {
+                next: 0,
+                domain_to_ip: HashMap::new(),
+                ip_to_domain: HashMap::new(),
+            }),
+        };
+        table.load()?;
+        Ok(table)
+    }
+
+    /// Builds the table from `[dns_server]` options, `None` when fake ip mode is off.
+    pub fn from_options(
+        options: &Option<HashMap<String, toml::Value>>,
+    ) -> io::Result<Option<Arc<Self>>> {
+        if !get_option_bool(options, "fake_ip") {
+            return Ok(None);
+        }
+        let range = get_option_str(options, "fake_ip_range")
+            .unwrap_or_else(|| DEFAULT_FAKE_IP_RANGE.to_string());
+        let table = Arc::new(FakeIpTable::new(
+            range.as_str(),
+            get_option_str(options, "fake_ip_persist"),
+        )?);
+        info!("fake ip pool {} enabled", table.net);
+        if table.persist_path.is_some() {
+            tokio::spawn(table.clone().persist_task());
+        }
+        Ok(Some(table))
+    }
+
+    // usable host offsets inside the pool, skipping the network, the first
+    // host (usually a gateway) and the broadcast address
+    fn first_offset(&self) -> u32 {
+        2
+    }
+
+    fn pool_size(&self) -> u32 {
+        (1u32 << (32 - self.net.prefix_len())) - 1 - self.first_offset()
+    }
+
+    fn offset_to_ip(&self, offset: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.net.network()) + self.first_offset() + offset)
+    }
+
+    /// Returns the fake ip of `domain`, allocating one if needed.
+    pub fn allocate(&self, domain: &str) -> Ipv4Addr {
+        let domain = normalize_domain(domain);
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(ip) = inner.domain_to_ip.get(&domain) {
+            return *ip;
+        }
+        let ip = self.offset_to_ip(inner.next);
+        inner.next = (inner.next + 1) % self.pool_size();
+        if let Some(old) = inner.ip_to_domain.remove(&ip) {
+            debug!("fake ip {} recycled from {}", ip, old);
+            inner.domain_to_ip.remove(&old);
+        }
+        inner.domain_to_ip.insert(domain.clone(), ip);
+        inner.ip_to_domain.insert(ip, domain);
+        self.dirty.store(true, Ordering::Relaxed);
+        ip
+    }
+
+    pub fn contains(&self, addr: &str) -> bool {
+        addr.parse::<Ipv4Addr>()
+            .map(|ip| self.net.contains(&ip))
+            .unwrap_or(false)
+    }
+
+    /// Translates a fake ip back to its domain.
+    pub fn lookup(&self, addr: &str) -> Option<String> {
+        if !self.contains(addr) {
+            return None;
+        }
+        let ip: Ipv4Addr = addr.parse().ok()?;
+        let domain = self.inner.lock().unwrap().ip_to_domain.get(&ip).cloned();
+        if domain.is_none() {
+            warn!("fake ip {} has no domain mapping", addr);
+        }
+        domain
+    }
+
+    pub fn fake_ip_of(&self, domain: &str) -> Option<Ipv4Addr> {
+        self.inner
+            .lock()
+            .unwrap()
+            .domain_to_ip
+            .get(&normalize_domain(domain))
+            .copied()
+    }
+
+    /// Client → remote direction: replace a fake destination with its domain.
+    pub fn restore_dst(&self, meta: &mut UDPMeta) {
+        if let Some(domain) = self.lookup(&meta.dst_addr) {
+            meta.dst_addr = domain;
+        }
+    }
+
+    /// Remote → client direction: hand the client back the fake ip it sent to.
+    pub fn mask_dst(&self, meta: &mut UDPMeta) {
+        if let Some(ip) = self.fake_ip_of(&meta.dst_addr) {
+            meta.dst_addr = ip.to_string();
+        }
+    }
+
+    fn load(&self) -> io::Result<()> {
+        let path = match &self.persist_path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let content = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let base = u32::from(self.net.network()) + self.first_offset();
+        for line in content.lines() {
+            let mut parts = line.split_whitespace();
+            let (Some(ip), Some(domain)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            let ip: Ipv4Addr = match ip.parse() {
+                Ok(ip) => ip,
+                Err(_) => continue,
+            };
+            let offset = u32::from(ip).wrapping_sub(base);
+            if !self.net.contains(&ip) || offset >= self.pool_size() {
+                continue;
+            }
+            let domain = normalize_domain(domain);
+            inner.domain_to_ip.insert(domain.clone(), ip);
+            inner.ip_to_domain.insert(ip, domain);
+            inner.next = inner.next.max((offset + 1) % self.pool_size());
+        }
+        info!(
+            "fake ip table loaded {} entries from {}",
+            inner.ip_to_domain.len(),
+            path
+        );
+        Ok(())
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.persist_path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let content = {
+            let inner = self.inner.lock().unwrap();
+            let mut lines: Vec<(&Ipv4Addr, &String)> = inner.ip_to_domain.iter().collect();
+            lines.sort();
+            lines
+                .into_iter()
+                .map(|(ip, domain)| format!("{} {}\n", ip, domain))
+                .collect::<String>()
+        };
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    async fn persist_task(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+        loop {
+            interval.tick().await;
+            if self.dirty.swap(false, Ordering::Relaxed)
+                && let Err(e) = self.save()
+            {
+                warn!("fake ip table save error: {}", e);
+                self.dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_and_lookup() {
+        let table = FakeIpTable::new("198.18.0.0/15", None).unwrap();
+        let ip = table.allocate("Example.com.");
+        assert_eq!(ip, Ipv4Addr::new(198, 18, 0, 2));
+        assert_eq!(table.allocate("example.com"), ip);
+        assert_eq!(
+            table.lookup(&ip.to_string()),
+            Some("example.com".to_string())
+        );
+        assert_eq!(table.allocate("other.com"), Ipv4Addr::new(198, 18, 0, 3));
+        assert!(table.lookup("1.1.1.1").is_none());
+        assert!(table.lookup("198.18.0.100").is_none());
+    }
+
+    #[test]
+    fn test_pool_recycle() {
+        // /29: 8 addresses, offsets .2 - .6 usable
+        let table = FakeIpTable::new("10.0.0.0/29", None).unwrap();
+        for i in 0..5 {
+            table.allocate(&format!("d{}.com", i));
+        }
+        let ip = table.allocate("d5.com");
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 2));
+        assert!(table.fake_ip_of("d0.com").is_none());
+        assert_eq!(table.lookup("10.0.0.2"), Some("d5.com".to_string()));
+    }
+
+    #[test]
+    fn test_udp_meta_translation() {
+        let table = FakeIpTable::new("198.18.0.0/15", None).unwrap();
+        let ip = table.allocate("example.com");
+        let mut meta = UDPMeta {
+            dst_addr: ip.to_string(),
+            dst_port: 443,
+            src_addr: "127.0.0.1".to_string(),
+            src_port: 5000,
+        };
+        table.restore_dst(&mut meta);
+        assert_eq!(meta.dst_addr, "example.com");
+        table.mask_dst(&mut meta);
+        assert_eq!(meta.dst_addr, ip.to_string());
+    }
+
+    #[test]
+    fn test_persist_round_trip() {
+        let path = std::env::temp_dir().join(format!("rog-fake-ip-{}", uuid::Uuid::new_v4()));
+        let path_str = path.to_string_lossy().to_string();
+        {
+            let table = FakeIpTable::new("198.18.0.0/15", Some(path_str.clone())).unwrap();
+            table.allocate("a.com");
+            table.allocate("b.com");
+            table.save().unwrap();
+        }
+        let table = FakeIpTable::new("198.18.0.0/15", Some(path_str)).unwrap();
+        assert_eq!(table.lookup("198.18.0.3"), Some("b.com".to_string()));
+        assert_eq!(table.allocate("c.com"), Ipv4Addr::new(198, 18, 0, 4));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::def::config::get_option_str;
+use crate::dns::fake_ip::FakeIpTable;
+use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::A;
+use hickory_resolver::proto::rr::{DNSClass, RData, Record, RecordType};
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::spawn;
+use tokio::time::timeout;
+
+const DEFAULT_UPSTREAM: &str = "8.8.8.8:53";
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const FAKE_IP_TTL: u32 = 1;
+
+struct DnsServer {
+    upstream: SocketAddr,
+    fake_ip: Option<Arc<FakeIpTable>>,
+}
+
+pub async fn start_dns_server(
+    endpoint: String,
+    options: &Option<HashMap<String, toml::Value>>,
+    fake_ip: Option<Arc<FakeIpTable>>,
+) -> io::Result<()> {
+    let upstream = get_option_str(options, "upstream")
+        .unwrap_or_else(|| DEFAULT_UPSTREAM.to_string())
+        .parse::<SocketAddr>()
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid dns upstream: {}", e),
+            )
+        })?;
+    let socket = Arc::new(UdpSocket::bind(&endpoint).await?);
+    info!("dns server listening on {}", endpoint);
+    let server = Arc::new(DnsServer { upstream, fake_ip });
+
+    spawn(async move {
+        let mut buf = [0u8; 4096];
+        loop {
+            let (n, src) = match socket.recv_from(&mut buf).await {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("dns server recv error: {}", e);
+                    continue;
+                }
+            };
+            let query = buf[..n].to_vec();
+            let socket = socket.clone();
+            let server = server.clone();
+            spawn(async move {
+                match server.handle(&query).await {
+                    Ok(resp) => {
+                        if let Err(e) = socket.send_to(&resp, src).await {
+                            debug!("dns server send to {} error: {}", src, e);
+                        }
+                    }
+                    Err(e) => debug!("dns query from {} error: {}", src, e),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+impl DnsServer {
+    async fn handle(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        if let Some(fake_ip) = &self.fake_ip {
+            let msg = Message::from_vec(query).map_err(io::Error::other)?;
+            if let Some(resp) = fake_response(&msg, fake_ip) {
+                return resp.to_vec().map_err(io::Error::other);
+            }
+        }
+        self.forward(query).await
+    }
+
+    async fn forward(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let bind_addr = if self.upstream.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.send_to(query, self.upstream).await?;
+        let mut buf = [0u8; 4096];
+        let (n, _) = timeout(UPSTREAM_TIMEOUT, socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "dns upstream timeout"))??;
+        Ok(buf[..n].to_vec())
+    }
+}
+
+// A queries get a fake address, AAAA queries an empty answer so clients fall
+// back to IPv4; everything else is forwarded upstream.
+fn fake_response(msg: &Message, fake_ip: &FakeIpTable) -> Option<Message> {
+    if msg.message_type() != MessageType::Query || msg.queries().len() != 1 {
+        return None;
+    }
+    let query = &msg.queries()[0];
+    if query.query_class() != DNSClass::IN {
+        return None;
+    }
+    let mut resp = Message::new();
+    resp.set_id(msg.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(msg.op_code())
+        .set_recursion_desired(msg.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::NoError)
+        .add_query(query.clone());
+    match query.query_type() {
+        RecordType::A => {
+            let domain = query.name().to_utf8();
+            let ip = fake_ip.allocate(&domain);
+            debug!("fake ip {} -> {}", domain, ip);
+            resp.add_answer(Record::from_rdata(
+                query.name().clone(),
+                FAKE_IP_TTL,
+                RData::A(A(ip)),
+            ));
+            Some(resp)
+        }
+        RecordType::AAAA => Some(resp),
+        _ => None,
+    }
+}
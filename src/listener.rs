@@ -1,27 +1,55 @@
 use crate::def::{RunAcceptor, RunListener};
+use crate::listener::forward::ForwardListener;
 use crate::listener::grpc::GrpcListener;
 use crate::listener::htss5::Htss5RunAcceptor;
 use crate::listener::http::HttpRunAcceptor;
 use crate::listener::pb_tcp::PbTcpListener;
+#[cfg(target_os = "linux")]
+use crate::listener::redirect::RedirectRunListener;
 use crate::listener::rev_grpc::RevGrpcListener;
 use crate::listener::socks5::SocksRunAcceptor;
 use crate::listener::tcp::TcpRunListener;
+use crate::listener::tls::TlsRunListener;
+#[cfg(target_os = "linux")]
+use crate::listener::tproxy::TproxyRunListener;
 use crate::object::config::ObjectConfig;
+use crate::util::proxy_protocol::HeaderPolicy;
+use crate::util::tls::server_from_options;
 
+pub(crate) mod forward;
 pub(crate) mod grpc;
 pub(crate) mod htss5;
 pub(crate) mod http;
 pub(crate) mod pb_tcp;
+#[cfg(target_os = "linux")]
+pub(crate) mod redirect;
 mod rev_grpc;
 pub(crate) mod socks5;
 pub(crate) mod tcp;
+pub(crate) mod tls;
+#[cfg(target_os = "linux")]
+pub(crate) mod tproxy;
+pub(crate) mod udp_session;
+
+// plain tcp, or tls terminated when the listener has `tls_cert`, behind a
+// PROXY protocol header when it has `proxy_protocol`
+async fn tcp_listen(cfg: &ObjectConfig) -> std::io::Result<Box<dyn RunAcceptor>> {
+    let endpoint = cfg.listener.endpoint.as_str();
+    let proxy_protocol = HeaderPolicy::from_options(&cfg.listener.options)?;
+    match server_from_options(&cfg.listener.options)? {
+        Some(acceptor) => {
+            TlsRunListener::new(acceptor, proxy_protocol)
+                .listen(endpoint)
+                .await
+        }
+        None => TcpRunListener { proxy_protocol }.listen(endpoint).await,
+    }
+}
 
 pub async fn create(cfg: &ObjectConfig) -> std::io::Result<Box<dyn RunAcceptor>> {
     match cfg.listener.proto.as_str() {
         "socks5" => {
-            let listener = TcpRunListener {}
-                .listen(cfg.listener.endpoint.as_str())
-                .await?;
+            let listener = tcp_listen(cfg).await?;
             let socks5 = Box::new(SocksRunAcceptor::new(listener, None, None));
             Ok(socks5)
         }
@@ -36,9 +64,7 @@ pub async fn create(cfg: &ObjectConfig) -> std::io::Result<Box<dyn RunAcceptor>>
             Ok(acc)
         }
         "http" => {
-            let listener = TcpRunListener {}
-                .listen(cfg.listener.endpoint.as_str())
-                .await?;
+            let listener = tcp_listen(cfg).await?;
             let http = Box::new(HttpRunAcceptor::new(
                 listener,
                 None,
@@ -53,9 +79,7 @@ pub async fn create(cfg: &ObjectConfig) -> std::io::Result<Box<dyn RunAcceptor>>
             Ok(acc)
         }
         "htss5" => {
-            let listener = TcpRunListener {}
-                .listen(cfg.listener.endpoint.as_str())
-                .await?;
+            let listener = tcp_listen(cfg).await?;
             let http = Box::new(Htss5RunAcceptor::new(
                 listener,
                 None,
@@ -64,6 +88,30 @@ pub async fn create(cfg: &ObjectConfig) -> std::io::Result<Box<dyn RunAcceptor>>
             ));
             Ok(http)
         }
+        "forward" => {
+            let l = ForwardListener::new(cfg.clone());
+            l.listen(cfg.listener.endpoint.as_str()).await
+        }
+        #[cfg(target_os = "linux")]
+        "redirect" => {
+            RedirectRunListener {}
+                .listen(cfg.listener.endpoint.as_str())
+                .await
+        }
+        #[cfg(target_os = "linux")]
+        "tproxy" => {
+            TproxyRunListener {}
+                .listen(cfg.listener.endpoint.as_str())
+                .await
+        }
+        #[cfg(not(target_os = "linux"))]
+        "redirect" | "tproxy" => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "listener proto {} is only supported on linux",
+                cfg.listener.proto.as_str()
+            ),
+        )),
         _ => Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
             format!("listener proto {} not found", cfg.listener.proto.as_str()),
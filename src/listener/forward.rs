@@ -0,0 +1,195 @@
+use crate::def::config::get_option_str;
+use crate::def::{
+    RunAccStream, RunAcceptor, RunListener, RunStream, RunUdpWriter, UDPMeta, UDPPacket,
+};
+use crate::listener::udp_session::{UDP_SESSION_QUEUE, UdpSessionReader, UdpSessions};
+use crate::object::config::ObjectConfig;
+use crate::stream::tcp::TcpRunStream;
+use crate::util::RunAddr;
+use log::{info, warn};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{Mutex, mpsc};
+use tokio::{select, spawn};
+
+type UdpSession = (UdpSessionReader, ForwardUdpWriter, SocketAddr);
+
+/// Static port forward: every connection goes to the `target` option,
+/// through whatever connector the router picks for it.
+pub struct ForwardListener {
+    cfg: ObjectConfig,
+}
+
+pub struct ForwardRunAcceptor {
+    target: RunAddr,
+    tcp: Option<TcpListener>,
+    udp_sessions: Mutex<mpsc::Receiver<UdpSession>>,
+}
+
+impl ForwardListener {
+    pub fn new(cfg: ObjectConfig) -> Self {
+        Self { cfg }
+    }
+}
+
+#[async_trait::async_trait]
+impl RunListener for ForwardListener {
+    async fn listen(&self, addr: &str) -> std::io::Result<Box<dyn RunAcceptor>> {
+        let options = &self.cfg.listener.options;
+        let target = get_option_str(options, "target").ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "forward listener needs a target")
+        })?;
+        let target = parse_target(&target)?;
+        let network = get_option_str(options, "network").unwrap_or_else(|| "tcp".to_string());
+        let (tcp_on, udp_on) = match network.as_str() {
+            "tcp" => (true, false),
+            "udp" => (false, true),
+            "tcp,udp" | "udp,tcp" | "both" => (true, true),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("forward network {} not supported", network),
+                ));
+            }
+        };
+
+        let tcp = if tcp_on {
+            Some(TcpListener::bind(addr).await?)
+        } else {
+            None
+        };
+        let (session_tx, session_rx) = mpsc::channel(UDP_SESSION_QUEUE);
+        if udp_on {
+            let udp = Arc::new(UdpSocket::bind(addr).await?);
+            spawn(udp_dispatch(udp, target.clone(), session_tx));
+        }
+        info!("forward {} ({}) -> {}", addr, network, target.endpoint());
+        Ok(Box::new(ForwardRunAcceptor {
+            target,
+            tcp,
+            udp_sessions: Mutex::new(session_rx),
+        }))
+    }
+}
+
+#[async_trait::async_trait]
+impl RunAcceptor for ForwardRunAcceptor {
+    async fn accept(&self) -> std::io::Result<(RunAccStream, SocketAddr)> {
+        let mut udp_sessions = self.udp_sessions.lock().await;
+        let tcp_accept = async {
+            match &self.tcp {
+                Some(l) => l.accept().await,
+                None => std::future::pending().await,
+            }
+        };
+        select! {
+            r = tcp_accept => {
+                let (socket, addr) = r?;
+                let mut stream = TcpRunStream::new(socket);
+                stream.set_info(&mut |x| x.protocol_name = "forward".to_string());
+                Ok((RunAccStream::TCPStream(Box::new(stream)), addr))
+            }
+            r = udp_sessions.recv() => {
+                match r {
+                    None => Err(Error::other("forward udp dispatcher closed")),
+                    Some((reader, writer, src)) => Ok((
+                        RunAccStream::UDPSocket((Box::new(reader), Box::new(writer))),
+                        src,
+                    )),
+                }
+            }
+        }
+    }
+
+    async fn handshake(
+        &self,
+        _stream: &mut dyn RunStream,
+    ) -> std::io::Result<(RunAddr, Option<Vec<u8>>)> {
+        Ok((self.target.clone(), None))
+    }
+}
+
+async fn udp_dispatch(udp: Arc<UdpSocket>, target: RunAddr, session_tx: mpsc::Sender<UdpSession>) {
+    let mut sessions = UdpSessions::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, src) = match udp.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("forward udp recv error: {}", e);
+                continue;
+            }
+        };
+        let packet = UDPPacket {
+            meta: UDPMeta {
+                dst_addr: target.addr.clone(),
+                dst_port: target.port,
+                src_addr: src.ip().to_string(),
+                src_port: src.port(),
+            },
+            data: buf[..n].to_vec(),
+        };
+        let Some(reader) = sessions.dispatch(src, packet) else {
+            continue;
+        };
+        let writer = ForwardUdpWriter {
+            socket: udp.clone(),
+            client: src,
+        };
+        if session_tx.send((reader, writer, src)).await.is_err() {
+            warn!("forward udp acceptor closed");
+            break;
+        }
+    }
+}
+
+/// Replies go back through the listening socket to the session's client.
+pub struct ForwardUdpWriter {
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+}
+
+#[async_trait::async_trait]
+impl RunUdpWriter for ForwardUdpWriter {
+    async fn write(&self, packet: UDPPacket) -> std::io::Result<()> {
+        self.socket.send_to(&packet.data, self.client).await?;
+        Ok(())
+    }
+}
+
+fn parse_target(target: &str) -> std::io::Result<RunAddr> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid forward target {}", target),
+        )
+    };
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(RunAddr {
+        addr: host.to_string(),
+        port,
+        udp: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        let t = parse_target("db.internal:5432").unwrap();
+        assert_eq!((t.addr.as_str(), t.port), ("db.internal", 5432));
+        let t = parse_target("[::1]:53").unwrap();
+        assert_eq!((t.addr.as_str(), t.port), ("::1", 53));
+        assert!(parse_target("db.internal").is_err());
+        assert!(parse_target(":80").is_err());
+    }
+}
@@ -1,11 +1,14 @@
 use crate::def::config::get_option_bool;
-use crate::def::{RunAccStream, RunAcceptor, RunListener, RunStream};
+use crate::def::{Rejection, RunAccStream, RunAcceptor, RunListener, RunStream};
 use crate::object::config::ObjectConfig;
 use crate::proto::v1::pb::rog_service_server::{RogService, RogServiceServer};
 use crate::proto::v1::pb::{StreamReq, StreamRes, UdpReq, UdpRes};
 use crate::stream::grpc_server::{self, GrpcServerRunStream};
 use crate::stream::grpc_udp_server::{GrpcUdpServerReadHalf, GrpcUdpServerWriteHalf};
 use crate::util::RunAddr;
+use crate::util::auth_token::TokenVerifier;
+use crate::util::grpc_transport::server_tls_config;
+use crate::util::users::UserTable;
 use futures::Stream;
 use std::io::Error;
 use std::net::SocketAddr;
@@ -31,10 +34,18 @@ impl GrpcListener {
     }
 }
 
+/// An incoming stream call with its client address.
+type StreamCall = (GrpcServerRunStream, Option<SocketAddr>);
+/// An incoming udp call with its response channel and client address.
+type UdpCall = (
+    Streaming<UdpReq>,
+    Sender<Result<UdpRes, Status>>,
+    Option<SocketAddr>,
+);
+
 struct GrpcServer {
-    sender: Sender<GrpcServerRunStream>,
-    udp_sender: Sender<(Streaming<UdpReq>, Sender<Result<UdpRes, Status>>, String)>,
-    cfg: ObjectConfig,
+    sender: Sender<StreamCall>,
+    udp_sender: Sender<UdpCall>,
     // router: Arc<dyn RouterSet>, // Removed
 }
 type ResponseStream = Pin<Box<dyn Stream<Item = Result<StreamRes, Status>> + Send>>;
@@ -49,9 +60,10 @@ impl RogService for GrpcServer {
         request: Request<Streaming<StreamReq>>,
     ) -> Result<Response<Self::streamStream>, Status> {
         let (tx, rx) = mpsc::channel(8);
+        let peer = request.remote_addr();
         let request = request.into_inner();
         let stream = GrpcServerRunStream::new(Arc::new(Mutex::new(request)), tx);
-        match self.sender.send(stream).await {
+        match self.sender.send((stream, peer)).await {
             Ok(_) => {}
             Err(err) => return Err(Status::new(Code::Internal, format!("{}", err))),
         }
@@ -65,8 +77,9 @@ impl RogService for GrpcServer {
         request: Request<Streaming<UdpReq>>,
     ) -> Result<Response<Self::udpStream>, Status> {
         let (tx, rx) = mpsc::channel::<Result<UdpRes, Status>>(8);
+        let peer = request.remote_addr();
         let request = request.into_inner();
-        let stream = (request, tx, self.cfg.listener.pw.clone().unwrap());
+        let stream = (request, tx, peer);
         match self.udp_sender.send(stream).await {
             Ok(_) => {}
             Err(err) => return Err(Status::new(Code::Internal, format!("{}", err))),
@@ -218,9 +231,10 @@ impl RogService for GrpcServer {
 }
 
 pub struct GrpcRunListener {
-    receiver: Arc<Mutex<Receiver<GrpcServerRunStream>>>,
-    udp_receiver: Arc<Mutex<Receiver<(Streaming<UdpReq>, Sender<Result<UdpRes, Status>>, String)>>>,
-    auth: String,
+    receiver: Arc<Mutex<Receiver<StreamCall>>>,
+    udp_receiver: Arc<Mutex<Receiver<UdpCall>>>,
+    users: Arc<UserTable>,
+    verifier: Arc<TokenVerifier>,
 }
 
 #[async_trait::async_trait]
@@ -231,13 +245,15 @@ impl RunListener for GrpcListener {
         let rog = GrpcServer {
             sender: tx,
             udp_sender: udp_tx,
-            cfg: self.cfg.clone(),
             // router: self.router.clone(), // Removed
         };
         let keep_alive = get_option_bool(&self.cfg.listener.options, "keep_alive");
         let addr = addr.to_owned();
+        let mut builder = Server::builder();
+        if let Some(tls) = server_tls_config(&self.cfg.listener.options)? {
+            builder = builder.tls_config(tls).map_err(Error::other)?;
+        }
         spawn(async move {
-            let mut builder = Server::builder();
             if keep_alive {
                 builder = builder
                     .http2_keepalive_interval(Some(Duration::from_secs(30)))
@@ -251,7 +267,14 @@ impl RunListener for GrpcListener {
         Ok(Box::new(GrpcRunListener {
             receiver: Arc::new(Mutex::new(rx)),
             udp_receiver: Arc::new(Mutex::new(udp_rx)),
-            auth: self.cfg.listener.pw.as_ref().unwrap().clone(),
+            users: Arc::new(UserTable::new(
+                self.cfg.listener.pw.as_ref(),
+                &self.cfg.users,
+            )?),
+            verifier: Arc::new(TokenVerifier::new(get_option_bool(
+                &self.cfg.listener.options,
+                "legacy_auth",
+            ))),
         }))
     }
 }
@@ -265,18 +288,18 @@ impl RunAcceptor for GrpcRunListener {
             r = udp_receiver.recv() => {
                 match r {
                     None => Err(Error::other("receiver closed")),
-                    Some((r,w,a)) => Ok(((RunAccStream::UDPSocket(
+                    Some((r,w,peer)) => Ok(((RunAccStream::UDPSocket(
                         (
-                            Box::new(GrpcUdpServerReadHalf::new(r,a)),
+                            Box::new(GrpcUdpServerReadHalf::new(r, self.users.clone(), self.verifier.clone())),
                             Box::new(GrpcUdpServerWriteHalf::new(w))
                             )
-                    )), "127.0.9.28:2809".parse().unwrap())),
+                    )), peer.unwrap_or_else(|| "127.0.9.28:2809".parse().unwrap()))),
                 }
             }
             r = receiver.recv() => {
                 match r {
                     None => Err(Error::other("receiver closed")),
-                    Some(stream) => Ok((RunAccStream::TCPStream( Box::new(stream)), "127.0.0.1:2809".parse().unwrap())),
+                    Some((stream,peer)) => Ok((RunAccStream::TCPStream( Box::new(stream)), peer.unwrap_or_else(|| "127.0.0.1:2809".parse().unwrap()))),
                 }
             }
         }
@@ -293,12 +316,36 @@ impl RunAcceptor for GrpcRunListener {
             .unwrap();
         match stream.handshake().await? {
             Some((addr, auth)) => {
-                if auth != self.auth {
-                    return Err(Error::other("invalid auth"));
-                }
+                let context = format!("stream {}", addr.endpoint());
+                let cred = self
+                    .users
+                    .find(|c| self.verifier.verify(&c.pw, &auth, &context).is_ok())
+                    .ok_or_else(|| Error::other("invalid auth"))?;
+                stream.set_info(&mut |x| x.user = cred.user.clone());
                 Ok((addr, None))
             }
             None => Err(Error::other("handshake failed")),
         }
     }
+
+    async fn reject(
+        &self,
+        stream: &mut dyn RunStream,
+        rejection: Rejection,
+    ) -> std::io::Result<()> {
+        let stream = stream
+            .as_any_mut()
+            .downcast_mut::<grpc_server::GrpcServerRunStream>()
+            .unwrap();
+        stream.fail(rejection_status(rejection)).await
+    }
+}
+
+/// What a grpc client is told when admission control turns its call away.
+pub(crate) fn rejection_status(rejection: Rejection) -> Status {
+    let message = match rejection {
+        Rejection::Overloaded => "too many connections",
+        Rejection::RateLimited => "too many connections from this client",
+    };
+    Status::resource_exhausted(message)
 }
@@ -1,5 +1,6 @@
 use crate::consts::TCP_IO_BUFFER_SIZE;
-use crate::def::{RunAccStream, RunAcceptor, RunStream};
+use crate::def::{Rejection, RunAccStream, RunAcceptor, RunStream};
+use crate::listener::http::reject_response;
 use crate::util;
 use crate::util::RunAddr;
 use log::{debug, info, trace};
@@ -181,4 +182,29 @@ impl RunAcceptor for Htss5RunAcceptor {
         stream.write(&confirm.to_bytes()).await?;
         Ok(())
     }
+
+    fn confirms_first(&self, stream: &dyn RunStream) -> bool {
+        stream.get_info().protocol_name == "socks5"
+    }
+
+    async fn reject(
+        &self,
+        stream: &mut dyn RunStream,
+        rejection: Rejection,
+    ) -> std::io::Result<()> {
+        let mut buf = [0u8; TCP_IO_BUFFER_SIZE];
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || buf[0] != 5 {
+            return stream.write(reject_response(rejection)).await;
+        }
+        let (hello, _) = util::socks5::client_hello::ClientHello::parse_bytes(&buf[..n])?;
+        if !hello.contains(util::socks5::NO_AUTH) {
+            return Ok(());
+        }
+        let hello_back =
+            util::socks5::server_hello::ServerHello::new(hello.version, util::socks5::NO_AUTH);
+        stream.write(&hello_back.to_bytes()).await?;
+        stream.read(&mut buf).await?;
+        self.post_handshake(stream, true, 0).await
+    }
 }
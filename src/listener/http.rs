@@ -1,11 +1,23 @@
 use crate::consts::TCP_IO_BUFFER_SIZE;
-use crate::def::{RunAccStream, RunAcceptor, RunStream};
+use crate::def::{Rejection, RunAccStream, RunAcceptor, RunStream};
 use crate::util::RunAddr;
 use log::{debug, trace};
 use std::io::ErrorKind;
 use std::net::SocketAddr;
 use url::Url;
 
+/// Response to a request turned away by admission control.
+pub(crate) fn reject_response(rejection: Rejection) -> &'static [u8] {
+    match rejection {
+        Rejection::Overloaded => {
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        }
+        Rejection::RateLimited => {
+            b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub struct HttpRunAcceptor {
     inner: Box<dyn RunAcceptor>,
@@ -125,4 +137,14 @@ impl RunAcceptor for HttpRunAcceptor {
             cache,
         ))
     }
+
+    async fn reject(
+        &self,
+        stream: &mut dyn RunStream,
+        rejection: Rejection,
+    ) -> std::io::Result<()> {
+        let mut buf = [0u8; TCP_IO_BUFFER_SIZE];
+        stream.read(&mut buf).await?;
+        stream.write(reject_response(rejection)).await
+    }
 }
@@ -1,19 +1,32 @@
+use crate::def::config::get_option_bool;
 use crate::def::{RunAccStream, RunAcceptor, RunListener, RunStream};
+use crate::listener::tls::{MAX_PENDING_HANDSHAKES, accept_split};
 use crate::object::config::ObjectConfig;
+use crate::proto::v1::pb::UdpReq;
 use crate::stream::pb_tcp_server::PbTcpServerRunStream;
 use crate::stream::pb_tcp_udp_server::{PbTcpUdpServerReader, PbTcpUdpServerWriter};
 use crate::util::RunAddr;
+use crate::util::mux::MuxSession;
+use crate::util::proxy_protocol::{self, HeaderPolicy};
+use crate::util::secure::{ReplayGuard, SecureKey, server_handshake};
 use crate::util::tcp_frame::*;
+use crate::util::tls::server_from_options;
+use crate::util::users::{Credential, UserTable};
 use log::{error, warn};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::mpsc::Receiver;
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, mpsc};
+use tokio::time::timeout;
 use tokio::{select, spawn};
 
+/// How long a client has to name its conn type, after the secure handshake
+/// when it asks for one.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct PbTcpListener {
     cfg: ObjectConfig,
 }
@@ -25,19 +38,185 @@ impl PbTcpListener {
 }
 
 pub struct PbTcpRunAcceptor {
-    stream_receiver: Arc<Mutex<Receiver<PbTcpServerRunStream>>>,
-    udp_receiver: Arc<Mutex<Receiver<(OwnedReadHalf, OwnedWriteHalf, String)>>>,
-    auth: String,
+    stream_receiver: Arc<Mutex<Receiver<(PbTcpServerRunStream, SocketAddr)>>>,
+    udp_receiver: Arc<Mutex<Receiver<UdpConn>>>,
+    users: Arc<UserTable>,
+    encrypt: EncryptMode,
+    padding: FramePadding,
+}
+
+/// A udp connection with its credential, already authenticated first packet,
+/// client address and whether it runs inside a secure session.
+type UdpConn = (
+    FrameReader,
+    FrameWriter,
+    Credential,
+    UdpReq,
+    SocketAddr,
+    bool,
+);
+
+/// Shared by every connection of one listener.
+struct ConnHandler {
+    stream_tx: mpsc::Sender<(PbTcpServerRunStream, SocketAddr)>,
+    udp_tx: mpsc::Sender<UdpConn>,
+    users: Arc<UserTable>,
+    /// One per credential of `users`.
+    secure_keys: Vec<SecureKey>,
+    replay_guard: ReplayGuard,
+    /// Refuse connections that skip the secure handshake.
+    secure_required: bool,
+    padding: FramePadding,
+}
+
+impl ConnHandler {
+    /// `slot` is held until the connection is open.
+    async fn serve(
+        self: Arc<Self>,
+        reader: FrameReader,
+        writer: FrameWriter,
+        addr: SocketAddr,
+        slot: OwnedSemaphorePermit,
+    ) {
+        let opened = timeout(OPEN_TIMEOUT, self.open(reader, writer))
+            .await
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "pb_tcp open timeout")));
+        drop(slot);
+        let (reader, writer, conn_type, session) = match opened {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("pb_tcp connection from {} error: {}", addr, e);
+                return;
+            }
+        };
+        if conn_type != CONN_TYPE_MUX {
+            self.dispatch(conn_type, reader, writer, addr, session)
+                .await;
+            return;
+        }
+        let (_session, mut incoming) = MuxSession::server(reader, writer);
+        while let Some((mut reader, writer)) = incoming.recv().await {
+            let handler = Arc::clone(&self);
+            let session = session.clone();
+            spawn(async move {
+                match read_conn_type(&mut reader).await {
+                    Ok(conn_type) => {
+                        handler
+                            .dispatch(conn_type, reader, writer, addr, session)
+                            .await
+                    }
+                    Err(e) => warn!("pb_tcp mux read conn type from {} error: {}", addr, e),
+                }
+            });
+        }
+    }
+
+    /// Reads the conn type, running the secure handshake first when the
+    /// client asks for it. A secure session comes back with the credential
+    /// whose key passed the handshake.
+    async fn open(
+        &self,
+        mut reader: FrameReader,
+        writer: FrameWriter,
+    ) -> std::io::Result<(FrameReader, FrameWriter, u8, Option<Credential>)> {
+        let conn_type = read_conn_type(&mut reader).await?;
+        if conn_type != CONN_TYPE_SECURE {
+            if self.secure_required {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "secure handshake required",
+                ));
+            }
+            return Ok((reader, writer, conn_type, None));
+        }
+        let (mut reader, writer, index) =
+            server_handshake(reader, writer, &self.secure_keys, &self.replay_guard).await?;
+        let cred = self.users.credentials()[index].clone();
+        let conn_type = read_conn_type(&mut reader).await?;
+        Ok((reader, writer, conn_type, Some(cred)))
+    }
+
+    /// Hands a stream or udp connection, direct or inside a mux session, to
+    /// the acceptor. `session` is the credential of a secure session, which
+    /// takes the place of the per stream sealed auth.
+    async fn dispatch(
+        &self,
+        conn_type: u8,
+        mut reader: FrameReader,
+        writer: FrameWriter,
+        addr: SocketAddr,
+        session: Option<Credential>,
+    ) {
+        match conn_type {
+            CONN_TYPE_STREAM => {
+                let mut stream = PbTcpServerRunStream::new(reader, writer, self.padding);
+                if let Some(cred) = session {
+                    stream = stream.with_session(cred);
+                }
+                if self.stream_tx.send((stream, addr)).await.is_err() {
+                    warn!("pb_tcp stream channel closed");
+                }
+            }
+            CONN_TYPE_UDP => {
+                // the first packet tells which credential the session uses
+                let first: UdpReq = match read_msg(&mut reader).await {
+                    Ok(req) => req,
+                    Err(e) => {
+                        warn!("pb_tcp udp read from {} error: {}", addr, e);
+                        return;
+                    }
+                };
+                let secure = session.is_some();
+                let Some(cred) = session.or_else(|| self.users.find_sealed(&first.auth).cloned())
+                else {
+                    warn!("pb_tcp udp invalid auth from {}", addr);
+                    return;
+                };
+                if self
+                    .udp_tx
+                    .send((reader, writer, cred, first, addr, secure))
+                    .await
+                    .is_err()
+                {
+                    warn!("pb_tcp udp channel closed");
+                }
+            }
+            _ => {
+                warn!("pb_tcp unexpected conn type {} from {}", conn_type, addr);
+            }
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl RunListener for PbTcpListener {
     async fn listen(&self, addr: &str) -> std::io::Result<Box<dyn RunAcceptor>> {
-        let (stream_tx, stream_rx) = mpsc::channel::<PbTcpServerRunStream>(8);
-        let (udp_tx, udp_rx) = mpsc::channel::<(OwnedReadHalf, OwnedWriteHalf, String)>(8);
-        let auth = self.cfg.listener.pw.as_ref().unwrap().clone();
+        let (stream_tx, stream_rx) = mpsc::channel::<(PbTcpServerRunStream, SocketAddr)>(8);
+        let (udp_tx, udp_rx) = mpsc::channel::<UdpConn>(8);
+        let users = Arc::new(UserTable::new(
+            self.cfg.listener.pw.as_ref(),
+            &self.cfg.users,
+        )?);
         let bind_addr = addr.to_owned();
+        let tls = server_from_options(&self.cfg.listener.options)?;
+        let encrypt = EncryptMode::from_options(&self.cfg.listener.options)?;
+        let padding = FramePadding::from_options(&self.cfg.listener.options);
+        let proxy = HeaderPolicy::from_options(&self.cfg.listener.options)?;
+        let handler = Arc::new(ConnHandler {
+            stream_tx,
+            udp_tx,
+            secure_keys: users
+                .credentials()
+                .iter()
+                .map(|c| SecureKey::new(&c.pw))
+                .collect(),
+            users: users.clone(),
+            replay_guard: ReplayGuard::default(),
+            secure_required: get_option_bool(&self.cfg.listener.options, "secure_required"),
+            padding,
+        });
 
+        let handshakes = Arc::new(Semaphore::new(MAX_PENDING_HANDSHAKES));
         spawn(async move {
             let listener = match TcpListener::bind(&bind_addr).await {
                 Ok(l) => l,
@@ -48,41 +227,45 @@ impl RunListener for PbTcpListener {
             };
 
             loop {
-                let (tcp_stream, addr) = match listener.accept().await {
+                let (mut tcp_stream, peer) = match listener.accept().await {
                     Ok(s) => s,
                     Err(e) => {
                         error!("pb_tcp listener accept error: {}", e);
                         continue;
                     }
                 };
-                let stream_tx = stream_tx.clone();
-                let udp_tx = udp_tx.clone();
-                let auth = auth.clone();
+                if let Some(policy) = &proxy
+                    && !policy.trusts(peer)
+                {
+                    warn!("pb_tcp PROXY header from untrusted {} refused", peer);
+                    continue;
+                }
+                let Ok(slot) = handshakes.clone().acquire_owned().await else {
+                    return;
+                };
+                let header = proxy.is_some();
+                let handler = Arc::clone(&handler);
+                let tls = tls.clone();
                 spawn(async move {
-                    let (mut reader, writer) = tcp_stream.into_split();
-                    let conn_type = match read_conn_type(&mut reader).await {
-                        Ok(t) => t,
+                    let addr = if header {
+                        match proxy_protocol::accept(&mut tcp_stream, peer).await {
+                            Ok(client) => client,
+                            Err(e) => {
+                                warn!("pb_tcp PROXY header from {} error: {}", peer, e);
+                                return;
+                            }
+                        }
+                    } else {
+                        peer
+                    };
+                    let (reader, writer) = match accept_split(tls.as_ref(), tcp_stream).await {
+                        Ok(halves) => halves,
                         Err(e) => {
-                            warn!("pb_tcp read conn type from {} error: {}", addr, e);
+                            warn!("pb_tcp tls handshake with {} error: {}", addr, e);
                             return;
                         }
                     };
-                    match conn_type {
-                        CONN_TYPE_STREAM => {
-                            let stream = PbTcpServerRunStream::new(reader, writer);
-                            if stream_tx.send(stream).await.is_err() {
-                                warn!("pb_tcp stream channel closed");
-                            }
-                        }
-                        CONN_TYPE_UDP => {
-                            if udp_tx.send((reader, writer, auth)).await.is_err() {
-                                warn!("pb_tcp udp channel closed");
-                            }
-                        }
-                        _ => {
-                            warn!("pb_tcp unknown conn type {} from {}", conn_type, addr);
-                        }
-                    }
+                    handler.serve(reader, writer, addr, slot).await;
                 });
             }
         });
@@ -90,7 +273,9 @@ impl RunListener for PbTcpListener {
         Ok(Box::new(PbTcpRunAcceptor {
             stream_receiver: Arc::new(Mutex::new(stream_rx)),
             udp_receiver: Arc::new(Mutex::new(udp_rx)),
-            auth: self.cfg.listener.pw.as_ref().unwrap().clone(),
+            users,
+            encrypt,
+            padding,
         }))
     }
 }
@@ -104,22 +289,25 @@ impl RunAcceptor for PbTcpRunAcceptor {
             r = udp_receiver.recv() => {
                 match r {
                     None => Err(Error::other("udp receiver closed")),
-                    Some((reader, writer, pw)) => {
+                    Some((reader, writer, cred, first, addr, secure)) => {
                         let reader = Arc::new(Mutex::new(reader));
                         let writer = Arc::new(Mutex::new(writer));
+                        let pw = (!secure).then(|| cred.pw.clone());
                         Ok((
                             RunAccStream::UDPSocket((
                                 Box::new(PbTcpUdpServerReader::new(
                                     Arc::clone(&reader),
-                                    pw.clone(),
-                                    pw.clone(),
+                                    cred,
+                                    first,
+                                    secure,
                                 )),
                                 Box::new(PbTcpUdpServerWriter::new(
                                     Arc::clone(&writer),
                                     pw,
+                                    self.padding,
                                 )),
                             )),
-                            "127.0.9.28:2809".parse().unwrap(),
+                            addr,
                         ))
                     }
                 }
@@ -127,10 +315,7 @@ impl RunAcceptor for PbTcpRunAcceptor {
             r = stream_receiver.recv() => {
                 match r {
                     None => Err(Error::other("stream receiver closed")),
-                    Some(stream) => Ok((
-                        RunAccStream::TCPStream(Box::new(stream)),
-                        "127.0.0.1:2809".parse().unwrap(),
-                    )),
+                    Some((stream, addr)) => Ok((RunAccStream::TCPStream(Box::new(stream)), addr)),
                 }
             }
         }
@@ -145,14 +330,223 @@ impl RunAcceptor for PbTcpRunAcceptor {
             .as_any_mut()
             .downcast_mut::<PbTcpServerRunStream>()
             .unwrap();
-        match stream.handshake(&self.auth).await? {
-            Some((addr, auth)) => {
-                if auth != self.auth {
-                    return Err(Error::other("invalid auth"));
-                }
+        match stream.handshake(&self.users, self.encrypt).await? {
+            Some((addr, cred)) => {
+                stream.set_info(&mut |x| x.user = cred.user.clone());
                 Ok((addr, None))
             }
             None => Err(Error::other("handshake failed")),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::pb_tcp::PbTcpRunConnector;
+    use crate::def::RunConnector;
+    use crate::def::config::{Connector, Listener, User};
+    use crate::object::timeouts::Timeouts;
+    use std::collections::HashMap;
+
+    fn options(pairs: &[(&str, toml::Value)]) -> Option<HashMap<String, toml::Value>> {
+        Some(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        )
+    }
+
+    async fn start(
+        listener_options: Option<HashMap<String, toml::Value>>,
+        connector_options: Option<HashMap<String, toml::Value>>,
+        pw: &str,
+    ) -> (Box<dyn RunAcceptor>, PbTcpRunConnector) {
+        let endpoint = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let cfg = ObjectConfig {
+            listener: Listener {
+                endpoint: endpoint.clone(),
+                name: "in".to_string(),
+                user: None,
+                pw: Some("pw".to_string()),
+                proto: "pb_tcp".to_string(),
+                router: "r".to_string(),
+                options: listener_options,
+            },
+            connector: HashMap::new(),
+            server_id: String::new(),
+            users: vec![User {
+                name: "alice".to_string(),
+                pw: "alice pw".to_string(),
+                enabled: None,
+                router: None,
+                connectors: None,
+            }],
+            timeouts: Timeouts::default(),
+        };
+        let acceptor = PbTcpListener::new(cfg).listen(&endpoint).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let connector = PbTcpRunConnector::new(&Connector {
+            endpoint: Some(endpoint),
+            name: "out".to_string(),
+            user: None,
+            pw: Some(pw.to_string()),
+            proto: "pb_tcp".to_string(),
+            options: connector_options,
+        })
+        .unwrap();
+        (acceptor, connector)
+    }
+
+    async fn accept_stream(acceptor: &dyn RunAcceptor) -> std::io::Result<Box<dyn RunStream>> {
+        let (stream, _) = acceptor.accept().await?;
+        let RunAccStream::TCPStream(mut server) = stream else {
+            panic!("expected a stream");
+        };
+        let (addr, _) = acceptor.handshake(server.as_mut()).await?;
+        assert_eq!(addr.endpoint(), "example.com:80");
+        Ok(server)
+    }
+
+    #[tokio::test]
+    async fn test_secure_mux_round_trip() {
+        let yes = toml::Value::Boolean(true);
+        let (acceptor, connector) = start(
+            options(&[("secure_required", yes.clone()), ("padding", yes.clone())]),
+            options(&[
+                ("secure", yes.clone()),
+                ("mux", yes.clone()),
+                ("padding", yes.clone()),
+                ("padding_buckets", yes),
+            ]),
+            "pw",
+        )
+        .await;
+
+        for _ in 0..2 {
+            let mut client = connector
+                .connect("example.com:80".to_string())
+                .await
+                .unwrap();
+            let mut server = accept_stream(acceptor.as_ref()).await.unwrap();
+
+            client.write(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            server.write(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_policy() {
+        let (acceptor, connector) = start(
+            options(&[("encrypt", toml::Value::String("always".to_string()))]),
+            options(&[("encrypt", toml::Value::String("never".to_string()))]),
+            "pw",
+        )
+        .await;
+        let _client = connector
+            .connect("example.com:80".to_string())
+            .await
+            .unwrap();
+        let err = accept_stream(acceptor.as_ref()).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_user_identity() {
+        let yes = toml::Value::Boolean(true);
+        let (acceptor, connector) = start(None, options(&[("secure", yes)]), "alice pw").await;
+        let _client = connector
+            .connect("example.com:80".to_string())
+            .await
+            .unwrap();
+        let server = accept_stream(acceptor.as_ref()).await.unwrap();
+        assert_eq!(server.get_info().user.as_deref(), Some("alice"));
+
+        let (acceptor, connector) = start(None, None, "pw").await;
+        let _client = connector
+            .connect("example.com:80".to_string())
+            .await
+            .unwrap();
+        let server = accept_stream(acceptor.as_ref()).await.unwrap();
+        assert_eq!(server.get_info().user, None);
+
+        let (acceptor, connector) = start(None, None, "mallory pw").await;
+        let _client = connector
+            .connect("example.com:80".to_string())
+            .await
+            .unwrap();
+        let err = accept_stream(acceptor.as_ref()).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_secure_session_identity() {
+        use crate::def::{UDPMeta, UDPPacket};
+
+        // the handshake key names the user; the session carries no sealed
+        // auth or payload encryption, so the encrypt policy doesn't apply
+        let yes = toml::Value::Boolean(true);
+        let (acceptor, connector) = start(
+            options(&[("encrypt", toml::Value::String("always".to_string()))]),
+            options(&[("secure", yes)]),
+            "alice pw",
+        )
+        .await;
+        let mut client = connector
+            .connect("example.com:80".to_string())
+            .await
+            .unwrap();
+        let mut server = accept_stream(acceptor.as_ref()).await.unwrap();
+        assert_eq!(server.get_info().user.as_deref(), Some("alice"));
+        client.write(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let (mut client_r, client_w) = connector
+            .udp_tunnel("127.0.0.1:5000".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let meta = || UDPMeta {
+            dst_addr: "1.2.3.4".to_string(),
+            dst_port: 53,
+            src_addr: "127.0.0.1".to_string(),
+            src_port: 5000,
+        };
+        client_w
+            .write(UDPPacket {
+                meta: meta(),
+                data: b"query".to_vec(),
+            })
+            .await
+            .unwrap();
+        let (RunAccStream::UDPSocket((mut server_r, server_w)), _) =
+            acceptor.accept().await.unwrap()
+        else {
+            panic!("expected a udp session");
+        };
+        assert_eq!(server_r.user().as_deref(), Some("alice"));
+        let packet = server_r.read().await.unwrap();
+        assert_eq!(packet.meta.dst_addr, "1.2.3.4");
+        assert_eq!(packet.data, b"query");
+        server_w
+            .write(UDPPacket {
+                meta: meta(),
+                data: b"answer".to_vec(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(client_r.read().await.unwrap().data, b"answer");
+    }
+}
@@ -0,0 +1,79 @@
+use crate::def::{RunAccStream, RunAcceptor, RunListener, RunStream};
+use crate::stream::tcp::TcpRunStream;
+use crate::util::RunAddr;
+use crate::util::transparent::{canonical, original_dst};
+use log::debug;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+/// Accepts connections redirected by iptables `REDIRECT` / `DNAT`, the
+/// destination is recovered with `SO_ORIGINAL_DST`.
+pub struct RedirectRunAcceptor {
+    inner: TcpListener,
+}
+
+pub struct RedirectRunListener {}
+
+#[async_trait::async_trait]
+impl RunAcceptor for RedirectRunAcceptor {
+    async fn accept(&self) -> std::io::Result<(RunAccStream, SocketAddr)> {
+        let (socket, addr) = self.inner.accept().await?;
+        let local = socket.local_addr()?;
+        // without NAT the original destination is the listener itself,
+        // relaying it would loop back forever
+        let dst = match original_dst(&socket, &local) {
+            Ok(dst) if canonical(dst) != canonical(local) => Some(canonical(dst)),
+            Ok(_) => None,
+            Err(e) => {
+                debug!("redirect original dst of {} error: {}", addr, e);
+                None
+            }
+        };
+        let mut stream = TcpRunStream::new(socket);
+        stream.set_info(&mut |x| {
+            x.protocol_name = "redirect".to_string();
+            x.src_addr = Some(addr.ip().to_string());
+            x.src_port = Some(addr.port());
+            x.dst_addr = dst.map(|d| d.ip().to_string());
+            x.dst_port = dst.map(|d| d.port());
+        });
+        Ok((RunAccStream::TCPStream(Box::new(stream)), addr))
+    }
+
+    async fn handshake(
+        &self,
+        stream: &mut dyn RunStream,
+    ) -> std::io::Result<(RunAddr, Option<Vec<u8>>)> {
+        original_run_addr(stream)
+    }
+}
+
+#[async_trait::async_trait]
+impl RunListener for RedirectRunListener {
+    async fn listen(&self, addr: &str) -> std::io::Result<Box<dyn RunAcceptor>> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Box::new(RedirectRunAcceptor { inner: listener }))
+    }
+}
+
+/// Builds the target from the destination recorded in `StreamInfo` at accept time.
+pub(crate) fn original_run_addr(
+    stream: &dyn RunStream,
+) -> std::io::Result<(RunAddr, Option<Vec<u8>>)> {
+    let info = stream.get_info();
+    match (info.dst_addr.clone(), info.dst_port) {
+        (Some(addr), Some(port)) => Ok((
+            RunAddr {
+                addr,
+                port,
+                udp: false,
+            },
+            None,
+        )),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "no original destination",
+        )),
+    }
+}
@@ -1,33 +1,86 @@
-use crate::def::config::get_option_bool;
-use crate::def::{RunAccStream, RunAcceptor, RunListener, RunStream, RunUdpReader, RunUdpWriter};
+use crate::connector::grpc::{backoff, parse_address};
+use crate::connector::rev_grpc::Heartbeat;
+use crate::def::config::{get_option_bool, get_option_str_list};
+use crate::def::{
+    RunAccStream, RunAcceptor, RunListener, RunStream, RunUdpReader, RunUdpWriter, UDPPacket,
+};
 use crate::object::config::ObjectConfig;
 use crate::proto::v1::pb::rog_reverse_service_client::RogReverseServiceClient;
-use crate::proto::v1::pb::{ManagerReq, ManagerRes, RevStreamReq, RevUdpReq};
+use crate::proto::v1::pb::{AddrInfo, ManagerReq, ManagerRes, Publish, RevStreamReq, RevUdpReq};
 use crate::stream::rev_grpc_client::RevGrpcClientRunStream;
 use crate::stream::rev_grpc_udp_client::{RevGrpcUdpClientReader, RevGrpcUdpClientWriter};
 use crate::util::RunAddr;
-use crate::util::grpc_transport::connect_channel_without_proxy;
+use crate::util::auth_token::client_auth;
+use crate::util::grpc_transport::{connect_channel_without_proxy, with_client_tls};
 use futures::StreamExt;
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
 use std::io;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::{Mutex, mpsc};
-use tokio::time::sleep;
+use tokio::time::{Instant, sleep, sleep_until};
 use tokio::{select, spawn};
 use tonic::Request;
 use tonic::codegen::tokio_stream;
 use tonic::transport::{Channel, Endpoint};
 
+/// The local targets of the ports published on the server, by port and
+/// whether it is UDP.
+type Published = HashMap<(u32, bool), (String, u16)>;
+
+/// `publish` entries, `"[tcp:|udp:]port=host:port"`.
+fn parse_publish(entries: &[String]) -> io::Result<Published> {
+    let mut published = HashMap::new();
+    for entry in entries {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid publish '{}'", entry),
+            )
+        };
+        let (port, target) = entry.split_once('=').ok_or_else(invalid)?;
+        let (udp, port) = match port.trim().split_once(':') {
+            Some(("tcp", port)) => (false, port),
+            Some(("udp", port)) => (true, port),
+            Some(_) => return Err(invalid()),
+            None => (false, port.trim()),
+        };
+        let port: u16 = port.parse().map_err(|_| invalid())?;
+        let target = parse_address(target.trim()).map_err(|_| invalid())?;
+        if port == 0 || published.insert((port as u32, udp), target).is_some() {
+            return Err(invalid());
+        }
+    }
+    Ok(published)
+}
+
+/// Sends every packet of a published UDP port to its local target.
+struct PublishedUdpReader {
+    inner: Box<dyn RunUdpReader>,
+    target: (String, u16),
+}
+
+#[async_trait::async_trait]
+impl RunUdpReader for PublishedUdpReader {
+    async fn read(&mut self) -> io::Result<UDPPacket> {
+        let mut packet = self.inner.read().await?;
+        packet.meta.dst_addr = self.target.0.clone();
+        packet.meta.dst_port = self.target.1;
+        Ok(packet)
+    }
+}
+
 async fn handle_manage_req(
     req: ManagerRes,
     client: &mut RogReverseServiceClient<Channel>,
     tx: &mpsc::Sender<RevGrpcClientRunStream>,
     udp_tx: &mpsc::Sender<(Box<dyn RunUdpReader>, Box<dyn RunUdpWriter>)>,
-    auth: &str,
+    pw: &str,
+    legacy_auth: bool,
 ) -> Result<(), ()> {
     if req.udp.unwrap() == 0 {
         // tcp
@@ -36,11 +89,13 @@ async fn handle_manage_req(
         let srx = Request::new(srx);
 
         let uuid_str = req.conn_id.clone().unwrap_or_default();
+        let auth = client_auth(pw, &format!("rev_stream {}", uuid_str), legacy_auth);
         if let Err(e) = stx
             .send(RevStreamReq {
-                auth: auth.to_string(),
+                auth,
                 payload: None,
                 conn_id: Some(uuid_str),
+                ..Default::default()
             })
             .await
         {
@@ -78,10 +133,11 @@ async fn handle_manage_req(
         let urx = tokio_stream::wrappers::ReceiverStream::new(urx);
         let urx = Request::new(urx);
 
-        // 先发送握手包（conn_id + auth）
+        // 先发送握手包（conn_id + auth），后续数据包沿用同一个 token
+        let auth = client_auth(pw, &format!("rev_udp {}", conn_id), legacy_auth);
         if let Err(e) = utx
             .send(RevUdpReq {
-                auth: auth.to_string(),
+                auth: auth.clone(),
                 payload: None,
                 addr_info: None,
                 conn_id: Some(conn_id),
@@ -95,10 +151,16 @@ async fn handle_manage_req(
         match client.udp(urx).await {
             Ok(stream) => {
                 let res_stream = stream.into_inner();
-                let reader =
+                let mut reader =
                     Box::new(RevGrpcUdpClientReader::new(res_stream)) as Box<dyn RunUdpReader>;
-                let writer = Box::new(RevGrpcUdpClientWriter::new(utx, auth.to_string()))
-                    as Box<dyn RunUdpWriter>;
+                if let (Some(_), Some(addr_info)) = (&req.publish, &req.addr_info) {
+                    reader = Box::new(PublishedUdpReader {
+                        inner: reader,
+                        target: (addr_info.dst_addr.clone(), addr_info.dst_port as u16),
+                    });
+                }
+                let writer =
+                    Box::new(RevGrpcUdpClientWriter::new(utx, auth)) as Box<dyn RunUdpWriter>;
                 if let Err(_) = udp_tx.send((reader, writer)).await {
                     error!("rev grpc udp pair send error");
                     return Err(());
@@ -113,6 +175,113 @@ async fn handle_manage_req(
     Ok(())
 }
 
+/// One connection of the manager stream to the reverse server.
+struct ManagerSession {
+    endpoint: Endpoint,
+    tag: String,
+    pw: String,
+    legacy_auth: bool,
+    /// Answer the server's pings, and reconnect when they stop coming.
+    heartbeat: Option<Heartbeat>,
+    /// Ports to publish on the server.
+    published: Published,
+    tx: mpsc::Sender<RevGrpcClientRunStream>,
+    utx: mpsc::Sender<(Box<dyn RunUdpReader>, Box<dyn RunUdpWriter>)>,
+}
+
+impl ManagerSession {
+    /// Fails when no manager stream could be opened, and returns once an
+    /// opened one ends.
+    async fn run(&self) -> io::Result<()> {
+        let channel = connect_channel_without_proxy(self.endpoint.clone())
+            .await
+            .map_err(io::Error::other)?;
+        debug!("rev grpc server endpoint connected");
+        let (mtx, mrx) = mpsc::channel::<ManagerReq>(8);
+        let mrx = tokio_stream::wrappers::ReceiverStream::new(mrx);
+        let mrx = Request::new(mrx);
+
+        let mut client = RogReverseServiceClient::new(channel);
+
+        mtx.send(ManagerReq {
+            auth: client_auth(&self.pw, &format!("manager {}", self.tag), self.legacy_auth),
+            tag: self.tag.clone(),
+            heartbeat: self.heartbeat.map(|_| true),
+            publish: self
+                .published
+                .keys()
+                .map(|&(port, udp)| Publish { port, udp })
+                .collect(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| io::Error::other(format!("manager initial auth send error {}", e)))?;
+
+        let mut manager_stream = client
+            .manager(mrx)
+            .await
+            .map_err(|e| io::Error::other(format!("rev grpc manager error {}", e)))?
+            .into_inner();
+        debug!("rev grpc manager stream created");
+        // known from the first ping, servers without heartbeats never send one
+        let mut silence: Option<Duration> = None;
+        let mut last_seen = Instant::now();
+        loop {
+            let deadline = last_seen + silence.unwrap_or_default();
+            select! {
+                manage_req = manager_stream.next() => {
+                    last_seen = Instant::now();
+                    match manage_req {
+                        None => {
+                            info!("manager stream next none");
+                            return Ok(());
+                        }
+                        Some(Ok(mut req)) => {
+                            if let Some(seq) = req.ping {
+                                if let Some(heartbeat) = self.heartbeat {
+                                    let interval = Duration::from_secs(req.heartbeat_interval.unwrap_or(0) as u64);
+                                    silence = (!interval.is_zero()).then(|| interval * (heartbeat.missed + 1));
+                                    let _ = mtx.try_send(ManagerReq {
+                                        pong: Some(seq),
+                                        ..Default::default()
+                                    });
+                                }
+                                continue;
+                            }
+                            if let Some(publish) = &req.publish {
+                                // the server only knows the port, the target is ours
+                                let Some((host, port)) = self.published.get(&(publish.port, publish.udp)) else {
+                                    warn!("request for unpublished port {:?}", publish);
+                                    continue;
+                                };
+                                let src = req.addr_info.take().unwrap_or_default();
+                                req.addr_info = Some(AddrInfo {
+                                    dst_addr: host.clone(),
+                                    dst_port: *port as u32,
+                                    ..src
+                                });
+                            }
+                            // todo: param check
+                            debug!("manager manager stream got req {:?}", req);
+                            if handle_manage_req(req, &mut client, &self.tx, &self.utx, &self.pw, self.legacy_auth).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        Some(Err(err)) => {
+                            warn!("manager stream error: {:?}", err);
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = sleep_until(deadline), if silence.is_some() => {
+                    warn!("no heartbeat from the reverse server for {:?}, reconnecting", silence.unwrap_or_default());
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
 pub struct RevGrpcListener {
     cfg: ObjectConfig,
 }
@@ -134,6 +303,7 @@ impl RunListener for RevGrpcListener {
         let keep_alive = get_option_bool(&self.cfg.listener.options, "keep_alive");
         let endpoint = Endpoint::new(addr.to_string())
             .map_err(|e| io::Error::other("rev grpc endpoint new error"))?;
+        let endpoint = with_client_tls(endpoint, &self.cfg.listener.options)?;
         let endpoint = if keep_alive {
             endpoint
                 .http2_keep_alive_interval(Duration::from_secs(30))
@@ -145,80 +315,27 @@ impl RunListener for RevGrpcListener {
 
         let (tx, rx) = mpsc::channel(8);
         let (utx, urx) = mpsc::channel(8);
-        let auth = self.cfg.listener.pw.clone().unwrap();
-        let tag = self.cfg.listener.name.clone();
+        let session = ManagerSession {
+            endpoint,
+            tag: self.cfg.listener.name.clone(),
+            pw: self.cfg.listener.pw.clone().unwrap(),
+            legacy_auth: get_option_bool(&self.cfg.listener.options, "legacy_auth"),
+            heartbeat: Heartbeat::from_options(&self.cfg.listener.options),
+            published: parse_publish(&get_option_str_list(&self.cfg.listener.options, "publish"))?,
+            tx,
+            utx,
+        };
         spawn(async move {
+            let mut failures = 0;
             loop {
-                let channel;
-                loop {
-                    let t = connect_channel_without_proxy(endpoint.clone()).await;
-                    match t {
-                        Ok(c) => {
-                            debug!("rev grpc server endpoint connected");
-                            channel = c;
-                            break;
-                        }
-                        Err(e) => {
-                            trace!("rev grpc server endpoint connect error {:?}", e);
-                            sleep(Duration::from_millis(300)).await;
-                        }
-                    }
-                }
-                let (mtx, mrx) = mpsc::channel::<ManagerReq>(8);
-                let mrx = tokio_stream::wrappers::ReceiverStream::new(mrx);
-                let mrx = Request::new(mrx);
-
-                let mut client = RogReverseServiceClient::new(channel);
-
-                if let Err(e) = mtx
-                    .send(ManagerReq {
-                        auth: auth.clone(),
-                        tag: tag.clone(),
-                        addr_info: None,
-                        udp: None,
-                        conn_id: None,
-                    })
-                    .await
-                {
-                    error!("manager initial auth send error {}", e);
-                    sleep(Duration::from_millis(300)).await;
-                    continue;
-                }
-
-                let manager_stream = client.manager(mrx).await;
-                match manager_stream {
-                    Ok(manager_stream) => {
-                        debug!("rev grpc manager stream created");
-                        let mut manager_stream = manager_stream.into_inner();
-                        loop {
-                            select! {
-                                manage_req = manager_stream.next() => {
-                                    match manage_req {
-                                        None => {
-                                            info!("manager stream next none");
-                                            break;
-                                        }
-                                        Some(Ok(req)) => {
-                                            // todo: param check
-                                            debug!("manager manager stream got req {:?}", req);
-                                            if handle_manage_req(req, &mut client, &tx, &utx, &auth).await.is_err() {
-                                                break;
-                                            }
-                                        }
-                                        Some(Err(err)) => {
-                                            warn!("manager stream error: {:?}", err);
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        info!("rev grpc manager error");
-                        sleep(Duration::from_millis(300)).await;
+                match session.run().await {
+                    Ok(()) => failures = 0,
+                    Err(e) => {
+                        debug!("rev grpc manager session error: {}", e);
+                        failures += 1;
                     }
                 }
+                sleep(backoff(failures)).await;
             }
         });
 
@@ -268,3 +385,149 @@ impl RunAcceptor for RevGrpcRunListener {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_publish() {
+        let entries = [
+            "18080=127.0.0.1:8080",
+            "udp:15353=127.0.0.1:53",
+            "tcp:2222=[::1]:22",
+        ];
+        let published = parse_publish(&entries.map(String::from)).unwrap();
+        assert_eq!(
+            published.get(&(18080, false)),
+            Some(&("127.0.0.1".to_string(), 8080))
+        );
+        assert_eq!(
+            published.get(&(15353, true)),
+            Some(&("127.0.0.1".to_string(), 53))
+        );
+        assert_eq!(
+            published.get(&(2222, false)),
+            Some(&("[::1]".to_string(), 22))
+        );
+        for bad in ["18080", "quic:1=a:1", "0=a:1", "1=a", "x=a:1"] {
+            assert!(parse_publish(&[bad.to_string()]).is_err(), "{}", bad);
+        }
+        let twice = ["1=a:1", "1=b:2"].map(String::from);
+        assert!(parse_publish(&twice).is_err());
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test]
+    async fn test_publish_relay() {
+        use crate::connector::rev_grpc::start_reverse_server;
+        use crate::def::UDPMeta;
+        use crate::object::timeouts::Timeouts;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+        let (server_port, port, denied) = (free_port(), free_port(), free_port());
+        let options = Some(HashMap::from([
+            (
+                "publish_allow".to_string(),
+                toml::Value::String(format!("publish-e2e:{}", port)),
+            ),
+            (
+                "publish_bind".to_string(),
+                toml::Value::String("127.0.0.1".to_string()),
+            ),
+        ]));
+        let pw_map = HashMap::from([("publish-e2e".to_string(), Some("pw".to_string()))]);
+        start_reverse_server(
+            format!("127.0.0.1:{}", server_port),
+            pw_map,
+            &options,
+            Timeouts::default(),
+        )
+        .await
+        .unwrap();
+
+        let (tx, mut rx) = mpsc::channel(8);
+        let (utx, mut urx) = mpsc::channel(8);
+        let entries = [
+            format!("{}=127.0.0.1:8080", port),
+            format!("udp:{}=127.0.0.1:53", port),
+            format!("{}=127.0.0.1:8081", denied),
+        ];
+        let session = ManagerSession {
+            endpoint: Endpoint::new(format!("http://127.0.0.1:{}", server_port)).unwrap(),
+            tag: "publish-e2e".to_string(),
+            pw: "pw".to_string(),
+            legacy_auth: false,
+            heartbeat: None,
+            published: parse_publish(&entries).unwrap(),
+            tx,
+            utx,
+        };
+        spawn(async move {
+            loop {
+                let _ = session.run().await;
+                sleep(Duration::from_millis(50)).await;
+            }
+        });
+
+        // tcp: the stream comes out of the client aimed at the local target
+        let mut conn = None;
+        for _ in 0..100 {
+            if let Ok(c) = TcpStream::connect(("127.0.0.1", port)).await {
+                conn = Some(c);
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        let mut conn = conn.expect("published tcp port never opened");
+        conn.write_all(b"hello").await.unwrap();
+        let mut stream = rx.recv().await.unwrap();
+        let info = stream.get_info();
+        assert_eq!(info.dst_addr.as_deref(), Some("127.0.0.1"));
+        assert_eq!(info.dst_port, Some(8080));
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write(b"world").await.unwrap();
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        // udp: one datagram there and back
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(b"ping", ("127.0.0.1", port)).await.unwrap();
+        let (mut reader, writer) = urx.recv().await.unwrap();
+        let packet = reader.read().await.unwrap();
+        assert_eq!(packet.data, b"ping");
+        assert_eq!(packet.meta.dst_addr, "127.0.0.1");
+        assert_eq!(packet.meta.dst_port, 53);
+        writer
+            .write(UDPPacket {
+                meta: UDPMeta {
+                    dst_addr: packet.meta.src_addr.clone(),
+                    dst_port: packet.meta.src_port,
+                    src_addr: packet.meta.dst_addr.clone(),
+                    src_port: packet.meta.dst_port,
+                },
+                data: b"pong".to_vec(),
+            })
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"pong");
+
+        // the port outside publish_allow was never bound
+        TcpListener::bind(("127.0.0.1", denied)).await.unwrap();
+    }
+}
@@ -1,5 +1,5 @@
 use crate::def::RunStream;
-use crate::def::{RunAccStream, RunAcceptor};
+use crate::def::{Rejection, RunAccStream, RunAcceptor};
 use crate::util;
 use crate::util::RunAddr;
 use std::net::SocketAddr;
@@ -60,4 +60,14 @@ impl RunAcceptor for SocksRunAcceptor {
         stream.write(&confirm.to_bytes()).await?;
         Ok(())
     }
+
+    fn confirms_first(&self, _: &dyn RunStream) -> bool {
+        true
+    }
+
+    async fn reject(&self, stream: &mut dyn RunStream, _: Rejection) -> std::io::Result<()> {
+        // a general failure reply needs the request first
+        self.handshake(stream).await?;
+        self.post_handshake(stream, true, 0).await
+    }
 }
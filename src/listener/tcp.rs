@@ -1,14 +1,36 @@
 use crate::def::{RunAccStream, RunAcceptor, RunListener, RunStream};
 use crate::stream::tcp::TcpRunStream;
 use crate::util::RunAddr;
+use crate::util::proxy_protocol::{self, HeaderPolicy};
+use log::{debug, error};
 use std::net::SocketAddr;
 use tokio::net::TcpListener;
+use tokio::spawn;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{Mutex, mpsc};
 
 pub struct TcpRunAcceptor {
     inner: TcpListener,
 }
 
-pub struct TcpRunListener {}
+pub struct TcpRunListener {
+    /// Connections start with a PROXY protocol header naming the client.
+    pub proxy_protocol: Option<HeaderPolicy>,
+}
+
+/// Hands out connections prepared in their own tasks (TLS or PROXY
+/// protocol handshakes), so a slow client doesn't hold up `accept`.
+pub struct QueuedRunAcceptor {
+    receiver: Mutex<Receiver<(Box<dyn RunStream>, SocketAddr)>>,
+}
+
+impl QueuedRunAcceptor {
+    pub fn new(receiver: Receiver<(Box<dyn RunStream>, SocketAddr)>) -> Self {
+        Self {
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
 
 #[async_trait::async_trait]
 impl RunAcceptor for TcpRunAcceptor {
@@ -36,10 +58,118 @@ impl RunAcceptor for TcpRunAcceptor {
     }
 }
 
+#[async_trait::async_trait]
+impl RunAcceptor for QueuedRunAcceptor {
+    async fn accept(&self) -> std::io::Result<(RunAccStream, SocketAddr)> {
+        match self.receiver.lock().await.recv().await {
+            Some((stream, addr)) => Ok((RunAccStream::TCPStream(stream), addr)),
+            None => Err(std::io::Error::other("listener closed")),
+        }
+    }
+
+    async fn handshake(
+        &self,
+        _stream: &mut dyn RunStream,
+    ) -> std::io::Result<(RunAddr, Option<Vec<u8>>)> {
+        Ok((
+            RunAddr {
+                addr: "".to_string(),
+                port: 0,
+                udp: false,
+            },
+            None,
+        ))
+    }
+}
+
 #[async_trait::async_trait]
 impl RunListener for TcpRunListener {
     async fn listen(&self, addr: &str) -> std::io::Result<Box<dyn RunAcceptor>> {
         let listener = TcpListener::bind(addr).await?;
-        Ok(Box::new(TcpRunAcceptor { inner: listener }))
+        let Some(policy) = self.proxy_protocol.clone() else {
+            return Ok(Box::new(TcpRunAcceptor { inner: listener }));
+        };
+        let (tx, rx) = mpsc::channel::<(Box<dyn RunStream>, SocketAddr)>(8);
+        spawn(async move {
+            loop {
+                let (mut socket, peer) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("tcp listener accept error: {}", e);
+                        continue;
+                    }
+                };
+                if !policy.trusts(peer) {
+                    debug!("PROXY header from untrusted {} refused", peer);
+                    continue;
+                }
+                let slot = policy.slot().await;
+                let tx = tx.clone();
+                spawn(async move {
+                    let client = match proxy_protocol::accept(&mut socket, peer).await {
+                        Ok(client) => client,
+                        Err(e) => {
+                            debug!("PROXY header from {} error: {}", peer, e);
+                            return;
+                        }
+                    };
+                    drop(slot);
+                    let mut stream = TcpRunStream::new(socket);
+                    stream.set_info(&mut |x| x.protocol_name = "tcp".to_string());
+                    let _ = tx.send((Box::new(stream), client)).await;
+                });
+            }
+        });
+        Ok(Box::new(QueuedRunAcceptor::new(rx)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn listen(from: &str) -> (Box<dyn RunAcceptor>, SocketAddr) {
+        let options = Some(HashMap::from([
+            ("proxy_protocol".to_string(), toml::Value::Boolean(true)),
+            (
+                "proxy_protocol_from".to_string(),
+                toml::Value::String(from.to_string()),
+            ),
+        ]));
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpRunListener {
+            proxy_protocol: HeaderPolicy::from_options(&options).unwrap(),
+        };
+        (listener.listen(&addr.to_string()).await.unwrap(), addr)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_from() {
+        let client: SocketAddr = "192.0.2.1:51234".parse().unwrap();
+        let (acceptor, addr) = listen("127.0.0.0/8").await;
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(&proxy_protocol::encode_v2(client, addr))
+            .await
+            .unwrap();
+        let (_, source) = acceptor.accept().await.unwrap();
+        assert_eq!(source, client);
+
+        // a peer outside proxy_protocol_from can't name a client
+        let (acceptor, addr) = listen("10.0.0.0/8").await;
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let _ = conn
+            .write_all(&proxy_protocol::encode_v2(client, addr))
+            .await;
+        let mut buf = [0u8; 1];
+        assert!(!matches!(conn.read(&mut buf).await, Ok(n) if n > 0));
+        let accepted =
+            tokio::time::timeout(std::time::Duration::from_millis(200), acceptor.accept()).await;
+        assert!(accepted.is_err());
     }
 }
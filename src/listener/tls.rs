@@ -0,0 +1,122 @@
+use crate::def::{RunAcceptor, RunListener, RunStream};
+use crate::listener::tcp::QueuedRunAcceptor;
+use crate::stream::tls::TlsRunStream;
+use crate::util::proxy_protocol::{self, HeaderPolicy};
+use crate::util::tcp_frame::{FrameReader, FrameWriter};
+use log::{debug, error};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio::sync::{Semaphore, mpsc};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Handshakes a listener runs at once before it hands connections over to
+/// admission control, further connections wait in the accept backlog.
+pub(crate) const MAX_PENDING_HANDSHAKES: usize = 256;
+
+/// `TcpRunListener` with TLS termination. Handshakes run in their own tasks
+/// so a slow client doesn't hold up `accept`.
+pub struct TlsRunListener {
+    acceptor: TlsAcceptor,
+    /// A PROXY protocol header comes before the TLS handshake.
+    proxy_protocol: Option<HeaderPolicy>,
+}
+
+impl TlsRunListener {
+    pub fn new(acceptor: TlsAcceptor, proxy_protocol: Option<HeaderPolicy>) -> Self {
+        Self {
+            acceptor,
+            proxy_protocol,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RunListener for TlsRunListener {
+    async fn listen(&self, addr: &str) -> std::io::Result<Box<dyn RunAcceptor>> {
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, rx) = mpsc::channel::<(Box<dyn RunStream>, SocketAddr)>(8);
+        let acceptor = self.acceptor.clone();
+        let proxy = self.proxy_protocol.clone();
+        let handshakes = Arc::new(Semaphore::new(MAX_PENDING_HANDSHAKES));
+        spawn(async move {
+            loop {
+                let (mut socket, peer) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("tls listener accept error: {}", e);
+                        continue;
+                    }
+                };
+                if let Some(policy) = &proxy
+                    && !policy.trusts(peer)
+                {
+                    debug!("PROXY header from untrusted {} refused", peer);
+                    continue;
+                }
+                let Ok(slot) = handshakes.clone().acquire_owned().await else {
+                    return;
+                };
+                let header = proxy.is_some();
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                spawn(async move {
+                    let addr = if header {
+                        match proxy_protocol::accept(&mut socket, peer).await {
+                            Ok(client) => client,
+                            Err(e) => {
+                                debug!("PROXY header from {} error: {}", peer, e);
+                                return;
+                            }
+                        }
+                    } else {
+                        peer
+                    };
+                    let tls = match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                        Ok(Ok(s)) => s,
+                        Ok(Err(e)) => {
+                            debug!("tls handshake with {} error: {}", addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("tls handshake with {} timeout", addr);
+                            return;
+                        }
+                    };
+                    let mut stream = TlsRunStream::new(tls);
+                    stream.set_info(&mut |x| x.protocol_name = "tls".to_string());
+                    let _ = tx.send((Box::new(stream), addr)).await;
+                    drop(slot);
+                });
+            }
+        });
+        Ok(Box::new(QueuedRunAcceptor::new(rx)))
+    }
+}
+
+/// Splits an accepted connection into frame halves, after the tls handshake
+/// when `acceptor` is set. Used by listeners with their own framing (pb_tcp).
+pub(crate) async fn accept_split(
+    acceptor: Option<&TlsAcceptor>,
+    socket: TcpStream,
+) -> std::io::Result<(FrameReader, FrameWriter)> {
+    match acceptor {
+        Some(acceptor) => {
+            let tls = timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::TimedOut, "tls handshake timeout")
+                })??;
+            let (r, w) = tokio::io::split(tls);
+            Ok((Box::new(r), Box::new(w)))
+        }
+        None => {
+            let (r, w) = socket.into_split();
+            Ok((Box::new(r), Box::new(w)))
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::def::{
+    RunAccStream, RunAcceptor, RunListener, RunStream, RunUdpWriter, UDPMeta, UDPPacket,
+};
+use crate::listener::redirect::original_run_addr;
+use crate::listener::udp_session::{UDP_SESSION_QUEUE, UdpSessionReader, UdpSessions};
+use crate::stream::tcp::TcpRunStream;
+use crate::util::RunAddr;
+use crate::util::transparent::{
+    canonical, recv_with_orig_dst, transparent_tcp_listener, transparent_udp_listener,
+    transparent_udp_reply_socket,
+};
+use log::warn;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::Interest;
+use tokio::net::{TcpListener, UdpSocket, lookup_host};
+use tokio::sync::{Mutex, mpsc};
+use tokio::{select, spawn};
+
+type UdpSession = (UdpSessionReader, TproxyUdpWriter, SocketAddr);
+
+/// iptables `TPROXY` listener for TCP and UDP on the same endpoint.
+pub struct TproxyRunListener {}
+
+pub struct TproxyRunAcceptor {
+    tcp: TcpListener,
+    udp_sessions: Mutex<mpsc::Receiver<UdpSession>>,
+}
+
+#[async_trait::async_trait]
+impl RunListener for TproxyRunListener {
+    async fn listen(&self, addr: &str) -> std::io::Result<Box<dyn RunAcceptor>> {
+        let addr = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "tproxy endpoint not resolved"))?;
+        let tcp = transparent_tcp_listener(addr)?;
+        let udp = Arc::new(transparent_udp_listener(addr)?);
+        let (session_tx, session_rx) = mpsc::channel(UDP_SESSION_QUEUE);
+        spawn(udp_dispatch(udp, session_tx));
+        Ok(Box::new(TproxyRunAcceptor {
+            tcp,
+            udp_sessions: Mutex::new(session_rx),
+        }))
+    }
+}
+
+#[async_trait::async_trait]
+impl RunAcceptor for TproxyRunAcceptor {
+    async fn accept(&self) -> std::io::Result<(RunAccStream, SocketAddr)> {
+        let mut udp_sessions = self.udp_sessions.lock().await;
+        select! {
+            r = self.tcp.accept() => {
+                let (socket, addr) = r?;
+                // the local address of a tproxied connection is the original destination
+                let dst = canonical(socket.local_addr()?);
+                let own = dst == canonical(self.tcp.local_addr()?);
+                let mut stream = TcpRunStream::new(socket);
+                stream.set_info(&mut |x| {
+                    x.protocol_name = "tproxy".to_string();
+                    x.src_addr = Some(addr.ip().to_string());
+                    x.src_port = Some(addr.port());
+                    x.dst_addr = (!own).then(|| dst.ip().to_string());
+                    x.dst_port = (!own).then_some(dst.port());
+                });
+                Ok((RunAccStream::TCPStream(Box::new(stream)), addr))
+            }
+            r = udp_sessions.recv() => {
+                match r {
+                    None => Err(Error::other("tproxy udp dispatcher closed")),
+                    Some((reader, writer, src)) => Ok((
+                        RunAccStream::UDPSocket((Box::new(reader), Box::new(writer))),
+                        src,
+                    )),
+                }
+            }
+        }
+    }
+
+    async fn handshake(
+        &self,
+        stream: &mut dyn RunStream,
+    ) -> std::io::Result<(RunAddr, Option<Vec<u8>>)> {
+        original_run_addr(stream)
+    }
+}
+
+// Receives every tproxied datagram and hands each client source its own session.
+async fn udp_dispatch(udp: Arc<UdpSocket>, session_tx: mpsc::Sender<UdpSession>) {
+    let mut sessions = UdpSessions::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let res = udp
+            .async_io(Interest::READABLE, || recv_with_orig_dst(&*udp, &mut buf))
+            .await;
+        let (n, src, dst) = match res {
+            Ok((n, src, dst)) => (n, canonical(src), canonical(dst)),
+            Err(e) => {
+                warn!("tproxy udp recv error: {}", e);
+                continue;
+            }
+        };
+        let packet = UDPPacket {
+            meta: UDPMeta {
+                dst_addr: dst.ip().to_string(),
+                dst_port: dst.port(),
+                src_addr: src.ip().to_string(),
+                src_port: src.port(),
+            },
+            data: buf[..n].to_vec(),
+        };
+        let Some(reader) = sessions.dispatch(src, packet) else {
+            continue;
+        };
+        let writer = TproxyUdpWriter {
+            client: src,
+            default_from: dst,
+            sockets: Mutex::new(HashMap::new()),
+        };
+        if session_tx.send((reader, writer, src)).await.is_err() {
+            warn!("tproxy udp acceptor closed");
+            break;
+        }
+    }
+}
+
+/// Sends replies from a socket bound to the remote address, so the client
+/// sees them coming from the destination it originally talked to.
+pub struct TproxyUdpWriter {
+    client: SocketAddr,
+    default_from: SocketAddr,
+    sockets: Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>,
+}
+
+#[async_trait::async_trait]
+impl RunUdpWriter for TproxyUdpWriter {
+    async fn write(&self, packet: UDPPacket) -> std::io::Result<()> {
+        // a reply carries the remote as dst; domains can't be bound, fall back
+        // to the destination the session started with
+        let from = match packet.meta.dst_addr.parse::<IpAddr>() {
+            Ok(ip) => canonical(SocketAddr::new(ip, packet.meta.dst_port)),
+            Err(_) => self.default_from,
+        };
+        let socket = {
+            let mut sockets = self.sockets.lock().await;
+            match sockets.get(&from) {
+                Some(s) => s.clone(),
+                None => {
+                    let s = Arc::new(transparent_udp_reply_socket(from)?);
+                    sockets.insert(from, s.clone());
+                    s
+                }
+            }
+        };
+        socket.send_to(&packet.data, self.client).await?;
+        Ok(())
+    }
+}
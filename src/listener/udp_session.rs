@@ -0,0 +1,91 @@
+use crate::def::{RunUdpReader, UDPPacket};
+use log::debug;
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::SocketAddr;
+use tokio::sync::mpsc;
+
+pub(crate) const UDP_SESSION_QUEUE: usize = 64;
+
+/// Per client source NAT table for listeners that share one UDP socket
+/// between all clients. Each source becomes its own `RunAccStream::UDPSocket`.
+pub(crate) struct UdpSessions {
+    sessions: HashMap<SocketAddr, mpsc::Sender<UDPPacket>>,
+}
+
+impl UdpSessions {
+    pub fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Queues the packet on the session of `src`, returns the reader of a new
+    /// session when there is none alive.
+    pub fn dispatch(&mut self, src: SocketAddr, packet: UDPPacket) -> Option<UdpSessionReader> {
+        if let Some(tx) = self.sessions.get(&src)
+            && !tx.is_closed()
+        {
+            if tx.try_send(packet).is_err() {
+                debug!("udp session {} queue full, drop", src);
+            }
+            return None;
+        }
+        self.sessions.retain(|_, tx| !tx.is_closed());
+        let (tx, rx) = mpsc::channel(UDP_SESSION_QUEUE);
+        let _ = tx.try_send(packet);
+        self.sessions.insert(src, tx);
+        Some(UdpSessionReader { rx })
+    }
+}
+
+/// Packets of one client source. The relay ends idle sessions after
+/// `session_timeout`, which also drops them from the table.
+pub struct UdpSessionReader {
+    rx: mpsc::Receiver<UDPPacket>,
+}
+
+#[async_trait::async_trait]
+impl RunUdpReader for UdpSessionReader {
+    async fn read(&mut self) -> std::io::Result<UDPPacket> {
+        self.rx
+            .recv()
+            .await
+            .ok_or_else(|| Error::other("udp session closed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::def::UDPMeta;
+
+    fn packet(data: &[u8]) -> UDPPacket {
+        UDPPacket {
+            meta: UDPMeta {
+                dst_addr: "10.0.0.1".to_string(),
+                dst_port: 53,
+                src_addr: "127.0.0.1".to_string(),
+                src_port: 5000,
+            },
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_per_source() {
+        let mut sessions = UdpSessions::new();
+        let a: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        let mut ra = sessions.dispatch(a, packet(b"a1")).unwrap();
+        assert!(sessions.dispatch(a, packet(b"a2")).is_none());
+        let mut rb = sessions.dispatch(b, packet(b"b1")).unwrap();
+        assert_eq!(ra.read().await.unwrap().data, b"a1");
+        assert_eq!(ra.read().await.unwrap().data, b"a2");
+        assert_eq!(rb.read().await.unwrap().data, b"b1");
+
+        // a dropped session is replaced by a new one
+        drop(ra);
+        assert!(sessions.dispatch(a, packet(b"a3")).is_some());
+    }
+}
@@ -3,23 +3,30 @@ use crate::consts::TCP_IO_BUFFER_SIZE;
 use crate::def::config::Config;
 use crate::object::Object;
 use crate::object::config::ObjectConfig;
+use crate::object::timeouts::Timeouts;
+use crate::quota::QuotaManager;
 use futures::future::select_all;
-use log::error;
+use log::{error, info};
 use proxy_observe::ObserveRegistry;
 use std::env;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::{fs, spawn};
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+mod admin;
 mod block;
 mod connector;
 mod consts;
 mod def;
+mod dns;
 mod listener;
 mod object;
 mod proto;
+mod quota;
+mod rate_limit;
 mod router;
 mod stream;
 mod test;
@@ -41,7 +48,8 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
-    let contents = fs::read_to_string(env::var("ROG_CONFIG").unwrap()).await?;
+    let config_path = env::var("ROG_CONFIG").unwrap();
+    let contents = fs::read_to_string(&config_path).await?;
 
     // 解析 TOML
     let cfg_res = toml::from_str::<Config>(&contents);
@@ -65,7 +73,13 @@ async fn main() -> std::io::Result<()> {
             buffer_size,
             block_number
         );
-        Some(Arc::new(BlockManager::new(block_number)))
+        let min_size = match &cfg.buffer_connection_min {
+            Some(n) => util::parse::parse_size(n)?,
+            None => 0,
+        };
+        Some(Arc::new(BlockManager::new(block_number).with_min_blocks(
+            min_size.div_ceil(TCP_IO_BUFFER_SIZE as u64),
+        )))
     } else {
         None
     };
@@ -78,6 +92,17 @@ async fn main() -> std::io::Result<()> {
     )
     .await;
     let router = Arc::new(router);
+    if let Some(admin) = cfg.admin.clone() {
+        admin::start_admin_server(
+            admin,
+            admin::AdminState {
+                config: cfg.clone(),
+                router: router.clone(),
+                block_manager: block_manager.clone(),
+            },
+        )
+        .await?;
+    }
     let observe_registry = ObserveRegistry::new();
     spawn(observe_registry.sampler_task());
     if let Some(listen_addr) = proxy_observe::env_listen_addr() {
@@ -98,8 +123,34 @@ async fn main() -> std::io::Result<()> {
             rev_server.endpoint,
             pw_map,
             &rev_server.options,
+            Timeouts::new(&cfg).with_options(&rev_server.options),
         )
-        .await;
+        .await?;
+    }
+
+    let quota_state = match &cfg.quota_state {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&config_path).with_file_name("quota_state.toml"),
+    };
+    let quota = QuotaManager::new(cfg.quota.as_deref().unwrap_or_default(), Some(quota_state))?;
+    let quota = (!quota.is_empty()).then(|| {
+        let quota = quota::init(quota);
+        spawn(quota.clone().save_task());
+        quota
+    });
+    let limiter = rate_limit::RateLimiter::new(cfg.rate_limit.as_deref().unwrap_or_default())?;
+    if !limiter.is_empty() {
+        rate_limit::init(limiter);
+    }
+    spawn(object::timeouts::report_task());
+
+    let fake_ip = match &cfg.dns_server {
+        Some(dns_server) => dns::fake_ip::FakeIpTable::from_options(&dns_server.options)?,
+        None => None,
+    };
+    if let Some(dns_server) = cfg.dns_server.clone() {
+        dns::server::start_dns_server(dns_server.endpoint, &dns_server.options, fake_ip.clone())
+            .await?;
     }
 
     let mut fs = Vec::new();
@@ -113,18 +164,50 @@ async fn main() -> std::io::Result<()> {
         let server_id = server_id.clone();
         let block_manager = block_manager.clone();
         let observe_registry = observe_registry.clone();
+        let fake_ip = fake_ip.clone();
         fs.push(spawn(async move {
             let obj_conf = Arc::new(ObjectConfig::build(l.name.as_str(), &cfg, server_id));
-            let obj = Object::new(obj_conf, router, block_manager, observe_registry);
+            let obj = Object::new(obj_conf, router, block_manager, observe_registry, fake_ip);
             obj.start().await
         }));
     }
 
-    if fs.is_empty() {
-        futures::future::pending::<()>().await;
-    } else {
-        let (a, _, _) = select_all(fs).await;
-        error!("error: {:?}", a);
+    let listeners = async {
+        if fs.is_empty() {
+            futures::future::pending::<()>().await;
+        } else {
+            let (a, _, _) = select_all(fs).await;
+            error!("error: {:?}", a);
+        }
+    };
+    tokio::select! {
+        _ = listeners => {}
+        _ = shutdown_signal() => info!("shutting down"),
+    }
+    // usage counted since the last periodic save
+    if let Some(quota) = quota
+        && let Err(e) = quota.save().await
+    {
+        error!("failed to save quota state: {}", e);
     }
     Ok(())
 }
+
+/// Resolves on Ctrl-C, or on SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+                return;
+            }
+            Err(e) => error!("failed to listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
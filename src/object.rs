@@ -1,28 +1,52 @@
-use crate::block::BlockManager;
-use crate::def::{RouterSet, RunAccStream, RunConnector};
+use crate::block::{BlockManager, BlockPool};
+use crate::connector::health;
+use crate::consts::TCP_IO_BUFFER_SIZE;
+use crate::def::config::get_option_str;
+use crate::def::{RouterSet, RunAccStream, RunConnector, RunStream};
+use crate::dns::fake_ip::FakeIpTable;
+use crate::object::admission::Admission;
 use crate::object::config::ObjectConfig;
+use crate::object::connections::ConnInfo;
+use crate::object::limits::Limits;
+use crate::object::sniff::{SniffConfig, sniff_stream};
+use crate::object::timeouts::Kind;
+use crate::stream::tcp::TcpRunStream;
+use crate::util::RunAddr;
+use crate::util::parse::parse_size;
 use crate::{connector, listener};
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use proxy_observe::{ConnectionMeta, ObserveRegistry};
+use socket2::SockRef;
 use std::collections::HashMap;
 use std::io;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::spawn;
 use tokio::sync::Mutex;
+use tokio::time::timeout;
 // Already present, but ensure it's used for cache
 
+pub mod admission;
 pub mod config;
+pub mod connections;
+pub mod limits;
 pub mod raw_udp;
+pub mod sniff;
 pub mod tcp;
+pub mod timeouts;
 pub mod udp;
 
+/// How long a rejected client gets to read its error reply.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Object {
     config: Arc<ObjectConfig>,
     router: Arc<dyn RouterSet>,
     connector_cache: Arc<Mutex<HashMap<String, Arc<Box<dyn RunConnector>>>>>, // New field
-    block_manager: Option<Arc<BlockManager>>,
+    block_pool: Option<Arc<BlockPool>>,
     observe_registry: ObserveRegistry,
+    fake_ip: Option<Arc<FakeIpTable>>,
 }
 
 impl Object {
@@ -31,13 +55,32 @@ impl Object {
         router: Arc<dyn RouterSet>,
         block_manager: Option<Arc<BlockManager>>,
         observe_registry: ObserveRegistry,
+        fake_ip: Option<Arc<FakeIpTable>>,
     ) -> Self {
+        // the listener's own share of the buffer, from `options.buffer_size`
+        let block_pool = block_manager.map(|manager| {
+            let budget = get_option_str(&config.listener.options, "buffer_size").and_then(|s| {
+                match parse_size(&s) {
+                    Ok(0) => None,
+                    Ok(n) => Some(n.div_ceil(TCP_IO_BUFFER_SIZE as u64)),
+                    Err(e) => {
+                        warn!(
+                            "listener {} buffer_size ignored: {}",
+                            config.listener.name, e
+                        );
+                        None
+                    }
+                }
+            });
+            manager.pool(&config.listener.name, budget)
+        });
         Self {
             config,
             router,
             connector_cache: Arc::new(Mutex::new(HashMap::new())), // Initialize cache
-            block_manager,
+            block_pool,
             observe_registry,
+            fake_ip,
         }
     }
 
@@ -50,27 +93,109 @@ impl Object {
         })?;
         let main_acceptor = Arc::new(acc);
         let connector_cache_outer = self.connector_cache.clone(); // Clone cache Arc for the loop
+        let sniff_config = SniffConfig::from_listener(&config_outer.listener);
+        let admission = Admission::from_options(&config_outer.listener.options);
 
         loop {
             let (acc_stream, peer_addr) = main_acceptor.accept().await.map_err(|e| {
                 error!("Failed to accept connection: {}", e);
                 e
             })?;
+            let ticket = match &admission {
+                None => None,
+                Some(admission) => match admission.admit(peer_addr.ip()) {
+                    Ok(ticket) => Some(ticket),
+                    Err(rejection) => {
+                        debug!("rejected {}: {:?}", peer_addr, rejection);
+                        if let Some(slot) = admission.reject_slot() {
+                            let acceptor = Arc::clone(&main_acceptor);
+                            spawn(async move {
+                                let _ = timeout(REJECT_TIMEOUT, async {
+                                    match acc_stream {
+                                        RunAccStream::TCPStream(mut stream) => {
+                                            acceptor.reject(stream.as_mut(), rejection).await
+                                        }
+                                        RunAccStream::UDPSocket((_, mut writer)) => {
+                                            writer.reject(rejection).await
+                                        }
+                                    }
+                                })
+                                .await;
+                                drop(slot);
+                            });
+                        }
+                        continue;
+                    }
+                },
+            };
             let main_acceptor_clone = Arc::clone(&main_acceptor);
             let router_clone = Arc::clone(&router_outer);
             let config_clone = Arc::clone(&config_outer);
             let connector_cache_clone = Arc::clone(&connector_cache_outer); // Clone cache Arc for the spawned task
-            let block_manager_clone = self.block_manager.clone();
+            let block_pool_clone = self.block_pool.clone();
             let observe_registry_clone = self.observe_registry.clone();
+            let fake_ip_clone = self.fake_ip.clone();
             spawn(async move {
+                let _ticket = ticket;
                 match acc_stream {
                     RunAccStream::TCPStream(mut tcp_stream) => {
-                        let addr_res = main_acceptor_clone.handshake(tcp_stream.as_mut()).await;
+                        let addr_res = timeouts::bounded(
+                            config_clone.timeouts.handshake,
+                            Kind::Handshake,
+                            main_acceptor_clone.handshake(tcp_stream.as_mut()),
+                        )
+                        .await;
                         match addr_res {
                             Err(e) => {
                                 error!("Handshake error: {}", e);
                             }
-                            Ok((addr, payload_cache)) => {
+                            Ok((mut addr, mut payload_cache)) => {
+                                if let Some(domain) =
+                                    fake_ip_clone.as_ref().and_then(|f| f.lookup(&addr.addr))
+                                {
+                                    debug!("fake ip {} restored to {}", addr.addr, domain);
+                                    addr.addr = domain;
+                                }
+                                // sniffing needs the client's first bytes, clients that wait
+                                // for the confirmation get it before the remote is connected
+                                let mut confirmed = false;
+                                let mut sniffed: Option<String> = None;
+                                if sniff_config.enabled && !addr.udp {
+                                    if payload_cache.is_none()
+                                        && main_acceptor_clone.confirms_first(tcp_stream.as_ref())
+                                    {
+                                        if let Err(e) = main_acceptor_clone
+                                            .post_handshake(tcp_stream.as_mut(), false, 0)
+                                            .await
+                                        {
+                                            error!("Error in post_handshake: {}", e);
+                                            return Ok(());
+                                        }
+                                        confirmed = true;
+                                    }
+                                    match sniff_stream(
+                                        tcp_stream.as_mut(),
+                                        payload_cache,
+                                        sniff_config.timeout,
+                                    )
+                                    .await
+                                    {
+                                        Ok((domain, cache)) => {
+                                            sniffed = domain;
+                                            payload_cache = cache;
+                                        }
+                                        Err(e) => {
+                                            debug!("sniff read error: {}", e);
+                                            return Ok(());
+                                        }
+                                    }
+                                    if let Some(domain) = &sniffed {
+                                        debug!("sniffed {} for {:?}", domain, addr);
+                                        if sniff_config.override_dst {
+                                            addr.addr = domain.clone();
+                                        }
+                                    }
+                                }
                                 let addr_ref = &addr;
                                 if addr_ref.udp {
                                     // Assuming udp::handle_udp_connection might also need caching if it creates connectors.
@@ -82,19 +207,62 @@ impl Object {
                                         router_clone.clone(), // Pass cloned router
                                         addr,
                                         observe_registry_clone.clone(),
+                                        fake_ip_clone.clone(),
                                     )
                                     .await
                                     {
                                         error!("Error handling UDP connection: {}", e);
                                     }
                                 } else {
+                                    let route_addr = match &sniffed {
+                                        Some(domain) => RunAddr {
+                                            addr: domain.clone(),
+                                            port: addr_ref.port,
+                                            udp: false,
+                                        },
+                                        None => addr.clone(),
+                                    };
+                                    let user = tcp_stream.get_info().user.clone();
+                                    let router_name = config_clone.router_name(user.as_deref());
                                     let client_name = router_clone
                                         .route(
                                             config_clone.listener.name.as_str(),
-                                            config_clone.listener.router.as_str(),
-                                            addr_ref,
+                                            router_name,
+                                            &route_addr,
                                         )
                                         .await;
+                                    let allowed = if !config_clone
+                                        .connector_allowed(user.as_deref(), client_name.as_str())
+                                    {
+                                        Err(Error::new(
+                                            ErrorKind::PermissionDenied,
+                                            format!(
+                                                "user {:?} is not allowed to use connector '{}'",
+                                                user, client_name
+                                            ),
+                                        ))
+                                    } else {
+                                        Limits::open(
+                                            user.as_deref(),
+                                            config_clone.listener.name.as_str(),
+                                            client_name.as_str(),
+                                            Some(peer_addr.ip()),
+                                        )
+                                    };
+                                    let limits = match allowed {
+                                        Ok(limits) => limits,
+                                        Err(e) => {
+                                            warn!("rejected {:?}: {}", addr_ref, e);
+                                            if confirmed {
+                                                reset(tcp_stream.as_mut());
+                                            } else {
+                                                let _ = main_acceptor_clone
+                                                    .post_handshake(tcp_stream.as_mut(), true, 0)
+                                                    .await;
+                                            }
+                                            return Ok(());
+                                        }
+                                    };
                                     let conn_conf = match config_clone
                                         .connector
                                         .get(client_name.as_str())
@@ -105,6 +273,20 @@ impl Object {
                                             return Ok(()); // Exit the task for this connection
                                         }
                                     };
+                                    let timeouts =
+                                        config_clone.timeouts.with_options(&conn_conf.options);
+                                    let limits = limits
+                                        .with_idle(timeouts.idle, Kind::Idle)
+                                        .with_linger(timeouts.linger)
+                                        .with_tracking(ConnInfo {
+                                            network: "tcp",
+                                            listener: config_clone.listener.name.clone(),
+                                            user: user.clone(),
+                                            source: peer_addr.to_string(),
+                                            destination: endpoint_for_observe(addr_ref),
+                                            connector: client_name.clone(),
+                                            site: sniffed.clone(),
+                                        });
 
                                     let connector_obj: Arc<Box<dyn RunConnector>>;
                                     {
@@ -144,9 +326,18 @@ impl Object {
                                     }
 
                                     debug!("Handshake successful {:?}", addr_ref);
-                                    let client_stream_res = Arc::clone(&connector_obj)
-                                        .connect(addr_ref.endpoint())
-                                        .await;
+                                    let connect_start = Instant::now();
+                                    let client_stream_res = timeouts::bounded(
+                                        timeouts.connect,
+                                        Kind::Connect,
+                                        connector_obj.connect_from(addr_ref.endpoint(), peer_addr),
+                                    )
+                                    .await;
+                                    health::record(
+                                        &client_name,
+                                        &client_stream_res,
+                                        connect_start.elapsed(),
+                                    );
 
                                     let error_occurred = client_stream_res.is_err();
                                     debug!(
@@ -164,7 +355,13 @@ impl Object {
                                                 e
                                             );
                                             // We still need to run post_handshake to inform the client
-                                            if let Err(e) = main_acceptor_clone
+                                            if confirmed {
+                                                debug!(
+                                                    "resetting {}, it was confirmed before the connect",
+                                                    peer_addr
+                                                );
+                                                reset(tcp_stream.as_mut());
+                                            } else if let Err(e) = main_acceptor_clone
                                                 .post_handshake(tcp_stream.as_mut(), true, 0)
                                                 .await
                                             {
@@ -177,33 +374,44 @@ impl Object {
                                         }
                                     };
 
-                                    if let Err(e) = main_acceptor_clone
-                                        .post_handshake(tcp_stream.as_mut(), false, 0)
-                                        .await
+                                    if !confirmed
+                                        && let Err(e) = main_acceptor_clone
+                                            .post_handshake(tcp_stream.as_mut(), false, 0)
+                                            .await
                                     {
                                         error!("Error in post_handshake: {}", e);
                                         return Ok(());
                                     }
+                                    if let Some(user) = &user {
+                                        info!(
+                                            "user {} from {} to {} via {}",
+                                            user,
+                                            peer_addr,
+                                            addr_ref.endpoint(),
+                                            client_name
+                                        );
+                                    }
                                     // let (mut r, mut w) = tcp_stream.split();
                                     let observe = observe_registry_clone.open(ConnectionMeta {
                                         service: "rog".to_string(),
                                         network: "tcp".to_string(),
                                         listener: config_clone.listener.name.clone(),
-                                        router: Some(config_clone.listener.router.clone()),
+                                        router: Some(router_name.to_string()),
                                         route: Some(client_name.clone()),
-                                        inbound: Some(config_clone.listener.name.clone()),
+                                        inbound: Some(config_clone.inbound(user.as_deref())),
                                         outbound: Some(client_name.clone()),
                                         source: peer_addr.to_string(),
                                         destination: endpoint_for_observe(addr_ref),
-                                        site: None,
+                                        site: sniffed,
                                     });
                                     if let Err(e) = tcp::handle_tcp_connection(
                                         addr,
                                         payload_cache,
                                         client_stream,
                                         tcp_stream,
-                                        block_manager_clone,
+                                        block_pool_clone,
                                         Some(observe),
+                                        limits,
                                     )
                                     .await
                                     {
@@ -223,6 +431,7 @@ impl Object {
                             router_clone,
                             connector_cache_clone,
                             observe_registry_clone,
+                            fake_ip_clone,
                         )
                         .await
                         {
@@ -240,6 +449,14 @@ impl Object {
     }
 }
 
+/// Closes a plain TCP client with a reset, for a connection that fails after
+/// the client was told it succeeded, so it doesn't look like a normal end.
+fn reset(stream: &mut dyn RunStream) {
+    if let Some(tcp) = stream.as_any_mut().downcast_ref::<TcpRunStream>() {
+        let _ = SockRef::from(tcp.inner()).set_linger(Some(Duration::ZERO));
+    }
+}
+
 fn endpoint_for_observe(addr: &crate::util::RunAddr) -> String {
     if addr.addr.contains(':') {
         format!("[{}]:{}", addr.addr, addr.port)
@@ -255,3 +472,90 @@ pub(crate) fn udp_endpoint_for_observe(addr: &str, port: u16) -> String {
         format!("{}:{}", addr, port)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::def::config::Config;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    struct Direct;
+
+    #[async_trait::async_trait]
+    impl RouterSet for Direct {
+        async fn route(&self, _: &str, _: &str, _: &RunAddr) -> String {
+            "direct".to_string()
+        }
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test]
+    async fn test_sniff_connect_failure() {
+        let (port, closed) = (free_port(), free_port());
+        let cfg: Config = toml::from_str(&format!(
+            r#"
+            [[listener]]
+            endpoint = "127.0.0.1:{}"
+            name = "in"
+            proto = "socks5"
+            router = "default"
+            options = {{ sniff = true }}
+
+            [[router]]
+            name = "default"
+            default = "direct"
+
+            [[connector]]
+            name = "direct"
+            proto = "tcp"
+            "#,
+            port
+        ))
+        .unwrap();
+        let obj = Object::new(
+            Arc::new(ObjectConfig::build("in", &cfg, "test".to_string())),
+            Arc::new(Direct),
+            None,
+            ObserveRegistry::new(),
+            None,
+        );
+        spawn(async move { obj.start().await });
+
+        let mut conn = None;
+        for _ in 0..100 {
+            if let Ok(c) = TcpStream::connect(("127.0.0.1", port)).await {
+                conn = Some(c);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut conn = conn.unwrap();
+        conn.write_all(&[5, 1, 0]).await.unwrap();
+        let mut hello = [0u8; 2];
+        conn.read_exact(&mut hello).await.unwrap();
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&closed.to_be_bytes());
+        conn.write_all(&request).await.unwrap();
+
+        // socks5 clients send nothing before the reply, so it comes first
+        let mut reply = [0u8; 10];
+        conn.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+        conn.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+
+        // the failed connect can't be reported anymore, the client sees a reset
+        let mut buf = [0u8; 16];
+        let err = conn.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+}
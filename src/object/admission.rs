@@ -0,0 +1,188 @@
+//! Admission control on accepted connections, from the listener options
+//! `max_connections`, `max_connections_per_source_ip`,
+//! `connection_rate_per_source_ip` and `connection_burst_per_source_ip`.
+
+use crate::def::Rejection;
+use crate::def::config::get_option_u64;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Sources are forgotten once idle and the map grows past this.
+const MAX_IDLE_SOURCES: usize = 1024;
+/// Rejections answered at once; beyond that connections are just closed.
+const MAX_REJECTING: usize = 64;
+
+struct Source {
+    active: u64,
+    /// New connection tokens, refilled at `rate` per second up to `burst`.
+    tokens: f64,
+    last: Instant,
+}
+
+pub struct Admission {
+    max: Option<u64>,
+    per_source_max: Option<u64>,
+    rate: Option<f64>,
+    burst: f64,
+    state: Mutex<State>,
+    rejecting: Arc<Semaphore>,
+}
+
+#[derive(Default)]
+struct State {
+    active: u64,
+    sources: HashMap<IpAddr, Source>,
+}
+
+impl Admission {
+    /// `None` when the listener sets no limit.
+    pub fn from_options(options: &Option<HashMap<String, toml::Value>>) -> Option<Arc<Self>> {
+        let max = get_option_u64(options, "max_connections");
+        let per_source_max = get_option_u64(options, "max_connections_per_source_ip");
+        let rate = get_option_u64(options, "connection_rate_per_source_ip").filter(|r| *r > 0);
+        if max.is_none() && per_source_max.is_none() && rate.is_none() {
+            return None;
+        }
+        let burst = get_option_u64(options, "connection_burst_per_source_ip")
+            .or(rate)
+            .unwrap_or(1)
+            .max(1);
+        Some(Arc::new(Self {
+            max,
+            per_source_max,
+            rate: rate.map(|r| r as f64),
+            burst: burst as f64,
+            state: Mutex::new(State::default()),
+            rejecting: Arc::new(Semaphore::new(MAX_REJECTING)),
+        }))
+    }
+
+    /// Counts a new connection from `source` until the returned ticket is dropped.
+    pub fn admit(self: &Arc<Self>, source: IpAddr) -> Result<Ticket, Rejection> {
+        self.admit_at(source, Instant::now())
+    }
+
+    fn admit_at(self: &Arc<Self>, source: IpAddr, now: Instant) -> Result<Ticket, Rejection> {
+        let mut state = self.state.lock().unwrap();
+        if self.max.is_some_and(|max| state.active >= max) {
+            return Err(Rejection::Overloaded);
+        }
+        let tracked = self.per_source_max.is_some() || self.rate.is_some();
+        if tracked {
+            if state.sources.len() > MAX_IDLE_SOURCES {
+                let (rate, burst) = (self.rate.unwrap_or(0.0), self.burst);
+                state.sources.retain(|_, s| {
+                    s.active > 0
+                        || s.tokens + now.duration_since(s.last).as_secs_f64() * rate < burst
+                });
+            }
+            let burst = self.burst;
+            let src = state.sources.entry(source).or_insert(Source {
+                active: 0,
+                tokens: burst,
+                last: now,
+            });
+            if self.per_source_max.is_some_and(|max| src.active >= max) {
+                return Err(Rejection::RateLimited);
+            }
+            if let Some(rate) = self.rate {
+                let tokens =
+                    (src.tokens + now.duration_since(src.last).as_secs_f64() * rate).min(burst);
+                src.last = now;
+                if tokens < 1.0 {
+                    src.tokens = tokens;
+                    return Err(Rejection::RateLimited);
+                }
+                src.tokens = tokens - 1.0;
+            }
+            src.active += 1;
+        }
+        state.active += 1;
+        Ok(Ticket {
+            admission: self.clone(),
+            source: tracked.then_some(source),
+        })
+    }
+
+    /// A slot for answering a rejected client, `None` when too many are in progress.
+    pub fn reject_slot(&self) -> Option<OwnedSemaphorePermit> {
+        self.rejecting.clone().try_acquire_owned().ok()
+    }
+
+    fn release(&self, source: Option<IpAddr>) {
+        let mut state = self.state.lock().unwrap();
+        state.active -= 1;
+        if let Some(ip) = source
+            && let Some(src) = state.sources.get_mut(&ip)
+        {
+            src.active -= 1;
+            if src.active == 0 && self.rate.is_none() {
+                state.sources.remove(&ip);
+            }
+        }
+    }
+}
+
+/// One admitted connection.
+pub struct Ticket {
+    admission: Arc<Admission>,
+    source: Option<IpAddr>,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.admission.release(self.source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn admission(pairs: &[(&str, i64)]) -> Arc<Admission> {
+        let options = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), toml::Value::Integer(*v)))
+            .collect();
+        Admission::from_options(&Some(options)).unwrap()
+    }
+
+    #[test]
+    fn test_connection_counts() {
+        assert!(Admission::from_options(&None).is_none());
+        let a = admission(&[("max_connections", 3), ("max_connections_per_source_ip", 2)]);
+        let x: IpAddr = "10.0.0.1".parse().unwrap();
+        let y: IpAddr = "10.0.0.2".parse().unwrap();
+        let t1 = a.admit(x).unwrap();
+        let _t2 = a.admit(x).unwrap();
+        assert_eq!(a.admit(x).err(), Some(Rejection::RateLimited));
+        let _t3 = a.admit(y).unwrap();
+        assert_eq!(a.admit(y).err(), Some(Rejection::Overloaded));
+        drop(t1);
+        let _t4 = a.admit(x).unwrap();
+    }
+
+    #[test]
+    fn test_connection_rate() {
+        let a = admission(&[
+            ("connection_rate_per_source_ip", 2),
+            ("connection_burst_per_source_ip", 3),
+        ]);
+        let x: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        for _ in 0..3 {
+            a.admit_at(x, now).unwrap();
+        }
+        assert_eq!(a.admit_at(x, now).err(), Some(Rejection::RateLimited));
+        // other sources have their own budget
+        a.admit_at("10.0.0.2".parse().unwrap(), now).unwrap();
+        // two per second
+        let later = now + Duration::from_millis(500);
+        a.admit_at(x, later).unwrap();
+        assert!(a.admit_at(x, later).is_err());
+    }
+}
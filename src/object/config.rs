@@ -1,4 +1,5 @@
 use crate::def::config;
+use crate::object::timeouts::Timeouts;
 use serde::Deserialize;
 use std::collections::HashMap;
 
@@ -7,6 +8,9 @@ pub struct ObjectConfig {
     pub listener: config::Listener,
     pub connector: HashMap<String, config::Connector>,
     pub server_id: String,
+    pub users: Vec<config::User>,
+    /// Global timeouts with the listener's overrides.
+    pub timeouts: Timeouts,
 }
 
 impl ObjectConfig {
@@ -27,9 +31,38 @@ impl ObjectConfig {
         }
 
         Self {
+            timeouts: Timeouts::new(cfg).with_options(&listener.options),
             listener,
             connector,
             server_id,
+            users: cfg.user.clone().unwrap_or_default(),
+        }
+    }
+
+    pub fn user(&self, name: Option<&str>) -> Option<&config::User> {
+        let name = name?;
+        self.users.iter().find(|u| u.name == name)
+    }
+
+    /// Router for traffic of `user`: its own `router` if set, else the listener's.
+    pub fn router_name(&self, user: Option<&str>) -> &str {
+        self.user(user)
+            .and_then(|u| u.router.as_deref())
+            .unwrap_or(self.listener.router.as_str())
+    }
+
+    pub fn connector_allowed(&self, user: Option<&str>, connector: &str) -> bool {
+        match self.user(user).and_then(|u| u.connectors.as_ref()) {
+            Some(allowed) => allowed.iter().any(|c| c == connector),
+            None => true,
+        }
+    }
+
+    /// Observe `inbound`: the listener name, plus `/<user>` when authenticated as one.
+    pub fn inbound(&self, user: Option<&str>) -> String {
+        match user {
+            Some(user) => format!("{}/{}", self.listener.name, user),
+            None => self.listener.name.clone(),
         }
     }
 }
@@ -0,0 +1,144 @@
+//! The relays that are running right now, for the admin API.
+
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+
+static CONNECTIONS: LazyLock<DashMap<u64, Weak<Tracked>>> = LazyLock::new(DashMap::new);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ConnInfo {
+    pub network: &'static str,
+    pub listener: String,
+    pub user: Option<String>,
+    pub source: String,
+    pub destination: String,
+    pub connector: String,
+    pub site: Option<String>,
+}
+
+/// One open connection, unlisted once the last clone of it is dropped.
+pub struct Tracked {
+    id: u64,
+    info: ConnInfo,
+    started: SystemTime,
+    up: AtomicU64,
+    down: AtomicU64,
+    kill: CancellationToken,
+}
+
+impl Tracked {
+    pub fn add_up(&self, n: u64) {
+        self.up.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_down(&self, n: u64) {
+        self.down.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Cancelled when the connection is killed.
+    pub fn killed(&self) -> CancellationToken {
+        self.kill.clone()
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        CONNECTIONS.remove(&self.id);
+    }
+}
+
+pub fn register(info: ConnInfo) -> Arc<Tracked> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let tracked = Arc::new(Tracked {
+        id,
+        info,
+        started: SystemTime::now(),
+        up: AtomicU64::new(0),
+        down: AtomicU64::new(0),
+        kill: CancellationToken::new(),
+    });
+    CONNECTIONS.insert(id, Arc::downgrade(&tracked));
+    tracked
+}
+
+#[derive(Serialize, Debug)]
+pub struct Connection {
+    pub id: u64,
+    #[serde(flatten)]
+    pub info: ConnInfo,
+    /// Unix seconds.
+    pub started: u64,
+    pub up: u64,
+    pub down: u64,
+}
+
+/// Every open connection, oldest first.
+pub fn list() -> Vec<Connection> {
+    // collected first, the map must not be locked when the last reference drops
+    let open: Vec<Arc<Tracked>> = CONNECTIONS
+        .iter()
+        .filter_map(|entry| entry.value().upgrade())
+        .collect();
+    let mut list: Vec<Connection> = open
+        .iter()
+        .map(|c| Connection {
+            id: c.id,
+            info: c.info.clone(),
+            started: c
+                .started
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            up: c.up.load(Ordering::Relaxed),
+            down: c.down.load(Ordering::Relaxed),
+        })
+        .collect();
+    list.sort_by_key(|c| c.id);
+    list
+}
+
+/// Closes connection `id`, false when there is no such connection.
+pub fn kill(id: u64) -> bool {
+    let tracked = CONNECTIONS
+        .get(&id)
+        .and_then(|entry| entry.value().upgrade());
+    match tracked {
+        Some(tracked) => {
+            tracked.kill.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_kill() {
+        let tracked = register(ConnInfo {
+            network: "tcp",
+            listener: "test_register_kill".to_string(),
+            ..Default::default()
+        });
+        tracked.add_up(3);
+        tracked.add_down(5);
+        let id = tracked.id;
+        let listed = list().into_iter().find(|c| c.id == id).unwrap();
+        assert_eq!((listed.up, listed.down), (3, 5));
+        assert_eq!(listed.info.listener, "test_register_kill");
+
+        let killed = tracked.killed();
+        assert!(kill(id));
+        assert!(killed.is_cancelled());
+        drop(tracked);
+        assert!(!kill(id));
+        assert!(list().iter().all(|c| c.id != id));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::object::connections::{self, ConnInfo, Tracked};
+use crate::object::timeouts::{IdleTimer, Kind};
+use crate::quota::{self, QuotaMeter};
+use crate::rate_limit::{self, Throttle};
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Quotas, bandwidth limits, idle and linger timeouts of one relayed
+/// connection, and its entry in the admin API's connection list.
+#[derive(Clone, Default)]
+pub struct Limits {
+    quota: Option<QuotaMeter>,
+    throttle: Option<Throttle>,
+    idle: Option<IdleTimer>,
+    /// Started once one direction of the relay has closed.
+    linger: Option<IdleTimer>,
+    tracked: Option<Arc<Tracked>>,
+}
+
+impl Limits {
+    /// Fails when a quota of the connection is already used up.
+    pub fn open(
+        user: Option<&str>,
+        listener: &str,
+        connector: &str,
+        source: Option<IpAddr>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            quota: quota::meter(user, listener, connector)?,
+            throttle: rate_limit::throttle(user, listener, connector, source),
+            idle: None,
+            linger: None,
+            tracked: None,
+        })
+    }
+
+    pub fn with_idle(mut self, limit: Option<Duration>, kind: Kind) -> Self {
+        self.idle = limit.map(|limit| IdleTimer::new(limit, kind));
+        self
+    }
+
+    pub fn with_linger(mut self, limit: Option<Duration>) -> Self {
+        self.linger = limit.map(|limit| IdleTimer::new(limit, Kind::Linger));
+        self
+    }
+
+    /// Once one direction of the relay has closed, cancels `token` when the
+    /// other one carries no data for the linger timeout.
+    pub fn linger(&self, token: &CancellationToken) {
+        if let Some(linger) = &self.linger {
+            linger.touch();
+            tokio::spawn(linger.clone().run(token.clone()));
+        }
+    }
+
+    fn touch(&self) {
+        if let Some(idle) = &self.idle {
+            idle.touch();
+        }
+        if let Some(linger) = &self.linger {
+            linger.touch();
+        }
+    }
+
+    /// Lists the connection until every clone of these limits is dropped.
+    pub fn with_tracking(mut self, info: ConnInfo) -> Self {
+        self.tracked = Some(connections::register(info));
+        self
+    }
+
+    /// Cancels `token` once the relay goes idle or is killed. The watchers
+    /// run until `token` is cancelled, so the caller has to cancel it on exit.
+    pub fn watch(&self, token: &CancellationToken) {
+        if let Some(idle) = &self.idle {
+            tokio::spawn(idle.clone().run(token.clone()));
+        }
+        if let Some(tracked) = &self.tracked {
+            let killed = tracked.killed();
+            let token = token.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = killed.cancelled() => token.cancel(),
+                    _ = token.cancelled() => {}
+                }
+            });
+        }
+    }
+
+    /// Waits for upload bandwidth and counts `n` bytes, failing once a quota runs out.
+    pub async fn up(&self, n: u64) -> io::Result<()> {
+        self.touch();
+        if let Some(tracked) = &self.tracked {
+            tracked.add_up(n);
+        }
+        if let Some(throttle) = &self.throttle {
+            throttle.up(n as usize).await;
+        }
+        match &self.quota {
+            Some(quota) => quota.add_up(n),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn down(&self, n: u64) -> io::Result<()> {
+        self.touch();
+        if let Some(tracked) = &self.tracked {
+            tracked.add_down(n);
+        }
+        if let Some(throttle) = &self.throttle {
+            throttle.down(n as usize).await;
+        }
+        match &self.quota {
+            Some(quota) => quota.add_down(n),
+            None => Ok(()),
+        }
+    }
+}
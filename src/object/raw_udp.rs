@@ -1,13 +1,21 @@
 use crate::connector;
+use crate::connector::health;
 use crate::def::{RouterSet, RunConnector, RunUdpReader, RunUdpWriter, UDPPacket};
+use crate::dns::fake_ip::FakeIpTable;
 use crate::object::config::ObjectConfig;
+use crate::object::connections::ConnInfo;
+use crate::object::limits::Limits;
+use crate::object::sniff::SniffConfig;
+use crate::object::timeouts::{self, Kind};
 use crate::object::udp_endpoint_for_observe;
 use crate::util::RunAddr;
-use log::{debug, warn};
+use crate::util::sniff::sniff_quic;
+use log::{debug, info, warn};
 use proxy_observe::{ConnectionMeta, ObserveRegistry};
 use std::collections::HashMap;
 use std::io::{self, Result};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::Mutex;
 use tokio::{select, spawn};
 use tokio_util::sync::CancellationToken;
@@ -19,20 +27,58 @@ pub async fn handle_raw_udp(
     router: Arc<dyn RouterSet>,
     connector_cache: Arc<Mutex<HashMap<String, Arc<Box<dyn RunConnector>>>>>,
     observe_registry: ObserveRegistry,
+    fake_ip: Option<Arc<FakeIpTable>>,
 ) -> Result<()> {
     debug!("raw udp, route based on the first packet");
-    let first_packet = r.read().await?;
+    let mut first_packet = r.read().await?;
+    if let Some(fake_ip) = &fake_ip {
+        fake_ip.restore_dst(&mut first_packet.meta);
+    }
+    // the destination itself is kept, replies have to come back from it
+    let sniffed = if SniffConfig::from_listener(&config.listener).enabled {
+        sniff_quic(&first_packet.data)
+    } else {
+        None
+    };
+    if let Some(domain) = &sniffed {
+        debug!("sniffed quic {} for {}", domain, first_packet.meta.dst_addr);
+    }
+    let user = r.user();
+    let router_name = config.router_name(user.as_deref());
     let client_name = router
         .route(
             config.listener.name.as_str(),
-            config.listener.router.as_str(),
+            router_name,
             &RunAddr {
-                addr: first_packet.meta.dst_addr.clone(),
+                addr: sniffed
+                    .clone()
+                    .unwrap_or_else(|| first_packet.meta.dst_addr.clone()),
                 port: first_packet.meta.dst_port,
                 udp: true,
             },
         )
         .await;
+    if !config.connector_allowed(user.as_deref(), client_name.as_str()) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "user {:?} is not allowed to use connector '{}'",
+                user, client_name
+            ),
+        ));
+    }
+    let limits = Limits::open(
+        user.as_deref(),
+        config.listener.name.as_str(),
+        client_name.as_str(),
+        first_packet.meta.src_addr.parse().ok(),
+    )?;
+    if let Some(user) = &user {
+        info!(
+            "user {} udp from {}:{} via {}",
+            user, first_packet.meta.src_addr, first_packet.meta.src_port, client_name
+        );
+    }
 
     let conn_conf = config.connector.get(client_name.as_str()).ok_or_else(|| {
         io::Error::new(
@@ -40,6 +86,24 @@ pub async fn handle_raw_udp(
             format!("Connector config '{}' not found for UDP", client_name),
         )
     })?;
+    let timeouts = config.timeouts.with_options(&conn_conf.options);
+    let limits = limits
+        .with_idle(timeouts.session, Kind::Session)
+        .with_tracking(ConnInfo {
+            network: "udp",
+            listener: config.listener.name.clone(),
+            user: user.clone(),
+            source: udp_endpoint_for_observe(
+                &first_packet.meta.src_addr,
+                first_packet.meta.src_port,
+            ),
+            destination: udp_endpoint_for_observe(
+                &first_packet.meta.dst_addr,
+                first_packet.meta.dst_port,
+            ),
+            connector: client_name.clone(),
+            site: sniffed.clone(),
+        });
 
     let connector_obj: Arc<Box<dyn RunConnector>>;
     {
@@ -60,38 +124,47 @@ pub async fn handle_raw_udp(
         service: "rog".to_string(),
         network: "udp".to_string(),
         listener: config.listener.name.clone(),
-        router: Some(config.listener.router.clone()),
+        router: Some(router_name.to_string()),
         route: Some(client_name.clone()),
-        inbound: Some(config.listener.name.clone()),
+        inbound: Some(config.inbound(user.as_deref())),
         outbound: Some(client_name.clone()),
         source: udp_endpoint_for_observe(&first_packet.meta.src_addr, first_packet.meta.src_port),
         destination: udp_endpoint_for_observe(
             &first_packet.meta.dst_addr,
             first_packet.meta.dst_port,
         ),
-        site: None,
+        site: sniffed,
     });
 
-    let (mut udp_reader, udp_writer) = connector_obj
-        .udp_tunnel(format!(
+    let connect_start = Instant::now();
+    let tunnel = timeouts::bounded(
+        timeouts.connect,
+        Kind::Connect,
+        connector_obj.udp_tunnel(format!(
             "{}:{}",
             first_packet.meta.src_addr, first_packet.meta.src_port,
-        ))
-        .await?
-        .ok_or_else(|| {
-            io::Error::other("UDP tunnel creation failed or not supported by connector")
-        })?;
+        )),
+    )
+    .await;
+    health::record(&client_name, &tunnel, connect_start.elapsed());
+    let (mut udp_reader, udp_writer) = tunnel?.ok_or_else(|| {
+        io::Error::other("UDP tunnel creation failed or not supported by connector")
+    })?;
 
     let first_packet_len = first_packet.data.len() as u64;
     udp_writer.write(first_packet).await?;
     observe.add_tx(first_packet_len);
+    limits.up(first_packet_len).await?;
 
     let cancel_token = CancellationToken::new();
+    limits.watch(&cancel_token);
 
     debug!("raw udp loop start");
 
     let token_b = cancel_token.clone();
     let observe_tx = observe.clone();
+    let limits_tx = limits.clone();
+    let fake_ip_b = fake_ip.clone();
     let b: tokio::task::JoinHandle<Result<()>> = spawn(async move {
         loop {
             let res: Result<UDPPacket> = select! {
@@ -109,7 +182,7 @@ pub async fn handle_raw_udp(
                     debug!("raw udp loop b read error {:?}", e);
                     break;
                 }
-                Ok(packet) => {
+                Ok(mut packet) => {
                     debug!(
                         "raw udp loop b read src_addr {:?} {} {:?} {}",
                         packet.meta.src_addr,
@@ -121,6 +194,9 @@ pub async fn handle_raw_udp(
                         warn!("raw udp drop");
                         continue;
                     }
+                    if let Some(fake_ip) = &fake_ip_b {
+                        fake_ip.restore_dst(&mut packet.meta);
+                    }
 
                     debug!("raw udp server get udp_packet {:?}", &packet);
                     let packet_len = packet.data.len() as u64;
@@ -130,6 +206,10 @@ pub async fn handle_raw_udp(
                         break;
                     }
                     observe_tx.add_tx(packet_len);
+                    if let Err(e) = limits_tx.up(packet_len).await {
+                        warn!("raw udp session cut: {}", e);
+                        break;
+                    }
                 }
             }
         }
@@ -157,7 +237,10 @@ pub async fn handle_raw_udp(
                     debug!("raw udp loop c tunnel read error {:?}", e);
                     break;
                 }
-                Ok(udp_packet) => {
+                Ok(mut udp_packet) => {
+                    if let Some(fake_ip) = &fake_ip {
+                        fake_ip.mask_dst(&mut udp_packet.meta);
+                    }
                     let packet_len = udp_packet.data.len() as u64;
                     debug!(
                         "raw udp tunnel udp_packet read src {:?} {:?}",
@@ -168,6 +251,10 @@ pub async fn handle_raw_udp(
                         break;
                     }
                     observe_rx.add_rx(packet_len);
+                    if let Err(e) = limits.down(packet_len).await {
+                        warn!("raw udp session cut: {}", e);
+                        break;
+                    }
                 }
             }
         }
@@ -0,0 +1,65 @@
+use crate::def::RunStream;
+use crate::def::config::{Listener, get_option_bool, get_option_u64};
+use crate::util::sniff::{SNIFF_MAX_LEN, SniffResult, sniff_tcp};
+use log::debug;
+use std::io;
+use std::time::Duration;
+use tokio::time::{Instant, timeout_at};
+
+const DEFAULT_SNIFF_TIMEOUT_MS: u64 = 300;
+
+/// Listener options of the sniffing stage.
+#[derive(Debug, Clone, Copy)]
+pub struct SniffConfig {
+    pub enabled: bool,
+    /// Send the sniffed domain to the connector instead of the original address.
+    pub override_dst: bool,
+    pub timeout: Duration,
+}
+
+impl SniffConfig {
+    pub fn from_listener(listener: &Listener) -> Self {
+        SniffConfig {
+            enabled: get_option_bool(&listener.options, "sniff"),
+            override_dst: get_option_bool(&listener.options, "sniff_override"),
+            timeout: Duration::from_millis(
+                get_option_u64(&listener.options, "sniff_timeout_ms")
+                    .unwrap_or(DEFAULT_SNIFF_TIMEOUT_MS),
+            ),
+        }
+    }
+}
+
+/// Reads the first client bytes until a domain is recognized, the data can't
+/// match any protocol, or the timeout hits (server-first protocols). Bytes
+/// read here are appended to `payload_cache` so they reach the remote untouched.
+pub async fn sniff_stream(
+    stream: &mut dyn RunStream,
+    payload_cache: Option<Vec<u8>>,
+    wait: Duration,
+) -> io::Result<(Option<String>, Option<Vec<u8>>)> {
+    let mut data = payload_cache.unwrap_or_default();
+    let deadline = Instant::now() + wait;
+    let mut buf = vec![0u8; 4096];
+    let domain = loop {
+        if !data.is_empty() {
+            match sniff_tcp(&data) {
+                SniffResult::Found(domain) => break Some(domain),
+                SniffResult::NoMatch => break None,
+                SniffResult::Incomplete if data.len() >= SNIFF_MAX_LEN => break None,
+                SniffResult::Incomplete => {}
+            }
+        }
+        match timeout_at(deadline, stream.read(&mut buf)).await {
+            Err(_) => {
+                debug!("sniff timeout after {} bytes", data.len());
+                break None;
+            }
+            Ok(Ok(0)) => break None,
+            Ok(Ok(n)) => data.extend_from_slice(&buf[..n]),
+            Ok(Err(e)) => return Err(e),
+        }
+    };
+    let cache = if data.is_empty() { None } else { Some(data) };
+    Ok((domain, cache))
+}
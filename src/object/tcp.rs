@@ -1,57 +1,168 @@
-use crate::block::{BlockManager, DataBlock};
-use crate::consts::TCP_IO_BUFFER_SIZE;
-use crate::def::RunStream;
+use crate::block::{BlockPool, DataBlock};
+use crate::def::{RunStream, RunWriteHalf};
+use crate::object::limits::Limits;
+#[cfg(target_os = "linux")]
+use crate::stream::tcp::TcpRunStream;
 use crate::util::RunAddr;
+#[cfg(target_os = "linux")]
+use crate::util::splice;
 use bytes::Bytes;
-use log::debug;
+use log::{debug, warn};
 use proxy_observe::ObserveConnection;
 use std::io::Result;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(target_os = "linux")]
+use tokio::net::TcpStream;
 use tokio::select;
 use tokio_util::sync::CancellationToken;
 
+/// Each direction of a relay half-closes on its own. The relay ends once both
+/// have, or when the open one stays silent for the linger timeout.
+#[derive(Clone)]
+struct HalfClose {
+    closed: Arc<AtomicBool>,
+    token: CancellationToken,
+    limits: Limits,
+}
+
+impl HalfClose {
+    /// Passes the EOF of one direction on to its writer.
+    async fn close(&self, w: &mut Box<dyn RunWriteHalf>) -> Result<()> {
+        w.shutdown().await?;
+        self.finish();
+        Ok(())
+    }
+
+    fn finish(&self) {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            self.token.cancel();
+        } else {
+            self.limits.linger(&self.token);
+        }
+    }
+}
+
+/// Relays two plain TCP sockets in the kernel with splice(2).
+#[cfg(target_os = "linux")]
+async fn splice_relay(
+    client: &TcpStream,
+    server: &TcpStream,
+    observe: Option<ObserveConnection>,
+    half_close: &HalfClose,
+) {
+    let limits = &half_close.limits;
+    let finish = |task: &str, res: Result<()>| match res {
+        Ok(()) => half_close.finish(),
+        Err(e) => {
+            debug!("Splice task {} error: {:?}", task, e);
+            half_close.token.cancel();
+        }
+    };
+    let s2c = async {
+        let res = splice::pump(server, client, |n| {
+            if let Some(observe) = &observe {
+                observe.add_tx(n);
+            }
+            async move {
+                limits
+                    .up(n)
+                    .await
+                    .inspect_err(|e| warn!("connection cut: {}", e))
+            }
+        })
+        .await;
+        finish("s2c", res);
+    };
+    let c2s = async {
+        let res = splice::pump(client, server, |n| {
+            if let Some(observe) = &observe {
+                observe.add_rx(n);
+            }
+            async move {
+                limits
+                    .down(n)
+                    .await
+                    .inspect_err(|e| warn!("connection cut: {}", e))
+            }
+        })
+        .await;
+        finish("c2s", res);
+    };
+    select! {
+        _ = async { tokio::join!(s2c, c2s) } => {}
+        _ = half_close.token.cancelled() => {}
+    }
+}
+
 pub async fn handle_tcp_connection(
     addr: RunAddr,
     cache: Option<Vec<u8>>,
-    client_stream: Box<dyn RunStream>,
-    server_stream: Box<dyn RunStream>,
-    block_manager: Option<Arc<BlockManager>>,
+    mut client_stream: Box<dyn RunStream>,
+    mut server_stream: Box<dyn RunStream>,
+    block_pool: Option<Arc<BlockPool>>,
     observe: Option<ObserveConnection>,
+    limits: Limits,
 ) -> Result<()> {
     debug!("Post Handshake successful {:?}", addr);
-    let (mut client_r, mut client_w) = client_stream.split();
-    let (mut server_r, mut server_w) = server_stream.split();
     let cancel_token = CancellationToken::new();
+    // ends the watchers on every exit, early errors included
+    let _cancel_on_exit = cancel_token.clone().drop_guard();
+    limits.watch(&cancel_token);
+    let half_close = HalfClose {
+        closed: Arc::new(AtomicBool::new(false)),
+        token: cancel_token.clone(),
+        limits: limits.clone(),
+    };
 
     if let Some(c) = cache {
-        client_w.write(c.as_slice()).await?;
+        client_stream.write(c.as_slice()).await?;
         if let Some(observe) = &observe {
             observe.add_tx(c.len() as u64);
         }
+        limits
+            .up(c.len() as u64)
+            .await
+            .inspect_err(|e| warn!("connection cut: {}", e))?;
     }
 
+    #[cfg(target_os = "linux")]
+    if let (Some(client_tcp), Some(server_tcp)) = (
+        client_stream.as_any_mut().downcast_ref::<TcpRunStream>(),
+        server_stream.as_any_mut().downcast_ref::<TcpRunStream>(),
+    ) {
+        debug!("start splice");
+        splice_relay(client_tcp.inner(), server_tcp.inner(), observe, &half_close).await;
+        return Ok(());
+    }
+
+    let (mut client_r, mut client_w) = client_stream.split();
+    let (mut server_r, mut server_w) = server_stream.split();
     debug!("start loop");
-    if let Some(block_manager) = block_manager {
+    if let Some(block_pool) = block_pool {
+        let conn = block_pool.connection(addr.endpoint());
         // s read
-        let s2c_data_block = Arc::new(DataBlock::new(block_manager.clone()));
+        let s2c_data_block = Arc::new(DataBlock::new(conn.clone()));
         let s2c_data_block_r = s2c_data_block.clone();
         let server_read_token = cancel_token.clone();
         let server_reader_task = tokio::spawn(async move {
-            let mut buf = [0u8; TCP_IO_BUFFER_SIZE];
             loop {
                 select! {
-                    tn = server_r.read(&mut buf) => {
+                    tn = server_r.read_chunk() => {
                         match tn {
                             Err(e) => {
                                 debug!("Reader task server_reader error: {:?}", e);
                                 break;
                             }
-                            Ok(0) => {
-                                debug!("Reader task server_reader: r.read() returned 0 (EOF)");
-                                break;
+                            Ok(data) if data.is_empty() => {
+                                debug!("Reader task server_reader: r.read_chunk() returned EOF");
+                                // an empty block tells the writer to half-close
+                                select! {
+                                    _ = s2c_data_block.provide(Bytes::new()) => return,
+                                    _ = server_read_token.cancelled() => break,
+                                }
                             }
-                            Ok(n) => {
-                                let data = Bytes::copy_from_slice(&buf[..n]);
+                            Ok(data) => {
                                 select! {
                                     _ = s2c_data_block.provide(data) => {}
                                     _ = server_read_token.cancelled() => {
@@ -74,16 +185,32 @@ pub async fn handle_tcp_connection(
         // c write
         let client_write_token = cancel_token.clone();
         let observe_tx = observe.clone();
+        let limits_tx = limits.clone();
+        let half_close_tx = half_close.clone();
         let client_writer_task = tokio::spawn(async move {
             loop {
                 select! {
                     data = s2c_data_block_r.consume() =>{
-                        if let Err(e) = client_w.write(&data).await {
-                                debug!("Reader task client_writer: w.write() error: {:?}", e);
+                        if data.is_empty() {
+                            match half_close_tx.close(&mut client_w).await {
+                                Ok(()) => return,
+                                Err(e) => {
+                                    debug!("Writer task client_writer: w.shutdown() error: {:?}", e);
+                                    break;
+                                }
+                            }
+                        }
+                        let n = data.len() as u64;
+                        if let Err(e) = client_w.write_chunk(data).await {
+                                debug!("Reader task client_writer: w.write_chunk() error: {:?}", e);
                                  break;
                              }
                         if let Some(observe) = &observe_tx {
-                            observe.add_tx(data.len() as u64);
+                            observe.add_tx(n);
+                        }
+                        if let Err(e) = limits_tx.up(n).await {
+                            warn!("connection cut: {}", e);
+                            break;
                         }
                     }
                     _ = client_write_token.cancelled() => {
@@ -96,25 +223,27 @@ pub async fn handle_tcp_connection(
             client_write_token.cancel();
         });
         // c read
-        let c2s_data_block = Arc::new(DataBlock::new(block_manager.clone()));
+        let c2s_data_block = Arc::new(DataBlock::new(conn));
         let c2s_data_block_r = c2s_data_block.clone();
         let client_read_token = cancel_token.clone();
         let client_reader_task = tokio::spawn(async move {
-            let mut buf = [0u8; TCP_IO_BUFFER_SIZE];
             loop {
                 select! {
-                     tn = client_r.read(&mut buf) => {
+                     tn = client_r.read_chunk() => {
                         match tn {
                             Err(e) => {
                                 debug!("Reader task client_reader error: {:?}", e);
                                 break;
                             }
-                            Ok(0) => {
-                                debug!("Reader task client_reader: r.read() returned 0 (EOF)");
-                                break;
+                            Ok(data) if data.is_empty() => {
+                                debug!("Reader task client_reader: r.read_chunk() returned EOF");
+                                // an empty block tells the writer to half-close
+                                select! {
+                                    _ = c2s_data_block.provide(Bytes::new()) => return,
+                                    _ = client_read_token.cancelled() => break,
+                                }
                             }
-                            Ok(n) => {
-                                let data = Bytes::copy_from_slice(&buf[..n]);
+                            Ok(data) => {
                                 select! {
                                     _ = c2s_data_block.provide(data) => {}
                                     _ = client_read_token.cancelled() => {
@@ -138,16 +267,32 @@ pub async fn handle_tcp_connection(
         // s write
         let server_write_token = cancel_token.clone();
         let observe_rx = observe.clone();
+        let limits_rx = limits.clone();
+        let half_close_rx = half_close.clone();
         let server_writer_task = tokio::spawn(async move {
             loop {
                 select! {
                     data = c2s_data_block_r.consume() =>{
-                        if let Err(e) = server_w.write(&data).await {
-                                debug!("Reader task server_writer: w.write() error: {:?}", e);
+                        if data.is_empty() {
+                            match half_close_rx.close(&mut server_w).await {
+                                Ok(()) => return,
+                                Err(e) => {
+                                    debug!("Writer task server_writer: w.shutdown() error: {:?}", e);
+                                    break;
+                                }
+                            }
+                        }
+                        let n = data.len() as u64;
+                        if let Err(e) = server_w.write_chunk(data).await {
+                                debug!("Reader task server_writer: w.write_chunk() error: {:?}", e);
                                  break;
                              }
                         if let Some(observe) = &observe_rx {
-                            observe.add_rx(data.len() as u64);
+                            observe.add_rx(n);
+                        }
+                        if let Err(e) = limits_rx.down(n).await {
+                            warn!("connection cut: {}", e);
+                            break;
                         }
                     }
                      _ = server_write_token.cancelled() => {
@@ -170,27 +315,39 @@ pub async fn handle_tcp_connection(
     }
     let token_reader = cancel_token.clone();
     let observe_tx = observe.clone();
+    let limits_tx = limits.clone();
+    let half_close_tx = half_close.clone();
     let s2c = tokio::spawn(async move {
-        let mut buf = [0u8; TCP_IO_BUFFER_SIZE];
         loop {
             select! {
-                tn = server_r.read(&mut buf) => {
+                tn = server_r.read_chunk() => {
                     match tn {
                         Err(e) => {
                             debug!("Reader task s2c error: {:?}", e);
                             break;
                         }
-                        Ok(0) => {
-                            debug!("Reader task s2c: r.read() returned 0 (EOF)");
-                            break;
+                        Ok(data) if data.is_empty() => {
+                            debug!("Reader task s2c: r.read_chunk() returned EOF");
+                            match half_close_tx.close(&mut client_w).await {
+                                Ok(()) => return,
+                                Err(e) => {
+                                    debug!("Reader task s2c: w.shutdown() error: {:?}", e);
+                                    break;
+                                }
+                            }
                         }
-                        Ok(n) => {
-                            if let Err(e) = client_w.write(&buf[..n]).await {
-                                debug!("Reader task s2c: w.write() error: {:?}", e);
+                        Ok(data) => {
+                            let n = data.len() as u64;
+                            if let Err(e) = client_w.write_chunk(data).await {
+                                debug!("Reader task s2c: w.write_chunk() error: {:?}", e);
                                 break;
                             }
                             if let Some(observe) = &observe_tx {
-                                observe.add_tx(n as u64);
+                                observe.add_tx(n);
+                            }
+                            if let Err(e) = limits_tx.up(n).await {
+                                warn!("connection cut: {}", e);
+                                break;
                             }
                         }
                     }
@@ -207,27 +364,39 @@ pub async fn handle_tcp_connection(
 
     let token_writer = cancel_token.clone();
     let observe_rx = observe.clone();
+    let limits_rx = limits.clone();
+    let half_close_rx = half_close;
     let c2s = tokio::spawn(async move {
-        let mut buf = [0u8; TCP_IO_BUFFER_SIZE];
         loop {
             select! {
-                tn = client_r.read(&mut buf) => {
+                tn = client_r.read_chunk() => {
                     match tn {
                         Err(e) => {
                             debug!("Writer task c2s error: {:?}", e);
                             break;
                         }
-                        Ok(0) => {
-                            debug!("Writer task c2s: r.read() returned 0 (EOF)");
-                            break;
+                        Ok(data) if data.is_empty() => {
+                            debug!("Writer task c2s: r.read_chunk() returned EOF");
+                            match half_close_rx.close(&mut server_w).await {
+                                Ok(()) => return,
+                                Err(e) => {
+                                    debug!("Writer task c2s: w.shutdown() error: {:?}", e);
+                                    break;
+                                }
+                            }
                         }
-                        Ok(n) => {
-                            if let Err(e) = server_w.write(&buf[..n]).await {
-                                debug!("Writer task c2s: w.write() error: {:?}", e);
+                        Ok(data) => {
+                            let n = data.len() as u64;
+                            if let Err(e) = server_w.write_chunk(data).await {
+                                debug!("Writer task c2s: w.write_chunk() error: {:?}", e);
                                 break;
                             }
                             if let Some(observe) = &observe_rx {
-                                observe.add_rx(n as u64);
+                                observe.add_rx(n);
+                            }
+                            if let Err(e) = limits_rx.down(n).await {
+                                warn!("connection cut: {}", e);
+                                break;
                             }
                         }
                     }
@@ -251,3 +420,325 @@ pub async fn handle_tcp_connection(
     debug!("end loop");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BlockManager;
+    use crate::def::{RunReadHalf, StreamInfo};
+    use crate::object::connections::{self, ConnInfo};
+    use crate::stream::pb_tcp_client::PbTcpClientRunStream;
+    use crate::stream::pb_tcp_server::PbTcpServerRunStream;
+    use crate::stream::tcp::TcpRunStream;
+    use crate::util::tcp_frame::FramePadding;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (b, _) = listener.accept().await.unwrap();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn test_half_close() {
+        half_close(None).await;
+        half_close(Some(Arc::new(BlockManager::new(4)).pool("test", None))).await;
+    }
+
+    /// Goes through the buffered relays, the pb_tcp side keeps splice out.
+    async fn half_close(block_pool: Option<Arc<BlockPool>>) {
+        let (app, inbound) = pair().await;
+        let (outbound, mut remote) = pair().await;
+        let (ar, aw) = app.into_split();
+        let (ir, iw) = inbound.into_split();
+        let app = PbTcpClientRunStream::new(
+            Box::new(ar),
+            Box::new(aw),
+            String::new(),
+            false,
+            FramePadding::default(),
+        );
+        let inbound =
+            PbTcpServerRunStream::new(Box::new(ir), Box::new(iw), FramePadding::default());
+        let addr = RunAddr {
+            addr: "remote".to_string(),
+            port: 80,
+            udp: false,
+        };
+        let relay = tokio::spawn(handle_tcp_connection(
+            addr,
+            None,
+            Box::new(TcpRunStream::new(outbound)),
+            Box::new(inbound),
+            block_pool,
+            None,
+            Limits::default(),
+        ));
+
+        // the request ends with a FIN, the response still gets through
+        let (mut app_r, mut app_w) = Box::new(app).split();
+        app_w.write(b"ping").await.unwrap();
+        app_w.shutdown().await.unwrap();
+        let mut req = Vec::new();
+        remote.read_to_end(&mut req).await.unwrap();
+        assert_eq!(req, b"ping");
+        remote.write_all(b"pong").await.unwrap();
+        remote.shutdown().await.unwrap();
+        let mut res = Vec::new();
+        loop {
+            let chunk = app_r.read_chunk().await.unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            res.extend_from_slice(&chunk);
+        }
+        assert_eq!(res, b"pong");
+        relay.await.unwrap().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_splice_half_close() {
+        let (mut app, inbound) = pair().await;
+        let (outbound, mut remote) = pair().await;
+        let addr = RunAddr {
+            addr: "remote".to_string(),
+            port: 80,
+            udp: false,
+        };
+        let limits = Limits::default().with_tracking(ConnInfo {
+            destination: "splice-half-close".to_string(),
+            ..Default::default()
+        });
+        let relay = tokio::spawn(handle_tcp_connection(
+            addr,
+            None,
+            Box::new(TcpRunStream::new(outbound)),
+            Box::new(TcpRunStream::new(inbound)),
+            None,
+            None,
+            limits.clone(),
+        ));
+
+        app.write_all(b"ping").await.unwrap();
+        app.shutdown().await.unwrap();
+        let mut req = Vec::new();
+        remote.read_to_end(&mut req).await.unwrap();
+        assert_eq!(req, b"ping");
+        remote.write_all(b"pong!").await.unwrap();
+        remote.shutdown().await.unwrap();
+        let mut res = Vec::new();
+        app.read_to_end(&mut res).await.unwrap();
+        assert_eq!(res, b"pong!");
+        relay.await.unwrap().unwrap();
+
+        let conn = connections::list()
+            .into_iter()
+            .find(|c| c.info.destination == "splice-half-close")
+            .unwrap();
+        assert_eq!((conn.up, conn.down), (4, 5));
+    }
+
+    #[tokio::test]
+    async fn test_watch_ends_on_early_exit() {
+        // the cached bytes can't be written, so the relay fails before it starts
+        let (a, b) = tokio::io::duplex(64);
+        drop(b);
+        let (ar, aw) = tokio::io::split(a);
+        let client = PbTcpClientRunStream::new(
+            Box::new(ar),
+            Box::new(aw),
+            String::new(),
+            false,
+            FramePadding::default(),
+        );
+        let (server, _remote) = pair().await;
+        let addr = RunAddr {
+            addr: "remote".to_string(),
+            port: 80,
+            udp: false,
+        };
+        let limits = Limits::default().with_tracking(ConnInfo {
+            destination: "early-exit".to_string(),
+            ..Default::default()
+        });
+        let res = handle_tcp_connection(
+            addr,
+            Some(b"hello".to_vec()),
+            Box::new(client),
+            Box::new(TcpRunStream::new(server)),
+            None,
+            None,
+            limits,
+        )
+        .await;
+        assert!(res.is_err());
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let metrics = tokio::runtime::Handle::current().metrics();
+        assert_eq!(metrics.num_alive_tasks(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_linger_resets() {
+        let (mut app, inbound) = pair().await;
+        let (outbound, mut remote) = pair().await;
+        let addr = RunAddr {
+            addr: "remote".to_string(),
+            port: 80,
+            udp: false,
+        };
+        let linger = std::time::Duration::from_millis(200);
+        let relay = tokio::spawn(handle_tcp_connection(
+            addr,
+            None,
+            Box::new(TcpRunStream::new(outbound)),
+            Box::new(TcpRunStream::new(inbound)),
+            None,
+            None,
+            Limits::default().with_linger(Some(linger)),
+        ));
+
+        // a download after the request's FIN outlasts the linger timeout
+        app.shutdown().await.unwrap();
+        let mut req = Vec::new();
+        remote.read_to_end(&mut req).await.unwrap();
+        let sender = tokio::spawn(async move {
+            for _ in 0..8 {
+                remote.write_all(b"data").await.unwrap();
+                tokio::time::sleep(linger / 2).await;
+            }
+            remote
+        });
+        let mut res = vec![0u8; 32];
+        app.read_exact(&mut res).await.unwrap();
+        let _remote = sender.await.unwrap();
+
+        // then silence cuts it
+        let start = std::time::Instant::now();
+        relay.await.unwrap().unwrap();
+        assert!(start.elapsed() < linger * 3);
+        assert_eq!(app.read(&mut res).await.unwrap(), 0);
+    }
+
+    /// Hides a `TcpRunStream` from the splice downcast, so tcp -> tcp relays
+    /// still take the buffered path (and the block pool) in the bench.
+    struct Buffered(TcpRunStream);
+
+    #[async_trait::async_trait]
+    impl RunStream for Buffered {
+        fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+
+        fn get_info(&self) -> &StreamInfo {
+            self.0.get_info()
+        }
+
+        fn set_info(&mut self, f: &mut dyn FnMut(&mut StreamInfo)) {
+            self.0.set_info(f)
+        }
+
+        fn split(self: Box<Self>) -> (Box<dyn RunReadHalf>, Box<dyn RunWriteHalf>) {
+            Box::new(self.0).split()
+        }
+
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.0.read(buf).await
+        }
+
+        async fn write(&mut self, buf: &[u8]) -> Result<()> {
+            self.0.write(buf).await
+        }
+    }
+
+    const BENCH_BYTES: usize = 256 << 20;
+
+    /// Pushes `BENCH_BYTES` from the inbound side to the remote and returns MB/s.
+    async fn relay_throughput(
+        inbound: Box<dyn RunStream>,
+        mut app: Box<dyn RunStream>,
+        block_pool: Option<Arc<BlockPool>>,
+    ) -> f64 {
+        let (outbound, mut remote) = pair().await;
+        let addr = RunAddr {
+            addr: "remote".to_string(),
+            port: 80,
+            udp: false,
+        };
+        let start = std::time::Instant::now();
+        let relay = tokio::spawn(handle_tcp_connection(
+            addr,
+            None,
+            Box::new(TcpRunStream::new(outbound)),
+            inbound,
+            block_pool,
+            None,
+            Limits::default(),
+        ));
+        let sink = tokio::spawn(async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut total = 0;
+            while total < BENCH_BYTES {
+                total += remote.read(&mut buf).await.unwrap();
+            }
+        });
+        let chunk = vec![7u8; 16 * 1024];
+        for _ in 0..BENCH_BYTES / chunk.len() {
+            app.write(&chunk).await.unwrap();
+        }
+        sink.await.unwrap();
+        let secs = start.elapsed().as_secs_f64();
+        drop(app);
+        let _ = relay.await;
+        BENCH_BYTES as f64 / secs / (1 << 20) as f64
+    }
+
+    /// `cargo test --release bench_relay -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_relay() {
+        // both sides tcp: spliced on linux, which never touches the pool
+        let (app, inbound) = pair().await;
+        let mbps = relay_throughput(
+            Box::new(TcpRunStream::new(inbound)),
+            Box::new(TcpRunStream::new(app)),
+            None,
+        )
+        .await;
+        println!("tcp -> tcp, direct: {:.0} MB/s", mbps);
+
+        for blocks in [false, true] {
+            let block_pool =
+                blocks.then(|| Arc::new(BlockManager::new(1 << 16)).pool("bench", None));
+            let (app, inbound) = pair().await;
+            let mbps = relay_throughput(
+                Box::new(Buffered(TcpRunStream::new(inbound))),
+                Box::new(TcpRunStream::new(app)),
+                block_pool.clone(),
+            )
+            .await;
+            println!("tcp -> tcp buffered, blocks {}: {:.0} MB/s", blocks, mbps);
+
+            let (app, inbound) = pair().await;
+            let (ar, aw) = app.into_split();
+            let (ir, iw) = inbound.into_split();
+            let app = PbTcpClientRunStream::new(
+                Box::new(ar),
+                Box::new(aw),
+                String::new(),
+                false,
+                FramePadding::default(),
+            );
+            let inbound =
+                PbTcpServerRunStream::new(Box::new(ir), Box::new(iw), FramePadding::default());
+            let mbps = relay_throughput(Box::new(inbound), Box::new(app), block_pool).await;
+            println!("pb_tcp -> tcp, blocks {}: {:.0} MB/s", blocks, mbps);
+        }
+    }
+}
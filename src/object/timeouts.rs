@@ -0,0 +1,244 @@
+//! `handshake_timeout`, `connect_timeout`, `idle_timeout`, `session_timeout`
+//! and `linger_timeout`, set globally and overridden by listener or connector
+//! options. Values are seconds, 0 disables one.
+
+use crate::def::config::{self, get_option_u64};
+use log::{debug, info};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::{Duration, Instant, sleep, sleep_until, timeout};
+use tokio_util::sync::CancellationToken;
+
+const HANDSHAKE_TIMEOUT: u64 = 30;
+const CONNECT_TIMEOUT: u64 = 30;
+const IDLE_TIMEOUT: u64 = 3600;
+const SESSION_TIMEOUT: u64 = 60;
+const LINGER_TIMEOUT: u64 = 60;
+const REPORT_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Handshake,
+    Connect,
+    /// A TCP relay without traffic.
+    Idle,
+    /// A UDP session without traffic.
+    Session,
+    /// A half-closed TCP relay whose other direction is still open.
+    Linger,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 5] = [
+        Kind::Handshake,
+        Kind::Connect,
+        Kind::Idle,
+        Kind::Session,
+        Kind::Linger,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Handshake => "handshake",
+            Kind::Connect => "connect",
+            Kind::Idle => "idle",
+            Kind::Session => "session",
+            Kind::Linger => "linger",
+        }
+    }
+}
+
+static EXPIRED: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+
+fn record(kind: Kind) {
+    EXPIRED[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How often each kind of timeout hit since startup.
+pub fn expired() -> [(Kind, u64); 5] {
+    Kind::ALL.map(|kind| (kind, EXPIRED[kind as usize].load(Ordering::Relaxed)))
+}
+
+/// Logs the timeout counters whenever they changed.
+pub async fn report_task() {
+    let mut last = expired();
+    loop {
+        sleep(REPORT_INTERVAL).await;
+        let now = expired();
+        if now != last {
+            let counts: Vec<String> = now
+                .iter()
+                .map(|(kind, n)| format!("{}={}", kind.name(), n))
+                .collect();
+            info!("timeouts since start: {}", counts.join(" "));
+            last = now;
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    pub handshake: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub session: Option<Duration>,
+    pub linger: Option<Duration>,
+}
+
+fn secs(value: Option<u64>, default: u64) -> Option<Duration> {
+    Some(value.unwrap_or(default))
+        .filter(|s| *s > 0)
+        .map(Duration::from_secs)
+}
+
+impl Timeouts {
+    /// The global settings.
+    pub fn new(cfg: &config::Config) -> Self {
+        Self {
+            handshake: secs(cfg.handshake_timeout, HANDSHAKE_TIMEOUT),
+            connect: secs(cfg.connect_timeout, CONNECT_TIMEOUT),
+            idle: secs(cfg.idle_timeout, IDLE_TIMEOUT),
+            session: secs(cfg.session_timeout, SESSION_TIMEOUT),
+            linger: secs(cfg.linger_timeout, LINGER_TIMEOUT),
+        }
+    }
+
+    /// These settings overridden by a listener's or connector's `options`.
+    pub fn with_options(self, options: &Option<HashMap<String, toml::Value>>) -> Self {
+        let get = |key: &str, current: Option<Duration>| match get_option_u64(options, key) {
+            Some(s) => secs(Some(s), 0),
+            None => current,
+        };
+        Self {
+            handshake: get("handshake_timeout", self.handshake),
+            connect: get("connect_timeout", self.connect),
+            idle: get("idle_timeout", self.idle),
+            session: get("session_timeout", self.session),
+            linger: get("linger_timeout", self.linger),
+        }
+    }
+}
+
+/// Runs `fut` for at most `limit`, failing with `TimedOut` after that.
+pub async fn bounded<T>(
+    limit: Option<Duration>,
+    kind: Kind,
+    fut: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    let Some(limit) = limit else {
+        return fut.await;
+    };
+    match timeout(limit, fut).await {
+        Ok(res) => res,
+        Err(_) => {
+            record(kind);
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} timeout", kind.name()),
+            ))
+        }
+    }
+}
+
+/// Cancels a relay once it has seen no traffic for its timeout.
+#[derive(Clone)]
+pub struct IdleTimer {
+    limit: Duration,
+    kind: Kind,
+    start: Instant,
+    /// Milliseconds from `start` to the last traffic.
+    last: Arc<AtomicU64>,
+}
+
+impl IdleTimer {
+    pub fn new(limit: Duration, kind: Kind) -> Self {
+        Self {
+            limit,
+            kind,
+            start: Instant::now(),
+            last: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn deadline(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed)) + self.limit
+    }
+
+    /// Watches until `token` is cancelled, cancelling it itself on expiry.
+    pub async fn run(self, token: CancellationToken) {
+        loop {
+            let deadline = self.deadline();
+            tokio::select! {
+                _ = sleep_until(deadline) => {
+                    if self.deadline() <= Instant::now() {
+                        debug!("{} timeout after {:?}", self.kind.name(), self.limit);
+                        record(self.kind);
+                        token.cancel();
+                        return;
+                    }
+                }
+                _ = token.cancelled() => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let global = Timeouts {
+            handshake: Some(Duration::from_secs(30)),
+            connect: Some(Duration::from_secs(30)),
+            idle: None,
+            session: Some(Duration::from_secs(60)),
+            linger: Some(Duration::from_secs(60)),
+        };
+        let options = Some(
+            [
+                ("idle_timeout", 120),
+                ("session_timeout", 0),
+                ("sniff_timeout_ms", 5),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), toml::Value::Integer(v)))
+            .collect(),
+        );
+        let t = global.with_options(&options);
+        assert_eq!(t.handshake, global.handshake);
+        assert_eq!(t.idle, Some(Duration::from_secs(120)));
+        assert_eq!(t.session, None);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timer() {
+        let timer = IdleTimer::new(Duration::from_millis(100), Kind::Idle);
+        let token = CancellationToken::new();
+        let watch = tokio::spawn(timer.clone().run(token.clone()));
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            timer.touch();
+        }
+        assert!(!token.is_cancelled());
+        watch.await.unwrap();
+        assert!(token.is_cancelled());
+        assert!(timer.start.elapsed() >= Duration::from_millis(250));
+        let res = bounded(Some(Duration::from_millis(10)), Kind::Connect, async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(())
+        })
+        .await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -1,13 +1,21 @@
 use crate::connector;
+use crate::connector::health;
 use crate::def::{RouterSet, RunAcceptor, RunStream, UDPPacket};
+use crate::dns::fake_ip::FakeIpTable;
 use crate::object::config::ObjectConfig;
+use crate::object::connections::ConnInfo;
+use crate::object::limits::Limits;
+use crate::object::sniff::SniffConfig;
+use crate::object::timeouts::{self, Kind};
 use crate::object::udp_endpoint_for_observe;
 use crate::util::RunAddr;
+use crate::util::sniff::sniff_quic;
 use log::{debug, info, warn};
 use proxy_observe::{ConnectionMeta, ObserveRegistry};
 use std::io::Result;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::net::UdpSocket;
 use tokio::{select, spawn};
 use tokio_util::sync::CancellationToken;
@@ -19,6 +27,7 @@ pub async fn handle_udp_connection(
     router: Arc<dyn RouterSet>,
     addr: RunAddr,
     observe_registry: ObserveRegistry,
+    fake_ip: Option<Arc<FakeIpTable>>,
 ) -> Result<()> {
     info!("udp? {:?}", addr);
     let udp_socket_base_res = UdpSocket::bind("127.0.0.1:0").await;
@@ -82,24 +91,67 @@ pub async fn handle_udp_connection(
         n,
         &buf[..n]
     );
-    let udp_packet = UDPPacket::parse(&buf[..n], src_addr)?;
+    let mut udp_packet = UDPPacket::parse(&buf[..n], src_addr)?;
     if udp_packet.data.is_empty() {
         warn!("udp first packet drop");
         cancel_token.cancel();
         return Ok(());
     }
+    if let Some(fake_ip) = &fake_ip {
+        fake_ip.restore_dst(&mut udp_packet.meta);
+    }
+    // the destination itself is kept, replies have to come back from it
+    let sniffed = if SniffConfig::from_listener(&config.listener).enabled {
+        sniff_quic(&udp_packet.data)
+    } else {
+        None
+    };
+    if let Some(domain) = &sniffed {
+        debug!("sniffed quic {} for {}", domain, udp_packet.meta.dst_addr);
+    }
     let client_name = router
         .route(
             config.listener.name.as_str(),
             config.listener.router.as_str(),
             &RunAddr {
-                addr: udp_packet.meta.dst_addr.clone(),
+                addr: sniffed
+                    .clone()
+                    .unwrap_or_else(|| udp_packet.meta.dst_addr.clone()),
                 port: udp_packet.meta.dst_port,
                 udp: false,
             },
         )
         .await;
     let conn_conf = config.connector.get(client_name.as_str()).unwrap();
+    let source = udp_packet.meta.src_addr.parse().ok();
+    let limits = match Limits::open(
+        None,
+        config.listener.name.as_str(),
+        client_name.as_str(),
+        source,
+    ) {
+        Ok(limits) => limits,
+        Err(e) => {
+            warn!("udp rejected: {}", e);
+            cancel_token.cancel();
+            return Err(e);
+        }
+    };
+    let timeouts = config.timeouts.with_options(&conn_conf.options);
+    let limits = limits
+        .with_idle(timeouts.session, Kind::Session)
+        .with_tracking(ConnInfo {
+            network: "udp",
+            listener: config.listener.name.clone(),
+            user: None,
+            source: udp_endpoint_for_observe(&udp_packet.meta.src_addr, udp_packet.meta.src_port),
+            destination: udp_endpoint_for_observe(
+                &udp_packet.meta.dst_addr,
+                udp_packet.meta.dst_port,
+            ),
+            connector: client_name.clone(),
+            site: sniffed.clone(),
+        });
     let ctor = connector::create(conn_conf).await?;
     let observe = observe_registry.open(ConnectionMeta {
         service: "rog".to_string(),
@@ -111,15 +163,20 @@ pub async fn handle_udp_connection(
         outbound: Some(client_name.clone()),
         source: udp_endpoint_for_observe(&udp_packet.meta.src_addr, udp_packet.meta.src_port),
         destination: udp_endpoint_for_observe(&udp_packet.meta.dst_addr, udp_packet.meta.dst_port),
-        site: None,
+        site: sniffed,
     });
-    let (mut udp_tunnel_reader, udp_tunnal_writer) = ctor
-        .udp_tunnel(format!(
+    let connect_start = Instant::now();
+    let tunnel = timeouts::bounded(
+        timeouts.connect,
+        Kind::Connect,
+        ctor.udp_tunnel(format!(
             "{}:{}",
             udp_packet.meta.src_addr, udp_packet.meta.src_port,
-        ))
-        .await?
-        .unwrap();
+        )),
+    )
+    .await;
+    health::record(&client_name, &tunnel, connect_start.elapsed());
+    let (mut udp_tunnel_reader, udp_tunnal_writer) = tunnel?.unwrap();
     let first_packet_len = udp_packet.data.len() as u64;
     let t_res = udp_tunnal_writer.write(udp_packet).await;
     if t_res.is_err() {
@@ -131,11 +188,18 @@ pub async fn handle_udp_connection(
         return Err(t_res.err().unwrap());
     }
     observe.add_tx(first_packet_len);
+    if let Err(e) = limits.up(first_packet_len).await {
+        warn!("udp session cut: {}", e);
+        cancel_token.cancel();
+        return Err(e);
+    }
 
     debug!("udp loop start");
+    limits.watch(&cancel_token);
 
     let token_b = cancel_token.clone();
     let observe_tx = observe.clone();
+    let limits_tx = limits.clone();
     let b: tokio::task::JoinHandle<Result<()>> = spawn(async move {
         let mut buf = [0u8; 65536];
         loop {
@@ -161,7 +225,7 @@ pub async fn handle_udp_connection(
                         n,
                         &buf[..n]
                     );
-                    let udp_packet = match UDPPacket::parse(&buf[..n], src_addr) {
+                    let mut udp_packet = match UDPPacket::parse(&buf[..n], src_addr) {
                         Ok(p) => p,
                         Err(e) => {
                             warn!("udp loop b parse error {:?}", e);
@@ -172,6 +236,9 @@ pub async fn handle_udp_connection(
                         warn!("udp drop");
                         continue;
                     }
+                    if let Some(fake_ip) = &fake_ip {
+                        fake_ip.restore_dst(&mut udp_packet.meta);
+                    }
 
                     debug!("udp server get udp_packet {:?}", &udp_packet);
                     let packet_len = udp_packet.data.len() as u64;
@@ -180,6 +247,10 @@ pub async fn handle_udp_connection(
                         break;
                     }
                     observe_tx.add_tx(packet_len);
+                    if let Err(e) = limits_tx.up(packet_len).await {
+                        warn!("udp session cut: {}", e);
+                        break;
+                    }
                 }
             }
         }
@@ -225,6 +296,10 @@ pub async fn handle_udp_connection(
                         }
                     }
                     observe_rx.add_rx(packet_len);
+                    if let Err(e) = limits.down(packet_len).await {
+                        warn!("udp session cut: {}", e);
+                        break;
+                    }
                 }
             }
         }
@@ -0,0 +1,342 @@
+//! Traffic quotas (`[[quota]]`) on users, listeners and connectors. Usage is
+//! counted per UTC day or month and saved to `quota_state` every minute.
+
+use crate::def::config;
+use crate::util::parse::parse_size;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::time::sleep;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    /// The UTC day ("2024-05-31") or month ("2024-05") `secs` falls in.
+    fn key(self, secs: u64) -> String {
+        let (y, m, d) = civil_date((secs / 86400) as i64);
+        match self {
+            Period::Day => format!("{:04}-{:02}-{:02}", y, m, d),
+            Period::Month => format!("{:04}-{:02}", y, m),
+        }
+    }
+}
+
+/// Year, month and day of a day count since 1970-01-01.
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    Up,
+    Down,
+    Both,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct Usage {
+    period: String,
+    up: u64,
+    down: u64,
+}
+
+struct Quota {
+    name: String,
+    user: Option<String>,
+    listener: Option<String>,
+    connector: Option<String>,
+    period: Period,
+    limit: u64,
+    direction: Direction,
+    usage: Mutex<Usage>,
+}
+
+impl Quota {
+    fn new(cfg: &config::Quota) -> io::Result<Self> {
+        let invalid = |what: &str| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("quota '{}': invalid {}", cfg.name, what),
+            )
+        };
+        let period = match cfg.period.as_str() {
+            "day" => Period::Day,
+            "month" => Period::Month,
+            _ => return Err(invalid("period, expected day or month")),
+        };
+        let direction = match cfg.direction.as_deref().unwrap_or("both") {
+            "up" => Direction::Up,
+            "down" => Direction::Down,
+            "both" => Direction::Both,
+            _ => return Err(invalid("direction, expected up, down or both")),
+        };
+        let limit = parse_size(&cfg.limit).map_err(|_| invalid("limit"))?;
+        Ok(Self {
+            name: cfg.name.clone(),
+            user: cfg.user.clone(),
+            listener: cfg.listener.clone(),
+            connector: cfg.connector.clone(),
+            period,
+            limit,
+            direction,
+            usage: Mutex::new(Usage::default()),
+        })
+    }
+
+    fn matches(&self, user: Option<&str>, listener: &str, connector: &str) -> bool {
+        self.user.as_deref().is_none_or(|u| Some(u) == user)
+            && self.listener.as_deref().is_none_or(|l| l == listener)
+            && self.connector.as_deref().is_none_or(|c| c == connector)
+    }
+
+    /// Counts traffic, failing once the limit is used up.
+    fn add(&self, up: u64, down: u64, now: u64) -> io::Result<()> {
+        let mut usage = self.usage.lock().unwrap();
+        let period = self.period.key(now);
+        if usage.period != period {
+            *usage = Usage {
+                period,
+                ..Default::default()
+            };
+        }
+        usage.up += up;
+        usage.down += down;
+        let used = match self.direction {
+            Direction::Up => usage.up,
+            Direction::Down => usage.down,
+            Direction::Both => usage.up + usage.down,
+        };
+        if used >= self.limit {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "quota '{}' exhausted ({} of {} bytes in {})",
+                    self.name, used, self.limit, usage.period
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct QuotaManager {
+    quotas: Vec<Arc<Quota>>,
+    state_path: Option<PathBuf>,
+}
+
+impl QuotaManager {
+    /// Builds the quotas and restores their usage from `state_path`.
+    pub fn new(quotas: &[config::Quota], state_path: Option<PathBuf>) -> io::Result<Self> {
+        let quotas = quotas
+            .iter()
+            .map(|q| Quota::new(q).map(Arc::new))
+            .collect::<io::Result<Vec<_>>>()?;
+        let manager = Self { quotas, state_path };
+        if let Some(path) = &manager.state_path
+            && !manager.quotas.is_empty()
+        {
+            match std::fs::read_to_string(path) {
+                Ok(state) => match toml::from_str(&state) {
+                    Ok(state) => manager.restore(state),
+                    Err(e) => warn!("ignoring quota state {}: {}", path.display(), e),
+                },
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => warn!("ignoring quota state {}: {}", path.display(), e),
+            }
+        }
+        Ok(manager)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.quotas.is_empty()
+    }
+
+    /// Meter for a new connection, `None` when no quota applies. Fails when
+    /// one of the quotas is already used up.
+    pub fn meter(
+        &self,
+        user: Option<&str>,
+        listener: &str,
+        connector: &str,
+    ) -> io::Result<Option<QuotaMeter>> {
+        let quotas: Vec<_> = self
+            .quotas
+            .iter()
+            .filter(|q| q.matches(user, listener, connector))
+            .cloned()
+            .collect();
+        if quotas.is_empty() {
+            return Ok(None);
+        }
+        let meter = QuotaMeter { quotas };
+        meter.add(0, 0)?;
+        Ok(Some(meter))
+    }
+
+    fn snapshot(&self) -> HashMap<String, Usage> {
+        self.quotas
+            .iter()
+            .map(|q| (q.name.clone(), q.usage.lock().unwrap().clone()))
+            .collect()
+    }
+
+    fn restore(&self, mut state: HashMap<String, Usage>) {
+        for quota in &self.quotas {
+            if let Some(usage) = state.remove(&quota.name) {
+                *quota.usage.lock().unwrap() = usage;
+            }
+        }
+    }
+
+    pub async fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+        let state = toml::to_string(&self.snapshot()).map_err(Error::other)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, state).await?;
+        fs::rename(&tmp, path).await
+    }
+
+    pub async fn save_task(self: Arc<Self>) {
+        if let Some(path) = &self.state_path {
+            info!("quota usage is saved to {}", path.display());
+        }
+        loop {
+            sleep(SAVE_INTERVAL).await;
+            if let Err(e) = self.save().await {
+                warn!("failed to save quota state: {}", e);
+            }
+        }
+    }
+}
+
+static MANAGER: OnceLock<Arc<QuotaManager>> = OnceLock::new();
+
+/// Installs the process wide quotas, once at startup.
+pub fn init(manager: QuotaManager) -> Arc<QuotaManager> {
+    MANAGER.get_or_init(|| Arc::new(manager)).clone()
+}
+
+/// [`QuotaManager::meter`] on the installed quotas.
+pub fn meter(
+    user: Option<&str>,
+    listener: &str,
+    connector: &str,
+) -> io::Result<Option<QuotaMeter>> {
+    match MANAGER.get() {
+        Some(manager) => manager.meter(user, listener, connector),
+        None => Ok(None),
+    }
+}
+
+/// Counts one connection's traffic against every quota that applies to it.
+#[derive(Clone)]
+pub struct QuotaMeter {
+    quotas: Vec<Arc<Quota>>,
+}
+
+impl QuotaMeter {
+    fn add(&self, up: u64, down: u64) -> io::Result<()> {
+        let now = now();
+        let mut res = Ok(());
+        for quota in &self.quotas {
+            if let Err(e) = quota.add(up, down, now)
+                && res.is_ok()
+            {
+                res = Err(e);
+            }
+        }
+        res
+    }
+
+    pub fn add_up(&self, n: u64) -> io::Result<()> {
+        self.add(n, 0)
+    }
+
+    pub fn add_down(&self, n: u64) -> io::Result<()> {
+        self.add(0, n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(direction: &str, limit: &str) -> config::Quota {
+        config::Quota {
+            name: "q".to_string(),
+            user: Some("alice".to_string()),
+            listener: None,
+            connector: None,
+            period: "day".to_string(),
+            limit: limit.to_string(),
+            direction: Some(direction.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_period_key() {
+        assert_eq!(Period::Day.key(0), "1970-01-01");
+        // 2024-02-29 12:00 UTC
+        assert_eq!(Period::Day.key(1_709_208_000), "2024-02-29");
+        assert_eq!(Period::Month.key(1_709_208_000), "2024-02");
+        // 2000-12-31 23:59:59 UTC
+        assert_eq!(Period::Day.key(978_307_199), "2000-12-31");
+    }
+
+    #[test]
+    fn test_quota_limits() {
+        let q = Quota::new(&quota("up", "1k")).unwrap();
+        assert!(q.matches(Some("alice"), "in", "out"));
+        assert!(!q.matches(None, "in", "out"));
+        let day = 86_400;
+        q.add(1000, 5000, day).unwrap();
+        assert!(q.add(24, 0, day).is_err());
+        // a new day starts over
+        q.add(1000, 0, 2 * day).unwrap();
+
+        let manager = QuotaManager::new(&[quota("both", "100")], None).unwrap();
+        assert!(manager.meter(None, "in", "out").unwrap().is_none());
+        let meter = manager.meter(Some("alice"), "in", "out").unwrap().unwrap();
+        meter.add_up(60).unwrap();
+        assert!(meter.add_down(40).is_err());
+        assert!(manager.meter(Some("alice"), "in", "out").is_err());
+
+        let restored = QuotaManager::new(&[quota("both", "100")], None).unwrap();
+        restored.restore(manager.snapshot());
+        assert_eq!(restored.snapshot(), manager.snapshot());
+        let state = toml::to_string(&manager.snapshot()).unwrap();
+        assert_eq!(
+            toml::from_str::<HashMap<String, Usage>>(&state).unwrap(),
+            manager.snapshot()
+        );
+    }
+}
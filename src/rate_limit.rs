@@ -0,0 +1,283 @@
+//! Bandwidth limits (`[[rate_limit]]`) as token buckets on users, listeners,
+//! connectors or single source IPs, separately for upload and download.
+
+use crate::def::config;
+use crate::util::parse::parse_size;
+use dashmap::DashMap;
+use std::io::{self, Error, ErrorKind};
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant, sleep};
+
+/// Per source buckets are dropped once unused and the map grows past this.
+const MAX_IDLE_SOURCES: usize = 1024;
+
+/// Waiters queue on the mutex in FIFO order, so connections sharing a bucket
+/// take turns chunk by chunk.
+struct Bucket {
+    /// Bytes per second.
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    async fn take(&self, n: usize) {
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        let (tokens, last) = *state;
+        let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.rate).min(self.burst)
+            - n as f64;
+        *state = (tokens, now);
+        if tokens < 0.0 {
+            // hold the lock so later callers queue behind this one
+            sleep(Duration::from_secs_f64(-tokens / self.rate)).await;
+        }
+    }
+}
+
+/// Up and down buckets of one scope.
+struct Pair {
+    up: Option<Arc<Bucket>>,
+    down: Option<Arc<Bucket>>,
+}
+
+impl Pair {
+    fn new(up: Option<u64>, down: Option<u64>, burst: Option<u64>) -> Self {
+        let bucket = |rate: u64| Arc::new(Bucket::new(rate, burst.unwrap_or(rate).max(1)));
+        Self {
+            up: up.map(bucket),
+            down: down.map(bucket),
+        }
+    }
+
+    /// Throttles hold the buckets, not the pair.
+    fn in_use(&self) -> bool {
+        [&self.up, &self.down]
+            .into_iter()
+            .flatten()
+            .any(|b| Arc::strong_count(b) > 1)
+    }
+}
+
+struct Limit {
+    user: Option<String>,
+    listener: Option<String>,
+    connector: Option<String>,
+    up: Option<u64>,
+    down: Option<u64>,
+    burst: Option<u64>,
+    /// One pair per source IP instead of one shared pair.
+    per_source: bool,
+    shared: Arc<Pair>,
+    sources: DashMap<IpAddr, Arc<Pair>>,
+}
+
+impl Limit {
+    fn new(cfg: &config::RateLimit) -> io::Result<Self> {
+        let size = |value: &Option<String>, what: &str| -> io::Result<Option<u64>> {
+            match value {
+                Some(v) => match parse_size(v) {
+                    Ok(n) if n > 0 => Ok(Some(n)),
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("rate_limit '{}': invalid {}", cfg.name, what),
+                    )),
+                },
+                None => Ok(None),
+            }
+        };
+        let up = size(&cfg.up, "up")?;
+        let down = size(&cfg.down, "down")?;
+        let burst = size(&cfg.burst, "burst")?;
+        Ok(Self {
+            user: cfg.user.clone(),
+            listener: cfg.listener.clone(),
+            connector: cfg.connector.clone(),
+            up,
+            down,
+            burst,
+            per_source: cfg.per_source.unwrap_or(false),
+            shared: Arc::new(Pair::new(up, down, burst)),
+            sources: DashMap::new(),
+        })
+    }
+
+    fn matches(&self, user: Option<&str>, listener: &str, connector: &str) -> bool {
+        self.user.as_deref().is_none_or(|u| Some(u) == user)
+            && self.listener.as_deref().is_none_or(|l| l == listener)
+            && self.connector.as_deref().is_none_or(|c| c == connector)
+    }
+
+    fn buckets(&self, source: Option<IpAddr>) -> (Option<Arc<Bucket>>, Option<Arc<Bucket>>) {
+        let pair = match source {
+            Some(ip) if self.per_source => {
+                if self.sources.len() > MAX_IDLE_SOURCES {
+                    self.sources.retain(|_, p| p.in_use());
+                }
+                self.sources
+                    .entry(ip)
+                    .or_insert_with(|| Arc::new(Pair::new(self.up, self.down, self.burst)))
+                    .clone()
+            }
+            _ => self.shared.clone(),
+        };
+        (pair.up.clone(), pair.down.clone())
+    }
+}
+
+pub struct RateLimiter {
+    limits: Vec<Limit>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &[config::RateLimit]) -> io::Result<Self> {
+        let limits = limits
+            .iter()
+            .map(Limit::new)
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self { limits })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    /// Throttle for a new connection, `None` when no limit applies.
+    pub fn throttle(
+        &self,
+        user: Option<&str>,
+        listener: &str,
+        connector: &str,
+        source: Option<IpAddr>,
+    ) -> Option<Throttle> {
+        let mut throttle = Throttle::default();
+        for limit in self
+            .limits
+            .iter()
+            .filter(|l| l.matches(user, listener, connector))
+        {
+            let (up, down) = limit.buckets(source);
+            throttle.up.extend(up);
+            throttle.down.extend(down);
+        }
+        if throttle.up.is_empty() && throttle.down.is_empty() {
+            None
+        } else {
+            Some(throttle)
+        }
+    }
+}
+
+static LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+/// Installs the process wide limits, once at startup.
+pub fn init(limiter: RateLimiter) {
+    let _ = LIMITER.set(limiter);
+}
+
+/// [`RateLimiter::throttle`] on the installed limits.
+pub fn throttle(
+    user: Option<&str>,
+    listener: &str,
+    connector: &str,
+    source: Option<IpAddr>,
+) -> Option<Throttle> {
+    LIMITER
+        .get()
+        .and_then(|l| l.throttle(user, listener, connector, source))
+}
+
+/// The buckets one connection draws from; every one of them has to allow a chunk.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    up: Vec<Arc<Bucket>>,
+    down: Vec<Arc<Bucket>>,
+}
+
+impl Throttle {
+    pub async fn up(&self, n: usize) {
+        for bucket in &self.up {
+            bucket.take(n).await;
+        }
+    }
+
+    pub async fn down(&self, n: usize) {
+        for bucket in &self.down {
+            bucket.take(n).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(per_source: bool) -> config::RateLimit {
+        config::RateLimit {
+            name: "l".to_string(),
+            user: None,
+            listener: Some("in".to_string()),
+            connector: None,
+            per_source: Some(per_source),
+            up: Some("100k".to_string()),
+            down: None,
+            burst: Some("1k".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bucket_rate() {
+        let limiter = RateLimiter::new(&[limit(false)]).unwrap();
+        assert!(limiter.throttle(None, "other", "out", None).is_none());
+        let throttle = limiter.throttle(None, "in", "out", None).unwrap();
+        let start = Instant::now();
+        // the burst passes at once
+        throttle.up(1024).await;
+        throttle.down(1 << 20).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        // then 100k per second, shared by every connection of the bucket
+        let other = limiter.throttle(None, "in", "out", None).unwrap();
+        throttle.up(10 * 1024).await;
+        other.up(10 * 1024).await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_per_source() {
+        let limiter = RateLimiter::new(&[limit(true)]).unwrap();
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let t1 = limiter.throttle(None, "in", "out", Some(a)).unwrap();
+        let t2 = limiter.throttle(None, "in", "out", Some(a)).unwrap();
+        let t3 = limiter.throttle(None, "in", "out", Some(b)).unwrap();
+        assert!(Arc::ptr_eq(&t1.up[0], &t2.up[0]));
+        assert!(!Arc::ptr_eq(&t1.up[0], &t3.up[0]));
+        assert!(t1.down.is_empty());
+    }
+
+    #[test]
+    fn test_per_source_prune_keeps_active() {
+        let limiter = RateLimiter::new(&[limit(true)]).unwrap();
+        let held: IpAddr = "10.0.0.1".parse().unwrap();
+        let throttle = limiter.throttle(None, "in", "out", Some(held)).unwrap();
+        for i in 0..2 * MAX_IDLE_SOURCES as u32 {
+            let ip = IpAddr::from(std::net::Ipv4Addr::from(0x0b00_0000 + i));
+            limiter.throttle(None, "in", "out", Some(ip));
+        }
+        let sources = &limiter.limits[0].sources;
+        assert!(sources.len() <= MAX_IDLE_SOURCES + 1);
+        let again = limiter.throttle(None, "in", "out", Some(held)).unwrap();
+        assert!(Arc::ptr_eq(&throttle.up[0], &again.up[0]));
+    }
+}
@@ -7,9 +7,8 @@ mod test;
 
 use crate::def;
 use crate::def::config::{RouteData, Router};
-use crate::router::data::load_route_data;
+use crate::router::data::{DataInfo, RouteDataSet};
 use crate::router::default_router::DefaultBaseRouter;
-use crate::router::matcher::Matcher;
 use crate::router::resolver::Resolver;
 use crate::util::RunAddr;
 use std::collections::HashMap;
@@ -18,7 +17,7 @@ use std::sync::Arc;
 // #[derive(Clone)]
 pub struct DefaultRouter {
     router_map: HashMap<String, DefaultBaseRouter>,
-    data_map: Arc<HashMap<String, Box<dyn Matcher>>>,
+    data: Arc<RouteDataSet>,
 }
 
 #[async_trait::async_trait]
@@ -50,21 +49,31 @@ impl def::RouterSet for DefaultRouter {
 
 impl DefaultRouter {
     pub async fn new(cfg: &[Router], data_cfg: &[RouteData], resolver: Arc<Resolver>) -> Self {
-        let data_map = Arc::new(load_route_data(data_cfg).await);
+        let data = Arc::new(RouteDataSet::load(data_cfg).await);
         let mut router_set = DefaultRouter {
             router_map: HashMap::new(),
-            data_map: data_map.clone(),
+            data: data.clone(),
         };
         for r in cfg {
             let router = DefaultBaseRouter::new(
                 r.name.clone(),
                 r.default.clone(),
                 r.route_rules.clone().unwrap_or(vec![]),
-                data_map.clone(),
+                data.clone(),
                 resolver.clone(),
             );
             router_set.router_map.insert(r.name.clone(), router);
         }
         router_set
     }
+
+    /// The `[[data]]` sets with their sizes and load times.
+    pub fn data_info(&self) -> Vec<DataInfo> {
+        self.data.info()
+    }
+
+    /// Reloads the `[[data]]` sets from their sources.
+    pub async fn refresh_data(&self) -> Vec<DataInfo> {
+        self.data.refresh().await
+    }
 }
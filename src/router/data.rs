@@ -2,13 +2,16 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::def::config;
 use crate::router::consts;
 use crate::router::consts::FORMAT_LAN;
 use crate::router::matcher::{Matcher, get_matcher_factory_fn};
 use reqwest::Url;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct InnerRouteData {
@@ -18,55 +21,153 @@ pub struct InnerRouteData {
     pub format: String,
 }
 
-pub async fn load_route_data(data_cfg: &[config::RouteData]) -> HashMap<String, Box<dyn Matcher>> {
-    let mut data_map: HashMap<String, Box<dyn Matcher>> = HashMap::new();
-    for rd_cfg in data_cfg {
-        let rd_cfg = &rd_cfg;
-        let mut rd = InnerRouteData {
-            name: rd_cfg.name.clone(),
-            data: Vec::new(),
-            lines: Vec::new(),
-            format: rd_cfg.format.clone(),
+/// What the admin API shows of one `[[data]]` set.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DataInfo {
+    pub name: String,
+    pub format: String,
+    /// Lines of the data in use.
+    pub entries: usize,
+    /// Unix seconds of the load of the data in use.
+    pub loaded: Option<u64>,
+    /// Why the last load failed.
+    pub error: Option<String>,
+}
+
+pub type DataMap = HashMap<String, Arc<dyn Matcher>>;
+
+struct Loaded {
+    map: Arc<DataMap>,
+    info: Vec<DataInfo>,
+}
+
+/// The `[[data]]` sets, reloadable while the routers use them.
+pub struct RouteDataSet {
+    cfg: Vec<config::RouteData>,
+    loaded: RwLock<Loaded>,
+    refreshing: Mutex<()>,
+}
+
+impl RouteDataSet {
+    pub async fn load(data_cfg: &[config::RouteData]) -> Self {
+        let set = RouteDataSet {
+            cfg: data_cfg.to_vec(),
+            loaded: RwLock::new(Loaded {
+                map: Arc::new(HashMap::new()),
+                info: Vec::new(),
+            }),
+            refreshing: Mutex::new(()),
         };
+        set.refresh().await;
+        set
+    }
 
-        if rd.format == FORMAT_LAN {
-            rd.lines = vec![
-                "10.0.0.0/8".to_string(),
-                "172.16.0.0/12".to_string(),
-                "192.168.0.0/16".to_string(),
-                "127.0.0.0/8".to_string(),
-            ];
-            rd.format = consts::FORMAT_CIDR.to_string();
-        } else if rd_cfg.url.is_some() {
-            if let Err(e) =
-                load_data_from_source(&mut rd, rd_cfg.url.as_ref().unwrap().as_str()).await
-            {
-                log::warn!(
-                    "Error loading data for '{}' from '{}': {:?}",
-                    rd_cfg.name,
-                    rd_cfg.url.as_ref().unwrap(),
-                    e
-                );
-                continue;
+    pub fn matchers(&self) -> Arc<DataMap> {
+        self.loaded.read().unwrap().map.clone()
+    }
+
+    pub fn info(&self) -> Vec<DataInfo> {
+        self.loaded.read().unwrap().info.clone()
+    }
+
+    /// Loads every set again. A set that fails keeps its previous data.
+    pub async fn refresh(&self) -> Vec<DataInfo> {
+        let _refreshing = self.refreshing.lock().await;
+        let (old_map, old_info) = {
+            let loaded = self.loaded.read().unwrap();
+            (loaded.map.clone(), loaded.info.clone())
+        };
+        let mut data_map = DataMap::new();
+        let mut info = Vec::new();
+        for rd_cfg in &self.cfg {
+            let mut data_info = DataInfo {
+                name: rd_cfg.name.clone(),
+                format: rd_cfg.format.clone(),
+                entries: 0,
+                loaded: None,
+                error: None,
+            };
+            match load_one(rd_cfg).await {
+                Ok((matcher, entries)) => {
+                    data_map.insert(rd_cfg.name.clone(), matcher);
+                    data_info.entries = entries;
+                    data_info.loaded = Some(now());
+                }
+                Err(e) => {
+                    log::warn!("{}", e);
+                    if let Some(matcher) = old_map.get(&rd_cfg.name) {
+                        data_map.insert(rd_cfg.name.clone(), matcher.clone());
+                        if let Some(old) = old_info.iter().find(|i| i.name == rd_cfg.name) {
+                            data_info.entries = old.entries;
+                            data_info.loaded = old.loaded;
+                        }
+                    }
+                    data_info.error = Some(e);
+                }
             }
-        } else if rd_cfg.data.is_some() {
-            rd.lines = rd_cfg
-                .data
-                .as_ref()
-                .unwrap()
-                .split("\n")
-                .filter(|s| !s.trim().is_empty())
-                .map(|s| s.to_string())
-                .collect();
-        } else {
-            log::warn!("No data source provided for route data '{}'", rd_cfg.name);
-            continue;
-        }
-        if let Some(factory) = get_matcher_factory_fn(&rd.format) {
-            data_map.insert(rd_cfg.name.clone(), factory(rd.lines, rd.data));
+            info.push(data_info);
         }
+        *self.loaded.write().unwrap() = Loaded {
+            map: Arc::new(data_map),
+            info: info.clone(),
+        };
+        info
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The matcher of one set and its number of lines.
+async fn load_one(rd_cfg: &config::RouteData) -> Result<(Arc<dyn Matcher>, usize), String> {
+    let mut rd = InnerRouteData {
+        name: rd_cfg.name.clone(),
+        data: Vec::new(),
+        lines: Vec::new(),
+        format: rd_cfg.format.clone(),
+    };
+
+    if rd.format == FORMAT_LAN {
+        rd.lines = vec![
+            "10.0.0.0/8".to_string(),
+            "172.16.0.0/12".to_string(),
+            "192.168.0.0/16".to_string(),
+            "127.0.0.0/8".to_string(),
+        ];
+        rd.format = consts::FORMAT_CIDR.to_string();
+    } else if let Some(url) = &rd_cfg.url {
+        load_data_from_source(&mut rd, url.as_str())
+            .await
+            .map_err(|e| {
+                format!(
+                    "Error loading data for '{}' from '{}': {}",
+                    rd_cfg.name, url, e
+                )
+            })?;
+    } else if let Some(data) = &rd_cfg.data {
+        rd.lines = data
+            .split("\n")
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.to_string())
+            .collect();
+    } else {
+        return Err(format!(
+            "No data source provided for route data '{}'",
+            rd_cfg.name
+        ));
     }
-    data_map
+    let factory = get_matcher_factory_fn(&rd.format).ok_or_else(|| {
+        format!(
+            "Unknown format '{}' of route data '{}'",
+            rd.format, rd_cfg.name
+        )
+    })?;
+    let entries = rd.lines.len();
+    Ok((Arc::from(factory(rd.lines, rd.data)), entries))
 }
 
 async fn load_data_from_source(rd: &mut InnerRouteData, source_url: &str) -> Result<(), String> {
@@ -1,17 +1,16 @@
 use crate::def::config::RouteRule;
-use crate::router::matcher::Matcher;
+use crate::router::data::RouteDataSet;
 use crate::router::matcher::util::ExcludeMatcher;
 use crate::router::resolver::Resolver;
 use crate::util::RunAddr;
 use log::warn;
-use std::collections::HashMap;
 use std::sync::Arc;
 
 pub struct DefaultBaseRouter {
     name: String,
     default_tag: String,
     rules: Vec<CompiledRouteRule>,
-    data_map: Arc<HashMap<String, Box<dyn Matcher>>>,
+    data: Arc<RouteDataSet>,
     resolver: Arc<Resolver>,
 }
 
@@ -40,7 +39,7 @@ impl DefaultBaseRouter {
         name: String,
         default_tag: String,
         rules: Vec<RouteRule>,
-        data_map: Arc<HashMap<String, Box<dyn Matcher>>>,
+        data: Arc<RouteDataSet>,
         resolver: Arc<Resolver>,
     ) -> Self {
         let rules = rules.into_iter().map(CompiledRouteRule::new).collect();
@@ -48,12 +47,13 @@ impl DefaultBaseRouter {
             name,
             default_tag,
             rules,
-            data_map,
+            data,
             resolver,
         }
     }
 
     pub(crate) async fn route(&self, addr: &RunAddr) -> (String, String) {
+        let data_map = self.data.matchers();
         for rule in &self.rules {
             let mut hosts = vec![addr.addr.clone().to_string()];
             if rule.domain_to_ip {
@@ -73,7 +73,7 @@ impl DefaultBaseRouter {
                 }
             }
             for host in &hosts {
-                if let Some(data) = self.data_map.get(&rule.name) {
+                if let Some(data) = data_map.get(&rule.name) {
                     if rule.exclude.is_match(host) {
                         continue;
                     }
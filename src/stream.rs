@@ -11,4 +11,49 @@ pub(crate) mod rev_grpc_server;
 pub(crate) mod rev_grpc_udp_client;
 pub(crate) mod rev_grpc_udp_server;
 pub(crate) mod tcp;
+pub(crate) mod tls;
 pub(crate) mod udp;
+
+use crate::consts::TCP_IO_BUFFER_SIZE;
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Chunks read from a socket are carved out of one allocation, which is
+/// reused once the relay has dropped all of them.
+const CHUNK_ARENA_SIZE: usize = 16 * TCP_IO_BUFFER_SIZE;
+
+/// Reads up to `TCP_IO_BUFFER_SIZE` bytes from `reader` into `arena`. The
+/// arena is allocated on the first read, halves that are never read from
+/// don't hold one.
+pub(crate) async fn read_chunk_into<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    arena: &mut BytesMut,
+) -> std::io::Result<Bytes> {
+    if arena.capacity() < TCP_IO_BUFFER_SIZE {
+        arena.reserve(CHUNK_ARENA_SIZE);
+    }
+    reader
+        .take(TCP_IO_BUFFER_SIZE as u64)
+        .read_buf(arena)
+        .await?;
+    Ok(arena.split().freeze())
+}
+
+/// What a stream frame hands to the reader: empty at EOF, `None` for an empty
+/// frame that doesn't end the stream. The `eof` marker decides when the peer
+/// sets it; peers that predate it end with an empty payload.
+pub(crate) fn frame_payload(payload: Bytes, eof: Option<bool>) -> Option<Bytes> {
+    match eof {
+        Some(true) => Some(Bytes::new()),
+        Some(false) if payload.is_empty() => None,
+        _ => Some(payload),
+    }
+}
+
+/// Copies the front of `cache` into `buf` and keeps the rest for the next read.
+pub(crate) fn drain_cache(cache: &mut Bytes, buf: &mut [u8]) -> usize {
+    let n = cache.len().min(buf.len());
+    buf[..n].copy_from_slice(&cache[..n]);
+    cache.advance(n);
+    n
+}
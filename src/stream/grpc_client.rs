@@ -1,5 +1,7 @@
 use crate::def::{RunReadHalf, RunStream, RunWriteHalf, StreamInfo};
 use crate::proto::v1::pb::{StreamReq, StreamRes};
+use crate::stream::{drain_cache, frame_payload};
+use bytes::Bytes;
 use futures::StreamExt;
 use std::any::Any;
 use std::io::ErrorKind;
@@ -10,8 +12,7 @@ use tonic::Streaming;
 
 pub struct GrpcClientReadHalf {
     reader: Arc<Mutex<Streaming<StreamRes>>>,
-    cache: Vec<u8>,
-    cache_pos: usize,
+    cache: Bytes,
 }
 
 pub struct GrpcClientWriteHalf {
@@ -21,56 +22,64 @@ pub struct GrpcClientWriteHalf {
 pub struct GrpcClientRunStream {
     reader: Arc<Mutex<Streaming<StreamRes>>>,
     writer: Sender<StreamReq>,
-    cache: Vec<u8>,
-    cache_pos: usize,
+    cache: Bytes,
     info: StreamInfo,
 }
 
+/// The next payload, empty once the peer half-closed.
+async fn next_payload(reader: &Mutex<Streaming<StreamRes>>) -> std::io::Result<Bytes> {
+    let mut reader = reader.lock().await;
+    loop {
+        match reader.next().await {
+            Some(Ok(data)) => {
+                if let Some(payload) = frame_payload(data.payload, data.eof) {
+                    return Ok(payload);
+                }
+            }
+            Some(Err(e)) => return Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
+            None => return Err(std::io::Error::other("no more data")),
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl RunReadHalf for GrpcClientReadHalf {
     async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.cache_pos < self.cache.len() {
-            let available = self.cache.len() - self.cache_pos;
-            let to_copy = available.min(buf.len());
-            buf[..to_copy].copy_from_slice(&self.cache[self.cache_pos..self.cache_pos + to_copy]);
-            self.cache_pos += to_copy;
-            if self.cache_pos >= self.cache.len() {
-                self.cache.clear();
-                self.cache_pos = 0;
-            }
-            return Ok(to_copy);
+        if self.cache.is_empty() {
+            self.cache = next_payload(&self.reader).await?;
         }
+        Ok(drain_cache(&mut self.cache, buf))
+    }
 
-        self.cache.clear();
-        self.cache_pos = 0;
-
-        let res = self.reader.lock().await.next().await;
-        if res.is_none() {
-            return Err(std::io::Error::other("no more data"));
-        }
-        let res = res.unwrap();
-        match res {
-            Ok(data) => {
-                if data.payload.is_empty() {
-                    return Ok(0);
-                }
-                let to_copy = buf.len().min(data.payload.len());
-                buf[..to_copy].copy_from_slice(&data.payload[..to_copy]);
-                if data.payload.len() > to_copy {
-                    self.cache.extend_from_slice(&data.payload[to_copy..]);
-                }
-                Ok(to_copy)
-            }
-            Err(e) => Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
+    async fn read_chunk(&mut self) -> std::io::Result<Bytes> {
+        if !self.cache.is_empty() {
+            return Ok(std::mem::take(&mut self.cache));
         }
+        next_payload(&self.reader).await
     }
 }
 
 #[async_trait::async_trait]
 impl RunWriteHalf for GrpcClientWriteHalf {
     async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.write_chunk(Bytes::copy_from_slice(buf)).await
+    }
+
+    async fn write_chunk(&mut self, data: Bytes) -> std::io::Result<()> {
+        let req = StreamReq {
+            payload: Some(data),
+            ..Default::default()
+        };
+        match self.writer.send(req).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
+        }
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
         let req = StreamReq {
-            payload: Some(buf.to_vec()),
+            payload: Some(Bytes::new()),
+            eof: Some(true),
             ..Default::default()
         };
         match self.writer.send(req).await {
@@ -85,8 +94,7 @@ impl GrpcClientRunStream {
         Self {
             reader,
             writer,
-            cache: Vec::new(),
-            cache_pos: 0,
+            cache: Bytes::new(),
             info: StreamInfo::default(),
         }
     }
@@ -105,8 +113,7 @@ impl RunStream for GrpcClientRunStream {
         (
             Box::new(GrpcClientReadHalf {
                 reader: Arc::clone(&self.reader),
-                cache: Vec::new(),
-                cache_pos: 0,
+                cache: self.cache,
             }),
             Box::new(GrpcClientWriteHalf {
                 writer: self.writer,
@@ -115,40 +122,10 @@ impl RunStream for GrpcClientRunStream {
     }
 
     async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.cache_pos < self.cache.len() {
-            let available = self.cache.len() - self.cache_pos;
-            let to_copy = available.min(buf.len());
-            buf[..to_copy].copy_from_slice(&self.cache[self.cache_pos..self.cache_pos + to_copy]);
-            self.cache_pos += to_copy;
-            if self.cache_pos >= self.cache.len() {
-                self.cache.clear();
-                self.cache_pos = 0;
-            }
-            return Ok(to_copy);
-        }
-
-        self.cache.clear();
-        self.cache_pos = 0;
-
-        let res = self.reader.lock().await.next().await;
-        if res.is_none() {
-            return Err(std::io::Error::other("no more data"));
-        }
-        let res = res.unwrap();
-        match res {
-            Ok(data) => {
-                if data.payload.is_empty() {
-                    return Ok(0);
-                }
-                let to_copy = buf.len().min(data.payload.len());
-                buf[..to_copy].copy_from_slice(&data.payload[..to_copy]);
-                if data.payload.len() > to_copy {
-                    self.cache.extend_from_slice(&data.payload[to_copy..]);
-                }
-                Ok(to_copy)
-            }
-            Err(e) => Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
+        if self.cache.is_empty() {
+            self.cache = next_payload(&self.reader).await?;
         }
+        Ok(drain_cache(&mut self.cache, buf))
     }
 
     fn as_any_mut(&mut self) -> &mut dyn Any {
@@ -157,7 +134,7 @@ impl RunStream for GrpcClientRunStream {
 
     async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
         let req = StreamReq {
-            payload: Some(buf.to_vec()),
+            payload: Some(Bytes::copy_from_slice(buf)),
             ..Default::default()
         };
         match self.writer.send(req).await {
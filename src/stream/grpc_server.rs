@@ -1,6 +1,8 @@
 use crate::def::{RunReadHalf, RunStream, RunWriteHalf, StreamInfo};
 use crate::proto::v1::pb::{StreamReq, StreamRes};
+use crate::stream::{drain_cache, frame_payload};
 use crate::util::RunAddr;
+use bytes::Bytes;
 use futures::StreamExt;
 use std::any::Any;
 use std::io::ErrorKind;
@@ -15,8 +17,7 @@ use tonic::{Status, Streaming};
 
 pub struct GrpcServerReadHalf {
     reader: Arc<Mutex<Streaming<StreamReq>>>,
-    cache: Vec<u8>,
-    cache_pos: usize,
+    cache: Bytes,
 }
 
 pub struct GrpcServerWriteHalf {
@@ -26,57 +27,64 @@ pub struct GrpcServerWriteHalf {
 pub struct GrpcServerRunStream {
     reader: Arc<Mutex<Streaming<StreamReq>>>,
     writer: Sender<Result<StreamRes, Status>>,
-    cache: Vec<u8>,
-    cache_pos: usize,
+    cache: Bytes,
     info: StreamInfo,
 }
 
+/// The next payload, empty once the peer half-closed.
+async fn next_payload(reader: &Mutex<Streaming<StreamReq>>) -> std::io::Result<Bytes> {
+    let mut reader = reader.lock().await;
+    loop {
+        match reader.next().await {
+            Some(Ok(data)) => {
+                if let Some(payload) = frame_payload(data.payload.unwrap_or_default(), data.eof) {
+                    return Ok(payload);
+                }
+            }
+            Some(Err(e)) => return Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
+            None => return Err(std::io::Error::other("no more data")),
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl RunReadHalf for GrpcServerReadHalf {
     async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.cache_pos < self.cache.len() {
-            let available = self.cache.len() - self.cache_pos;
-            let to_copy = available.min(buf.len());
-            buf[..to_copy].copy_from_slice(&self.cache[self.cache_pos..self.cache_pos + to_copy]);
-            self.cache_pos += to_copy;
-            if self.cache_pos >= self.cache.len() {
-                self.cache.clear();
-                self.cache_pos = 0;
-            }
-            return Ok(to_copy);
+        if self.cache.is_empty() {
+            self.cache = next_payload(&self.reader).await?;
         }
+        Ok(drain_cache(&mut self.cache, buf))
+    }
 
-        self.cache.clear();
-        self.cache_pos = 0;
-
-        let res = self.reader.lock().await.next().await;
-        if res.is_none() {
-            return Err(std::io::Error::other("no more data"));
-        }
-        let res = res.unwrap();
-        match res {
-            Ok(data) => {
-                let payload = data.payload.unwrap();
-                if payload.is_empty() {
-                    return Ok(0);
-                }
-                let to_copy = buf.len().min(payload.len());
-                buf[..to_copy].copy_from_slice(&payload[..to_copy]);
-                if payload.len() > to_copy {
-                    self.cache.extend_from_slice(&payload[to_copy..]);
-                }
-                Ok(to_copy)
-            }
-            Err(e) => Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
+    async fn read_chunk(&mut self) -> std::io::Result<Bytes> {
+        if !self.cache.is_empty() {
+            return Ok(std::mem::take(&mut self.cache));
         }
+        next_payload(&self.reader).await
     }
 }
 
 #[async_trait::async_trait]
 impl RunWriteHalf for GrpcServerWriteHalf {
     async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.write_chunk(Bytes::copy_from_slice(buf)).await
+    }
+
+    async fn write_chunk(&mut self, data: Bytes) -> std::io::Result<()> {
         let res = StreamRes {
-            payload: buf.to_vec(),
+            payload: data,
+            ..Default::default()
+        };
+        match self.writer.send(Ok(res)).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
+        }
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        let res = StreamRes {
+            eof: Some(true),
+            ..Default::default()
         };
         match self.writer.send(Ok(res)).await {
             Ok(_) => Ok(()),
@@ -93,12 +101,19 @@ impl GrpcServerRunStream {
         Self {
             reader,
             writer,
-            cache: Vec::new(),
-            cache_pos: 0,
+            cache: Bytes::new(),
             info: StreamInfo::default(),
         }
     }
 
+    /// Ends the call with `status` instead of relaying it.
+    pub async fn fail(&self, status: Status) -> std::io::Result<()> {
+        self.writer
+            .send(Err(status))
+            .await
+            .map_err(|e| std::io::Error::new(ErrorKind::Interrupted, e.to_string()))
+    }
+
     pub async fn handshake(&mut self) -> std::io::Result<Option<(RunAddr, String)>> {
         let auth = self.reader.lock().await.next().await;
         match auth {
@@ -131,8 +146,7 @@ impl RunStream for GrpcServerRunStream {
         (
             Box::new(GrpcServerReadHalf {
                 reader: Arc::clone(&self.reader),
-                cache: Vec::new(),
-                cache_pos: 0,
+                cache: self.cache,
             }),
             Box::new(GrpcServerWriteHalf {
                 writer: self.writer,
@@ -141,41 +155,10 @@ impl RunStream for GrpcServerRunStream {
     }
 
     async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.cache_pos < self.cache.len() {
-            let available = self.cache.len() - self.cache_pos;
-            let to_copy = available.min(buf.len());
-            buf[..to_copy].copy_from_slice(&self.cache[self.cache_pos..self.cache_pos + to_copy]);
-            self.cache_pos += to_copy;
-            if self.cache_pos >= self.cache.len() {
-                self.cache.clear();
-                self.cache_pos = 0;
-            }
-            return Ok(to_copy);
-        }
-
-        self.cache.clear();
-        self.cache_pos = 0;
-
-        let res = self.reader.lock().await.next().await;
-        if res.is_none() {
-            return Err(std::io::Error::other("no more data"));
-        }
-        let res = res.unwrap();
-        match res {
-            Ok(data) => {
-                let payload = data.payload.unwrap();
-                if payload.is_empty() {
-                    return Ok(0);
-                }
-                let to_copy = buf.len().min(payload.len());
-                buf[..to_copy].copy_from_slice(&payload[..to_copy]);
-                if payload.len() > to_copy {
-                    self.cache.extend_from_slice(&payload[to_copy..]);
-                }
-                Ok(to_copy)
-            }
-            Err(e) => Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
+        if self.cache.is_empty() {
+            self.cache = next_payload(&self.reader).await?;
         }
+        Ok(drain_cache(&mut self.cache, buf))
     }
 
     fn as_any_mut(&mut self) -> &mut dyn Any {
@@ -184,7 +167,8 @@ impl RunStream for GrpcServerRunStream {
 
     async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
         let res = StreamRes {
-            payload: buf.to_vec(),
+            payload: Bytes::copy_from_slice(buf),
+            ..Default::default()
         };
         match self.writer.send(Ok(res)).await {
             Ok(_) => Ok(()),
@@ -1,21 +1,39 @@
-use crate::def::{RunUdpReader, RunUdpWriter, UDPPacket};
+use crate::def::{Rejection, RunUdpReader, RunUdpWriter, UDPPacket};
+use crate::listener::grpc::rejection_status;
 use crate::proto::v1::pb::{UdpReq, UdpRes};
+use crate::util::auth_token::{TokenVerifier, ct_eq};
+use crate::util::users::UserTable;
 use futures::StreamExt;
 use std::io::ErrorKind;
+use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 use tonic::{Status, Streaming};
 // empty stream
 pub struct GrpcUdpServerReadHalf {
     reader: Streaming<UdpReq>,
-    auth: String,
+    users: Arc<UserTable>,
+    verifier: Arc<TokenVerifier>,
+    /// Token accepted on the first packet; later packets must repeat it.
+    session: Option<String>,
+    user: Option<String>,
 }
 
 pub struct GrpcUdpServerWriteHalf {
     writer: Sender<Result<UdpRes, Status>>,
 }
 impl GrpcUdpServerReadHalf {
-    pub fn new(reader: Streaming<UdpReq>, auth: String) -> Self {
-        Self { reader, auth }
+    pub fn new(
+        reader: Streaming<UdpReq>,
+        users: Arc<UserTable>,
+        verifier: Arc<TokenVerifier>,
+    ) -> Self {
+        Self {
+            reader,
+            users,
+            verifier,
+            session: None,
+            user: None,
+        }
     }
 }
 
@@ -55,8 +73,17 @@ impl RunUdpReader for GrpcUdpServerReadHalf {
         let res = res.unwrap();
         match res {
             Ok(data) => {
-                if self.auth != data.auth {
-                    return Err(std::io::Error::other("auth mismatch"));
+                match &self.session {
+                    Some(session) if ct_eq(session, &data.auth) => {}
+                    Some(_) => return Err(std::io::Error::other("auth mismatch")),
+                    None => {
+                        let cred = self
+                            .users
+                            .find(|c| self.verifier.verify(&c.pw, &data.auth, "udp").is_ok())
+                            .ok_or_else(|| std::io::Error::other("auth mismatch"))?;
+                        self.user = cred.user.clone();
+                        self.session = Some(data.auth.clone());
+                    }
                 }
                 // let n = data.payload.as_ref().unwrap().len();
                 // buf[..n].copy_from_slice(data.payload.as_ref().unwrap());
@@ -66,6 +93,10 @@ impl RunUdpReader for GrpcUdpServerReadHalf {
             Err(e) => Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
         }
     }
+
+    fn user(&self) -> Option<String> {
+        self.user.clone()
+    }
 }
 
 #[async_trait::async_trait]
@@ -86,4 +117,25 @@ impl RunUdpWriter for GrpcUdpServerWriteHalf {
             Err(e) => Err(std::io::Error::new(ErrorKind::Interrupted, e.to_string())),
         }
     }
+
+    async fn reject(&mut self, rejection: Rejection) -> std::io::Result<()> {
+        self.writer
+            .send(Err(rejection_status(rejection)))
+            .await
+            .map_err(|e| std::io::Error::new(ErrorKind::Interrupted, e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reject() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let mut writer = GrpcUdpServerWriteHalf::new(tx);
+        writer.reject(Rejection::Overloaded).await.unwrap();
+        let status = rx.recv().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
 }
@@ -1,121 +1,143 @@
 use crate::def::{RunReadHalf, RunStream, RunWriteHalf, StreamInfo};
 use crate::proto::v1::pb::{StreamReq, StreamRes};
+use crate::stream::{drain_cache, frame_payload};
 use crate::util::crypto::{decrypt_bytes, encrypt_bytes};
-use crate::util::tcp_frame::{read_msg, write_frame};
+use crate::util::tcp_frame::{FramePadding, FrameReader, FrameWriter, read_msg, write_frame};
+use bytes::Bytes;
 use std::any::Any;
 use std::sync::Arc;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::Mutex;
 
 pub struct PbTcpClientReadHalf {
-    reader: Arc<Mutex<OwnedReadHalf>>,
-    cache: Vec<u8>,
-    cache_pos: usize,
+    reader: Arc<Mutex<FrameReader>>,
+    cache: Bytes,
     pw: String,
     encrypt: bool,
 }
 
 pub struct PbTcpClientWriteHalf {
-    writer: Arc<Mutex<OwnedWriteHalf>>,
+    writer: Arc<Mutex<FrameWriter>>,
     pw: String,
     encrypt: bool,
+    padding: FramePadding,
 }
 
 pub struct PbTcpClientRunStream {
-    reader: Arc<Mutex<OwnedReadHalf>>,
-    writer: Arc<Mutex<OwnedWriteHalf>>,
-    cache: Vec<u8>,
-    cache_pos: usize,
+    reader: Arc<Mutex<FrameReader>>,
+    writer: Arc<Mutex<FrameWriter>>,
+    cache: Bytes,
     info: StreamInfo,
     pw: String,
     encrypt: bool,
+    padding: FramePadding,
 }
 
 impl PbTcpClientRunStream {
-    pub fn new(reader: OwnedReadHalf, writer: OwnedWriteHalf, pw: String, encrypt: bool) -> Self {
+    pub fn new(
+        reader: FrameReader,
+        writer: FrameWriter,
+        pw: String,
+        encrypt: bool,
+        padding: FramePadding,
+    ) -> Self {
         Self {
             reader: Arc::new(Mutex::new(reader)),
             writer: Arc::new(Mutex::new(writer)),
-            cache: Vec::new(),
-            cache_pos: 0,
+            cache: Bytes::new(),
             info: StreamInfo::default(),
             pw,
             encrypt,
+            padding,
         }
     }
 }
 
-async fn read_payload(
-    reader: &Arc<Mutex<OwnedReadHalf>>,
-    cache: &mut Vec<u8>,
-    cache_pos: &mut usize,
-    buf: &mut [u8],
+/// The next payload, decrypted, empty once the peer half-closed.
+async fn next_payload(
+    reader: &Mutex<FrameReader>,
     pw: &str,
     encrypt: bool,
-) -> std::io::Result<usize> {
-    if *cache_pos < cache.len() {
-        let available = cache.len() - *cache_pos;
-        let to_copy = available.min(buf.len());
-        buf[..to_copy].copy_from_slice(&cache[*cache_pos..*cache_pos + to_copy]);
-        *cache_pos += to_copy;
-        if *cache_pos >= cache.len() {
-            cache.clear();
-            *cache_pos = 0;
+) -> std::io::Result<Bytes> {
+    let mut r = reader.lock().await;
+    loop {
+        let msg: StreamRes = read_msg(&mut *r).await?;
+        let Some(payload) = frame_payload(msg.payload, msg.eof) else {
+            continue;
+        };
+        if payload.is_empty() || !encrypt {
+            return Ok(payload);
+        }
+        let payload = decrypt_bytes(&payload, pw)?;
+        // an encrypted empty write isn't an eof either
+        if !payload.is_empty() {
+            return Ok(payload.into());
         }
-        return Ok(to_copy);
     }
+}
 
-    cache.clear();
-    *cache_pos = 0;
-
-    let mut r = reader.lock().await;
-    let msg: StreamRes = read_msg(&mut *r).await?;
-    let mut payload = msg.payload;
-    if payload.is_empty() {
-        return Ok(0);
-    }
+async fn send_payload(
+    writer: &Mutex<FrameWriter>,
+    data: Bytes,
+    pw: &str,
+    encrypt: bool,
+    padding: FramePadding,
+) -> std::io::Result<()> {
+    let payload = if encrypt {
+        encrypt_bytes(&data, pw)?.into()
+    } else {
+        data
+    };
+    let req = StreamReq {
+        payload: Some(payload),
+        ..Default::default()
+    };
+    let mut w = writer.lock().await;
+    write_frame(&mut *w, &req, padding).await
+}
 
-    if encrypt {
-        payload = decrypt_bytes(&payload, pw)?;
+#[async_trait::async_trait]
+impl RunReadHalf for PbTcpClientReadHalf {
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cache.is_empty() {
+            self.cache = next_payload(&self.reader, &self.pw, self.encrypt).await?;
+        }
+        Ok(drain_cache(&mut self.cache, buf))
     }
 
-    let to_copy = buf.len().min(payload.len());
-    buf[..to_copy].copy_from_slice(&payload[..to_copy]);
-    if payload.len() > to_copy {
-        cache.extend_from_slice(&payload[to_copy..]);
+    async fn read_chunk(&mut self) -> std::io::Result<Bytes> {
+        if !self.cache.is_empty() {
+            return Ok(std::mem::take(&mut self.cache));
+        }
+        next_payload(&self.reader, &self.pw, self.encrypt).await
     }
-    Ok(to_copy)
 }
 
 #[async_trait::async_trait]
-impl RunReadHalf for PbTcpClientReadHalf {
-    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        read_payload(
-            &self.reader,
-            &mut self.cache,
-            &mut self.cache_pos,
-            buf,
+impl RunWriteHalf for PbTcpClientWriteHalf {
+    async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        send_payload(
+            &self.writer,
+            Bytes::copy_from_slice(buf),
             &self.pw,
             self.encrypt,
+            self.padding,
         )
         .await
     }
-}
 
-#[async_trait::async_trait]
-impl RunWriteHalf for PbTcpClientWriteHalf {
-    async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        let payload = if self.encrypt {
-            encrypt_bytes(buf, &self.pw)?
-        } else {
-            buf.to_vec()
-        };
+    async fn write_chunk(&mut self, data: Bytes) -> std::io::Result<()> {
+        send_payload(&self.writer, data, &self.pw, self.encrypt, self.padding).await
+    }
+
+    /// Sends an empty eof frame, unencrypted like every empty payload.
+    async fn shutdown(&mut self) -> std::io::Result<()> {
         let req = StreamReq {
-            payload: Some(payload),
+            payload: Some(Bytes::new()),
+            eof: Some(true),
             ..Default::default()
         };
         let mut w = self.writer.lock().await;
-        write_frame(&mut *w, &req).await
+        write_frame(&mut *w, &req, self.padding).await
     }
 }
 
@@ -133,8 +155,7 @@ impl RunStream for PbTcpClientRunStream {
         (
             Box::new(PbTcpClientReadHalf {
                 reader: Arc::clone(&self.reader),
-                cache: Vec::new(),
-                cache_pos: 0,
+                cache: self.cache,
                 pw: self.pw.clone(),
                 encrypt: self.encrypt,
             }),
@@ -142,37 +163,99 @@ impl RunStream for PbTcpClientRunStream {
                 writer: Arc::clone(&self.writer),
                 pw: self.pw.clone(),
                 encrypt: self.encrypt,
+                padding: self.padding,
             }),
         )
     }
 
     async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        read_payload(
-            &self.reader,
-            &mut self.cache,
-            &mut self.cache_pos,
-            buf,
+        if self.cache.is_empty() {
+            self.cache = next_payload(&self.reader, &self.pw, self.encrypt).await?;
+        }
+        Ok(drain_cache(&mut self.cache, buf))
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        send_payload(
+            &self.writer,
+            Bytes::copy_from_slice(buf),
             &self.pw,
             self.encrypt,
+            self.padding,
         )
         .await
     }
+}
 
-    fn as_any_mut(&mut self) -> &mut dyn Any {
-        self
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::pb_tcp_server::PbTcpServerRunStream;
+
+    #[tokio::test]
+    async fn test_cache_kept_across_split() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (ar, aw) = tokio::io::split(a);
+        let (br, bw) = tokio::io::split(b);
+        let mut client = PbTcpClientRunStream::new(
+            Box::new(ar),
+            Box::new(aw),
+            String::new(),
+            false,
+            FramePadding::default(),
+        );
+        let mut server =
+            PbTcpServerRunStream::new(Box::new(br), Box::new(bw), FramePadding::default());
+        server.write(b"hello world").await.unwrap();
+
+        // a short read leaves the rest of the payload cached
+        let mut buf = [0u8; 5];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+        let (mut reader, _writer) = Box::new(client).split();
+        assert_eq!(reader.read_chunk().await.unwrap(), " world");
     }
 
-    async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        let payload = if self.encrypt {
-            encrypt_bytes(buf, &self.pw)?
-        } else {
-            buf.to_vec()
-        };
-        let req = StreamReq {
-            payload: Some(payload),
-            ..Default::default()
+    #[tokio::test]
+    async fn test_eof_marker() {
+        let (a, b) = tokio::io::duplex(4096);
+        let (ar, aw) = tokio::io::split(a);
+        let (mut br, mut bw) = tokio::io::split(b);
+        let padding = FramePadding::default();
+        let client =
+            PbTcpClientRunStream::new(Box::new(ar), Box::new(aw), String::new(), false, padding);
+        let (mut reader, mut writer) = Box::new(client).split();
+
+        // our shutdown carries the marker
+        writer.shutdown().await.unwrap();
+        let msg: StreamReq = read_msg(&mut br).await.unwrap();
+        assert_eq!(msg.eof, Some(true));
+
+        // an empty frame from a peer that sets the field isn't the end
+        let frame = |payload: &'static [u8], eof| StreamRes {
+            payload: Bytes::from_static(payload),
+            eof,
         };
-        let mut w = self.writer.lock().await;
-        write_frame(&mut *w, &req).await
+        write_frame(&mut bw, &frame(b"", Some(false)), padding)
+            .await
+            .unwrap();
+        write_frame(&mut bw, &frame(b"data", Some(false)), padding)
+            .await
+            .unwrap();
+        write_frame(&mut bw, &frame(b"", Some(true)), padding)
+            .await
+            .unwrap();
+        assert_eq!(reader.read_chunk().await.unwrap(), "data");
+        assert!(reader.read_chunk().await.unwrap().is_empty());
+
+        // older peers end with a bare empty payload
+        write_frame(&mut bw, &frame(b"", None), padding)
+            .await
+            .unwrap();
+        assert!(reader.read_chunk().await.unwrap().is_empty());
     }
 }
@@ -1,142 +1,199 @@
 use crate::def::{RunReadHalf, RunStream, RunWriteHalf, StreamInfo};
 use crate::proto::v1::pb::{StreamReq, StreamRes};
+use crate::stream::{drain_cache, frame_payload};
 use crate::util::RunAddr;
 use crate::util::crypto::{decrypt_bytes, decrypt_field, encrypt_bytes};
-use crate::util::tcp_frame::{read_msg, write_frame};
+use crate::util::tcp_frame::{
+    EncryptMode, FramePadding, FrameReader, FrameWriter, read_msg, write_frame,
+};
+use crate::util::users::{Credential, UserTable};
+use bytes::Bytes;
 use std::any::Any;
 use std::sync::Arc;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::Mutex;
 
 pub struct PbTcpServerReadHalf {
-    reader: Arc<Mutex<OwnedReadHalf>>,
-    cache: Vec<u8>,
-    cache_pos: usize,
+    reader: Arc<Mutex<FrameReader>>,
+    cache: Bytes,
     pw: String,
     encrypt: bool,
 }
 
 pub struct PbTcpServerWriteHalf {
-    writer: Arc<Mutex<OwnedWriteHalf>>,
+    writer: Arc<Mutex<FrameWriter>>,
     pw: String,
     encrypt: bool,
+    padding: FramePadding,
 }
 
 pub struct PbTcpServerRunStream {
-    reader: Arc<Mutex<OwnedReadHalf>>,
-    writer: Arc<Mutex<OwnedWriteHalf>>,
-    cache: Vec<u8>,
-    cache_pos: usize,
+    reader: Arc<Mutex<FrameReader>>,
+    writer: Arc<Mutex<FrameWriter>>,
+    cache: Bytes,
     info: StreamInfo,
     pw: String,
     encrypt: bool,
+    padding: FramePadding,
+    /// The credential of a secure session, which replaces the sealed auth.
+    session: Option<Credential>,
 }
 
 impl PbTcpServerRunStream {
-    pub fn new(reader: OwnedReadHalf, writer: OwnedWriteHalf) -> Self {
+    pub fn new(reader: FrameReader, writer: FrameWriter, padding: FramePadding) -> Self {
         Self {
             reader: Arc::new(Mutex::new(reader)),
             writer: Arc::new(Mutex::new(writer)),
-            cache: Vec::new(),
-            cache_pos: 0,
+            cache: Bytes::new(),
             info: StreamInfo::default(),
             pw: String::new(),
             encrypt: false,
+            padding,
+            session: None,
         }
     }
 
-    pub async fn handshake(&mut self, pw: &str) -> std::io::Result<Option<(RunAddr, String)>> {
+    /// A stream inside a secure session, already authenticated as `cred`
+    /// and encrypted, so its request carries no auth and plain fields.
+    pub fn with_session(mut self, cred: Credential) -> Self {
+        self.session = Some(cred);
+        self
+    }
+
+    /// `mode` is the listener's policy; a stream asking for something else is
+    /// refused unless it is `Auto`.
+    pub async fn handshake(
+        &mut self,
+        users: &UserTable,
+        mode: EncryptMode,
+    ) -> std::io::Result<Option<(RunAddr, Credential)>> {
         let mut r = self.reader.lock().await;
         let req: StreamReq = read_msg(&mut *r).await?;
-        let auth = decrypt_field(&req.auth, pw)?;
-        let dst_addr = match req.dst_addr {
-            Some(ref enc) => decrypt_field(enc, pw)?,
-            None => return Err(std::io::Error::other("missing dst_addr")),
-        };
         let dst_port = req
             .dst_port
             .ok_or_else(|| std::io::Error::other("missing dst_port"))?;
+        let Some(dst_addr) = req.dst_addr else {
+            return Err(std::io::Error::other("missing dst_addr"));
+        };
+        let (cred, dst_addr) = match &self.session {
+            Some(cred) => (cred.clone(), dst_addr),
+            None => {
+                let cred = users.find_sealed(&req.auth).cloned().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::PermissionDenied, "invalid auth")
+                })?;
+                let dst_addr = decrypt_field(&dst_addr, &cred.pw)?;
+                (cred, dst_addr)
+            }
+        };
 
-        self.pw = pw.to_string();
-        self.encrypt = dst_port != 443;
+        if self.session.is_none() {
+            // peers without the field decide by port
+            let encrypt = req
+                .encrypt
+                .unwrap_or_else(|| EncryptMode::Auto.for_port(dst_port as u16));
+            if mode != EncryptMode::Auto && mode.for_port(dst_port as u16) != encrypt {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("stream encryption {} refused by listener policy", encrypt),
+                ));
+            }
+            self.pw = cred.pw.clone();
+            self.encrypt = encrypt;
+        }
 
         let ra = RunAddr {
             addr: dst_addr,
             port: dst_port as u16,
             udp: false,
         };
-        Ok(Some((ra, auth)))
+        Ok(Some((ra, cred)))
     }
 }
 
-async fn read_payload(
-    reader: &Arc<Mutex<OwnedReadHalf>>,
-    cache: &mut Vec<u8>,
-    cache_pos: &mut usize,
-    buf: &mut [u8],
+/// The next payload, decrypted, empty once the peer half-closed.
+async fn next_payload(
+    reader: &Mutex<FrameReader>,
     pw: &str,
     encrypt: bool,
-) -> std::io::Result<usize> {
-    if *cache_pos < cache.len() {
-        let available = cache.len() - *cache_pos;
-        let to_copy = available.min(buf.len());
-        buf[..to_copy].copy_from_slice(&cache[*cache_pos..*cache_pos + to_copy]);
-        *cache_pos += to_copy;
-        if *cache_pos >= cache.len() {
-            cache.clear();
-            *cache_pos = 0;
+) -> std::io::Result<Bytes> {
+    let mut r = reader.lock().await;
+    loop {
+        let msg: StreamReq = read_msg(&mut *r).await?;
+        let Some(payload) = frame_payload(msg.payload.unwrap_or_default(), msg.eof) else {
+            continue;
+        };
+        if payload.is_empty() || !encrypt {
+            return Ok(payload);
+        }
+        let payload = decrypt_bytes(&payload, pw)?;
+        // an encrypted empty write isn't an eof either
+        if !payload.is_empty() {
+            return Ok(payload.into());
         }
-        return Ok(to_copy);
     }
+}
 
-    cache.clear();
-    *cache_pos = 0;
-
-    let mut r = reader.lock().await;
-    let msg: StreamReq = read_msg(&mut *r).await?;
-    let mut payload = msg.payload.unwrap_or_default();
-    if payload.is_empty() {
-        return Ok(0);
-    }
+async fn send_payload(
+    writer: &Mutex<FrameWriter>,
+    data: Bytes,
+    pw: &str,
+    encrypt: bool,
+    padding: FramePadding,
+) -> std::io::Result<()> {
+    let payload = if encrypt {
+        encrypt_bytes(&data, pw)?.into()
+    } else {
+        data
+    };
+    let res = StreamRes {
+        payload,
+        ..Default::default()
+    };
+    let mut w = writer.lock().await;
+    write_frame(&mut *w, &res, padding).await
+}
 
-    if encrypt {
-        payload = decrypt_bytes(&payload, pw)?;
+#[async_trait::async_trait]
+impl RunReadHalf for PbTcpServerReadHalf {
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cache.is_empty() {
+            self.cache = next_payload(&self.reader, &self.pw, self.encrypt).await?;
+        }
+        Ok(drain_cache(&mut self.cache, buf))
     }
 
-    let to_copy = buf.len().min(payload.len());
-    buf[..to_copy].copy_from_slice(&payload[..to_copy]);
-    if payload.len() > to_copy {
-        cache.extend_from_slice(&payload[to_copy..]);
+    async fn read_chunk(&mut self) -> std::io::Result<Bytes> {
+        if !self.cache.is_empty() {
+            return Ok(std::mem::take(&mut self.cache));
+        }
+        next_payload(&self.reader, &self.pw, self.encrypt).await
     }
-    Ok(to_copy)
 }
 
 #[async_trait::async_trait]
-impl RunReadHalf for PbTcpServerReadHalf {
-    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        read_payload(
-            &self.reader,
-            &mut self.cache,
-            &mut self.cache_pos,
-            buf,
+impl RunWriteHalf for PbTcpServerWriteHalf {
+    async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        send_payload(
+            &self.writer,
+            Bytes::copy_from_slice(buf),
             &self.pw,
             self.encrypt,
+            self.padding,
         )
         .await
     }
-}
 
-#[async_trait::async_trait]
-impl RunWriteHalf for PbTcpServerWriteHalf {
-    async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        let payload = if self.encrypt {
-            encrypt_bytes(buf, &self.pw)?
-        } else {
-            buf.to_vec()
+    async fn write_chunk(&mut self, data: Bytes) -> std::io::Result<()> {
+        send_payload(&self.writer, data, &self.pw, self.encrypt, self.padding).await
+    }
+
+    /// Sends an empty eof frame, unencrypted like every empty payload.
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        let res = StreamRes {
+            eof: Some(true),
+            ..Default::default()
         };
-        let res = StreamRes { payload };
         let mut w = self.writer.lock().await;
-        write_frame(&mut *w, &res).await
+        write_frame(&mut *w, &res, self.padding).await
     }
 }
 
@@ -154,8 +211,7 @@ impl RunStream for PbTcpServerRunStream {
         (
             Box::new(PbTcpServerReadHalf {
                 reader: Arc::clone(&self.reader),
-                cache: Vec::new(),
-                cache_pos: 0,
+                cache: self.cache,
                 pw: self.pw.clone(),
                 encrypt: self.encrypt,
             }),
@@ -163,20 +219,16 @@ impl RunStream for PbTcpServerRunStream {
                 writer: Arc::clone(&self.writer),
                 pw: self.pw.clone(),
                 encrypt: self.encrypt,
+                padding: self.padding,
             }),
         )
     }
 
     async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        read_payload(
-            &self.reader,
-            &mut self.cache,
-            &mut self.cache_pos,
-            buf,
-            &self.pw,
-            self.encrypt,
-        )
-        .await
+        if self.cache.is_empty() {
+            self.cache = next_payload(&self.reader, &self.pw, self.encrypt).await?;
+        }
+        Ok(drain_cache(&mut self.cache, buf))
     }
 
     fn as_any_mut(&mut self) -> &mut dyn Any {
@@ -184,13 +236,13 @@ impl RunStream for PbTcpServerRunStream {
     }
 
     async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        let payload = if self.encrypt {
-            encrypt_bytes(buf, &self.pw)?
-        } else {
-            buf.to_vec()
-        };
-        let res = StreamRes { payload };
-        let mut w = self.writer.lock().await;
-        write_frame(&mut *w, &res).await
+        send_payload(
+            &self.writer,
+            Bytes::copy_from_slice(buf),
+            &self.pw,
+            self.encrypt,
+            self.padding,
+        )
+        .await
     }
 }
@@ -1,55 +1,90 @@
 use crate::def::{RunUdpReader, RunUdpWriter, UDPPacket};
 use crate::proto::v1::pb::{UdpReq, UdpRes};
+use crate::util::auth_token::ct_eq;
 use crate::util::crypto::{decrypt_bytes, decrypt_field, encrypt_bytes, encrypt_field};
-use crate::util::tcp_frame::{read_msg, write_frame};
+use crate::util::tcp_frame::{FramePadding, FrameReader, FrameWriter, read_msg, write_frame};
+use crate::util::users::Credential;
 use std::sync::Arc;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::Mutex;
 
 pub struct PbTcpUdpServerReader {
-    reader: Arc<Mutex<OwnedReadHalf>>,
-    auth: String,
-    pw: String,
+    reader: Arc<Mutex<FrameReader>>,
+    cred: Credential,
+    /// Already authenticated first packet, read by the listener.
+    first: Option<UdpReq>,
+    /// Inside a secure session packets carry no auth and plain fields.
+    secure: bool,
 }
 
 pub struct PbTcpUdpServerWriter {
-    writer: Arc<Mutex<OwnedWriteHalf>>,
-    pw: String,
+    writer: Arc<Mutex<FrameWriter>>,
+    /// `None` inside a secure session.
+    pw: Option<String>,
+    padding: FramePadding,
 }
 
 impl PbTcpUdpServerReader {
-    pub fn new(reader: Arc<Mutex<OwnedReadHalf>>, auth: String, pw: String) -> Self {
-        Self { reader, auth, pw }
+    pub fn new(
+        reader: Arc<Mutex<FrameReader>>,
+        cred: Credential,
+        first: UdpReq,
+        secure: bool,
+    ) -> Self {
+        Self {
+            reader,
+            cred,
+            first: Some(first),
+            secure,
+        }
     }
 }
 
 impl PbTcpUdpServerWriter {
-    pub fn new(writer: Arc<Mutex<OwnedWriteHalf>>, pw: String) -> Self {
-        Self { writer, pw }
+    pub fn new(writer: Arc<Mutex<FrameWriter>>, pw: Option<String>, padding: FramePadding) -> Self {
+        Self {
+            writer,
+            pw,
+            padding,
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl RunUdpReader for PbTcpUdpServerReader {
     async fn read(&mut self) -> std::io::Result<UDPPacket> {
-        let mut r = self.reader.lock().await;
-        let mut req: UdpReq = read_msg(&mut *r).await?;
-        let decrypted_auth = decrypt_field(&req.auth, &self.pw)?;
-        if decrypted_auth != self.auth {
-            return Err(std::io::Error::other("pb tcp udp auth mismatch"));
-        }
-        if let Some(ref enc) = req.dst_addr {
-            req.dst_addr = Some(decrypt_field(enc, &self.pw)?);
-        }
-        if let Some(ref enc) = req.src_addr {
-            req.src_addr = Some(decrypt_field(enc, &self.pw)?);
-        }
-        if let Some(payload) = req.payload {
-            req.payload = Some(decrypt_bytes(&payload, &self.pw)?);
+        let mut req = match self.first.take() {
+            Some(req) => req,
+            None => {
+                let mut r = self.reader.lock().await;
+                let req: UdpReq = read_msg(&mut *r).await?;
+                if !self.secure {
+                    let decrypted_auth = decrypt_field(&req.auth, &self.cred.pw)?;
+                    if !ct_eq(&decrypted_auth, &self.cred.pw) {
+                        return Err(std::io::Error::other("pb tcp udp auth mismatch"));
+                    }
+                }
+                req
+            }
+        };
+        if !self.secure {
+            let pw = self.cred.pw.as_str();
+            if let Some(ref enc) = req.dst_addr {
+                req.dst_addr = Some(decrypt_field(enc, pw)?);
+            }
+            if let Some(ref enc) = req.src_addr {
+                req.src_addr = Some(decrypt_field(enc, pw)?);
+            }
+            if let Some(payload) = req.payload {
+                req.payload = Some(decrypt_bytes(&payload, pw)?);
+            }
         }
         req.try_into()
             .map_err(|_| std::io::Error::other("pb tcp udp req convert error"))
     }
+
+    fn user(&self) -> Option<String> {
+        self.cred.user.clone()
+    }
 }
 
 #[async_trait::async_trait]
@@ -58,14 +93,16 @@ impl RunUdpWriter for PbTcpUdpServerWriter {
         let mut res: UdpRes = packet
             .try_into()
             .map_err(|_| std::io::Error::other("pb tcp udp res convert error"))?;
-        if let Some(ref v) = res.dst_addr {
-            res.dst_addr = Some(encrypt_field(v, &self.pw)?);
-        }
-        if let Some(ref v) = res.src_addr {
-            res.src_addr = Some(encrypt_field(v, &self.pw)?);
+        if let Some(pw) = &self.pw {
+            if let Some(ref v) = res.dst_addr {
+                res.dst_addr = Some(encrypt_field(v, pw)?);
+            }
+            if let Some(ref v) = res.src_addr {
+                res.src_addr = Some(encrypt_field(v, pw)?);
+            }
+            res.payload = encrypt_bytes(&res.payload, pw)?;
         }
-        res.payload = encrypt_bytes(&res.payload, &self.pw)?;
         let mut w = self.writer.lock().await;
-        write_frame(&mut *w, &res).await
+        write_frame(&mut *w, &res, self.padding).await
     }
 }
@@ -1,5 +1,6 @@
 use crate::def::{RunUdpReader, RunUdpWriter, UDPPacket};
 use crate::proto::v1::pb::{RevUdpReq, RevUdpRes};
+use crate::util::auth_token::ct_eq;
 use futures::StreamExt;
 use std::io::ErrorKind;
 use tokio::sync::mpsc::Sender;
@@ -36,7 +37,7 @@ impl RunUdpReader for RevGrpcUdpServerReader {
             None => Err(std::io::Error::other("rev grpc udp stream closed")),
             Some(Err(e)) => Err(std::io::Error::new(ErrorKind::BrokenPipe, e.to_string())),
             Some(Ok(req)) => {
-                if req.auth != self.auth {
+                if !ct_eq(&req.auth, &self.auth) {
                     return Err(std::io::Error::other("rev grpc udp auth mismatch"));
                 }
                 req.try_into()
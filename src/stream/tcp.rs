@@ -1,4 +1,6 @@
 use crate::def::{RunReadHalf, RunStream, RunWriteHalf, StreamInfo};
+use crate::stream::read_chunk_into;
+use bytes::{Bytes, BytesMut};
 use std::any::Any;
 use std::io::Result;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -6,6 +8,7 @@ use tokio::net::TcpStream;
 
 pub struct TcpReadHalf {
     reader: tokio::net::tcp::OwnedReadHalf,
+    arena: BytesMut,
 }
 
 // TcpStream 实现的写半边
@@ -25,6 +28,10 @@ impl RunReadHalf for TcpReadHalf {
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         self.reader.read(buf).await
     }
+
+    async fn read_chunk(&mut self) -> Result<Bytes> {
+        read_chunk_into(&mut self.reader, &mut self.arena).await
+    }
 }
 
 // 为 TcpWriteHalf 实现 MyWriteHalf trait
@@ -33,6 +40,10 @@ impl RunWriteHalf for TcpWriteHalf {
     async fn write(&mut self, buf: &[u8]) -> Result<()> {
         self.writer.write_all(buf).await
     }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        self.writer.shutdown().await
+    }
 }
 
 // 为 MyTcpStream 实现构造方法
@@ -43,6 +54,10 @@ impl TcpRunStream {
             info: StreamInfo::default(),
         }
     }
+
+    pub fn inner(&self) -> &TcpStream {
+        &self.inner
+    }
 }
 
 // 为 MyTcpStream 实现 MyStream trait
@@ -59,7 +74,10 @@ impl RunStream for TcpRunStream {
     fn split(self: Box<Self>) -> (Box<dyn RunReadHalf>, Box<dyn RunWriteHalf>) {
         let (reader, writer) = self.inner.into_split();
         (
-            Box::new(TcpReadHalf { reader }),
+            Box::new(TcpReadHalf {
+                reader,
+                arena: BytesMut::new(),
+            }),
             Box::new(TcpWriteHalf { writer }),
         )
     }
@@ -0,0 +1,88 @@
+use crate::def::{RunReadHalf, RunStream, RunWriteHalf, StreamInfo};
+use crate::stream::read_chunk_into;
+use bytes::{Bytes, BytesMut};
+use std::any::Any;
+use std::io::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+/// RunStream over an established TLS session (server or client side).
+pub struct TlsRunStream<S> {
+    inner: S,
+    info: StreamInfo,
+}
+
+pub struct TlsReadHalf<S> {
+    reader: ReadHalf<S>,
+    arena: BytesMut,
+}
+
+pub struct TlsWriteHalf<S> {
+    writer: WriteHalf<S>,
+}
+
+impl<S> TlsRunStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            inner: stream,
+            info: StreamInfo::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncRead + Send + Unpin> RunReadHalf for TlsReadHalf<S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.reader.read(buf).await
+    }
+
+    async fn read_chunk(&mut self) -> Result<Bytes> {
+        read_chunk_into(&mut self.reader, &mut self.arena).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncWrite + Send + Unpin> RunWriteHalf for TlsWriteHalf<S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer.write_all(buf).await?;
+        self.writer.flush().await
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        self.writer.shutdown().await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static> RunStream for TlsRunStream<S> {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_info(&self) -> &StreamInfo {
+        &self.info
+    }
+
+    fn set_info(&mut self, f: &mut dyn FnMut(&mut StreamInfo)) {
+        f(&mut self.info)
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn RunReadHalf>, Box<dyn RunWriteHalf>) {
+        let (reader, writer) = tokio::io::split(self.inner);
+        (
+            Box::new(TlsReadHalf {
+                reader,
+                arena: BytesMut::new(),
+            }),
+            Box::new(TlsWriteHalf { writer }),
+        )
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read(buf).await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.inner.write_all(buf).await?;
+        self.inner.flush().await
+    }
+}
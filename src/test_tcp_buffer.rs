@@ -1,6 +1,7 @@
 #[cfg(test)]
 mod tests {
-    use crate::block::{BlockManager, DataBlock};
+    use crate::block::{BlockManager, ConnBuffer, DataBlock};
+    use crate::consts::TCP_IO_BUFFER_SIZE;
     use bytes::Bytes;
     use std::sync::Arc;
     use tokio::time::{Duration, sleep};
@@ -15,7 +16,7 @@ mod tests {
         // Provide 3: taken(2) < 2 -> False. Block.
 
         let bm = Arc::new(BlockManager::new(limit));
-        let db = Arc::new(DataBlock::new(bm.clone()));
+        let db = Arc::new(DataBlock::new(conn(&bm, "a")));
 
         // Push 1 item.
         let res = db_provide_helper(db.clone(), Bytes::from_static(&[1])).await;
@@ -69,7 +70,7 @@ mod tests {
     #[tokio::test]
     async fn test_drop_releases_unconsumed_blocks() {
         let bm = Arc::new(BlockManager::new(1));
-        let db = Arc::new(DataBlock::new(bm.clone()));
+        let db = Arc::new(DataBlock::new(conn(&bm, "a")));
 
         db.provide(Bytes::from_static(&[1])).await;
         assert_eq!(
@@ -87,6 +88,120 @@ mod tests {
         );
     }
 
+    fn conn(bm: &Arc<BlockManager>, label: &str) -> Arc<ConnBuffer> {
+        bm.pool("test", None).connection(label.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_min_blocks_under_pressure() {
+        let bm = Arc::new(BlockManager::new(4).with_min_blocks(2));
+        let hog = Arc::new(DataBlock::new(conn(&bm, "hog")));
+        for i in 0..4 {
+            hog.provide(Bytes::from(vec![i; 10])).await;
+        }
+        assert!(!bm.can_take());
+        let h = tokio::spawn({
+            let hog = hog.clone();
+            async move { hog.provide(Bytes::from_static(&[9])).await }
+        });
+        sleep(Duration::from_millis(50)).await;
+        assert!(!h.is_finished(), "the hog waits once the pool is empty");
+
+        // another connection still gets its minimum right away
+        let victim = DataBlock::new(conn(&bm, "victim"));
+        for _ in 0..2 {
+            tokio::time::timeout(
+                Duration::from_millis(50),
+                victim.provide(Bytes::from_static(&[1])),
+            )
+            .await
+            .expect("minimum blocks never wait");
+        }
+        let stats = bm.buffered();
+        assert_eq!(stats[0].label, "hog");
+        assert_eq!(stats[0].bytes, 40);
+        assert_eq!(stats[1].bytes, 2);
+        assert_eq!(victim.consume().await, Bytes::from_static(&[1]));
+        drop(victim);
+        assert_eq!(bm.buffered().len(), 1);
+        h.abort();
+    }
+
+    #[tokio::test]
+    async fn test_waiters_served_in_order() {
+        let bm = Arc::new(BlockManager::new(2));
+        let a = Arc::new(DataBlock::new(conn(&bm, "a")));
+        let b = Arc::new(DataBlock::new(conn(&bm, "b")));
+        a.provide(Bytes::from_static(&[1])).await;
+        b.provide(Bytes::from_static(&[2])).await;
+
+        let a2 = tokio::spawn({
+            let a = a.clone();
+            async move { a.provide(Bytes::from_static(&[3])).await }
+        });
+        sleep(Duration::from_millis(20)).await;
+        let b2 = tokio::spawn({
+            let b = b.clone();
+            async move { b.provide(Bytes::from_static(&[4])).await }
+        });
+        sleep(Duration::from_millis(20)).await;
+
+        // a's freed block goes to a2, which asked first
+        a.consume().await;
+        a2.await.unwrap();
+        let a3 = tokio::spawn({
+            let a = a.clone();
+            async move { a.provide(Bytes::from_static(&[5])).await }
+        });
+        sleep(Duration::from_millis(20)).await;
+
+        // the next one goes to b2 although a keeps asking
+        a.consume().await;
+        b2.await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        assert!(!a3.is_finished());
+        b.consume().await;
+        a3.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_listener_budget() {
+        let bm = Arc::new(BlockManager::new(100));
+        let small = bm.pool("small", Some(2));
+        let db = DataBlock::new(small.connection("a".to_string()));
+        db.provide(Bytes::from_static(&[1])).await;
+        db.provide(Bytes::from_static(&[2])).await;
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(50),
+            db.provide(Bytes::from_static(&[3])),
+        )
+        .await;
+        assert!(blocked.is_err(), "the listener budget is used up");
+
+        // other listeners still have the global pool
+        let other = DataBlock::new(bm.pool("other", None).connection("b".to_string()));
+        for _ in 0..5 {
+            tokio::time::timeout(
+                Duration::from_millis(50),
+                other.provide(Bytes::from_static(&[1])),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_chunks_charge_by_size() {
+        // grpc and pb_tcp hand over payloads as large as the peer sent them
+        let bm = Arc::new(BlockManager::new(10));
+        let db = DataBlock::new(conn(&bm, "a"));
+        db.provide(Bytes::from(vec![0u8; 3 * TCP_IO_BUFFER_SIZE + 1]))
+            .await;
+        assert_eq!(bm.taken_blocks(), 4);
+        db.consume().await;
+        assert_eq!(bm.taken_blocks(), 0);
+    }
+
     async fn db_provide_helper(db: Arc<DataBlock>, data: Bytes) -> bool {
         // We use select with timeout to check if it blocks immediately,
         // but provide returns void (async).
@@ -3,11 +3,22 @@ use crate::util::socks5::request::Request;
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str;
 
+pub(crate) mod auth_token;
 pub(crate) mod crypto;
 pub(crate) mod grpc_transport;
+pub(crate) mod mux;
 pub mod parse;
+pub(crate) mod proxy_protocol;
+pub(crate) mod secure;
+pub(crate) mod sniff;
 pub(crate) mod socks5;
+#[cfg(target_os = "linux")]
+pub(crate) mod splice;
 pub(crate) mod tcp_frame;
+pub(crate) mod tls;
+#[cfg(target_os = "linux")]
+pub(crate) mod transparent;
+pub(crate) mod users;
 
 #[derive(Debug, Clone)]
 pub struct RunAddr {
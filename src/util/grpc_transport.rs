@@ -1,10 +1,76 @@
+use crate::def::config::get_option_str;
 use hyper_util::rt::TokioIo;
+use std::collections::HashMap;
 use std::io;
 
 use tokio::net::TcpStream;
-use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::transport::{
+    Certificate, Channel, ClientTlsConfig, Endpoint, Identity, ServerTlsConfig, Uri,
+};
 use tower::service_fn;
 
+type Options = Option<HashMap<String, toml::Value>>;
+
+fn read_pem(options: &Options, key: &str) -> io::Result<Option<Vec<u8>>> {
+    match get_option_str(options, key) {
+        Some(path) => std::fs::read(&path)
+            .map(Some)
+            .map_err(|e| io::Error::new(e.kind(), format!("{} {}: {}", key, path, e))),
+        None => Ok(None),
+    }
+}
+
+/// Server TLS for the grpc and reverse servers, `None` unless `tls_cert` is set.
+/// `tls_client_ca` turns on client certificate verification.
+pub(crate) fn server_tls_config(options: &Options) -> io::Result<Option<ServerTlsConfig>> {
+    let Some(cert) = read_pem(options, "tls_cert")? else {
+        return Ok(None);
+    };
+    let key = read_pem(options, "tls_key")?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "tls_cert is set but tls_key is missing",
+        )
+    })?;
+    let mut config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+    if let Some(ca) = read_pem(options, "tls_client_ca")? {
+        config = config.client_ca_root(Certificate::from_pem(ca));
+    }
+    Ok(Some(config))
+}
+
+/// Applies the `ca`, `client_cert`, `client_key` and `domain` options to a
+/// grpc client endpoint. Without them an `https` endpoint keeps tonic's
+/// default web roots.
+pub(crate) fn with_client_tls(endpoint: Endpoint, options: &Options) -> io::Result<Endpoint> {
+    let ca = read_pem(options, "ca")?;
+    let cert = read_pem(options, "client_cert")?;
+    let key = read_pem(options, "client_key")?;
+    let domain = get_option_str(options, "domain");
+    if ca.is_none() && cert.is_none() && key.is_none() && domain.is_none() {
+        return Ok(endpoint);
+    }
+    let mut config = ClientTlsConfig::new();
+    config = match ca {
+        Some(ca) => config.ca_certificate(Certificate::from_pem(ca)),
+        None => config.with_webpki_roots(),
+    };
+    match (cert, key) {
+        (Some(cert), Some(key)) => config = config.identity(Identity::from_pem(cert, key)),
+        (None, None) => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "client_cert and client_key must be set together",
+            ));
+        }
+    }
+    if let Some(domain) = domain {
+        config = config.domain_name(domain);
+    }
+    endpoint.tls_config(config).map_err(io::Error::other)
+}
+
 fn default_port(uri: &Uri) -> u16 {
     match uri.scheme_str() {
         Some("https") => 443,
@@ -35,3 +101,36 @@ pub(crate) async fn connect_channel_without_proxy(
         }))
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(pairs: &[(&str, &str)]) -> Options {
+        Some(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), toml::Value::String(v.to_string())))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_tls_options() {
+        assert!(server_tls_config(&None).unwrap().is_none());
+        let file = std::env::temp_dir().join(format!(
+            "rog_grpc_transport_test_{}_{}.pem",
+            std::process::id(),
+            uuid::Uuid::new_v4()
+        ));
+        std::fs::write(&file, "").unwrap();
+        let path = file.to_str().unwrap();
+        assert!(server_tls_config(&options(&[("tls_cert", path)])).is_err());
+
+        let endpoint = Endpoint::from_static("https://localhost:443");
+        assert!(with_client_tls(endpoint.clone(), &None).is_ok());
+        assert!(with_client_tls(endpoint.clone(), &options(&[("domain", "example.com")])).is_ok());
+        assert!(with_client_tls(endpoint, &options(&[("client_cert", path)])).is_err());
+        std::fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,510 @@
+//! Stream multiplexing over one pb_tcp connection (`CONN_TYPE_MUX`).
+//!
+//! Every logical stream is handed out as a plain `FrameReader` / `FrameWriter`
+//! pair, so the usual pb_tcp conn type, auth and framing run unchanged inside
+//! it. Frames are `[kind u8][stream id u32][len u32]` followed by `len` bytes
+//! for `DATA`; for `WINDOW` the len field is the credit returned.
+
+use crate::util::tcp_frame::{FrameReader, FrameWriter};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::io::{DuplexStream, duplex};
+use tokio::select;
+use tokio::spawn;
+use tokio::sync::{Notify, Semaphore, mpsc};
+
+const FRAME_SYN: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_WINDOW: u8 = 2;
+const FRAME_FIN: u8 = 3;
+const FRAME_RST: u8 = 4;
+
+const HEADER_LEN: usize = 9;
+const MAX_DATA: usize = 16 * 1024;
+/// Bytes a peer may send on one stream before the reader returns credit.
+const STREAM_WINDOW: u32 = 256 * 1024;
+const MAX_STREAMS: usize = 1024;
+
+type Incoming = mpsc::Sender<(FrameReader, FrameWriter)>;
+
+struct StreamEntry {
+    /// `None` once the peer sent FIN.
+    inbound: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Data received but not yet taken by the local side.
+    pending: Arc<AtomicU32>,
+    /// Send credit granted by the peer.
+    credit: Arc<Semaphore>,
+    /// Set when the stream ends without FIN, so the local reader fails.
+    reset: Arc<AtomicBool>,
+}
+
+impl StreamEntry {
+    fn abort(&self) {
+        if self.inbound.is_some() {
+            self.reset.store(true, Ordering::Release);
+        }
+        self.credit.close();
+    }
+}
+
+struct Shared {
+    streams: StdMutex<HashMap<u32, StreamEntry>>,
+    out: mpsc::Sender<Vec<u8>>,
+    closed: AtomicBool,
+    shutdown: Notify,
+    next_id: AtomicU32,
+}
+
+/// One multiplexed connection. Only the client side opens streams.
+pub struct MuxSession {
+    shared: Arc<Shared>,
+}
+
+fn frame(kind: u8, id: u32, len: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.push(kind);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+impl Shared {
+    async fn send(&self, kind: u8, id: u32, len: u32, payload: &[u8]) -> io::Result<()> {
+        self.out
+            .send(frame(kind, id, len, payload))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed"))
+    }
+
+    fn remove(&self, id: u32) {
+        if let Some(entry) = self.streams.lock().unwrap().remove(&id) {
+            entry.credit.close();
+        }
+    }
+
+    /// Like `remove`, but the local reader sees a reset instead of EOF.
+    fn reset(&self, id: u32) {
+        if let Some(entry) = self.streams.lock().unwrap().remove(&id) {
+            entry.abort();
+        }
+    }
+
+    fn close(&self) {
+        if !self.closed.swap(true, Ordering::AcqRel) {
+            for (_, entry) in self.streams.lock().unwrap().drain() {
+                entry.abort();
+            }
+            self.shutdown.notify_one();
+        }
+    }
+}
+
+impl MuxSession {
+    pub fn client(reader: FrameReader, writer: FrameWriter) -> Self {
+        Self::start(reader, writer, None, 1)
+    }
+
+    /// Server side; streams opened by the peer arrive on the returned channel.
+    pub fn server(
+        reader: FrameReader,
+        writer: FrameWriter,
+    ) -> (Self, mpsc::Receiver<(FrameReader, FrameWriter)>) {
+        let (tx, rx) = mpsc::channel(8);
+        (Self::start(reader, writer, Some(tx), 2), rx)
+    }
+
+    fn start(
+        reader: FrameReader,
+        writer: FrameWriter,
+        incoming: Option<Incoming>,
+        first_id: u32,
+    ) -> Self {
+        let (out_tx, out_rx) = mpsc::channel(256);
+        let shared = Arc::new(Shared {
+            streams: StdMutex::new(HashMap::new()),
+            out: out_tx,
+            closed: AtomicBool::new(false),
+            shutdown: Notify::new(),
+            next_id: AtomicU32::new(first_id),
+        });
+        spawn(write_loop(Arc::clone(&shared), writer, out_rx));
+        spawn(read_loop(Arc::clone(&shared), reader, incoming));
+        Self { shared }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    #[cfg(test)]
+    fn stream_count(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    /// Opens a new logical stream.
+    pub async fn open(&self) -> io::Result<(FrameReader, FrameWriter)> {
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "mux session closed",
+            ));
+        }
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        let halves = open_stream(&self.shared, id)?;
+        self.shared.send(FRAME_SYN, id, 0, &[]).await?;
+        Ok(halves)
+    }
+}
+
+impl Drop for MuxSession {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// The application read half; EOF after a reset or a lost session is an error.
+struct StreamReader {
+    inner: ReadHalf<DuplexStream>,
+    reset: Arc<AtomicBool>,
+}
+
+impl AsyncRead for StreamReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(()))
+                if buf.filled().len() == filled
+                    && buf.remaining() > 0
+                    && self.reset.load(Ordering::Acquire) =>
+            {
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "mux stream reset",
+                )))
+            }
+            res => res,
+        }
+    }
+}
+
+/// Registers stream `id` and starts its pumps; returns the application end.
+fn open_stream(shared: &Arc<Shared>, id: u32) -> io::Result<(FrameReader, FrameWriter)> {
+    let (app, local) = duplex(STREAM_WINDOW as usize);
+    let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+    let pending = Arc::new(AtomicU32::new(0));
+    let credit = Arc::new(Semaphore::new(STREAM_WINDOW as usize));
+    let reset = Arc::new(AtomicBool::new(false));
+    {
+        let mut streams = shared.streams.lock().unwrap();
+        if streams.len() >= MAX_STREAMS || streams.contains_key(&id) {
+            return Err(io::Error::other("mux stream limit reached"));
+        }
+        streams.insert(
+            id,
+            StreamEntry {
+                inbound: Some(inbound_tx),
+                pending: Arc::clone(&pending),
+                credit: Arc::clone(&credit),
+                reset: Arc::clone(&reset),
+            },
+        );
+    }
+    let (local_r, local_w) = tokio::io::split(local);
+    let halves_done = Arc::new(AtomicU32::new(0));
+    spawn(pump_out(
+        Arc::clone(shared),
+        id,
+        local_r,
+        credit,
+        Arc::clone(&halves_done),
+    ));
+    spawn(pump_in(
+        Arc::clone(shared),
+        id,
+        local_w,
+        inbound_rx,
+        pending,
+        halves_done,
+    ));
+    let (r, w) = tokio::io::split(app);
+    Ok((Box::new(StreamReader { inner: r, reset }), Box::new(w)))
+}
+
+fn half_done(shared: &Shared, id: u32, halves_done: &AtomicU32) {
+    if halves_done.fetch_add(1, Ordering::AcqRel) == 1 {
+        shared.remove(id);
+    }
+}
+
+/// Local writes -> DATA frames, within the peer's credit. EOF sends FIN.
+async fn pump_out(
+    shared: Arc<Shared>,
+    id: u32,
+    mut local: ReadHalf<DuplexStream>,
+    credit: Arc<Semaphore>,
+    halves_done: Arc<AtomicU32>,
+) {
+    let mut buf = vec![0u8; MAX_DATA];
+    loop {
+        let n = match local.read(&mut buf).await {
+            Ok(0) => {
+                let _ = shared.send(FRAME_FIN, id, 0, &[]).await;
+                break;
+            }
+            Ok(n) => n,
+            Err(_) => {
+                let _ = shared.send(FRAME_RST, id, 0, &[]).await;
+                shared.remove(id);
+                break;
+            }
+        };
+        match credit.acquire_many(n as u32).await {
+            Ok(permit) => permit.forget(),
+            // reset by the peer or the session is gone
+            Err(_) => break,
+        }
+        if shared
+            .send(FRAME_DATA, id, n as u32, &buf[..n])
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    half_done(&shared, id, &halves_done);
+}
+
+/// DATA frames -> local reads, returning credit as the data is taken.
+async fn pump_in(
+    shared: Arc<Shared>,
+    id: u32,
+    mut local: WriteHalf<DuplexStream>,
+    mut inbound: mpsc::UnboundedReceiver<Vec<u8>>,
+    pending: Arc<AtomicU32>,
+    halves_done: Arc<AtomicU32>,
+) {
+    while let Some(data) = inbound.recv().await {
+        if local.write_all(&data).await.is_err() {
+            // the local side is gone, stop the peer from sending more
+            let _ = shared.send(FRAME_RST, id, 0, &[]).await;
+            shared.remove(id);
+            break;
+        }
+        pending.fetch_sub(data.len() as u32, Ordering::AcqRel);
+        let _ = shared.send(FRAME_WINDOW, id, data.len() as u32, &[]).await;
+    }
+    let _ = local.shutdown().await;
+    half_done(&shared, id, &halves_done);
+}
+
+async fn write_loop(
+    shared: Arc<Shared>,
+    mut writer: FrameWriter,
+    mut out: mpsc::Receiver<Vec<u8>>,
+) {
+    loop {
+        let frame = select! {
+            f = out.recv() => f,
+            _ = shared.shutdown.notified() => None,
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+        if let Err(e) = writer.write_all(&frame).await {
+            debug!("mux write error: {}", e);
+            break;
+        }
+        // batch whatever is already queued into one flush
+        let mut failed = false;
+        while let Ok(frame) = out.try_recv() {
+            if writer.write_all(&frame).await.is_err() {
+                failed = true;
+                break;
+            }
+        }
+        if failed || writer.flush().await.is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+    shared.close();
+}
+
+async fn read_loop(shared: Arc<Shared>, mut reader: FrameReader, incoming: Option<Incoming>) {
+    if let Err(e) = read_frames(&shared, &mut reader, incoming).await {
+        debug!("mux session ended: {}", e);
+    }
+    shared.close();
+}
+
+async fn read_frames(
+    shared: &Arc<Shared>,
+    reader: &mut FrameReader,
+    incoming: Option<Incoming>,
+) -> io::Result<()> {
+    let mut header = [0u8; HEADER_LEN];
+    loop {
+        reader.read_exact(&mut header).await?;
+        let kind = header[0];
+        let id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+        let len = u32::from_be_bytes(header[5..9].try_into().unwrap());
+        match kind {
+            FRAME_SYN => {
+                let Some(incoming) = &incoming else {
+                    return Err(io::Error::other("mux peer opened a stream"));
+                };
+                match open_stream(shared, id) {
+                    Ok(halves) => {
+                        if incoming.send(halves).await.is_err() {
+                            return Err(io::Error::other("mux acceptor closed"));
+                        }
+                    }
+                    Err(e) => {
+                        warn!("mux reject stream {}: {}", id, e);
+                        shared.send(FRAME_RST, id, 0, &[]).await?;
+                    }
+                }
+            }
+            FRAME_DATA => {
+                if len as usize > MAX_DATA {
+                    return Err(io::Error::other("mux frame too large"));
+                }
+                let mut data = vec![0u8; len as usize];
+                reader.read_exact(&mut data).await?;
+                let overflow = {
+                    let streams = shared.streams.lock().unwrap();
+                    match streams.get(&id) {
+                        Some(StreamEntry {
+                            inbound: Some(inbound),
+                            pending,
+                            ..
+                        }) => {
+                            let pending = pending.fetch_add(len, Ordering::AcqRel) + len;
+                            pending > STREAM_WINDOW || inbound.send(data).is_err()
+                        }
+                        // late data after a reset or FIN
+                        _ => false,
+                    }
+                };
+                if overflow {
+                    warn!("mux stream {} exceeded its window", id);
+                    shared.reset(id);
+                    shared.send(FRAME_RST, id, 0, &[]).await?;
+                }
+            }
+            FRAME_WINDOW => {
+                if let Some(entry) = shared.streams.lock().unwrap().get(&id) {
+                    entry.credit.add_permits(len as usize);
+                }
+            }
+            FRAME_FIN => {
+                if let Some(entry) = shared.streams.lock().unwrap().get_mut(&id) {
+                    entry.inbound.take();
+                }
+            }
+            FRAME_RST => shared.reset(id),
+            _ => return Err(io::Error::other(format!("mux unknown frame {}", kind))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (
+        MuxSession,
+        mpsc::Receiver<(FrameReader, FrameWriter)>,
+        MuxSession,
+    ) {
+        let (a, b) = duplex(64 * 1024);
+        let (ar, aw) = tokio::io::split(a);
+        let (br, bw) = tokio::io::split(b);
+        let client = MuxSession::client(Box::new(ar), Box::new(aw));
+        let (server, incoming) = MuxSession::server(Box::new(br), Box::new(bw));
+        (client, incoming, server)
+    }
+
+    #[tokio::test]
+    async fn test_streams_and_flow_control() {
+        let (client, mut incoming, _server) = pair();
+        spawn(async move {
+            while let Some((mut r, mut w)) = incoming.recv().await {
+                spawn(async move {
+                    tokio::io::copy(&mut r, &mut w).await.unwrap();
+                    w.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        // well past one window, so the echo only completes if credit flows back
+        let data: Vec<u8> = (0..3 * STREAM_WINDOW).map(|i| i as u8).collect();
+        let mut tasks = Vec::new();
+        for _ in 0..4 {
+            let (mut r, mut w) = client.open().await.unwrap();
+            let data = data.clone();
+            tasks.push(spawn(async move {
+                let expected = data.clone();
+                let writer = spawn(async move {
+                    w.write_all(&data).await.unwrap();
+                    w.shutdown().await.unwrap();
+                });
+                let mut got = Vec::new();
+                r.read_to_end(&mut got).await.unwrap();
+                writer.await.unwrap();
+                assert_eq!(got, expected);
+            }));
+        }
+        for t in tasks {
+            t.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reset_on_drop() {
+        let (client, mut incoming, server) = pair();
+        let (_r, mut w) = client.open().await.unwrap();
+        drop(incoming.recv().await.unwrap());
+        // the server can't deliver the data and resets the stream
+        w.write_all(b"hello").await.unwrap();
+        for _ in 0..100 {
+            if client.stream_count() == 0 && server.stream_count() == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("stream was not reset");
+    }
+
+    #[tokio::test]
+    async fn test_reset_is_not_eof() {
+        let (client, mut incoming, server) = pair();
+        let (mut r, _w) = client.open().await.unwrap();
+        let (_sr, mut sw) = incoming.recv().await.unwrap();
+        sw.write_all(b"partial").await.unwrap();
+        let mut buf = [0u8; 7];
+        r.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"partial");
+        server.shared.send(FRAME_RST, 1, 0, &[]).await.unwrap();
+        let err = r.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        // streams cut off by a lost session fail the same way
+        let (mut r, _w) = client.open().await.unwrap();
+        let _stream = incoming.recv().await.unwrap();
+        drop(server);
+        let err = r.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+}
@@ -0,0 +1,320 @@
+//! HAProxy PROXY protocol: the v1 and v2 headers a load balancer puts in
+//! front of a connection to name its client, and the v2 header sent to
+//! upstreams.
+
+use crate::def::config::{get_option_bool, get_option_str_list};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Headers a listener reads at once, further connections wait in the backlog.
+const MAX_PENDING_HEADERS: usize = 256;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads the header `stream` has to start with. `None` when it names no
+/// client (`UNKNOWN`, `LOCAL` or a non-IP family).
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 6];
+    stream.read_exact(&mut start).await?;
+    if start == V1_PREFIX {
+        read_v1(stream).await
+    } else if start == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// The client of a connection from `peer`, a load balancer that sends a
+/// header first. `peer` itself when the header names no client.
+pub async fn accept<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer: SocketAddr,
+) -> io::Result<SocketAddr> {
+    let client = timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| io::Error::new(ErrorKind::TimedOut, "PROXY header timeout"))??;
+    Ok(client.unwrap_or(peer))
+}
+
+/// A listener's `proxy_protocol` settings: the balancers allowed to send
+/// the header (`proxy_protocol_from`), and a bound on headers in flight.
+#[derive(Clone)]
+pub struct HeaderPolicy {
+    trusted: Arc<Vec<IpNet>>,
+    pending: Arc<Semaphore>,
+}
+
+impl HeaderPolicy {
+    /// `None` unless the listener has `proxy_protocol`, which then needs
+    /// `proxy_protocol_from`: CIDRs or addresses of the balancers.
+    pub fn from_options(
+        options: &Option<HashMap<String, toml::Value>>,
+    ) -> io::Result<Option<Self>> {
+        if !get_option_bool(options, "proxy_protocol") {
+            return Ok(None);
+        }
+        let mut trusted = Vec::new();
+        for rule in get_option_str_list(options, "proxy_protocol_from") {
+            let net = match rule.parse::<IpNet>() {
+                Ok(net) => net,
+                Err(_) => rule.parse::<IpAddr>().map(IpNet::from).map_err(|_| {
+                    io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid proxy_protocol_from '{}'", rule),
+                    )
+                })?,
+            };
+            trusted.push(net);
+        }
+        if trusted.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "proxy_protocol needs proxy_protocol_from",
+            ));
+        }
+        Ok(Some(Self {
+            trusted: Arc::new(trusted),
+            pending: Arc::new(Semaphore::new(MAX_PENDING_HEADERS)),
+        }))
+    }
+
+    /// Whether `peer` may name the client. Others are closed unread.
+    pub fn trusts(&self, peer: SocketAddr) -> bool {
+        let ip = peer.ip().to_canonical();
+        self.trusted.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Waits until one more header may be read. Accept loops hold the
+    /// permit while reading it.
+    pub async fn slot(&self) -> OwnedSemaphorePermit {
+        self.pending
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("header semaphore is never closed")
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut line = V1_PREFIX.to_vec();
+    // byte by byte, whatever follows the header belongs to the connection
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line[V1_PREFIX.len()..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let bad = || invalid("bad PROXY v1 header");
+    let line = std::str::from_utf8(line).map_err(|_| bad())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| bad())?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(bad());
+            }
+            let port: u16 = src_port.parse().map_err(|_| bad())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(bad()),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0u8; 10];
+    stream.read_exact(&mut head).await?;
+    if head[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("bad PROXY v2 signature"));
+    }
+    let len = u16::from_be_bytes([head[8], head[9]]) as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    parse_v2(head[6], head[7], &body)
+}
+
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, e.g. the balancer's health checks
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("bad PROXY v2 command")),
+    }
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            // IPv4 clients of mixed headers come back as IPv4
+            let ip = IpAddr::V6(Ipv6Addr::from(octets)).to_canonical();
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        // unspecified or unix sockets
+        0 | 3 => Ok(None),
+        _ => Err(invalid("bad PROXY v2 address")),
+    }
+}
+
+fn v6(addr: SocketAddr) -> Ipv6Addr {
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// A v2 header naming `src` as the client of a TCP connection to `dst`.
+/// Mixed families are both sent as IPv6.
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    // version 2, PROXY
+    header.push(0x21);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            header.push(0x11);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+        }
+        _ => {
+            header.push(0x21);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&v6(src).octets());
+            header.extend_from_slice(&v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&src.port().to_be_bytes());
+    header.extend_from_slice(&dst.port().to_be_bytes());
+    header
+}
+
+/// A v2 header for a connection made by the proxy itself, with no client.
+pub fn encode_v2_local() -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    // version 2, LOCAL, unspecified family, no addresses
+    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_v1() {
+        let mut data: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.2 51234 443\r\nGET /";
+        let client = read_header(&mut data).await.unwrap();
+        assert_eq!(client, Some("192.0.2.1:51234".parse().unwrap()));
+        assert_eq!(data, b"GET /");
+
+        let mut data: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n";
+        let client = read_header(&mut data).await.unwrap();
+        assert_eq!(client, Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut data).await.unwrap(), None);
+
+        for bad in [
+            &b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 x 2\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ] {
+            let mut data = bad;
+            assert!(read_header(&mut data).await.is_err());
+        }
+        let mut long = b"PROXY ".to_vec();
+        long.extend(std::iter::repeat_n(b'1', 200));
+        assert!(read_header(&mut long.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_round_trip() {
+        let src: SocketAddr = "192.0.2.1:51234".parse().unwrap();
+        let dst: SocketAddr = "198.51.100.2:443".parse().unwrap();
+        let mut data = encode_v2(src, dst);
+        assert_eq!(data.len(), 28);
+        data.extend_from_slice(b"payload");
+        let mut reader = data.as_slice();
+        assert_eq!(read_header(&mut reader).await.unwrap(), Some(src));
+        assert_eq!(reader, b"payload");
+
+        let src6: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        let data = encode_v2(src6, dst);
+        assert_eq!(data.len(), 52);
+        assert_eq!(read_header(&mut data.as_slice()).await.unwrap(), Some(src6));
+        let data = encode_v2(src, "[2001:db8::2]:443".parse().unwrap());
+        assert_eq!(read_header(&mut data.as_slice()).await.unwrap(), Some(src));
+
+        let local = encode_v2_local();
+        assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_header_policy() {
+        let options = |pairs: &[(&str, toml::Value)]| {
+            Some(
+                pairs
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect::<HashMap<_, _>>(),
+            )
+        };
+        assert!(HeaderPolicy::from_options(&None).unwrap().is_none());
+        let on = ("proxy_protocol", toml::Value::Boolean(true));
+        assert!(HeaderPolicy::from_options(&options(std::slice::from_ref(&on))).is_err());
+        let bad = (
+            "proxy_protocol_from",
+            toml::Value::String("10.0.0.0/33".into()),
+        );
+        assert!(HeaderPolicy::from_options(&options(&[on.clone(), bad])).is_err());
+
+        let from = (
+            "proxy_protocol_from",
+            toml::Value::String("10.0.0.0/8, 2001:db8::7".into()),
+        );
+        let policy = HeaderPolicy::from_options(&options(&[on, from]))
+            .unwrap()
+            .unwrap();
+        assert!(policy.trusts("10.1.2.3:4000".parse().unwrap()));
+        assert!(policy.trusts("[::ffff:10.1.2.3]:4000".parse().unwrap()));
+        assert!(policy.trusts("[2001:db8::7]:4000".parse().unwrap()));
+        assert!(!policy.trusts("192.0.2.1:4000".parse().unwrap()));
+        assert!(!policy.trusts("[2001:db8::8]:4000".parse().unwrap()));
+
+        let permits: Vec<_> =
+            futures::future::join_all((0..MAX_PENDING_HEADERS).map(|_| policy.slot())).await;
+        assert!(
+            timeout(Duration::from_millis(50), policy.slot())
+                .await
+                .is_err()
+        );
+        drop(permits);
+        let _slot = policy.slot().await;
+    }
+}
@@ -0,0 +1,434 @@
+//! Authenticated key exchange for pb_tcp (`CONN_TYPE_SECURE`).
+//!
+//! The client sends `ver | timestamp | nonce | x25519 public | hmac`, the
+//! server answers `x25519 public | hmac`; both macs are keyed by a PBKDF2 hash
+//! of the password, so only password holders can complete the exchange.
+//! Per-direction keys come from HKDF over the shared secret and the
+//! transcript. Records are `seal(len u16) | seal(payload)` with ChaCha20-Poly1305
+//! and counter nonces; an empty record ends the stream, so a cut connection
+//! is told apart from a closed one. Client hellos are rejected outside `REPLAY_WINDOW` or
+//! when their nonce was already seen.
+
+use crate::util::tcp_frame::{FrameReader, FrameWriter};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, aead::Aead};
+use log::debug;
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hkdf, hmac, pbkdf2};
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, duplex};
+use tokio::spawn;
+use tokio::time::timeout;
+
+const VERSION: u8 = 2;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const PBKDF2_SALT: &[u8] = b"rog pb_tcp secure v1";
+const CLIENT_HELLO_LEN: usize = 1 + 8 + 16 + 32 + 32;
+const SERVER_HELLO_LEN: usize = 32 + 32;
+const TAG_LEN: usize = 16;
+const MAX_RECORD: usize = 16 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Allowed clock difference, and how long seen nonces are remembered.
+const REPLAY_WINDOW: u64 = 120;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Password hash used to authenticate the exchange. Slow to build, so make
+/// one per connector / listener.
+pub struct SecureKey {
+    mac: hmac::Key,
+    salt: [u8; 32],
+}
+
+impl SecureKey {
+    pub fn new(password: &str) -> Self {
+        let mut psk = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            PBKDF2_SALT,
+            password.as_bytes(),
+            &mut psk,
+        );
+        Self {
+            mac: hmac::Key::new(hmac::HMAC_SHA256, &psk),
+            salt: psk,
+        }
+    }
+}
+
+/// Client nonces seen within the replay window.
+#[derive(Default)]
+pub struct ReplayGuard {
+    seen: Mutex<HashMap<[u8; 16], u64>>,
+}
+
+impl ReplayGuard {
+    pub(crate) fn check(&self, nonce: [u8; 16], ts: u64, now: u64) -> io::Result<()> {
+        if ts.abs_diff(now) > REPLAY_WINDOW {
+            return Err(invalid("timestamp outside the time window"));
+        }
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, t| t.abs_diff(now) <= REPLAY_WINDOW);
+        if seen.insert(nonce, ts).is_some() {
+            return Err(invalid("nonce replayed"));
+        }
+        Ok(())
+    }
+}
+
+fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .map_err(|_| io::Error::other("random"))?;
+    Ok(buf)
+}
+
+fn ephemeral() -> io::Result<(EphemeralPrivateKey, [u8; 32])> {
+    let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+        .map_err(|_| io::Error::other("x25519 key generation"))?;
+    let public = private
+        .compute_public_key()
+        .map_err(|_| io::Error::other("x25519 public key"))?;
+    Ok((private, public.as_ref().try_into().unwrap()))
+}
+
+fn client_hello(key: &SecureKey, ts: u64) -> io::Result<(EphemeralPrivateKey, Vec<u8>)> {
+    let (private, public) = ephemeral()?;
+    let mut hello = Vec::with_capacity(CLIENT_HELLO_LEN);
+    hello.push(VERSION);
+    hello.extend_from_slice(&ts.to_be_bytes());
+    hello.extend_from_slice(&random::<16>()?);
+    hello.extend_from_slice(&public);
+    let tag = hmac::sign(&key.mac, &[b"rog client".as_slice(), &hello].concat());
+    hello.extend_from_slice(tag.as_ref());
+    Ok((private, hello))
+}
+
+/// Verifies a client hello against each accepted key and returns the index
+/// of the key that signed it along with the client's public key.
+fn check_client_hello(
+    keys: &[SecureKey],
+    guard: &ReplayGuard,
+    hello: &[u8],
+    now: u64,
+) -> io::Result<(usize, [u8; 32])> {
+    let (body, tag) = hello.split_at(CLIENT_HELLO_LEN - 32);
+    let signed = [b"rog client".as_slice(), body].concat();
+    let index = keys
+        .iter()
+        .position(|key| hmac::verify(&key.mac, &signed, tag).is_ok())
+        .ok_or_else(|| invalid("secure hello authentication failed"))?;
+    if body[0] != VERSION {
+        return Err(invalid("secure hello version"));
+    }
+    let ts = u64::from_be_bytes(body[1..9].try_into().unwrap());
+    guard.check(body[9..25].try_into().unwrap(), ts, now)?;
+    Ok((index, body[25..57].try_into().unwrap()))
+}
+
+/// What the server hello mac covers.
+fn server_mac_data(client_hello: &[u8], public: &[u8]) -> Vec<u8> {
+    [b"rog server".as_slice(), client_hello, public].concat()
+}
+
+/// (client to server, server to client) keys.
+fn session_keys(
+    key: &SecureKey,
+    private: EphemeralPrivateKey,
+    peer: &[u8],
+    client_hello: &[u8],
+    server_hello: &[u8],
+) -> io::Result<([u8; 32], [u8; 32])> {
+    let transcript = digest::digest(&digest::SHA256, &[client_hello, server_hello].concat());
+    let prk = agreement::agree_ephemeral(private, &UnparsedPublicKey::new(&X25519, peer), |dh| {
+        hkdf::Salt::new(hkdf::HKDF_SHA256, &key.salt).extract(dh)
+    })
+    .map_err(|_| invalid("x25519 agreement failed"))?;
+    let expand = |label: &[u8]| -> io::Result<[u8; 32]> {
+        let mut out = [0u8; 32];
+        prk.expand(&[label, transcript.as_ref()], hkdf::HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut out))
+            .map_err(|_| io::Error::other("hkdf"))?;
+        Ok(out)
+    };
+    Ok((expand(b"rog c2s")?, expand(b"rog s2c")?))
+}
+
+/// Runs the client side of the exchange; the returned halves carry plaintext.
+pub async fn client_handshake(
+    mut reader: FrameReader,
+    mut writer: FrameWriter,
+    key: &SecureKey,
+) -> io::Result<(FrameReader, FrameWriter)> {
+    let (private, hello) = client_hello(key, now())?;
+    writer.write_all(&hello).await?;
+    writer.flush().await?;
+    let mut reply = [0u8; SERVER_HELLO_LEN];
+    timeout(HANDSHAKE_TIMEOUT, reader.read_exact(&mut reply))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "secure handshake timeout"))??;
+    let (public, tag) = reply.split_at(32);
+    hmac::verify(&key.mac, &server_mac_data(&hello, public), tag)
+        .map_err(|_| invalid("secure server authentication failed"))?;
+    let (c2s, s2c) = session_keys(key, private, public, &hello, &reply)?;
+    Ok(start(reader, writer, c2s, s2c))
+}
+
+/// Runs the server side of the exchange for any of `keys`; the returned
+/// halves carry plaintext, along with the index of the client's key.
+pub async fn server_handshake(
+    mut reader: FrameReader,
+    mut writer: FrameWriter,
+    keys: &[SecureKey],
+    guard: &ReplayGuard,
+) -> io::Result<(FrameReader, FrameWriter, usize)> {
+    let mut hello = [0u8; CLIENT_HELLO_LEN];
+    timeout(HANDSHAKE_TIMEOUT, reader.read_exact(&mut hello))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "secure handshake timeout"))??;
+    let (index, peer) = check_client_hello(keys, guard, &hello, now())?;
+    let key = &keys[index];
+    let (private, public) = ephemeral()?;
+    let mut reply = public.to_vec();
+    reply.extend_from_slice(hmac::sign(&key.mac, &server_mac_data(&hello, &public)).as_ref());
+    writer.write_all(&reply).await?;
+    writer.flush().await?;
+    let (c2s, s2c) = session_keys(key, private, &peer, &hello, &reply)?;
+    let (reader, writer) = start(reader, writer, s2c, c2s);
+    Ok((reader, writer, index))
+}
+
+/// One direction of the record layer.
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            aead: ChaCha20Poly1305::new(&key.into()),
+            counter: 0,
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.nonce();
+        self.aead
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| io::Error::other("secure seal"))
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.nonce();
+        self.aead
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .map_err(|_| invalid("secure record authentication failed"))
+    }
+}
+
+/// Plaintext side of the read loop. Its EOF is an error unless the peer
+/// ended the stream with an empty record.
+struct SecureReader {
+    inner: ReadHalf<DuplexStream>,
+    ended: Arc<AtomicBool>,
+}
+
+impl AsyncRead for SecureReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(()))
+                if buf.filled().len() == filled
+                    && buf.remaining() > 0
+                    && !self.ended.load(Ordering::Acquire) =>
+            {
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "secure stream truncated",
+                )))
+            }
+            res => res,
+        }
+    }
+}
+
+fn start(
+    mut reader: FrameReader,
+    mut writer: FrameWriter,
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+) -> (FrameReader, FrameWriter) {
+    let (app, local) = duplex(2 * MAX_RECORD);
+    let (mut local_r, mut local_w) = tokio::io::split(local);
+    spawn(async move {
+        let mut cipher = Cipher::new(send_key);
+        let mut buf = vec![0u8; MAX_RECORD];
+        let res: io::Result<()> = async {
+            loop {
+                let n = local_r.read(&mut buf).await?;
+                let mut record = cipher.seal(&(n as u16).to_be_bytes())?;
+                record.extend_from_slice(&cipher.seal(&buf[..n])?);
+                writer.write_all(&record).await?;
+                writer.flush().await?;
+                if n == 0 {
+                    break;
+                }
+            }
+            writer.shutdown().await
+        }
+        .await;
+        if let Err(e) = res {
+            debug!("secure write loop: {}", e);
+        }
+    });
+    let ended = Arc::new(AtomicBool::new(false));
+    let read_ended = ended.clone();
+    spawn(async move {
+        let mut cipher = Cipher::new(recv_key);
+        let res: io::Result<()> = async {
+            let mut head = [0u8; 2 + TAG_LEN];
+            loop {
+                reader.read_exact(&mut head).await?;
+                let len = cipher.open(&head)?;
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                let mut body = vec![0u8; len + TAG_LEN];
+                reader.read_exact(&mut body).await?;
+                let payload = cipher.open(&body)?;
+                if payload.is_empty() {
+                    break;
+                }
+                local_w.write_all(&payload).await?;
+            }
+            read_ended.store(true, Ordering::Release);
+            Ok(())
+        }
+        .await;
+        if let Err(e) = res {
+            debug!("secure read loop: {}", e);
+        }
+        // without the end record the reader gets an error at this EOF
+        let _ = local_w.shutdown().await;
+    });
+    let (r, w) = tokio::io::split(app);
+    (Box::new(SecureReader { inner: r, ended }), Box::new(w))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_hello_checks() {
+        let keys = [SecureKey::new("pw"), SecureKey::new("pw2")];
+        let key = &keys[..1];
+        let guard = ReplayGuard::default();
+        let t = now();
+        let (_, hello) = client_hello(&keys[0], t).unwrap();
+        assert!(check_client_hello(key, &guard, &hello, t).is_ok());
+        // replayed
+        assert!(check_client_hello(key, &guard, &hello, t).is_err());
+        // stale
+        let (_, old) = client_hello(&keys[0], t - REPLAY_WINDOW - 1).unwrap();
+        assert!(check_client_hello(key, &guard, &old, t).is_err());
+        // wrong password
+        let (_, other) = client_hello(&SecureKey::new("other"), t).unwrap();
+        assert!(check_client_hello(key, &guard, &other, t).is_err());
+        // any accepted key
+        let (_, second) = client_hello(&keys[1], t).unwrap();
+        let (signer, _) = check_client_hello(&keys, &guard, &second, t).unwrap();
+        assert_eq!(signer, 1);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_round_trip() {
+        let key = std::sync::Arc::new(SecureKey::new("pw"));
+        let (a, b) = duplex(4096);
+        let (ar, aw) = tokio::io::split(a);
+        let (br, bw) = tokio::io::split(b);
+        let server_key = key.clone();
+        let server = spawn(async move {
+            let guard = ReplayGuard::default();
+            let (mut r, mut w, _) = server_handshake(
+                Box::new(br),
+                Box::new(bw),
+                std::slice::from_ref(&*server_key),
+                &guard,
+            )
+            .await
+            .unwrap();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+            w.shutdown().await.unwrap();
+        });
+        let (mut r, mut w) = client_handshake(Box::new(ar), Box::new(aw), &key)
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        w.write_all(&data).await.unwrap();
+        w.shutdown().await.unwrap();
+        let mut got = Vec::new();
+        r.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, data);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_truncated_stream() {
+        let (a, b) = duplex(4096);
+        let (ar, aw) = tokio::io::split(a);
+        let (br, bw) = tokio::io::split(b);
+        let key = [7u8; 32];
+        let (_, mut w) = start(Box::new(ar), Box::new(aw), key, key);
+        let (mut r, _w) = start(Box::new(br), Box::new(bw), key, key);
+        w.write_all(b"complete").await.unwrap();
+        w.shutdown().await.unwrap();
+        let mut got = Vec::new();
+        r.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"complete");
+
+        // the same records without the final empty one
+        let mut raw = Vec::new();
+        let mut cipher = Cipher::new(key);
+        raw.extend(cipher.seal(&4u16.to_be_bytes()).unwrap());
+        raw.extend(cipher.seal(b"part").unwrap());
+        let (mut r, _w) = start(
+            Box::new(std::io::Cursor::new(raw)),
+            Box::new(tokio::io::sink()),
+            key,
+            key,
+        );
+        let mut got = Vec::new();
+        let err = r.read_to_end(&mut got).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(got, b"part");
+    }
+}
@@ -0,0 +1,517 @@
+use ring::aead::quic::{AES_128, HeaderProtectionKey};
+use ring::aead::{AES_128_GCM, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use std::net::IpAddr;
+
+/// Upper bound of bytes buffered while waiting for a complete ClientHello / request head.
+pub const SNIFF_MAX_LEN: usize = 16 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SniffResult {
+    Found(String),
+    /// Looks like the protocol but more bytes are needed.
+    Incomplete,
+    NoMatch,
+}
+
+/// Sniffs the first bytes of a TCP stream: TLS ClientHello SNI or HTTP/1 Host.
+pub fn sniff_tcp(data: &[u8]) -> SniffResult {
+    match sniff_tls(data) {
+        SniffResult::NoMatch => sniff_http(data),
+        r => r,
+    }
+}
+
+pub fn sniff_tls(data: &[u8]) -> SniffResult {
+    // record header: type(1) version(2) length(2)
+    if data.is_empty() {
+        return SniffResult::Incomplete;
+    }
+    if data[0] != 0x16 {
+        return SniffResult::NoMatch;
+    }
+    if data.len() < 5 {
+        return SniffResult::Incomplete;
+    }
+    if data[1] != 0x03 {
+        return SniffResult::NoMatch;
+    }
+    let record_len = u16::from_be_bytes([data[3], data[4]]) as usize;
+    let record = &data[5..data.len().min(5 + record_len)];
+    if record.len() < 4 {
+        return SniffResult::Incomplete;
+    }
+    let hs_len = u32::from_be_bytes([0, record[1], record[2], record[3]]) as usize;
+    if record[0] != 0x01 {
+        return SniffResult::NoMatch;
+    }
+    // a ClientHello split over several records is reassembled from the record payloads
+    if 4 + hs_len > record.len() {
+        if record.len() < record_len {
+            return SniffResult::Incomplete;
+        }
+        let mut handshake = Vec::with_capacity(4 + hs_len);
+        let mut rest = data;
+        while handshake.len() < 4 + hs_len {
+            if rest.len() < 5 {
+                return SniffResult::Incomplete;
+            }
+            if rest[0] != 0x16 {
+                return SniffResult::NoMatch;
+            }
+            let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            if rest.len() < 5 + len {
+                return SniffResult::Incomplete;
+            }
+            handshake.extend_from_slice(&rest[5..5 + len]);
+            rest = &rest[5 + len..];
+        }
+        return parse_client_hello(&handshake);
+    }
+    parse_client_hello(record)
+}
+
+/// Parses a handshake message (without record layer) and extracts the SNI.
+pub fn parse_client_hello(hs: &[u8]) -> SniffResult {
+    let mut r = Reader::new(hs);
+    let parsed = (|| {
+        if r.u8()? != 0x01 {
+            return None;
+        }
+        let len = r.u24()? as usize;
+        let mut body = Reader::new(r.take(len)?);
+        body.take(2 + 32)?; // legacy_version, random
+        let n = body.u8()? as usize;
+        body.take(n)?; // session id
+        let n = body.u16()? as usize;
+        body.take(n)?; // cipher suites
+        let n = body.u8()? as usize;
+        body.take(n)?; // compression methods
+        let n = body.u16()? as usize;
+        let mut exts = Reader::new(body.take(n)?);
+        while !exts.is_empty() {
+            let ext_type = exts.u16()?;
+            let n = exts.u16()? as usize;
+            let ext = exts.take(n)?;
+            if ext_type != 0x0000 {
+                continue;
+            }
+            let mut sni = Reader::new(ext);
+            let n = sni.u16()? as usize;
+            let mut list = Reader::new(sni.take(n)?);
+            while !list.is_empty() {
+                let name_type = list.u8()?;
+                let n = list.u16()? as usize;
+                let name = list.take(n)?;
+                if name_type == 0 {
+                    return Some(Some(std::str::from_utf8(name).ok()?.to_string()));
+                }
+            }
+        }
+        Some(None)
+    })();
+    match parsed {
+        Some(Some(domain)) => found(domain),
+        _ => SniffResult::NoMatch,
+    }
+}
+
+const HTTP_METHODS: [&str; 9] = [
+    "GET ", "POST ", "PUT ", "HEAD ", "DELETE ", "OPTIONS ", "PATCH ", "TRACE ", "CONNECT ",
+];
+
+pub fn sniff_http(data: &[u8]) -> SniffResult {
+    let method_match = HTTP_METHODS.iter().any(|m| {
+        let m = m.as_bytes();
+        let n = m.len().min(data.len());
+        data[..n] == m[..n]
+    });
+    if !method_match {
+        return SniffResult::NoMatch;
+    }
+    let head_end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(p) => p,
+        None if data.len() < SNIFF_MAX_LEN => return SniffResult::Incomplete,
+        None => return SniffResult::NoMatch,
+    };
+    let head = String::from_utf8_lossy(&data[..head_end]);
+    for line in head.split("\r\n").skip(1) {
+        if let Some((name, value)) = line.split_once(':')
+            && name.trim().eq_ignore_ascii_case("host")
+        {
+            return found(strip_port(value.trim()).to_string());
+        }
+    }
+    SniffResult::NoMatch
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match host.rsplit_once(':') {
+        Some((h, port)) if port.parse::<u16>().is_ok() => h,
+        _ => host,
+    }
+}
+
+// ip literals carry no extra information for routing
+fn found(domain: String) -> SniffResult {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    if domain.is_empty() || domain.parse::<IpAddr>().is_ok() {
+        return SniffResult::NoMatch;
+    }
+    SniffResult::Found(domain)
+}
+
+const QUIC_V1: u32 = 0x0000_0001;
+const QUIC_V1_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// Decrypts a QUIC v1 client Initial packet and extracts the SNI of the
+/// ClientHello carried in its CRYPTO frames. Only the first datagram is
+/// looked at, so a ClientHello spanning several packets is not recognized.
+pub fn sniff_quic(packet: &[u8]) -> Option<String> {
+    let mut r = Reader::new(packet);
+    let first = r.u8()?;
+    // long header, fixed bit, packet type Initial
+    if first & 0xf0 != 0xc0 {
+        return None;
+    }
+    if r.u32()? != QUIC_V1 {
+        return None;
+    }
+    let n = r.u8()? as usize;
+    let dcid = r.take(n)?;
+    let n = r.u8()? as usize;
+    r.take(n)?; // scid
+    let n = r.varint()? as usize;
+    r.take(n)?; // token
+    let length = r.varint()? as usize;
+    let pn_offset = r.pos;
+    if packet.len() < pn_offset + length || length < 20 {
+        return None;
+    }
+
+    let keys = InitialKeys::client(dcid)?;
+    let sample = &packet[pn_offset + 4..pn_offset + 20];
+    let mask = keys.hp.new_mask(sample).ok()?;
+    let first = first ^ (mask[0] & 0x0f);
+    let pn_len = (first & 0x03) as usize + 1;
+
+    let mut header = packet[..pn_offset + pn_len].to_vec();
+    header[0] = first;
+    for i in 0..pn_len {
+        header[pn_offset + i] ^= mask[1 + i];
+    }
+    let mut nonce = keys.iv;
+    for i in 0..pn_len {
+        nonce[12 - pn_len + i] ^= header[pn_offset + i];
+    }
+    let mut payload = packet[pn_offset + pn_len..pn_offset + length].to_vec();
+    let plain = keys
+        .key
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&header[..]),
+            &mut payload,
+        )
+        .ok()?;
+
+    let crypto = collect_crypto(plain)?;
+    match parse_client_hello(&crypto) {
+        SniffResult::Found(domain) => Some(domain),
+        _ => None,
+    }
+}
+
+// reassembles CRYPTO frame data starting at offset 0
+fn collect_crypto(frames: &[u8]) -> Option<Vec<u8>> {
+    let mut r = Reader::new(frames);
+    let mut chunks: Vec<(usize, &[u8])> = Vec::new();
+    while !r.is_empty() {
+        match r.varint()? {
+            0x00 | 0x01 => {} // PADDING, PING
+            t @ (0x02 | 0x03) => {
+                // ACK
+                r.varint()?;
+                r.varint()?;
+                let ranges = r.varint()?;
+                r.varint()?;
+                for _ in 0..ranges {
+                    r.varint()?;
+                    r.varint()?;
+                }
+                if t == 0x03 {
+                    for _ in 0..3 {
+                        r.varint()?;
+                    }
+                }
+            }
+            0x06 => {
+                let offset = r.varint()? as usize;
+                let n = r.varint()? as usize;
+                chunks.push((offset, r.take(n)?));
+            }
+            _ => break,
+        }
+    }
+    chunks.sort_by_key(|(offset, _)| *offset);
+    let mut out = Vec::new();
+    for (offset, data) in chunks {
+        if offset > out.len() {
+            break;
+        }
+        let skip = out.len() - offset;
+        if skip < data.len() {
+            out.extend_from_slice(&data[skip..]);
+        }
+    }
+    if out.is_empty() { None } else { Some(out) }
+}
+
+struct InitialKeys {
+    key: LessSafeKey,
+    iv: [u8; 12],
+    hp: HeaderProtectionKey,
+}
+
+impl InitialKeys {
+    fn client(dcid: &[u8]) -> Option<Self> {
+        let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, &QUIC_V1_SALT).extract(dcid);
+        let mut secret = [0u8; 32];
+        expand_label(&initial, b"client in", &mut secret)?;
+        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+        let mut key = [0u8; 16];
+        let mut iv = [0u8; 12];
+        let mut hp = [0u8; 16];
+        expand_label(&secret, b"quic key", &mut key)?;
+        expand_label(&secret, b"quic iv", &mut iv)?;
+        expand_label(&secret, b"quic hp", &mut hp)?;
+        Some(InitialKeys {
+            key: LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &key).ok()?),
+            iv,
+            hp: HeaderProtectionKey::new(&AES_128, &hp).ok()?,
+        })
+    }
+}
+
+struct OkmLen(usize);
+
+impl hkdf::KeyType for OkmLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+// TLS 1.3 HKDF-Expand-Label with an empty context
+fn expand_label(prk: &hkdf::Prk, label: &[u8], out: &mut [u8]) -> Option<()> {
+    let len = (out.len() as u16).to_be_bytes();
+    let label_len = [(6 + label.len()) as u8];
+    let info: [&[u8]; 5] = [&len, &label_len, b"tls13 ", label, &[0]];
+    prk.expand(&info, OkmLen(out.len())).ok()?.fill(out).ok()
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let s = self.buf.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(s)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<u32> {
+        let b = self.take(3)?;
+        Some(u32::from_be_bytes([0, b[0], b[1], b[2]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.take(4)?;
+        Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let first = self.u8()?;
+        let len = 1usize << (first >> 6);
+        let mut v = (first & 0x3f) as u64;
+        for b in self.take(len - 1)? {
+            v = (v << 8) | *b as u64;
+        }
+        Some(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let name = sni.as_bytes();
+        let mut sni_ext = Vec::new();
+        sni_ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni_ext.push(0);
+        sni_ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni_ext.extend_from_slice(name);
+
+        let mut exts = Vec::new();
+        // an unrelated extension first (supported_versions)
+        exts.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        exts.extend_from_slice(&[0x00, 0x00]);
+        exts.extend_from_slice(&(sni_ext.len() as u16).to_be_bytes());
+        exts.extend_from_slice(&sni_ext);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[7u8; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+        body.extend_from_slice(&exts);
+
+        let mut hs = vec![0x01];
+        hs.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hs.extend_from_slice(&body);
+        hs
+    }
+
+    fn tls_record(payload: &[u8]) -> Vec<u8> {
+        let mut rec = vec![0x16, 0x03, 0x01];
+        rec.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        rec.extend_from_slice(payload);
+        rec
+    }
+
+    #[test]
+    fn test_sniff_tls_sni() {
+        let data = tls_record(&client_hello("Www.Example.com"));
+        assert_eq!(
+            sniff_tcp(&data),
+            SniffResult::Found("www.example.com".to_string())
+        );
+        assert_eq!(sniff_tcp(&data[..20]), SniffResult::Incomplete);
+
+        // ClientHello fragmented over two records
+        let hs = client_hello("split.example.com");
+        let mut data = tls_record(&hs[..30]);
+        data.extend_from_slice(&tls_record(&hs[30..]));
+        assert_eq!(
+            sniff_tls(&data),
+            SniffResult::Found("split.example.com".to_string())
+        );
+        assert_eq!(sniff_tls(&data[..40]), SniffResult::Incomplete);
+    }
+
+    #[test]
+    fn test_sniff_http_host() {
+        let req = b"GET / HTTP/1.1\r\nUser-Agent: x\r\nhost: example.com:8080\r\n\r\n";
+        assert_eq!(
+            sniff_tcp(req),
+            SniffResult::Found("example.com".to_string())
+        );
+        assert_eq!(sniff_tcp(&req[..20]), SniffResult::Incomplete);
+        assert_eq!(sniff_tcp(b"GE"), SniffResult::Incomplete);
+        assert_eq!(
+            sniff_tcp(b"GET / HTTP/1.1\r\nHost: 1.2.3.4\r\n\r\n"),
+            SniffResult::NoMatch
+        );
+        assert_eq!(sniff_tcp(b"SSH-2.0-OpenSSH\r\n"), SniffResult::NoMatch);
+    }
+
+    #[test]
+    fn test_quic_initial_keys() {
+        // RFC 9001 appendix A.1
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, &QUIC_V1_SALT).extract(&dcid);
+        let mut secret = [0u8; 32];
+        expand_label(&initial, b"client in", &mut secret).unwrap();
+        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+        let mut iv = [0u8; 12];
+        expand_label(&secret, b"quic iv", &mut iv).unwrap();
+        assert_eq!(
+            iv,
+            [
+                0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25, 0x5c
+            ]
+        );
+        let mut hp = [0u8; 16];
+        expand_label(&secret, b"quic hp", &mut hp).unwrap();
+        assert_eq!(
+            hp,
+            [
+                0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e, 0x99, 0x33, 0xad,
+                0xed, 0xd2
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sniff_quic_initial() {
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let hs = client_hello("quic.example.com");
+        // two CRYPTO frames out of order, then padding
+        let split = 20;
+        let mut frames = vec![0x06, 0x40, split as u8];
+        frames.extend_from_slice(&(0x4000u16 | (hs.len() - split) as u16).to_be_bytes());
+        frames.extend_from_slice(&hs[split..]);
+        frames.extend_from_slice(&[0x06, 0x00, split as u8]);
+        frames.extend_from_slice(&hs[..split]);
+        frames.resize(frames.len() + 64, 0);
+
+        let pn: u8 = 2;
+        let mut packet = vec![0xc0, 0x00, 0x00, 0x00, 0x01, dcid.len() as u8];
+        packet.extend_from_slice(&dcid);
+        packet.push(0); // scid
+        packet.push(0); // token
+        let length = 1 + frames.len() + 16;
+        packet.extend_from_slice(&(0x4000u16 | length as u16).to_be_bytes());
+        let pn_offset = packet.len();
+        packet.push(pn);
+
+        let keys = InitialKeys::client(&dcid).unwrap();
+        let mut nonce = keys.iv;
+        nonce[11] ^= pn;
+        let mut payload = frames.clone();
+        keys.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&packet[..]),
+                &mut payload,
+            )
+            .unwrap();
+        packet.extend_from_slice(&payload);
+        let mask = keys
+            .hp
+            .new_mask(&packet[pn_offset + 4..pn_offset + 20])
+            .unwrap();
+        packet[0] ^= mask[0] & 0x0f;
+        packet[pn_offset] ^= mask[1];
+
+        assert_eq!(sniff_quic(&packet), Some("quic.example.com".to_string()));
+        packet[pn_offset + 30] ^= 1;
+        assert_eq!(sniff_quic(&packet), None);
+        assert_eq!(sniff_quic(b"\x40short header"), None);
+    }
+}
@@ -0,0 +1,108 @@
+//! Linux zero-copy relaying of one TCP socket into another with splice(2)
+//! through a pipe, so the data never enters userspace.
+
+use socket2::SockRef;
+use std::future::Future;
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// Bytes moved per splice call, the default pipe capacity.
+const PIPE_CHUNK: usize = 64 * 1024;
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]))) }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// Moves everything `src` sends to `dst` and then shuts down `dst` for
+/// writing. `count` sees the size of every chunk after it was delivered and
+/// stops the relay by failing.
+pub(crate) async fn pump<F: Future<Output = io::Result<()>>>(
+    src: &TcpStream,
+    dst: &TcpStream,
+    count: impl Fn(u64) -> F,
+) -> io::Result<()> {
+    let (pipe_r, pipe_w) = pipe()?;
+    loop {
+        let n = src
+            .async_io(Interest::READABLE, || {
+                splice(src.as_raw_fd(), pipe_w.as_raw_fd(), PIPE_CHUNK)
+            })
+            .await?;
+        if n == 0 {
+            return SockRef::from(dst).shutdown(Shutdown::Write);
+        }
+        let mut left = n;
+        while left > 0 {
+            left -= dst
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe_r.as_raw_fd(), dst.as_raw_fd(), left)
+                })
+                .await?;
+        }
+        count(n as u64).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_pump() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut a = TcpStream::connect(addr).await.unwrap();
+        let (b, _) = listener.accept().await.unwrap();
+        let c = TcpStream::connect(addr).await.unwrap();
+        let (mut d, _) = listener.accept().await.unwrap();
+
+        let data: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&sent).await.unwrap();
+            a.shutdown().await.unwrap();
+        });
+        let counted = std::sync::atomic::AtomicU64::new(0);
+        let reader = tokio::spawn(async move {
+            let mut out = Vec::new();
+            d.read_to_end(&mut out).await.unwrap();
+            out
+        });
+        pump(&b, &c, |n| {
+            counted.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+            async { Ok(()) }
+        })
+        .await
+        .unwrap();
+        writer.await.unwrap();
+        assert_eq!(reader.await.unwrap(), data);
+        assert_eq!(counted.into_inner(), data.len() as u64);
+    }
+}
@@ -1,21 +1,151 @@
+use crate::def::config::{get_option_bool, get_option_str};
+use bytes::Bytes;
 use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
 use prost::Message;
+use std::collections::HashMap;
 use std::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub const CONN_TYPE_STREAM: u8 = 0x01;
 pub const CONN_TYPE_UDP: u8 = 0x02;
+/// Multiplexed connection, see `util::mux`. Each logical stream starts with
+/// its own `CONN_TYPE_STREAM` or `CONN_TYPE_UDP`.
+pub const CONN_TYPE_MUX: u8 = 0x03;
+/// Authenticated key exchange, see `util::secure`. The encrypted channel
+/// then starts with one of the other conn types.
+pub const CONN_TYPE_SECURE: u8 = 0x04;
+
+/// Read / write half of a pb_tcp connection, plain tcp or tls.
+pub type FrameReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type FrameWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Protobuf field number reserved for padding in every pb_tcp message.
+/// Decoders skip it as an unknown field, so padded frames read fine anywhere.
+const PADDING_FIELD_KEY: u8 = (15 << 3) | 2;
+const PADDING_RANDOM_MAX: u32 = 256;
+const PADDING_BUCKET_MIN: usize = 128;
+const PADDING_BUCKET_MAX: usize = 16 * 1024;
+
+/// How pb_tcp payloads are encrypted, from the `encrypt` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptMode {
+    Always,
+    Never,
+    /// Everything but port 443, which is assumed to carry TLS already.
+    Auto,
+}
+
+impl EncryptMode {
+    pub fn from_options(options: &Option<HashMap<String, toml::Value>>) -> io::Result<Self> {
+        match get_option_str(options, "encrypt").as_deref() {
+            None | Some("auto") => Ok(EncryptMode::Auto),
+            Some("always") => Ok(EncryptMode::Always),
+            Some("never") => Ok(EncryptMode::Never),
+            Some(other) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("encrypt must be always, never or auto, not {}", other),
+            )),
+        }
+    }
+
+    pub fn for_port(self, port: u16) -> bool {
+        match self {
+            EncryptMode::Always => true,
+            EncryptMode::Never => false,
+            EncryptMode::Auto => port != 443,
+        }
+    }
+}
+
+/// Frame size shaping, from the `padding` and `padding_buckets` options.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FramePadding {
+    /// Add 0-255 random bytes to every frame.
+    pub random: bool,
+    /// Round frame sizes up to a power of two (multiples of 16 KiB above that).
+    pub buckets: bool,
+}
+
+impl FramePadding {
+    pub fn from_options(options: &Option<HashMap<String, toml::Value>>) -> Self {
+        Self {
+            random: get_option_bool(options, "padding"),
+            buckets: get_option_bool(options, "padding_buckets"),
+        }
+    }
+
+    /// Pads the message that starts at `start` in `data`.
+    fn apply(&self, data: &mut Vec<u8>, start: usize) {
+        if !self.random && !self.buckets {
+            return;
+        }
+        let msg_len = data.len() - start;
+        let mut extra = if self.random {
+            (OsRng.next_u32() % PADDING_RANDOM_MAX) as usize
+        } else {
+            0
+        };
+        if self.buckets {
+            let mut target = bucket(msg_len + extra);
+            extra = target - msg_len;
+            // the padding field needs at least two bytes of its own
+            while extra != 0 && padding_len(extra).is_none() {
+                target = bucket(target + 1);
+                extra = target - msg_len;
+            }
+        }
+        let Some(len) = padding_len(extra) else {
+            return;
+        };
+        data.push(PADDING_FIELD_KEY);
+        let mut n = len;
+        while n >= 0x80 {
+            data.push((n as u8 & 0x7f) | 0x80);
+            n >>= 7;
+        }
+        data.push(n as u8);
+        let start = data.len();
+        data.resize(start + len, 0);
+        OsRng.fill_bytes(&mut data[start..]);
+    }
+}
+
+fn bucket(len: usize) -> usize {
+    if len <= PADDING_BUCKET_MAX {
+        len.max(PADDING_BUCKET_MIN).next_power_of_two()
+    } else {
+        len.div_ceil(PADDING_BUCKET_MAX) * PADDING_BUCKET_MAX
+    }
+}
+
+/// Padding bytes that make key + varint length + padding exactly `extra` long.
+fn padding_len(extra: usize) -> Option<usize> {
+    (1..=3).find_map(|varint_len| {
+        let len = extra.checked_sub(1 + varint_len)?;
+        let fits = match varint_len {
+            1 => len < 1 << 7,
+            2 => (1 << 7..1 << 14).contains(&len),
+            _ => (1 << 14..1 << 21).contains(&len),
+        };
+        fits.then_some(len)
+    })
+}
 
 pub async fn write_frame<W: AsyncWriteExt + Unpin, M: Message>(
     writer: &mut W,
     msg: &M,
+    padding: FramePadding,
 ) -> io::Result<()> {
-    let data = msg.encode_to_vec();
-    let len = data.len() as u64;
+    // the header goes in front so the frame leaves in a single write
+    let mut data = Vec::with_capacity(16 + msg.encoded_len());
+    data.resize(16, 0);
+    msg.encode(&mut data).map_err(io::Error::other)?;
+    padding.apply(&mut data, 16);
+    let len = (data.len() - 16) as u64;
     let r = OsRng.next_u64();
     let second = len.wrapping_sub(r);
-    writer.write_all(&r.to_be_bytes()).await?;
-    writer.write_all(&second.to_be_bytes()).await?;
+    data[..8].copy_from_slice(&r.to_be_bytes());
+    data[8..16].copy_from_slice(&second.to_be_bytes());
     writer.write_all(&data).await?;
     writer.flush().await?;
     Ok(())
@@ -39,7 +169,8 @@ pub async fn read_msg<R: AsyncReadExt + Unpin, M: Message + Default>(
     reader: &mut R,
 ) -> io::Result<M> {
     let data = read_frame(reader).await?;
-    M::decode(data.as_slice()).map_err(|e| io::Error::other(format!("protobuf decode: {}", e)))
+    // decoding from `Bytes` lets `bytes` fields share the frame's buffer
+    M::decode(Bytes::from(data)).map_err(|e| io::Error::other(format!("protobuf decode: {}", e)))
 }
 
 pub async fn read_conn_type<R: AsyncReadExt + Unpin>(reader: &mut R) -> io::Result<u8> {
@@ -56,3 +187,36 @@ pub async fn write_conn_type<W: AsyncWriteExt + Unpin>(
     writer.flush().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::v1::pb::StreamRes;
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(bucket(1), 128);
+        assert_eq!(bucket(129), 256);
+        assert_eq!(bucket(16 * 1024 + 1), 32 * 1024);
+        assert_eq!(padding_len(1), None);
+        assert_eq!(padding_len(2), Some(0));
+        assert_eq!(padding_len(129), Some(127));
+        assert_eq!(padding_len(130), None);
+        assert_eq!(padding_len(131), Some(128));
+
+        let msg = StreamRes {
+            payload: vec![7u8; 300].into(),
+            ..Default::default()
+        };
+        let padding = FramePadding {
+            random: true,
+            buckets: true,
+        };
+        for _ in 0..64 {
+            let mut data = msg.encode_to_vec();
+            padding.apply(&mut data, 0);
+            assert!(data.len().is_power_of_two());
+            assert_eq!(StreamRes::decode(data.as_slice()).unwrap(), msg);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Linux socket helpers for transparent proxying (iptables REDIRECT / TPROXY).
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsFd, AsRawFd};
+
+fn setsockopt_int(socket: &Socket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let value: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_transparent(socket: &Socket, addr: &SocketAddr) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => socket.set_ip_transparent_v4(true),
+        SocketAddr::V6(_) => setsockopt_int(socket, libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    }
+}
+
+fn new_socket(addr: &SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*addr), ty, Some(protocol))?;
+    set_transparent(&socket, addr)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// TCP listener accepting connections for any destination routed to it by TPROXY.
+pub fn transparent_tcp_listener(addr: SocketAddr) -> io::Result<tokio::net::TcpListener> {
+    let socket = new_socket(&addr, Type::STREAM, Protocol::TCP)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// UDP socket receiving TPROXY datagrams together with their original destination.
+pub fn transparent_udp_listener(addr: SocketAddr) -> io::Result<tokio::net::UdpSocket> {
+    let socket = new_socket(&addr, Type::DGRAM, Protocol::UDP)?;
+    match addr {
+        SocketAddr::V4(_) => setsockopt_int(&socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?,
+        SocketAddr::V6(_) => {
+            setsockopt_int(&socket, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)?;
+            // dual stack sockets also get ipv4 datagrams
+            let _ = setsockopt_int(&socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR);
+        }
+    }
+    socket.bind(&addr.into())?;
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+/// UDP socket bound to a foreign address, used to answer the client as the
+/// original destination.
+pub fn transparent_udp_reply_socket(from: SocketAddr) -> io::Result<tokio::net::UdpSocket> {
+    let socket = new_socket(&from, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&from.into())?;
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+/// Destination of a connection rewritten by iptables REDIRECT / DNAT.
+pub fn original_dst<S: AsFd>(stream: &S, local: &SocketAddr) -> io::Result<SocketAddr> {
+    let sock = SockRef::from(stream);
+    let addr = match local {
+        SocketAddr::V4(_) => sock.original_dst_v4()?,
+        SocketAddr::V6(_) => sock.original_dst_v6()?,
+    };
+    addr.as_socket()
+        .ok_or_else(|| io::Error::other("original dst is not an inet address"))
+}
+
+/// `recvmsg` on a socket with `IP_RECVORIGDSTADDR` set.
+/// Returns (len, source, original destination).
+pub fn recv_with_orig_dst<S: AsRawFd>(
+    socket: &S,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut src: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 32];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut src as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let src = sockaddr_to_std(&src)
+        .ok_or_else(|| io::Error::other("recvmsg: unknown source address family"))?;
+
+    let mut dst = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+        if (hdr.cmsg_level == libc::SOL_IP && hdr.cmsg_type == libc::IP_ORIGDSTADDR)
+            || (hdr.cmsg_level == libc::SOL_IPV6 && hdr.cmsg_type == libc::IPV6_ORIGDSTADDR)
+        {
+            let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+            let data_len = hdr.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize;
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    libc::CMSG_DATA(cmsg),
+                    &mut storage as *mut libc::sockaddr_storage as *mut u8,
+                    data_len.min(mem::size_of::<libc::sockaddr_storage>()),
+                );
+            }
+            dst = sockaddr_to_std(&storage);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    let dst = dst.ok_or_else(|| io::Error::other("recvmsg: no original destination"))?;
+    Ok((n as usize, src, dst))
+}
+
+fn sockaddr_to_std(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr =
+                unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe {
+                &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6)
+            };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Maps `::ffff:a.b.c.d` back to an ipv4 address.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recv_with_orig_dst() {
+        // IP_RECVORIGDSTADDR needs no privileges, unlike IP_TRANSPARENT
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        setsockopt_int(&socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR).unwrap();
+        let bind: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        socket.bind(&bind.into()).unwrap();
+        let socket: std::net::UdpSocket = socket.into();
+        let local = socket.local_addr().unwrap();
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"ping", local).unwrap();
+
+        let mut buf = [0u8; 16];
+        let (n, src, dst) = recv_with_orig_dst(&socket, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(src, client.local_addr().unwrap());
+        assert_eq!(dst, local);
+    }
+
+    #[test]
+    fn test_canonical() {
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:53".parse().unwrap();
+        assert_eq!(canonical(mapped), "10.0.0.1:53".parse().unwrap());
+    }
+}
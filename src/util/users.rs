@@ -0,0 +1,92 @@
+//! Credentials accepted by `grpc` and `pb_tcp` listeners: the listener's own
+//! `pw` plus every enabled `[[user]]`.
+
+use crate::def::config;
+use crate::util::auth_token::ct_eq;
+use crate::util::crypto::decrypt_field;
+use std::io;
+
+#[derive(Debug, Clone)]
+pub struct Credential {
+    /// `None` for the listener's `pw`.
+    pub user: Option<String>,
+    pub pw: String,
+}
+
+pub struct UserTable {
+    creds: Vec<Credential>,
+}
+
+impl UserTable {
+    pub fn new(pw: Option<&String>, users: &[config::User]) -> io::Result<Self> {
+        let listener = pw.map(|pw| Credential {
+            user: None,
+            pw: pw.clone(),
+        });
+        let users = users
+            .iter()
+            .filter(|u| u.enabled.unwrap_or(true))
+            .map(|u| Credential {
+                user: Some(u.name.clone()),
+                pw: u.pw.clone(),
+            });
+        let creds: Vec<Credential> = listener.into_iter().chain(users).collect();
+        if creds.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "listener needs a pw or an enabled [[user]]",
+            ));
+        }
+        Ok(Self { creds })
+    }
+
+    pub fn credentials(&self) -> &[Credential] {
+        &self.creds
+    }
+
+    pub fn find(&self, f: impl FnMut(&&Credential) -> bool) -> Option<&Credential> {
+        self.creds.iter().find(f)
+    }
+
+    /// The credential whose password decrypts a pb_tcp `auth` field to itself.
+    pub fn find_sealed(&self, auth: &str) -> Option<&Credential> {
+        self.find(|c| decrypt_field(auth, &c.pw).is_ok_and(|plain| ct_eq(&plain, &c.pw)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::crypto::encrypt_field;
+
+    fn user(name: &str, pw: &str, enabled: bool) -> config::User {
+        config::User {
+            name: name.to_string(),
+            pw: pw.to_string(),
+            enabled: Some(enabled),
+            router: None,
+            connectors: None,
+        }
+    }
+
+    #[test]
+    fn test_user_table() {
+        let users = [user("a", "pa", true), user("b", "pb", false)];
+        let table = UserTable::new(Some(&"pw".to_string()), &users).unwrap();
+        assert_eq!(table.credentials().len(), 2);
+        let sealed = encrypt_field("pa", "pa").unwrap();
+        assert_eq!(
+            table.find_sealed(&sealed).unwrap().user.as_deref(),
+            Some("a")
+        );
+        let sealed = encrypt_field("pw", "pw").unwrap();
+        assert_eq!(table.find_sealed(&sealed).unwrap().user, None);
+        // disabled
+        assert!(
+            table
+                .find_sealed(&encrypt_field("pb", "pb").unwrap())
+                .is_none()
+        );
+        assert!(UserTable::new(None, &users[1..]).is_err());
+    }
+}